@@ -24,7 +24,7 @@ use sp_runtime::{
 	generic::BlockId, traits::{Block as BlockT, HashFor},
 };
 use sp_state_machine::{
-	OverlayedChanges, ExecutionManager, ExecutionStrategy, StorageProof,
+	OverlayedChanges, ExecutionManager, ExecutionStrategy, StorageProof, ConsensusMismatch,
 };
 use sc_executor::{RuntimeVersion, NativeVersion};
 use sp_externalities::Extensions;
@@ -73,10 +73,7 @@ pub trait CallExecutor<B: BlockT> {
 	fn contextual_call<
 		'a,
 		IB: Fn() -> sp_blockchain::Result<()>,
-		EM: Fn(
-			Result<NativeOrEncoded<R>, Self::Error>,
-			Result<NativeOrEncoded<R>, Self::Error>
-		) -> Result<NativeOrEncoded<R>, Self::Error>,
+		EM: Fn(ConsensusMismatch<R, Self::Error>) -> Result<NativeOrEncoded<R>, Self::Error>,
 		R: Encode + Decode + PartialEq,
 		NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
 	>(
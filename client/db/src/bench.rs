@@ -346,6 +346,14 @@ impl<B: BlockT> StateBackend<HashFor<B>> for BenchmarkingState<B> {
 		self.state.borrow().as_ref().map_or(Default::default(), |s| s.pairs())
 	}
 
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		self.state.borrow().as_ref().map_or(Ok(0), |s| s.estimate_pairs_count())
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		self.state.borrow().as_ref().map_or(Ok(0), |s| s.estimate_storage_size())
+	}
+
 	fn keys(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
 		self.state.borrow().as_ref().map_or(Default::default(), |s| s.keys(prefix))
 	}
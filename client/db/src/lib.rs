@@ -227,10 +227,25 @@ impl<B: BlockT> StateBackend<HashFor<B>> for RefTrackingState<B> {
 		self.state.child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_roots<'a>(
+		&self,
+		child_deltas: Vec<(&'a ChildInfo, Vec<(&'a [u8], Option<&'a [u8]>)>)>,
+	) -> Vec<(B::Hash, bool, Self::Transaction)> where B::Hash: Ord {
+		self.state.child_storage_roots(child_deltas)
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.state.pairs()
 	}
 
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		self.state.estimate_pairs_count()
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		self.state.estimate_storage_size()
+	}
+
 	fn keys(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
 		self.state.keys(prefix)
 	}
@@ -713,6 +728,12 @@ impl<Block: BlockT> sc_state_db::NodeDb for StorageDb<Block> {
 	}
 }
 
+impl<Block: BlockT> sc_state_db::ColumnNodeDb for StorageDb<Block> {
+	fn get_in_column(&self, column: u32, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		Ok(self.db.get(column, key))
+	}
+}
+
 struct DbGenesisStorage<Block: BlockT>(pub Block::Hash);
 
 impl<Block: BlockT> DbGenesisStorage<Block> {
@@ -1341,6 +1362,14 @@ fn apply_state_commit(transaction: &mut Transaction<DbHash>, commit: sc_state_db
 	for key in commit.meta.deleted.into_iter() {
 		transaction.remove(columns::STATE_META, &key[..]);
 	}
+	for (column, changes) in commit.columns.into_iter() {
+		for (key, val) in changes.inserted.into_iter() {
+			transaction.set_from_vec(column, &key[..], val);
+		}
+		for key in changes.deleted.into_iter() {
+			transaction.remove(column, &key[..]);
+		}
+	}
 }
 
 impl<Block> sc_client_api::backend::AuxStore for Backend<Block> where Block: BlockT {
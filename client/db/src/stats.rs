@@ -128,6 +128,7 @@ impl StateUsageStats {
 			removed_nodes: unit(&self.removed_nodes, &self.bytes_removed_nodes),
 			cache_reads: unit(&self.reads_cache, &self.bytes_read_cache),
 			modified_reads: Default::default(),
+			well_known_reads: Default::default(),
 			overlay_writes: Default::default(),
 			// TODO: Proper tracking state of memory footprint here requires
 			//       imposing `MallocSizeOf` requirement on half of the codebase,
@@ -636,10 +636,25 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Cachin
 		self.state.child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_roots<'a>(
+		&self,
+		child_deltas: Vec<(&'a ChildInfo, Vec<(&'a [u8], Option<&'a [u8]>)>)>,
+	) -> Vec<(B::Hash, bool, Self::Transaction)> where B::Hash: Ord {
+		self.state.child_storage_roots(child_deltas)
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.state.pairs()
 	}
 
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		self.state.estimate_pairs_count()
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		self.state.estimate_storage_size()
+	}
+
 	fn keys(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
 		self.state.keys(prefix)
 	}
@@ -818,10 +833,25 @@ impl<S: StateBackend<HashFor<B>>, B: BlockT> StateBackend<HashFor<B>> for Syncin
 		self.caching_state().child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_roots<'a>(
+		&self,
+		child_deltas: Vec<(&'a ChildInfo, Vec<(&'a [u8], Option<&'a [u8]>)>)>,
+	) -> Vec<(B::Hash, bool, Self::Transaction)> where B::Hash: Ord {
+		self.caching_state().child_storage_roots(child_deltas)
+	}
+
 	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
 		self.caching_state().pairs()
 	}
 
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		self.caching_state().estimate_pairs_count()
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		self.caching_state().estimate_storage_size()
+	}
+
 	fn keys(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
 		self.caching_state().keys(prefix)
 	}
@@ -33,7 +33,7 @@ use sp_runtime::{
 use sp_externalities::Extensions;
 use sp_state_machine::{
 	self, Backend as StateBackend, OverlayedChanges, ExecutionStrategy, create_proof_check_backend,
-	execution_proof_check_on_trie_backend, ExecutionManager, StorageProof,
+	execution_proof_check_on_trie_backend, ExecutionManager, StorageProof, ConsensusMismatch,
 };
 use hash_db::Hasher;
 
@@ -100,10 +100,7 @@ impl<Block, B, Local> CallExecutor<Block> for
 	fn contextual_call<
 		'a,
 		IB: Fn() -> ClientResult<()>,
-		EM: Fn(
-			Result<NativeOrEncoded<R>, Self::Error>,
-			Result<NativeOrEncoded<R>, Self::Error>
-		) -> Result<NativeOrEncoded<R>, Self::Error>,
+		EM: Fn(ConsensusMismatch<R, Self::Error>) -> Result<NativeOrEncoded<R>, Self::Error>,
 		R: Encode + Decode + PartialEq,
 		NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
 	>(
@@ -127,10 +124,7 @@ impl<Block, B, Local> CallExecutor<Block> for
 		match self.backend.is_local_state_available(at) {
 			true => CallExecutor::contextual_call::<
 				_,
-				fn(
-					Result<NativeOrEncoded<R>, Local::Error>,
-					Result<NativeOrEncoded<R>, Local::Error>,
-				) -> Result<NativeOrEncoded<R>, Local::Error>,
+				fn(ConsensusMismatch<R, Local::Error>) -> Result<NativeOrEncoded<R>, Local::Error>,
 				_,
 				NC
 			>(
@@ -23,7 +23,7 @@ use sp_runtime::{
 };
 use sp_state_machine::{
 	self, OverlayedChanges, Ext, ExecutionManager, StateMachine, ExecutionStrategy,
-	backend::Backend as _, StorageProof,
+	backend::Backend as _, StorageProof, ConsensusMismatch,
 };
 use sc_executor::{RuntimeVersion, RuntimeInfo, NativeVersion};
 use sp_externalities::Extensions;
@@ -123,10 +123,7 @@ where
 	fn contextual_call<
 		'a,
 		IB: Fn() -> sp_blockchain::Result<()>,
-		EM: Fn(
-			Result<NativeOrEncoded<R>, Self::Error>,
-			Result<NativeOrEncoded<R>, Self::Error>
-		) -> Result<NativeOrEncoded<R>, Self::Error>,
+		EM: Fn(ConsensusMismatch<R, Self::Error>) -> Result<NativeOrEncoded<R>, Self::Error>,
 		R: Encode + Decode + PartialEq,
 		NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
 	>(
@@ -179,6 +176,8 @@ where
 					trie_state,
 					recorder.clone(),
 				);
+				let mut extensions = extensions.unwrap_or_default();
+				extensions.register(backend.proof_size_ext());
 
 				let mut state_machine = StateMachine::new(
 					&backend,
@@ -188,7 +187,7 @@ where
 					&self.executor,
 					method,
 					call_data,
-					extensions.unwrap_or_default(),
+					extensions,
 					&runtime_code,
 					self.spawn_handle.clone(),
 				);
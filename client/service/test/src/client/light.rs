@@ -55,7 +55,7 @@ use sp_blockchain::{
 };
 use std::panic::UnwindSafe;
 use std::cell::RefCell;
-use sp_state_machine::{OverlayedChanges, ExecutionManager};
+use sp_state_machine::{OverlayedChanges, ExecutionManager, ConsensusMismatch};
 use parity_scale_codec::{Decode, Encode};
 use super::prepare_client_with_key_changes;
 use substrate_test_runtime_client::{
@@ -208,10 +208,7 @@ impl CallExecutor<Block> for DummyCallExecutor {
 	fn contextual_call<
 		'a,
 		IB: Fn() -> ClientResult<()>,
-		EM: Fn(
-			Result<NativeOrEncoded<R>, Self::Error>,
-			Result<NativeOrEncoded<R>, Self::Error>
-		) -> Result<NativeOrEncoded<R>, Self::Error>,
+		EM: Fn(ConsensusMismatch<R, Self::Error>) -> Result<NativeOrEncoded<R>, Self::Error>,
 		R: Encode + Decode + PartialEq,
 		NC: FnOnce() -> Result<R, String> + UnwindSafe,
 	>(
@@ -0,0 +1,203 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Audit mode.
+//! Keeps a rolling log of the commit sets produced by the state db. A complete log can be
+//! replayed onto an empty database with `replay`, and the resulting state compared with the one
+//! of another node to find where they diverge.
+//! Commit sets of `insert_block` and `canonicalize_block` are only logged once confirmed
+//! by `apply_pending`, reverted ones are dropped. Commit sets of `revert_one`, `keep_prefix` and
+//! `set_best` are logged immediately.
+
+use std::collections::{HashMap, VecDeque};
+use codec::{Encode, Decode};
+use crate::{ColumnId, CommitSet, DBValue, Hash};
+
+/// Operation that produced a logged commit set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum AuditOperation {
+	/// `insert_block`.
+	InsertBlock,
+	/// `canonicalize_block`, including pruning.
+	Canonicalize,
+	/// `revert_one`.
+	Revert,
+	/// `keep_prefix`.
+	KeepPrefix,
+	/// `set_best`.
+	SetBest,
+}
+
+/// An entry of the replay log.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct ReplayEntry<Key: Hash> {
+	/// Sequence number. Starts at 0 and increases by one for each logged commit set.
+	pub sequence: u64,
+	/// Operation that produced the commit set.
+	pub operation: AuditOperation,
+	/// The logged commit set.
+	pub commit: CommitSet<Key>,
+}
+
+/// Rolling log of commit sets.
+pub(crate) struct ReplayLog<Key: Hash> {
+	capacity: usize,
+	next_sequence: u64,
+	entries: VecDeque<ReplayEntry<Key>>,
+	pending: Vec<(AuditOperation, CommitSet<Key>)>,
+}
+
+impl<Key: Hash> ReplayLog<Key> {
+	/// Creates a log keeping at most `capacity` entries.
+	pub fn new(capacity: usize) -> ReplayLog<Key> {
+		ReplayLog {
+			capacity,
+			next_sequence: 0,
+			entries: Default::default(),
+			pending: Default::default(),
+		}
+	}
+
+	/// Log a commit set that does not need to be confirmed.
+	pub fn note(&mut self, operation: AuditOperation, commit: &CommitSet<Key>) {
+		self.push(operation, commit.clone());
+	}
+
+	/// Log a commit set once `apply_pending` is called.
+	pub fn note_pending(&mut self, operation: AuditOperation, commit: &CommitSet<Key>) {
+		self.pending.push((operation, commit.clone()));
+	}
+
+	pub fn apply_pending(&mut self) {
+		for (operation, commit) in std::mem::take(&mut self.pending) {
+			self.push(operation, commit);
+		}
+	}
+
+	pub fn revert_pending(&mut self) {
+		self.pending.clear();
+	}
+
+	/// Logged entries, oldest first.
+	pub fn entries(&self) -> Vec<ReplayEntry<Key>> {
+		self.entries.iter().cloned().collect()
+	}
+
+	fn push(&mut self, operation: AuditOperation, commit: CommitSet<Key>) {
+		if self.capacity == 0 {
+			return;
+		}
+		if self.entries.len() == self.capacity {
+			self.entries.pop_front();
+		}
+		self.entries.push_back(ReplayEntry {
+			sequence: self.next_sequence,
+			operation,
+			commit,
+		});
+		self.next_sequence += 1;
+	}
+}
+
+/// Database content obtained by replaying a log.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReplayState<Key: Hash> {
+	/// State nodes.
+	pub data: HashMap<Key, DBValue>,
+	/// Metadata.
+	pub meta: HashMap<Vec<u8>, DBValue>,
+	/// Nodes of the other columns.
+	pub columns: HashMap<(ColumnId, Key), DBValue>,
+}
+
+/// Replay logged commit sets onto an empty database.
+///
+/// Entries must form a complete log, starting at sequence 0 with no gap. Otherwise the expected
+/// sequence number is returned as an error.
+pub fn replay<'a, Key: Hash>(
+	entries: impl IntoIterator<Item = &'a ReplayEntry<Key>>,
+) -> Result<ReplayState<Key>, u64> {
+	let mut state = ReplayState {
+		data: HashMap::new(),
+		meta: HashMap::new(),
+		columns: HashMap::new(),
+	};
+	let mut expected = 0;
+	for entry in entries {
+		if entry.sequence != expected {
+			return Err(expected);
+		}
+		let commit = &entry.commit;
+		state.data.extend(commit.data.inserted.iter().cloned());
+		for k in commit.data.deleted.iter() {
+			state.data.remove(k);
+		}
+		state.meta.extend(commit.meta.inserted.iter().cloned());
+		for k in commit.meta.deleted.iter() {
+			state.meta.remove(k);
+		}
+		for (column, changes) in commit.columns.iter() {
+			for (k, v) in changes.inserted.iter() {
+				state.columns.insert((*column, k.clone()), v.clone());
+			}
+			for k in changes.deleted.iter() {
+				state.columns.remove(&(*column, k.clone()));
+			}
+		}
+		expected += 1;
+	}
+	Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ReplayLog, AuditOperation, replay};
+	use sp_core::H256;
+	use crate::test::{make_db, make_commit};
+
+	#[test]
+	fn replay_matches_db() {
+		let mut db = make_db(&[]);
+		let mut log = ReplayLog::new(16);
+		let commit = make_commit(&[1, 2], &[]);
+		db.commit(&commit);
+		log.note_pending(AuditOperation::InsertBlock, &commit);
+		log.apply_pending();
+
+		log.note_pending(AuditOperation::Canonicalize, &make_commit(&[3], &[]));
+		log.revert_pending();
+
+		let commit = make_commit(&[4], &[1]);
+		db.commit(&commit);
+		log.note(AuditOperation::Revert, &commit);
+
+		let entries = log.entries();
+		assert_eq!(entries.iter().map(|e| e.sequence).collect::<Vec<_>>(), vec![0, 1]);
+		let state = replay(entries.iter()).unwrap();
+		assert_eq!(state.data, db.data);
+		assert_eq!(state.meta, db.meta);
+	}
+
+	#[test]
+	fn replay_detects_incomplete_log() {
+		let mut log = ReplayLog::<H256>::new(1);
+		log.note(AuditOperation::InsertBlock, &make_commit(&[1], &[]));
+		log.note(AuditOperation::InsertBlock, &make_commit(&[2], &[]));
+		assert_eq!(replay(log.entries().iter()).unwrap_err(), 0);
+	}
+}
@@ -31,29 +31,43 @@
 //! See `RefWindow` for pruning algorithm details. `StateDb` prunes on each canonicalization until pruning
 //! constraints are satisfied.
 
+mod audit;
 mod noncanonical;
 mod pruning;
 #[cfg(test)]
 mod test;
 
 use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use parking_lot::RwLock;
-use codec::Codec;
+use codec::{Codec, Encode, Decode};
 use std::collections::{HashMap, hash_map::Entry};
 use noncanonical::NonCanonicalOverlay;
 use pruning::RefWindow;
-use log::trace;
+use audit::ReplayLog;
+use log::{trace, warn};
 use parity_util_mem::{MallocSizeOf, malloc_size};
 use sc_client_api::{StateDbMemoryInfo, MemorySize};
 
+pub use audit::{AuditOperation, ReplayEntry, ReplayState, replay};
+
 const PRUNING_MODE: &[u8] = b"mode";
 const PRUNING_MODE_ARCHIVE: &[u8] = b"archive";
 const PRUNING_MODE_ARCHIVE_CANON: &[u8] = b"archive_canonical";
 const PRUNING_MODE_CONSTRAINED: &[u8] = b"constrained";
+const BEST_BLOCK: &[u8] = b"best_block";
+/// First bytes of a meta export.
+const META_EXPORT_MAGIC: &[u8] = b"statedb_meta";
+/// Version of the meta export format.
+const META_EXPORT_VERSION: u32 = 1;
 
 /// Database value type.
 pub type DBValue = Vec<u8>;
 
+/// Identifier of a column of the backing database.
+pub type ColumnId = u32;
+
 /// Basic set of requirements for the Block hash and node key types.
 pub trait Hash: Send + Sync + Sized + Eq + PartialEq + Clone + Default + fmt::Debug + Codec + std::hash::Hash + 'static {}
 impl<T: Send + Sync + Sized + Eq + PartialEq + Clone + Default + fmt::Debug + Codec + std::hash::Hash + 'static> Hash for T {}
@@ -75,6 +89,27 @@ pub trait NodeDb {
 	fn get(&self, key: &Self::Key) -> Result<Option<DBValue>, Self::Error>;
 }
 
+/// Backend database also storing nodes outside of the state column. Read-only.
+pub trait ColumnNodeDb: NodeDb {
+	/// Get a node from `column`, one of the columns written by `CommitSet::columns`.
+	fn get_in_column(&self, column: ColumnId, key: &Self::Key) -> Result<Option<DBValue>, Self::Error>;
+}
+
+/// Maintains an auxiliary index from the changes of canonicalized blocks.
+pub trait CanonicalizationIndexer<BlockHash, Key>: Send + Sync {
+	/// Called when block `hash` at height `number` is canonicalized, with the keys of the state
+	/// nodes inserted and deleted by the block itself, whatever the pruning mode. Entries added to
+	/// `meta` are written with the canonicalization commit set.
+	fn on_canonicalize(
+		&mut self,
+		hash: &BlockHash,
+		number: u64,
+		inserted: &[Key],
+		deleted: &[Key],
+		meta: &mut ChangeSet<Vec<u8>>,
+	);
+}
+
 /// Error type.
 pub enum Error<E: fmt::Debug> {
 	/// Database backend error.
@@ -89,6 +124,37 @@ pub enum Error<E: fmt::Debug> {
 	InvalidParent,
 	/// Invalid pruning mode specified. Contains expected mode.
 	InvalidPruningMode(String),
+	/// Trying to insert a block at a height that already has the maximum number of forks.
+	TooManyForks,
+}
+
+/// Availability of the state of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateAvailability {
+	/// The state is available.
+	Available,
+	/// The state was discarded by canonicalization or pruning, but is kept while the block is
+	/// pinned.
+	AvailablePinnedOnly,
+	/// The state of this canonical block was pruned. Contains the number of the oldest block
+	/// whose state is still kept.
+	PrunedAt(u64),
+	/// The block is not known: it was never inserted or is on a discarded fork.
+	NeverKnown,
+	/// The state of this non-canonical block is available but the block is not on the chain of
+	/// the best block. Only returned by `state_availability_at_best`.
+	OffBestChain,
+}
+
+/// Information about a pinned block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinInfo {
+	/// Tag given by the creator of the first pin, if any.
+	pub tag: Option<&'static str>,
+	/// Time of the first pin.
+	pub pinned_at: Instant,
+	/// Number of references held.
+	pub refs: u32,
 }
 
 /// Pinning error type.
@@ -112,12 +178,13 @@ impl<E: fmt::Debug> fmt::Debug for Error<E> {
 			Error::InvalidBlockNumber => write!(f, "Trying to insert block with invalid number"),
 			Error::InvalidParent => write!(f, "Trying to insert block with unknown parent"),
 			Error::InvalidPruningMode(e) => write!(f, "Expected pruning mode: {}", e),
+			Error::TooManyForks => write!(f, "Trying to insert block at a height with too many forks"),
 		}
 	}
 }
 
 /// A set of state node changes.
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Encode, Decode)]
 pub struct ChangeSet<H: Hash> {
 	/// Inserted nodes.
 	pub inserted: Vec<(H, DBValue)>,
@@ -126,12 +193,28 @@ pub struct ChangeSet<H: Hash> {
 }
 
 /// A set of changes to the backing database.
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, Encode, Decode)]
 pub struct CommitSet<H: Hash> {
 	/// State node changes.
 	pub data: ChangeSet<H>,
 	/// Metadata changes.
 	pub meta: ChangeSet<Vec<u8>>,
+	/// Node changes of other columns, by column. They bypass the non-canonical and pruning
+	/// overlays and are written as is.
+	pub columns: Vec<(ColumnId, ChangeSet<H>)>,
+}
+
+impl<H: Hash> CommitSet<H> {
+	/// Add node changes to `column`, after the ones already in the set for this column.
+	pub fn add_column_changes(&mut self, column: ColumnId, changes: ChangeSet<H>) {
+		match self.columns.iter_mut().find(|(c, _)| *c == column) {
+			Some((_, existing)) => {
+				existing.inserted.extend(changes.inserted);
+				existing.deleted.extend(changes.deleted);
+			},
+			None => self.columns.push((column, changes)),
+		}
+	}
 }
 
 /// Pruning constraints. If none are specified pruning is
@@ -141,6 +224,55 @@ pub struct Constraints {
 	pub max_blocks: Option<u32>,
 	/// Maximum memory in the pruning overlay.
 	pub max_mem: Option<usize>,
+	/// Maximum number of non-canonical blocks at the same height. Unlimited when unspecified.
+	pub max_forks_per_level: Option<u32>,
+}
+
+/// Time source of the time-based features: pin ages and pruning strategies.
+pub trait Clock: Send + Sync {
+	/// Current time.
+	fn now(&self) -> Instant;
+}
+
+/// `Clock` reading the system monotonic clock, the default one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+}
+
+/// Decides when the oldest block of the pruning window is pruned in `Constrained` mode.
+///
+/// `Constraints` is the default strategy. Pinned blocks are never pruned, whatever the strategy.
+pub trait PruningStrategy<BlockHash>: Send + Sync {
+	/// Whether the oldest block of the window, `hash` at height `number`, can be pruned while the
+	/// window holds `window_size` blocks using `mem_used` bytes. `now` is read from the state db
+	/// `Clock`. Returning `false` stops pruning until the next canonicalization.
+	fn may_prune(
+		&self,
+		hash: &BlockHash,
+		number: u64,
+		window_size: u64,
+		mem_used: usize,
+		now: Instant,
+	) -> bool;
+}
+
+impl<BlockHash> PruningStrategy<BlockHash> for Constraints {
+	fn may_prune(
+		&self,
+		_hash: &BlockHash,
+		_number: u64,
+		window_size: u64,
+		mem_used: usize,
+		_now: Instant,
+	) -> bool {
+		window_size > self.max_blocks.unwrap_or(0) as u64
+			&& !self.max_mem.map_or(false, |m| mem_used > m)
+	}
 }
 
 /// Pruning mode.
@@ -160,6 +292,7 @@ impl PruningMode {
 		PruningMode::Constrained(Constraints {
 			max_blocks: Some(n),
 			max_mem: None,
+			max_forks_per_level: None,
 		})
 	}
 
@@ -193,14 +326,87 @@ fn to_meta_key<S: Codec>(suffix: &[u8], data: &S) -> Vec<u8> {
 	buffer
 }
 
+/// Length of the checksum appended to journal records.
+const CHECKSUM_LEN: usize = 4;
+
+/// CRC-32 (IEEE) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for byte in data {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+		}
+	}
+	!crc
+}
+
+/// Encode a journal record and append its checksum.
+fn encode_checked<T: Encode>(record: &T) -> Vec<u8> {
+	let mut buffer = record.encode();
+	let checksum = crc32(&buffer);
+	buffer.extend_from_slice(&checksum.to_le_bytes());
+	buffer
+}
+
+/// Decode a journal record written by `encode_checked`. Records written before checksums were
+/// introduced are accepted as long as they decode exactly. Returns `None` for a torn or otherwise
+/// corrupted record.
+fn decode_checked<T: Decode>(buffer: &[u8]) -> Option<T> {
+	fn decode_exact<T: Decode>(mut buffer: &[u8]) -> Option<T> {
+		let decoded = T::decode(&mut buffer).ok()?;
+		if buffer.is_empty() { Some(decoded) } else { None }
+	}
+
+	if buffer.len() >= CHECKSUM_LEN {
+		let (record, checksum) = buffer.split_at(buffer.len() - CHECKSUM_LEN);
+		if &crc32(record).to_le_bytes()[..] == checksum {
+			if let Some(decoded) = decode_exact(record) {
+				return Some(decoded);
+			}
+		}
+	}
+	decode_exact(buffer)
+}
+
+/// Delete the `truncated` journal records with `commit`, except `rewritten` which the commit
+/// overwrites. They are moved to `pending` until the commit is applied, and restored from there
+/// if it is reverted.
+fn delete_truncated_journals<Key: Hash>(
+	truncated: &mut Vec<Vec<u8>>,
+	pending: &mut Vec<Vec<u8>>,
+	rewritten: Option<&[u8]>,
+	commit: &mut CommitSet<Key>,
+) {
+	for journal_key in truncated.drain(..) {
+		if rewritten != Some(&journal_key[..]) {
+			commit.meta.deleted.push(journal_key.clone());
+		}
+		pending.push(journal_key);
+	}
+}
+
 struct StateDbSync<BlockHash: Hash, Key: Hash> {
 	mode: PruningMode,
 	non_canonical: NonCanonicalOverlay<BlockHash, Key>,
 	pruning: Option<RefWindow<BlockHash, Key>>,
 	pinned: HashMap<BlockHash, u32>,
+	// tag and time of the first pin, by pinned block.
+	pin_origins: HashMap<BlockHash, (Option<&'static str>, Instant)>,
+	audit: Option<ReplayLog<Key>>,
+	indexers: Vec<Box<dyn CanonicalizationIndexer<BlockHash, Key>>>,
+	pruning_strategy: Option<Box<dyn PruningStrategy<BlockHash>>>,
+	clock: Arc<dyn Clock>,
+	// journal footprint thresholds in bytes, ascending, and how many of them are crossed.
+	journal_thresholds: Vec<usize>,
+	journal_thresholds_crossed: usize,
+	best: Option<(BlockHash, u64)>,
+	// best block to restore if pending changes are reverted.
+	pending_best: Option<Option<(BlockHash, u64)>>,
 }
 
-impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<BlockHash, Key> {
+impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf + AsRef<[u8]>> StateDbSync<BlockHash, Key> {
 	fn new<D: MetaDb>(
 		mode: PruningMode,
 		ref_counting: bool,
@@ -220,12 +426,26 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 			PruningMode::Constrained(_) => Some(RefWindow::new(db, ref_counting)?),
 			PruningMode::ArchiveAll | PruningMode::ArchiveCanonical => None,
 		};
+		let best = match db.get_meta(&to_meta_key(BEST_BLOCK, &())).map_err(Error::Db)? {
+			Some(buffer) => Some(decode_checked::<(BlockHash, u64)>(&buffer)
+				.ok_or_else(|| codec::Error::from("Corrupted best block record"))?),
+			None => None,
+		};
 
 		Ok(StateDbSync {
 			mode,
 			non_canonical,
 			pruning,
 			pinned: Default::default(),
+			pin_origins: Default::default(),
+			audit: None,
+			indexers: Vec::new(),
+			pruning_strategy: None,
+			clock: Arc::new(SystemClock),
+			journal_thresholds: Vec::new(),
+			journal_thresholds_crossed: 0,
+			best,
+			pending_best: None,
 		})
 	}
 
@@ -262,10 +482,17 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 				Ok(CommitSet {
 					data: changeset,
 					meta,
+					columns: Vec::new(),
 				})
 			},
 			PruningMode::Constrained(_) | PruningMode::ArchiveCanonical => {
+				if let PruningMode::Constrained(Constraints { max_forks_per_level: Some(max_forks), .. }) = self.mode {
+					if self.non_canonical.level_width(number) >= max_forks as usize {
+						return Err(Error::TooManyForks);
+					}
+				}
 				let commit = self.non_canonical.insert(hash, number, parent_hash, changeset);
+				self.check_journal_thresholds();
 				commit.map(|mut c| {
 					c.meta.inserted.extend(meta.inserted);
 					c
@@ -283,7 +510,25 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 			return Ok(commit)
 		}
 		match self.non_canonical.canonicalize(&hash, &mut commit) {
-			Ok(()) => {
+			Ok(number) => {
+				// The best block must descend from the canonicalized one.
+				if let Some((best_hash, best_number)) = self.best.clone() {
+					if self.non_canonical.ancestor(&best_hash, best_number, number).as_ref() != Some(hash) {
+						trace!(target: "state-db", "Best block {:?} discarded, now {:?}", best_hash, hash);
+						if self.pending_best.is_none() {
+							self.pending_best = Some(self.best.clone());
+						}
+						self.set_best_unchecked(hash.clone(), number, &mut commit.meta);
+					}
+				}
+				if !self.indexers.is_empty() {
+					let number = self.best_canonical().expect("A block was just canonicalized; qed");
+					let (inserted, deleted) = self.non_canonical.block_changes(hash)
+						.expect("A block was just canonicalized; qed");
+					for indexer in self.indexers.iter_mut() {
+						indexer.on_canonicalize(hash, number, inserted, deleted, &mut commit.meta);
+					}
+				}
 				if self.mode == PruningMode::ArchiveCanonical {
 					commit.data.deleted.clear();
 				}
@@ -294,6 +539,7 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 			pruning.note_canonical(&hash, &mut commit);
 		}
 		self.prune(&mut commit);
+		self.check_journal_thresholds();
 		Ok(commit)
 	}
 
@@ -319,19 +565,57 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 		}
 	}
 
+	fn state_availability(&self, hash: &BlockHash, number: u64) -> StateAvailability {
+		let availability = match self.mode {
+			PruningMode::ArchiveAll => StateAvailability::Available,
+			PruningMode::ArchiveCanonical | PruningMode::Constrained(_) => {
+				if self.best_canonical().map(|c| number > c).unwrap_or(true) {
+					if self.non_canonical.have_block(hash) {
+						StateAvailability::Available
+					} else {
+						StateAvailability::NeverKnown
+					}
+				} else {
+					match self.pruning {
+						None => StateAvailability::Available,
+						Some(ref pruning) if number < pruning.pending() =>
+							StateAvailability::PrunedAt(pruning.pending()),
+						Some(ref pruning) if !pruning.have_block(hash) => StateAvailability::NeverKnown,
+						Some(_) => StateAvailability::Available,
+					}
+				}
+			}
+		};
+		if availability != StateAvailability::Available && self.pinned.contains_key(hash) {
+			StateAvailability::AvailablePinnedOnly
+		} else {
+			availability
+		}
+	}
+
 	fn prune(&mut self, commit: &mut CommitSet<Key>) {
 		if let (&mut Some(ref mut pruning), &PruningMode::Constrained(ref constraints)) = (&mut self.pruning, &self.mode) {
+			let strategy: &dyn PruningStrategy<BlockHash> = match self.pruning_strategy {
+				Some(ref strategy) => strategy.as_ref(),
+				None => constraints,
+			};
+			let now = self.clock.now();
 			loop {
-				if pruning.window_size() <= constraints.max_blocks.unwrap_or(0) as u64 {
+				let next = match pruning.next_hash() {
+					Some(hash) => hash,
+					None => break,
+				};
+				if !strategy.may_prune(
+					&next,
+					pruning.pending(),
+					pruning.window_size(),
+					pruning.mem_used(),
+					now,
+				) {
 					break;
 				}
 
-				if constraints.max_mem.map_or(false, |m| pruning.mem_used() > m) {
-					break;
-				}
-
-				let pinned = &self.pinned;
-				if pruning.next_hash().map_or(false, |h| pinned.contains_key(&h)) {
+				if self.pinned.contains_key(&next) {
 					break;
 				}
 				pruning.prune_one(commit);
@@ -339,6 +623,14 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 		}
 	}
 
+	fn keep_prefix(&mut self, prefix: &[u8]) -> CommitSet<Key> {
+		let mut commit = CommitSet::default();
+		if let Some(ref mut pruning) = self.pruning {
+			pruning.keep_prefix(prefix, &mut commit);
+		}
+		commit
+	}
+
 	/// Revert all non-canonical blocks with the best block number.
 	/// Returns a database commit or `None` if not possible.
 	/// For archive an empty commit set is returned.
@@ -348,12 +640,69 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 				Some(CommitSet::default())
 			},
 			PruningMode::ArchiveCanonical | PruningMode::Constrained(_) => {
-				self.non_canonical.revert_one()
+				let top = self.non_canonical.level_widths().last().map(|(number, _)| *number);
+				// The parent of a reverted best block becomes the best block.
+				let best_parent = match (self.best.clone(), top) {
+					(Some((hash, number)), Some(top)) if number == top && number > 0 =>
+						self.non_canonical.ancestor(&hash, number, number - 1)
+							.map(|parent| (parent, number - 1)),
+					_ => None,
+				};
+				let mut commit = self.non_canonical.revert_one()?;
+				if let Some((parent, number)) = best_parent {
+					self.set_best_unchecked(parent, number, &mut commit.meta);
+				}
+				Some(commit)
 			},
 		}
 	}
 
-	fn pin(&mut self, hash: &BlockHash) -> Result<(), PinError> {
+	fn best(&self) -> Option<(BlockHash, u64)> {
+		self.best.clone()
+	}
+
+	fn set_best<E: fmt::Debug>(&mut self, hash: &BlockHash, number: u64) -> Result<CommitSet<Key>, Error<E>> {
+		let known = match self.mode {
+			PruningMode::ArchiveAll => true,
+			PruningMode::ArchiveCanonical | PruningMode::Constrained(_) =>
+				match self.non_canonical.last_canonicalized_block() {
+					Some((canonical_hash, canonical_number)) if number >= canonical_number =>
+						self.non_canonical.ancestor(hash, number, canonical_number) == Some(canonical_hash),
+					_ => false,
+				},
+		};
+		if !known {
+			return Err(Error::InvalidBlock);
+		}
+		let mut commit = CommitSet::default();
+		self.set_best_unchecked(hash.clone(), number, &mut commit.meta);
+		Ok(commit)
+	}
+
+	fn set_best_unchecked(&mut self, hash: BlockHash, number: u64, meta: &mut ChangeSet<Vec<u8>>) {
+		let best = (hash, number);
+		meta.inserted.push((to_meta_key(BEST_BLOCK, &()), encode_checked(&best)));
+		self.best = Some(best);
+	}
+
+	fn state_availability_at_best(&self, hash: &BlockHash, number: u64) -> StateAvailability {
+		let availability = self.state_availability(hash, number);
+		let (best_hash, best_number) = match (&self.best, availability) {
+			(Some(best), StateAvailability::Available) => best.clone(),
+			_ => return availability,
+		};
+		let canonical = self.best_canonical().map_or(false, |c| number <= c);
+		if canonical || self.mode == PruningMode::ArchiveAll {
+			return availability;
+		}
+		if self.non_canonical.ancestor(&best_hash, best_number, number).as_ref() == Some(hash) {
+			availability
+		} else {
+			StateAvailability::OffBestChain
+		}
+	}
+
+	fn pin(&mut self, hash: &BlockHash, tag: Option<&'static str>) -> Result<(), PinError> {
 		match self.mode {
 			PruningMode::ArchiveAll => Ok(()),
 			PruningMode::ArchiveCanonical | PruningMode::Constrained(_) => {
@@ -364,6 +713,7 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 					if *refs == 0 {
 						trace!(target: "state-db-pin", "Pinned block: {:?}", hash);
 						self.non_canonical.pin(hash);
+						self.pin_origins.insert(hash.clone(), (tag, self.clock.now()));
 					}
 					*refs += 1;
 					Ok(())
@@ -381,6 +731,7 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 				if *entry.get() == 0 {
 					trace!(target: "state-db-pin", "Unpinned block: {:?}", hash);
 					entry.remove();
+					self.pin_origins.remove(hash);
 					self.non_canonical.unpin(hash);
 				} else {
 					trace!(target: "state-db-pin", "Releasing reference for {:?}", hash);
@@ -390,6 +741,39 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 		}
 	}
 
+	fn pins(&self) -> Vec<(BlockHash, PinInfo)> {
+		self.pinned.iter().map(|(hash, refs)| {
+			let (tag, pinned_at) = self.pin_origins.get(hash).cloned()
+				.expect("Origin is recorded for each pinned block; qed");
+			(hash.clone(), PinInfo { tag, pinned_at, refs: *refs })
+		}).collect()
+	}
+
+	fn unpin_all_older_than(&mut self, age: Duration) -> Vec<BlockHash> {
+		let now = self.clock.now();
+		// A pin taken after `now` (clock going backwards) is not expired.
+		let expired: Vec<_> = self.pins().into_iter()
+			.filter_map(|(hash, info)| {
+				let held = now.checked_duration_since(info.pinned_at)?;
+				if held >= age { Some((hash, info, held)) } else { None }
+			})
+			.collect();
+		for (hash, info, held) in expired.iter() {
+			warn!(
+				target: "state-db-pin",
+				"Force-releasing {} pin(s) of block {:?} (tag: {:?}), held for {:?}",
+				info.refs,
+				hash,
+				info.tag,
+				held,
+			);
+			for _ in 0..info.refs {
+				self.unpin(hash);
+			}
+		}
+		expired.into_iter().map(|(hash, _, _)| hash).collect()
+	}
+
 	pub fn get<D: NodeDb, Q: ?Sized>(&self, key: &Q, db: &D) -> Result<Option<DBValue>, Error<D::Error>>
 	where
 		Q: AsRef<D::Key>,
@@ -407,6 +791,11 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 		if let Some(pruning) = &mut self.pruning {
 			pruning.apply_pending();
 		}
+		if let Some(audit) = &mut self.audit {
+			audit.apply_pending();
+		}
+		self.pending_best = None;
+		self.check_journal_thresholds();
 		trace!(
 			target: "forks",
 			"First available: {:?} ({}), Last canon: {:?} ({}), Best forks: {:?}",
@@ -423,6 +812,76 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDbSync<Block
 			pruning.revert_pending();
 		}
 		self.non_canonical.revert_pending();
+		if let Some(audit) = &mut self.audit {
+			audit.revert_pending();
+		}
+		if let Some(best) = self.pending_best.take() {
+			self.best = best;
+		}
+		self.check_journal_thresholds();
+	}
+
+	fn journal_bytes(&self) -> usize {
+		self.non_canonical.journal_bytes() + self.pruning.as_ref().map_or(0, |p| p.journal_bytes())
+	}
+
+	fn set_journal_thresholds(&mut self, mut thresholds: Vec<usize>) {
+		thresholds.sort();
+		thresholds.dedup();
+		self.journal_thresholds = thresholds;
+		self.journal_thresholds_crossed = 0;
+		self.check_journal_thresholds();
+	}
+
+	// Warn once when the journal footprint crosses a threshold, and again only after it went
+	// back below it.
+	fn check_journal_thresholds(&mut self) {
+		let bytes = self.journal_bytes();
+		let crossed = self.journal_thresholds.iter().take_while(|t| bytes >= **t).count();
+		if crossed > self.journal_thresholds_crossed {
+			warn!(
+				target: "state-db",
+				"State journal footprint is {} bytes, above the {} bytes threshold",
+				bytes,
+				self.journal_thresholds[crossed - 1],
+			);
+		}
+		self.journal_thresholds_crossed = crossed;
+	}
+
+	fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+		self.clock = clock;
+	}
+
+	fn set_pruning_strategy(&mut self, strategy: Option<Box<dyn PruningStrategy<BlockHash>>>) {
+		self.pruning_strategy = strategy;
+	}
+
+	fn add_canonicalization_indexer(&mut self, indexer: Box<dyn CanonicalizationIndexer<BlockHash, Key>>) {
+		self.indexers.push(indexer);
+	}
+
+	fn set_audit(&mut self, capacity: Option<usize>) {
+		self.audit = capacity.map(ReplayLog::new);
+	}
+
+	fn audit(&mut self, operation: AuditOperation, commit: &CommitSet<Key>) {
+		if let Some(audit) = &mut self.audit {
+			match operation {
+				AuditOperation::InsertBlock | AuditOperation::Canonicalize =>
+					audit.note_pending(operation, commit),
+				AuditOperation::Revert | AuditOperation::KeepPrefix | AuditOperation::SetBest =>
+					audit.note(operation, commit),
+			}
+		}
+	}
+
+	fn audit_log(&self) -> Vec<ReplayEntry<Key>> {
+		self.audit.as_ref().map(|audit| audit.entries()).unwrap_or_default()
+	}
+
+	fn level_widths(&self) -> Vec<(u64, usize)> {
+		self.non_canonical.level_widths()
 	}
 
 	fn memory_info(&self) -> StateDbMemoryInfo {
@@ -440,7 +899,7 @@ pub struct StateDb<BlockHash: Hash, Key: Hash> {
 	db: RwLock<StateDbSync<BlockHash, Key>>,
 }
 
-impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash, Key> {
+impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf + AsRef<[u8]>> StateDb<BlockHash, Key> {
 	/// Creates a new instance. Does not expect any metadata in the database.
 	pub fn new<D: MetaDb>(
 		mode: PruningMode,
@@ -452,6 +911,58 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 		})
 	}
 
+	/// Export the state db records of the meta column of `db`: mode, best block, journals and
+	/// canonicalization and pruning progress.
+	///
+	/// The returned blob can be restored with `import_meta`. Corrupted records are exported as
+	/// they are. Records written by canonicalization indexers are not included.
+	pub fn export_meta<D: MetaDb>(db: &D) -> Result<Vec<u8>, Error<D::Error>> {
+		let mut entries = Vec::new();
+		for key in Self::meta_keys(db)? {
+			if let Some(value) = db.get_meta(&key).map_err(Error::Db)? {
+				entries.push((key, value));
+			}
+		}
+		let mut blob = META_EXPORT_MAGIC.to_vec();
+		blob.extend(encode_checked(&(META_EXPORT_VERSION, entries)));
+		Ok(blob)
+	}
+
+	/// Commit set restoring the state db records of a blob produced by `export_meta` into `db`.
+	///
+	/// State db records of `db` missing from the blob are deleted. The state db must be created
+	/// again once the commit set is written.
+	pub fn import_meta<D: MetaDb>(db: &D, blob: &[u8]) -> Result<CommitSet<Key>, Error<D::Error>> {
+		if !blob.starts_with(META_EXPORT_MAGIC) {
+			return Err(codec::Error::from("Not a state db meta export").into());
+		}
+		let (version, entries) = decode_checked::<(u32, Vec<(Vec<u8>, DBValue)>)>(&blob[META_EXPORT_MAGIC.len()..])
+			.ok_or_else(|| codec::Error::from("Corrupted state db meta export"))?;
+		if version != META_EXPORT_VERSION {
+			return Err(codec::Error::from("Unsupported state db meta export version").into());
+		}
+		let mut commit = CommitSet::default();
+		for key in Self::meta_keys(db)? {
+			if !entries.iter().any(|(k, _)| *k == key) {
+				commit.meta.deleted.push(key);
+			}
+		}
+		commit.meta.inserted = entries;
+		Ok(commit)
+	}
+
+	fn meta_keys<D: MetaDb>(db: &D) -> Result<Vec<Vec<u8>>, Error<D::Error>> {
+		let mut keys = Vec::new();
+		for key in vec![to_meta_key(PRUNING_MODE, &()), to_meta_key(BEST_BLOCK, &())] {
+			if db.get_meta(&key).map_err(Error::Db)?.is_some() {
+				keys.push(key);
+			}
+		}
+		keys.extend(NonCanonicalOverlay::<BlockHash, Key>::meta_keys(db)?);
+		keys.extend(RefWindow::<BlockHash, Key>::meta_keys(db)?);
+		Ok(keys)
+	}
+
 	/// Add a new non-canonical block.
 	pub fn insert_block<E: fmt::Debug>(
 		&self,
@@ -460,7 +971,10 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 		parent_hash: &BlockHash,
 		changeset: ChangeSet<Key>,
 	) -> Result<CommitSet<Key>, Error<E>> {
-		self.db.write().insert_block(hash, number, parent_hash, changeset)
+		let mut db = self.db.write();
+		let commit = db.insert_block(hash, number, parent_hash, changeset)?;
+		db.audit(AuditOperation::InsertBlock, &commit);
+		Ok(commit)
 	}
 
 	/// Finalize a previously inserted block.
@@ -468,12 +982,32 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 		&self,
 		hash: &BlockHash,
 	) -> Result<CommitSet<Key>, Error<E>> {
-		self.db.write().canonicalize_block(hash)
+		let mut db = self.db.write();
+		let commit = db.canonicalize_block(hash)?;
+		db.audit(AuditOperation::Canonicalize, &commit);
+		Ok(commit)
 	}
 
 	/// Prevents pruning of specified block and its descendants.
 	pub fn pin(&self, hash: &BlockHash) -> Result<(), PinError> {
-		self.db.write().pin(hash)
+		self.db.write().pin(hash, None)
+	}
+
+	/// Same as `pin`, `tag` identifies the creator of the pin in `pins`.
+	pub fn pin_tagged(&self, hash: &BlockHash, tag: &'static str) -> Result<(), PinError> {
+		self.db.write().pin(hash, Some(tag))
+	}
+
+	/// List pinned blocks.
+	pub fn pins(&self) -> Vec<(BlockHash, PinInfo)> {
+		self.db.read().pins()
+	}
+
+	/// Release all references to blocks pinned for at least `age`, returning their hashes.
+	///
+	/// Meant as a recovery measure when leaked pins block pruning. Each released pin is logged.
+	pub fn unpin_all_older_than(&self, age: Duration) -> Vec<BlockHash> {
+		self.db.write().unpin_all_older_than(age)
 	}
 
 	/// Allows pruning of specified block.
@@ -481,6 +1015,16 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 		self.db.write().unpin(hash)
 	}
 
+	/// Exempt all state nodes whose key starts with `prefix` from pruning, regardless of
+	/// the pruning window. The keep-set is persisted, the returned commit must be written
+	/// to the database. This is a no-op for archive modes.
+	pub fn keep_prefix(&self, prefix: &[u8]) -> CommitSet<Key> {
+		let mut db = self.db.write();
+		let commit = db.keep_prefix(prefix);
+		db.audit(AuditOperation::KeepPrefix, &commit);
+		commit
+	}
+
 	/// Get a value from non-canonical/pruning overlay or the backing DB.
 	pub fn get<D: NodeDb, Q: ?Sized>(&self, key: &Q, db: &D) -> Result<Option<DBValue>, Error<D::Error>>
 		where
@@ -495,7 +1039,28 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 	/// Returns a database commit or `None` if not possible.
 	/// For archive an empty commit set is returned.
 	pub fn revert_one(&self) -> Option<CommitSet<Key>> {
-		self.db.write().revert_one()
+		let mut db = self.db.write();
+		let commit = db.revert_one()?;
+		db.audit(AuditOperation::Revert, &commit);
+		Some(commit)
+	}
+
+	/// Returns the best block, as set with `set_best`.
+	///
+	/// The best block follows canonicalization and reverts: it becomes the canonicalized block
+	/// when that one is not its ancestor, and its parent when it is reverted.
+	pub fn best(&self) -> Option<(BlockHash, u64)> {
+		self.db.read().best()
+	}
+
+	/// Set the best block. It must be the last canonicalized block or a non-canonical block
+	/// descending from it, except in `ArchiveAll` mode. The best block is persisted, the
+	/// returned commit must be written to the database.
+	pub fn set_best<E: fmt::Debug>(&self, hash: &BlockHash, number: u64) -> Result<CommitSet<Key>, Error<E>> {
+		let mut db = self.db.write();
+		let commit = db.set_best(hash, number)?;
+		db.audit(AuditOperation::SetBest, &commit);
+		Ok(commit)
 	}
 
 	/// Returns last finalized block number.
@@ -508,6 +1073,18 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 		return self.db.read().is_pruned(hash, number)
 	}
 
+	/// Get the availability of the state of a block, with the reason it is not available.
+	pub fn state_availability(&self, hash: &BlockHash, number: u64) -> StateAvailability {
+		self.db.read().state_availability(hash, number)
+	}
+
+	/// Same as `state_availability`, except that the state of non-canonical blocks that are not
+	/// on the chain of the best block is reported as `OffBestChain`. Without best block, this is
+	/// `state_availability`.
+	pub fn state_availability_at_best(&self, hash: &BlockHash, number: u64) -> StateAvailability {
+		self.db.read().state_availability_at_best(hash, number)
+	}
+
 	/// Apply all pending changes
 	pub fn apply_pending(&self) {
 		self.db.write().apply_pending();
@@ -522,13 +1099,68 @@ impl<BlockHash: Hash + MallocSizeOf, Key: Hash + MallocSizeOf> StateDb<BlockHash
 	pub fn memory_info(&self) -> StateDbMemoryInfo {
 		self.db.read().memory_info()
 	}
+
+	/// Encoded size in bytes of the journal records of the non-canonical blocks and of the
+	/// pruning window, including the changes not applied yet.
+	pub fn journal_bytes(&self) -> usize {
+		self.db.read().journal_bytes()
+	}
+
+	/// Log a warning whenever the journal footprint returned by `journal_bytes` rises above one
+	/// of the `thresholds`, in bytes. An empty list disables the warnings.
+	pub fn set_journal_size_thresholds(&self, thresholds: Vec<usize>) {
+		self.db.write().set_journal_thresholds(thresholds)
+	}
+
+	/// Replace the time source, `SystemClock` by default. Pins already taken keep the time read
+	/// from the previous clock.
+	pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+		self.db.write().set_clock(clock)
+	}
+
+	/// Replace the pruning strategy of `Constrained` mode, `None` restores the default one
+	/// using the mode `Constraints`. Takes effect from the next canonicalization.
+	pub fn set_pruning_strategy(&self, strategy: Option<Box<dyn PruningStrategy<BlockHash>>>) {
+		self.db.write().set_pruning_strategy(strategy)
+	}
+
+	/// Register an indexer called on each block canonicalization. Not called in `ArchiveAll`
+	/// mode, where canonicalization is a no-op.
+	pub fn add_canonicalization_indexer(&self, indexer: Box<dyn CanonicalizationIndexer<BlockHash, Key>>) {
+		self.db.write().add_canonicalization_indexer(indexer)
+	}
+
+	/// Enable audit mode, keeping the last `capacity` commit sets produced by this instance in a
+	/// replay log, or disable it with `None`. Enabling it again clears the log.
+	pub fn set_audit(&self, capacity: Option<usize>) {
+		self.db.write().set_audit(capacity)
+	}
+
+	/// Returns the content of the audit replay log, oldest entry first. Empty when audit mode is
+	/// disabled.
+	pub fn audit_log(&self) -> Vec<ReplayEntry<Key>> {
+		self.db.read().audit_log()
+	}
+
+	/// Returns the number of non-canonical blocks at each height of the canonicalization window,
+	/// lowest height first.
+	pub fn level_widths(&self) -> Vec<(u64, usize)> {
+		self.db.read().level_widths()
+	}
 }
 
 #[cfg(test)]
 mod tests {
 	use std::io;
+	use std::sync::Arc;
+	use std::time::{Duration, Instant};
+	use parking_lot::Mutex;
 	use sp_core::H256;
-	use crate::{StateDb, PruningMode, Constraints};
+	use codec::Encode;
+	use crate::{
+		StateDb, PruningMode, Constraints, Error, StateAvailability, CanonicalizationIndexer, ChangeSet,
+		PruningStrategy, Clock, ColumnId, ColumnNodeDb, AuditOperation, replay, to_meta_key, BEST_BLOCK,
+	};
 	use crate::test::{make_db, make_changeset, TestDb};
 
 	fn make_test_db(settings: PruningMode) -> (TestDb, StateDb<H256, H256>) {
@@ -615,6 +1247,7 @@ mod tests {
 		let (db, _) = make_test_db(PruningMode::Constrained(Constraints {
 			max_blocks: Some(0),
 			max_mem: None,
+			max_forks_per_level: None,
 		}));
 		assert!(db.data_eq(&make_db(&[21, 3, 922, 94])));
 	}
@@ -624,6 +1257,7 @@ mod tests {
 		let (db, sdb) = make_test_db(PruningMode::Constrained(Constraints {
 			max_blocks: Some(1),
 			max_mem: None,
+			max_forks_per_level: None,
 		}));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(0), 0));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(1), 1));
@@ -637,6 +1271,7 @@ mod tests {
 		let (db, sdb) = make_test_db(PruningMode::Constrained(Constraints {
 			max_blocks: Some(2),
 			max_mem: None,
+			max_forks_per_level: None,
 		}));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(0), 0));
 		assert!(sdb.is_pruned(&H256::from_low_u64_be(1), 1));
@@ -645,6 +1280,293 @@ mod tests {
 		assert!(db.data_eq(&make_db(&[1, 21, 3, 921, 922, 93, 94])));
 	}
 
+	#[test]
+	fn reports_state_availability() {
+		let (_, sdb) = make_test_db(PruningMode::Constrained(Constraints {
+			max_blocks: Some(2),
+			max_mem: None,
+			max_forks_per_level: None,
+		}));
+		let availability = |n, number| sdb.state_availability(&H256::from_low_u64_be(n), number);
+		assert_eq!(availability(1, 1), StateAvailability::PrunedAt(2));
+		assert_eq!(availability(21, 2), StateAvailability::Available);
+		assert_eq!(availability(22, 2), StateAvailability::NeverKnown);
+		assert_eq!(availability(4, 4), StateAvailability::Available);
+		assert_eq!(availability(5, 5), StateAvailability::NeverKnown);
+	}
+
+	struct LastModified;
+
+	impl CanonicalizationIndexer<H256, H256> for LastModified {
+		fn on_canonicalize(
+			&mut self,
+			_hash: &H256,
+			number: u64,
+			inserted: &[H256],
+			_deleted: &[H256],
+			meta: &mut ChangeSet<Vec<u8>>,
+		) {
+			meta.inserted.extend(inserted.iter().map(|k| (k.as_bytes().to_vec(), number.encode())));
+		}
+	}
+
+	#[test]
+	fn indexer_contributes_to_canonicalization_commit() {
+		let mut db = make_db(&[]);
+		let state_db = StateDb::<H256, H256>::new(PruningMode::ArchiveCanonical, false, &db).unwrap();
+		state_db.add_canonicalization_indexer(Box::new(LastModified));
+		let h1 = H256::from_low_u64_be(1);
+		db.commit(&state_db.insert_block::<io::Error>(&h1, 1, &H256::default(), make_changeset(&[1], &[])).unwrap());
+		state_db.apply_pending();
+		let commit = state_db.canonicalize_block::<io::Error>(&h1).unwrap();
+		db.commit(&commit);
+		state_db.apply_pending();
+		assert_eq!(db.meta.get(H256::from_low_u64_be(1).as_bytes()), Some(&1u64.encode()));
+	}
+
+	struct RecordChanges(Arc<Mutex<Vec<(H256, Vec<H256>, Vec<H256>)>>>);
+
+	impl CanonicalizationIndexer<H256, H256> for RecordChanges {
+		fn on_canonicalize(
+			&mut self,
+			hash: &H256,
+			_number: u64,
+			inserted: &[H256],
+			deleted: &[H256],
+			_meta: &mut ChangeSet<Vec<u8>>,
+		) {
+			self.0.lock().push((hash.clone(), inserted.to_vec(), deleted.to_vec()));
+		}
+	}
+
+	#[test]
+	fn indexer_receives_the_block_own_changes() {
+		let mut db = make_db(&[1]);
+		let state_db = StateDb::<H256, H256>::new(PruningMode::ArchiveCanonical, false, &db).unwrap();
+		let changes = Arc::new(Mutex::new(Vec::new()));
+		state_db.add_canonicalization_indexer(Box::new(RecordChanges(changes.clone())));
+		let h = H256::from_low_u64_be;
+		db.commit(&state_db.insert_block::<io::Error>(&h(21), 1, &h(0), make_changeset(&[2], &[1])).unwrap());
+		db.commit(&state_db.insert_block::<io::Error>(&h(22), 1, &h(0), make_changeset(&[3], &[])).unwrap());
+		state_db.apply_pending();
+		db.commit(&state_db.canonicalize_block::<io::Error>(&h(21)).unwrap());
+		state_db.apply_pending();
+
+		assert_eq!(*changes.lock(), vec![(h(21), vec![h(2)], vec![h(1)])]);
+	}
+
+	#[test]
+	fn column_changes_are_routed_to_their_column() {
+		const CHANGES_TRIE: ColumnId = 7;
+		let mut db = make_db(&[]);
+		let state_db = StateDb::<H256, H256>::new(PruningMode::ArchiveCanonical, false, &db).unwrap();
+		let h1 = H256::from_low_u64_be(1);
+		let mut commit = state_db.insert_block::<io::Error>(&h1, 1, &H256::default(), make_changeset(&[1], &[])).unwrap();
+		commit.add_column_changes(CHANGES_TRIE, make_changeset(&[10], &[]));
+		commit.add_column_changes(CHANGES_TRIE, make_changeset(&[11], &[]));
+		assert_eq!(commit.columns.len(), 1);
+		db.commit(&commit);
+		state_db.apply_pending();
+
+		let key = H256::from_low_u64_be(11);
+		assert_eq!(db.get_in_column(CHANGES_TRIE, &key).unwrap(), Some(key.as_bytes().to_vec()));
+		assert_eq!(db.get_in_column(CHANGES_TRIE, &h1).unwrap(), None);
+		assert!(db.data.get(&key).is_none());
+	}
+
+	/// Keeps the blocks at even heights in the window.
+	struct KeepEven;
+
+	impl PruningStrategy<H256> for KeepEven {
+		fn may_prune(
+			&self,
+			_hash: &H256,
+			number: u64,
+			window_size: u64,
+			_mem_used: usize,
+			_now: Instant,
+		) -> bool {
+			window_size > 1 && number % 2 == 1
+		}
+	}
+
+	#[test]
+	fn custom_pruning_strategy() {
+		let mut db = make_db(&[]);
+		let state_db = StateDb::<H256, H256>::new(PruningMode::keep_blocks(0), false, &db).unwrap();
+		state_db.set_pruning_strategy(Some(Box::new(KeepEven)));
+		let mut parent = H256::default();
+		for number in 1..4u64 {
+			let hash = H256::from_low_u64_be(number);
+			db.commit(&state_db.insert_block::<io::Error>(&hash, number, &parent, make_changeset(&[], &[])).unwrap());
+			state_db.apply_pending();
+			db.commit(&state_db.canonicalize_block::<io::Error>(&hash).unwrap());
+			state_db.apply_pending();
+			parent = hash;
+		}
+		// block 1 is pruned, block 2 blocks the window
+		assert_eq!(
+			state_db.state_availability(&H256::from_low_u64_be(1), 1),
+			StateAvailability::PrunedAt(2),
+		);
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(2), 2));
+		assert!(!state_db.is_pruned(&H256::from_low_u64_be(3), 3));
+	}
+
+	#[test]
+	fn journal_footprint_is_tracked() {
+		let mode = PruningMode::keep_blocks(1);
+		let (mut db, sdb) = make_test_db(mode.clone());
+		let bytes = sdb.journal_bytes();
+		assert!(bytes > 0);
+		let restored = StateDb::<H256, H256>::new(mode.clone(), false, &db).unwrap();
+		assert_eq!(restored.journal_bytes(), bytes);
+
+		sdb.set_journal_size_thresholds(vec![bytes + 1, 1]);
+		assert_eq!(sdb.db.read().journal_thresholds_crossed, 1);
+		db.commit(&sdb.insert_block::<io::Error>(
+			&H256::from_low_u64_be(5),
+			5,
+			&H256::from_low_u64_be(4),
+			make_changeset(&[5], &[]),
+		).unwrap());
+		sdb.apply_pending();
+		assert!(sdb.journal_bytes() > bytes);
+		assert_eq!(sdb.db.read().journal_thresholds_crossed, 2);
+
+		while let Some(commit) = sdb.revert_one() {
+			db.commit(&commit);
+		}
+		sdb.revert_pending();
+		let restored = StateDb::<H256, H256>::new(mode, false, &db).unwrap();
+		assert!(sdb.journal_bytes() < bytes);
+		assert_eq!(restored.journal_bytes(), sdb.journal_bytes());
+	}
+
+	#[test]
+	fn best_block_follows_fork_tree() {
+		let (mut db, sdb) = make_test_db(PruningMode::ArchiveCanonical);
+		let h = H256::from_low_u64_be;
+		assert_eq!(sdb.best(), None);
+		assert!(sdb.set_best::<io::Error>(&h(22), 2).is_err());
+		assert!(sdb.set_best::<io::Error>(&h(4), 5).is_err());
+		db.commit(&sdb.set_best::<io::Error>(&h(4), 4).unwrap());
+
+		db.commit(&sdb.insert_block::<io::Error>(&h(5), 5, &h(4), make_changeset(&[5], &[])).unwrap());
+		db.commit(&sdb.insert_block::<io::Error>(&h(52), 5, &h(4), make_changeset(&[52], &[])).unwrap());
+		sdb.apply_pending();
+		db.commit(&sdb.set_best::<io::Error>(&h(5), 5).unwrap());
+		assert_eq!(sdb.state_availability_at_best(&h(4), 4), StateAvailability::Available);
+		assert_eq!(sdb.state_availability_at_best(&h(52), 5), StateAvailability::OffBestChain);
+		assert_eq!(sdb.state_availability(&h(52), 5), StateAvailability::Available);
+		let restored = StateDb::<H256, H256>::new(PruningMode::ArchiveCanonical, false, &db).unwrap();
+		assert_eq!(restored.best(), Some((h(5), 5)));
+
+		// canonicalizing a fork of the best block moves it, until reverted.
+		db.commit(&sdb.canonicalize_block::<io::Error>(&h(4)).unwrap());
+		sdb.apply_pending();
+		assert_eq!(sdb.best(), Some((h(5), 5)));
+		sdb.canonicalize_block::<io::Error>(&h(52)).unwrap();
+		assert_eq!(sdb.best(), Some((h(52), 5)));
+		sdb.revert_pending();
+		assert_eq!(sdb.best(), Some((h(5), 5)));
+
+		db.commit(&sdb.revert_one().unwrap());
+		assert_eq!(sdb.best(), Some((h(4), 4)));
+		let restored = StateDb::<H256, H256>::new(PruningMode::ArchiveCanonical, false, &db).unwrap();
+		assert_eq!(restored.best(), Some((h(4), 4)));
+	}
+
+	#[test]
+	fn audit_log_replays_best_block() {
+		let mut db = make_db(&[]);
+		let sdb = StateDb::<H256, H256>::new(PruningMode::ArchiveCanonical, false, &db).unwrap();
+		sdb.set_audit(Some(16));
+		let h = H256::from_low_u64_be;
+		db.commit(&sdb.insert_block::<io::Error>(&h(1), 1, &h(0), make_changeset(&[1], &[])).unwrap());
+		sdb.apply_pending();
+		db.commit(&sdb.canonicalize_block::<io::Error>(&h(1)).unwrap());
+		sdb.apply_pending();
+		db.commit(&sdb.set_best::<io::Error>(&h(1), 1).unwrap());
+
+		let log = sdb.audit_log();
+		assert_eq!(log.last().unwrap().operation, AuditOperation::SetBest);
+		let replayed = replay(log.iter()).unwrap();
+		let best_key = to_meta_key(BEST_BLOCK, &());
+		assert!(replayed.meta.contains_key(&best_key));
+		assert_eq!(replayed.meta.get(&best_key), db.meta.get(&best_key));
+	}
+
+	#[test]
+	fn force_releases_old_pins() {
+		let (_, sdb) = make_test_db(PruningMode::Constrained(Constraints {
+			max_blocks: Some(1),
+			max_mem: None,
+			max_forks_per_level: None,
+		}));
+		let h4 = H256::from_low_u64_be(4);
+		assert!(sdb.pin_tagged(&h4, "test").is_ok());
+		assert!(sdb.pin(&h4).is_ok());
+		let pins = sdb.pins();
+		assert_eq!(pins.len(), 1);
+		assert_eq!(pins[0].0, h4);
+		assert_eq!(pins[0].1.tag, Some("test"));
+		assert_eq!(pins[0].1.refs, 2);
+
+		assert!(sdb.unpin_all_older_than(Duration::from_secs(3600)).is_empty());
+		assert_eq!(sdb.unpin_all_older_than(Duration::from_secs(0)), vec![h4]);
+		assert!(sdb.pins().is_empty());
+	}
+
+	struct ManualClock(Mutex<Instant>);
+
+	impl Clock for ManualClock {
+		fn now(&self) -> Instant {
+			*self.0.lock()
+		}
+	}
+
+	#[test]
+	fn pin_ages_follow_injected_clock() {
+		let (_, sdb) = make_test_db(PruningMode::Constrained(Constraints {
+			max_blocks: Some(1),
+			max_mem: None,
+			max_forks_per_level: None,
+		}));
+		let start = Instant::now();
+		let clock = Arc::new(ManualClock(Mutex::new(start)));
+		sdb.set_clock(clock.clone());
+		let h3 = H256::from_low_u64_be(3);
+		let h4 = H256::from_low_u64_be(4);
+		assert!(sdb.pin(&h3).is_ok());
+		*clock.0.lock() = start + Duration::from_secs(10);
+		assert!(sdb.pin(&h4).is_ok());
+		*clock.0.lock() = start + Duration::from_secs(15);
+
+		assert_eq!(sdb.unpin_all_older_than(Duration::from_secs(12)), vec![h3]);
+		assert_eq!(sdb.pins()[0].1.pinned_at, start + Duration::from_secs(10));
+		assert!(sdb.unpin_all_older_than(Duration::from_secs(6)).is_empty());
+		assert_eq!(sdb.unpin_all_older_than(Duration::from_secs(5)), vec![h4]);
+	}
+
+	#[test]
+	fn pins_from_the_future_are_not_expired() {
+		let (_, sdb) = make_test_db(PruningMode::Constrained(Constraints {
+			max_blocks: Some(1),
+			max_mem: None,
+			max_forks_per_level: None,
+		}));
+		let start = Instant::now();
+		let clock = Arc::new(ManualClock(Mutex::new(start + Duration::from_secs(10))));
+		sdb.set_clock(clock.clone());
+		let h4 = H256::from_low_u64_be(4);
+		assert!(sdb.pin(&h4).is_ok());
+		*clock.0.lock() = start;
+
+		assert!(sdb.unpin_all_older_than(Duration::from_secs(0)).is_empty());
+		assert_eq!(sdb.pins().len(), 1);
+	}
+
 	#[test]
 	fn detects_incompatible_mode() {
 		let mut db = make_db(&[]);
@@ -659,8 +1581,66 @@ mod tests {
 			)
 			.unwrap(),
 		);
-		let new_mode = PruningMode::Constrained(Constraints { max_blocks: Some(2), max_mem: None });
+		let new_mode = PruningMode::Constrained(Constraints {
+			max_blocks: Some(2),
+			max_mem: None,
+			max_forks_per_level: None,
+		});
 		let state_db: Result<StateDb<H256, H256>, _> = StateDb::new(new_mode, false, &db);
 		assert!(state_db.is_err());
 	}
+
+	#[test]
+	fn rejects_too_many_forks() {
+		let mut db = make_db(&[]);
+		let state_db = StateDb::<H256, H256>::new(PruningMode::Constrained(Constraints {
+			max_blocks: Some(2),
+			max_mem: None,
+			max_forks_per_level: Some(2),
+		}), false, &db).unwrap();
+		for fork in 1..=2 {
+			db.commit(
+				&state_db
+					.insert_block::<io::Error>(
+						&H256::from_low_u64_be(fork),
+						1,
+						&H256::from_low_u64_be(0),
+						make_changeset(&[fork], &[]),
+					)
+					.unwrap(),
+			);
+		}
+		assert!(matches!(
+			state_db.insert_block::<io::Error>(
+				&H256::from_low_u64_be(3),
+				1,
+				&H256::from_low_u64_be(0),
+				make_changeset(&[3], &[]),
+			),
+			Err(Error::TooManyForks)
+		));
+		assert_eq!(state_db.level_widths(), vec![(1, 2)]);
+	}
+
+	#[test]
+	fn meta_export_restores_wedged_db() {
+		let (db, _) = make_test_db(PruningMode::keep_blocks(1));
+		let blob = StateDb::<H256, H256>::export_meta(&db).unwrap();
+		assert!(StateDb::<H256, H256>::import_meta(&db, &blob[1..]).is_err());
+
+		let mut wedged = db.clone();
+		for value in wedged.meta.values_mut() {
+			value.truncate(1);
+		}
+		assert!(StateDb::<H256, H256>::new(PruningMode::keep_blocks(1), false, &wedged).is_err());
+
+		wedged.commit(&StateDb::<H256, H256>::import_meta(&wedged, &blob).unwrap());
+		assert_eq!(wedged.meta, db.meta);
+		let state_db = StateDb::<H256, H256>::new(PruningMode::keep_blocks(1), false, &wedged).unwrap();
+		assert_eq!(state_db.best_canonical(), Some(3));
+		assert_eq!(
+			state_db.state_availability(&H256::from_low_u64_be(4), 4),
+			StateAvailability::Available,
+		);
+	}
 }
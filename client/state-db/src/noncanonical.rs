@@ -23,13 +23,22 @@
 //! `revert_pending`
 
 use std::fmt;
-use std::collections::{HashMap, VecDeque, hash_map::Entry};
-use super::{Error, DBValue, ChangeSet, CommitSet, MetaDb, Hash, to_meta_key};
+use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque, hash_map::Entry};
+use super::{
+	Error, DBValue, ChangeSet, CommitSet, MetaDb, Hash, to_meta_key, encode_checked, decode_checked,
+	delete_truncated_journals,
+};
 use codec::{Encode, Decode};
-use log::trace;
+use log::{trace, warn};
+use parity_util_mem::{MallocSizeOf, MallocSizeOfOps, MallocShallowSizeOf};
 
 const NON_CANONICAL_JOURNAL: &[u8] = b"noncanonical_journal";
 const LAST_CANONICAL: &[u8] = b"last_canonical";
+/// Levels with fewer journal records than this are decoded on the calling thread.
+const PARALLEL_DECODE_THRESHOLD: usize = 8;
+/// Maximum number of threads decoding the records of a level.
+const MAX_DECODE_THREADS: usize = 8;
 
 /// See module documentation.
 #[derive(parity_util_mem_derive::MallocSizeOf)]
@@ -39,10 +48,16 @@ pub struct NonCanonicalOverlay<BlockHash: Hash, Key: Hash> {
 	parents: HashMap<BlockHash, BlockHash>,
 	pending_canonicalizations: Vec<BlockHash>,
 	pending_insertions: Vec<BlockHash>,
-	values: HashMap<Key, (u32, DBValue)>, //ref counted
+	values: OverlayValues<Key>,
 	//would be deleted but kept around because block is pinned, ref counted.
 	pinned: HashMap<BlockHash, u32>,
 	pinned_insertions: HashMap<BlockHash, (Vec<Key>, u32)>,
+	// journal records found after a corrupted one on load, deleted with the next commit.
+	truncated_journals: Vec<Vec<u8>>,
+	// truncated journal records handled by pending commits, restored on revert.
+	pending_truncated_journals: Vec<Vec<u8>>,
+	// encoded size of the journal records of the overlays in `levels`.
+	journal_bytes: usize,
 }
 
 #[derive(Encode, Decode)]
@@ -57,31 +72,101 @@ fn to_journal_key(block: u64, index: u64) -> Vec<u8> {
 	to_meta_key(NON_CANONICAL_JOURNAL, &(block, index))
 }
 
+/// Decode the journal records of a level, `None` for corrupted ones. Results are in the order
+/// of `records`, big levels are split between several threads.
+fn decode_journal_level<BlockHash: Hash, Key: Hash>(
+	records: &[DBValue],
+) -> Vec<Option<JournalRecord<BlockHash, Key>>> {
+	if records.len() < PARALLEL_DECODE_THRESHOLD {
+		return records.iter().map(|record| decode_checked(record)).collect();
+	}
+	let threads = std::cmp::min(MAX_DECODE_THREADS, records.len() / (PARALLEL_DECODE_THRESHOLD / 2));
+	let chunk_size = (records.len() + threads - 1) / threads;
+	crossbeam_utils::thread::scope(|scope| {
+		let handles: Vec<_> = records.chunks(chunk_size)
+			.map(|chunk| scope.spawn(move |_| {
+				chunk.iter().map(|record| decode_checked(record)).collect::<Vec<_>>()
+			}))
+			.collect();
+		handles.into_iter()
+			.flat_map(|handle| handle.join().expect("Journal decoding does not panic; qed"))
+			.collect()
+	}).expect("Journal decoding does not panic; qed")
+}
+
 #[cfg_attr(test, derive(PartialEq, Debug))]
 #[derive(parity_util_mem_derive::MallocSizeOf)]
 struct BlockOverlay<BlockHash: Hash, Key: Hash> {
 	hash: BlockHash,
 	journal_key: Vec<u8>,
+	journal_bytes: usize,
 	inserted: Vec<Key>,
 	deleted: Vec<Key>,
 }
 
-fn insert_values<Key: Hash>(values: &mut HashMap<Key, (u32, DBValue)>, inserted: Vec<(Key, DBValue)>) {
+/// Values inserted by the overlays, ref counted by key.
+/// Identical values inserted under different keys, as happens when sibling forks execute the
+/// same extrinsics, share a single allocation.
+#[cfg_attr(test, derive(PartialEq, Debug))]
+#[derive(Default)]
+struct OverlayValues<Key: Hash> {
+	values: HashMap<Key, (u32, Arc<DBValue>)>,
+	interned: HashSet<Arc<DBValue>>,
+}
+
+impl<Key: Hash> OverlayValues<Key> {
+	fn get<Q: ?Sized>(&self, key: &Q) -> Option<&DBValue>
+	where
+		Key: std::borrow::Borrow<Q>,
+		Q: std::hash::Hash + Eq,
+	{
+		self.values.get(key).map(|(_, value)| &**value)
+	}
+
+	fn intern(&mut self, value: DBValue) -> Arc<DBValue> {
+		if let Some(interned) = self.interned.get(&value) {
+			return interned.clone();
+		}
+		let value = Arc::new(value);
+		self.interned.insert(value.clone());
+		value
+	}
+}
+
+impl<Key: Hash + MallocSizeOf> MallocSizeOf for OverlayValues<Key> {
+	fn size_of(&self, ops: &mut MallocSizeOfOps) -> usize {
+		// Shared values are only counted once, through `interned`.
+		self.values.shallow_size_of(ops)
+			+ self.values.keys().map(|k| k.size_of(ops)).sum::<usize>()
+			+ self.interned.shallow_size_of(ops)
+			+ self.interned.iter().map(|v| v.size_of(ops)).sum::<usize>()
+	}
+}
+
+fn insert_values<Key: Hash>(values: &mut OverlayValues<Key>, inserted: Vec<(Key, DBValue)>) {
 	for (k, v) in inserted {
-		debug_assert!(values.get(&k).map_or(true, |(_, value)| *value == v));
-		let (ref mut counter, _) = values.entry(k).or_insert_with(|| (0, v));
-		*counter += 1;
+		debug_assert!(values.get(&k).map_or(true, |value| *value == v));
+		if let Some((ref mut counter, _)) = values.values.get_mut(&k) {
+			*counter += 1;
+		} else {
+			let v = values.intern(v);
+			values.values.insert(k, (1, v));
+		}
 	}
 }
 
-fn discard_values<Key: Hash>(values: &mut HashMap<Key, (u32, DBValue)>, inserted: Vec<Key>) {
+fn discard_values<Key: Hash>(values: &mut OverlayValues<Key>, inserted: Vec<Key>) {
 	for k in inserted {
-		match values.entry(k) {
+		match values.values.entry(k) {
 			Entry::Occupied(mut e) => {
 				let (ref mut counter, _) = e.get_mut();
 				*counter -= 1;
 				if *counter == 0 {
-					e.remove_entry();
+					let (_, (_, value)) = e.remove_entry();
+					// the last other reference is the interned one
+					if Arc::strong_count(&value) == 2 {
+						values.interned.remove(&*value);
+					}
 				}
 			},
 			Entry::Vacant(_) => {
@@ -93,10 +178,11 @@ fn discard_values<Key: Hash>(values: &mut HashMap<Key, (u32, DBValue)>, inserted
 
 fn discard_descendants<BlockHash: Hash, Key: Hash>(
 	levels: &mut (&mut [Vec<BlockOverlay<BlockHash, Key>>], &mut [Vec<BlockOverlay<BlockHash, Key>>]),
-	mut values: &mut HashMap<Key, (u32, DBValue)>,
+	mut values: &mut OverlayValues<Key>,
 	parents: &mut HashMap<BlockHash, BlockHash>,
 	pinned: &HashMap<BlockHash, u32>,
 	pinned_insertions: &mut HashMap<BlockHash, (Vec<Key>, u32)>,
+	journal_bytes: &mut usize,
 	hash: &BlockHash,
 ) -> u32 {
 	let (first, mut remainder) = if let Some((first, rest)) = levels.0.split_first_mut() {
@@ -121,8 +207,10 @@ fn discard_descendants<BlockHash: Hash, Key: Hash>(
 					parents,
 					pinned,
 					pinned_insertions,
+					journal_bytes,
 					&overlay.hash
 				);
+				*journal_bytes -= overlay.journal_bytes;
 				if pinned.contains_key(&overlay.hash) {
 					num_pinned += 1;
 				}
@@ -145,54 +233,127 @@ fn discard_descendants<BlockHash: Hash, Key: Hash>(
 }
 
 impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
+	/// Keys of the overlay records present in `db`, corrupted ones included.
+	///
+	/// Journal records can only be located from a valid last canonical record.
+	pub fn meta_keys<D: MetaDb>(db: &D) -> Result<Vec<Vec<u8>>, Error<D::Error>> {
+		let last_canonical_key = to_meta_key(LAST_CANONICAL, &());
+		let last_canonicalized = match db.get_meta(&last_canonical_key).map_err(|e| Error::Db(e))? {
+			Some(buffer) => decode_checked::<(BlockHash, u64)>(&buffer),
+			None => return Ok(Vec::new()),
+		};
+		let mut keys = vec![last_canonical_key];
+		if let Some((_, mut block)) = last_canonicalized {
+			block += 1;
+			loop {
+				let first = keys.len();
+				loop {
+					let journal_key = to_journal_key(block, (keys.len() - first) as u64);
+					if db.get_meta(&journal_key).map_err(|e| Error::Db(e))?.is_none() {
+						break;
+					}
+					keys.push(journal_key);
+				}
+				if keys.len() == first {
+					break;
+				}
+				block += 1;
+			}
+		}
+		Ok(keys)
+	}
+
 	/// Creates a new instance. Does not expect any metadata to be present in the DB.
 	pub fn new<D: MetaDb>(db: &D) -> Result<NonCanonicalOverlay<BlockHash, Key>, Error<D::Error>> {
 		let last_canonicalized = db.get_meta(&to_meta_key(LAST_CANONICAL, &()))
 			.map_err(|e| Error::Db(e))?;
 		let last_canonicalized = match last_canonicalized {
-			Some(buffer) => Some(<(BlockHash, u64)>::decode(&mut buffer.as_slice())?),
+			Some(buffer) => Some(decode_checked::<(BlockHash, u64)>(&buffer)
+				.ok_or_else(|| codec::Error::from("Corrupted last canonical record"))?),
 			None => None,
 		};
 		let mut levels = VecDeque::new();
 		let mut parents = HashMap::new();
-		let mut values = HashMap::new();
+		let mut values = OverlayValues::default();
+		let mut truncated_journals = Vec::new();
+		let mut journal_bytes = 0;
 		if let Some((ref hash, mut block)) = last_canonicalized {
 			// read the journal
 			trace!(target: "state-db", "Reading uncanonicalized journal. Last canonicalized #{} ({:?})", block, hash);
 			let mut total: u64 = 0;
+			let mut truncate_from = None;
 			block += 1;
 			loop {
-				let mut index: u64 = 0;
-				let mut level = Vec::new();
+				let mut journal_keys = Vec::new();
+				let mut records = Vec::new();
 				loop {
-					let journal_key = to_journal_key(block, index);
+					let journal_key = to_journal_key(block, journal_keys.len() as u64);
 					match db.get_meta(&journal_key).map_err(|e| Error::Db(e))? {
 						Some(record) => {
-							let record: JournalRecord<BlockHash, Key> = Decode::decode(&mut record.as_slice())?;
-							let inserted = record.inserted.iter().map(|(k, _)| k.clone()).collect();
-							let overlay = BlockOverlay {
-								hash: record.hash.clone(),
-								journal_key,
-								inserted: inserted,
-								deleted: record.deleted,
-							};
-							insert_values(&mut values, record.inserted);
-							trace!(target: "state-db", "Uncanonicalized journal entry {}.{} ({} inserted, {} deleted)", block, index, overlay.inserted.len(), overlay.deleted.len());
-							level.push(overlay);
-							parents.insert(record.hash, record.parent_hash);
-							index += 1;
-							total += 1;
+							journal_keys.push(journal_key);
+							records.push(record);
 						},
 						None => break,
 					}
 				}
+				let decoded = decode_journal_level::<BlockHash, Key>(&records);
+				let mut level = Vec::new();
+				let journals = journal_keys.into_iter().zip(records.iter().map(|r| r.len()));
+				for (index, ((journal_key, record_bytes), record)) in journals.zip(decoded).enumerate() {
+					let index = index as u64;
+					let record = match record {
+						Some(record) => record,
+						None => {
+							warn!(target: "state-db", "Corrupted uncanonicalized journal entry {}.{}, truncating journal", block, index);
+							truncate_from = Some((block, index));
+							break;
+						},
+					};
+					let inserted = record.inserted.iter().map(|(k, _)| k.clone()).collect();
+					let overlay = BlockOverlay {
+						hash: record.hash.clone(),
+						journal_key,
+						journal_bytes: record_bytes,
+						inserted: inserted,
+						deleted: record.deleted,
+					};
+					insert_values(&mut values, record.inserted);
+					trace!(target: "state-db", "Uncanonicalized journal entry {}.{} ({} inserted, {} deleted)", block, index, overlay.inserted.len(), overlay.deleted.len());
+					level.push(overlay);
+					parents.insert(record.hash, record.parent_hash);
+					journal_bytes += record_bytes;
+					total += 1;
+				}
 				if level.is_empty() {
 					break;
 				}
 				levels.push_back(level);
+				if truncate_from.is_some() {
+					break;
+				}
 				block += 1;
 			}
 			trace!(target: "state-db", "Finished reading uncanonicalized journal, {} entries", total);
+			if let Some((mut block, mut index)) = truncate_from {
+				// Everything journaled after the corrupted record is discarded.
+				loop {
+					let first = index;
+					loop {
+						let journal_key = to_journal_key(block, index);
+						if db.get_meta(&journal_key).map_err(|e| Error::Db(e))?.is_none() {
+							break;
+						}
+						truncated_journals.push(journal_key);
+						index += 1;
+					}
+					if index == first {
+						break;
+					}
+					block += 1;
+					index = 0;
+				}
+				trace!(target: "state-db", "Discarding {} journal records after corruption", truncated_journals.len());
+			}
 		}
 		Ok(NonCanonicalOverlay {
 			last_canonicalized,
@@ -203,6 +364,9 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 			pinned: Default::default(),
 			pinned_insertions: Default::default(),
 			values: values,
+			truncated_journals,
+			pending_truncated_journals: Default::default(),
+			journal_bytes,
 		})
 	}
 
@@ -213,7 +377,7 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		if self.levels.is_empty() && self.last_canonicalized.is_none() && number > 0 {
 			// assume that parent was canonicalized
 			let last_canonicalized = (parent_hash.clone(), number - 1);
-			commit.meta.inserted.push((to_meta_key(LAST_CANONICAL, &()), last_canonicalized.encode()));
+			commit.meta.inserted.push((to_meta_key(LAST_CANONICAL, &()), encode_checked(&last_canonicalized)));
 			self.last_canonicalized = Some(last_canonicalized);
 		} else if self.last_canonicalized.is_some() {
 			if number < front_block_number || number >= front_block_number + self.levels.len() as u64 + 1 {
@@ -245,27 +409,43 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		let journal_key = to_journal_key(number, index);
 
 		let inserted = changeset.inserted.iter().map(|(k, _)| k.clone()).collect();
+		let deleted = changeset.deleted.clone();
+		let journal_record = JournalRecord {
+			hash: hash.clone(),
+			parent_hash: parent_hash.clone(),
+			inserted: changeset.inserted,
+			deleted: changeset.deleted,
+		};
+		let encoded_record = encode_checked(&journal_record);
 		let overlay = BlockOverlay {
 			hash: hash.clone(),
 			journal_key: journal_key.clone(),
+			journal_bytes: encoded_record.len(),
 			inserted: inserted,
-			deleted: changeset.deleted.clone(),
+			deleted,
 		};
+		self.journal_bytes += overlay.journal_bytes;
 		level.push(overlay);
 		self.parents.insert(hash.clone(), parent_hash.clone());
-		let journal_record = JournalRecord {
-			hash: hash.clone(),
-			parent_hash: parent_hash.clone(),
-			inserted: changeset.inserted,
-			deleted: changeset.deleted,
-		};
-		commit.meta.inserted.push((journal_key, journal_record.encode()));
+		self.delete_truncated_journals(Some(&journal_key), &mut commit);
+		commit.meta.inserted.push((journal_key, encoded_record));
 		trace!(target: "state-db", "Inserted uncanonicalized changeset {}.{} ({} inserted, {} deleted)", number, index, journal_record.inserted.len(), journal_record.deleted.len());
 		insert_values(&mut self.values, journal_record.inserted);
 		self.pending_insertions.push(hash.clone());
 		Ok(commit)
 	}
 
+	/// Delete the truncated journal records with `commit`, except `rewritten` which the commit
+	/// overwrites. They are kept until `apply_pending`, and restored by `revert_pending`.
+	fn delete_truncated_journals(&mut self, rewritten: Option<&[u8]>, commit: &mut CommitSet<Key>) {
+		delete_truncated_journals(
+			&mut self.truncated_journals,
+			&mut self.pending_truncated_journals,
+			rewritten,
+			commit,
+		);
+	}
+
 	fn discard_journals(
 		&self,
 		level_index: usize,
@@ -285,6 +465,27 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		}
 	}
 
+	/// Encoded size in bytes of the journal records of the non-canonical blocks.
+	pub fn journal_bytes(&self) -> usize {
+		self.journal_bytes
+	}
+
+	/// Number of non-canonical blocks at height `number`.
+	pub fn level_width(&self, number: u64) -> usize {
+		number.checked_sub(self.front_block_number())
+			.and_then(|index| self.levels.get(index as usize))
+			.map_or(0, |level| level.len())
+	}
+
+	/// Number of non-canonical blocks at each height, lowest height first.
+	pub fn level_widths(&self) -> Vec<(u64, usize)> {
+		let front_block_number = self.front_block_number();
+		self.levels.iter()
+			.enumerate()
+			.map(|(index, level)| (front_block_number + index as u64, level.len()))
+			.collect()
+	}
+
 	fn front_block_number(&self) -> u64 {
 		self.last_canonicalized.as_ref().map(|&(_, n)| n + 1).unwrap_or(0)
 	}
@@ -301,6 +502,14 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		self.last_canonicalized.as_ref().map(|&(ref h, _)| h.clone())
 	}
 
+	/// Last canonicalized block, including pending canonicalizations.
+	pub fn last_canonicalized_block(&self) -> Option<(BlockHash, u64)> {
+		let number = self.last_canonicalized_block_number()?;
+		let hash = self.pending_canonicalizations.last().cloned()
+			.or_else(|| self.last_canonicalized_hash())?;
+		Some((hash, number))
+	}
+
 	pub fn top_level(&self) -> Vec<(BlockHash, u64)> {
 		let start = self.last_canonicalized_block_number().unwrap_or(0);
 		self.levels
@@ -310,12 +519,13 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 	}
 
 	/// Select a top-level root and canonicalized it. Discards all sibling subtrees and the root.
-	/// Returns a set of changes that need to be added to the DB.
+	/// Adds the changes that need to be written to the DB to `commit`, returns the number of the
+	/// canonicalized block.
 	pub fn canonicalize<E: fmt::Debug>(
 		&mut self,
 		hash: &BlockHash,
 		commit: &mut CommitSet<Key>,
-	) -> Result<(), Error<E>> {
+	) -> Result<u64, Error<E>> {
 		trace!(target: "state-db", "Canonicalizing {:?}", hash);
 		let level = self.levels.get(self.pending_canonicalizations.len()).ok_or_else(|| Error::InvalidBlock)?;
 		let index = level
@@ -341,15 +551,16 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		// get the one we need to canonicalize
 		let overlay = &level[index];
 		commit.data.inserted.extend(overlay.inserted.iter()
-			.map(|k| (k.clone(), self.values.get(k).expect("For each key in overlays there's a value in values").1.clone())));
+			.map(|k| (k.clone(), self.values.get(k).expect("For each key in overlays there's a value in values").clone())));
 		commit.data.deleted.extend(overlay.deleted.clone());
 
 		commit.meta.deleted.append(&mut discarded_journals);
+		self.delete_truncated_journals(None, commit);
 		let canonicalized = (hash.clone(), self.front_block_number() + self.pending_canonicalizations.len() as u64);
-		commit.meta.inserted.push((to_meta_key(LAST_CANONICAL, &()), canonicalized.encode()));
+		commit.meta.inserted.push((to_meta_key(LAST_CANONICAL, &()), encode_checked(&canonicalized)));
 		trace!(target: "state-db", "Discarding {} records", commit.meta.deleted.len());
 		self.pending_canonicalizations.push(hash.clone());
-		Ok(())
+		Ok(canonicalized.1)
 	}
 
 	fn apply_canonicalizations(&mut self) {
@@ -372,11 +583,13 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 						&mut self.parents,
 						&self.pinned,
 						&mut self.pinned_insertions,
+						&mut self.journal_bytes,
 						&overlay.hash,
 					)
 				} else {
 					0
 				};
+				self.journal_bytes -= overlay.journal_bytes;
 				if self.pinned.contains_key(&overlay.hash) {
 					pinned_children += 1;
 				}
@@ -400,10 +613,29 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		Key: std::borrow::Borrow<Q>,
 		Q: std::hash::Hash + Eq,
 	{
-		if let Some((_, value)) = self.values.get(&key) {
-			return Some(value.clone());
+		self.values.get(key).cloned()
+	}
+
+	/// Ancestor at height `number` of the non-canonical block `hash` at height `block_number`.
+	/// The ancestor may be the last canonicalized block. Returns `None` if `hash` is not known
+	/// or `number` is above `block_number`.
+	pub fn ancestor(&self, hash: &BlockHash, block_number: u64, number: u64) -> Option<BlockHash> {
+		if number > block_number {
+			return None;
+		}
+		let mut ancestor = hash.clone();
+		for _ in number..block_number {
+			ancestor = self.parents.get(&ancestor)?.clone();
 		}
-		None
+		Some(ancestor)
+	}
+
+	/// Keys of the state nodes inserted and deleted by block `hash`, as recorded in its journal.
+	pub fn block_changes(&self, hash: &BlockHash) -> Option<(&[Key], &[Key])> {
+		self.levels.iter()
+			.flat_map(|level| level.iter())
+			.find(|overlay| overlay.hash == *hash)
+			.map(|overlay| (&overlay.inserted[..], &overlay.deleted[..]))
 	}
 
 	/// Check if the block is in the canonicalization queue.
@@ -417,6 +649,7 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 		self.levels.pop_back().map(|level| {
 			let mut commit = CommitSet::default();
 			for overlay in level.into_iter() {
+				self.journal_bytes -= overlay.journal_bytes;
 				commit.meta.deleted.push(overlay.journal_key);
 				self.parents.remove(&overlay.hash);
 				discard_values(&mut self.values, overlay.inserted);
@@ -436,6 +669,7 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 				.expect("Hash is added in insert");
 
 			let	overlay = self.levels[level_index].pop().expect("Empty levels are not allowed in self.levels");
+			self.journal_bytes -= overlay.journal_bytes;
 			discard_values(&mut self.values, overlay.inserted);
 			if self.levels[level_index].is_empty() {
 				debug_assert_eq!(level_index, self.levels.len() - 1);
@@ -448,12 +682,14 @@ impl<BlockHash: Hash, Key: Hash> NonCanonicalOverlay<BlockHash, Key> {
 	pub fn apply_pending(&mut self) {
 		self.apply_canonicalizations();
 		self.pending_insertions.clear();
+		self.pending_truncated_journals.clear();
 	}
 
 	/// Revert all pending changes
 	pub fn revert_pending(&mut self) {
 		self.pending_canonicalizations.clear();
 		self.revert_insertions();
+		self.truncated_journals.append(&mut self.pending_truncated_journals);
 	}
 
 	/// Pin state values in memory
@@ -621,6 +857,110 @@ mod tests {
 		assert_eq!(overlay.last_canonicalized, overlay2.last_canonicalized);
 	}
 
+	#[test]
+	fn identical_values_are_shared() {
+		let h1 = H256::random();
+		let h2 = H256::random();
+		let mut db = make_db(&[]);
+		let mut overlay = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		let changeset = |key| ChangeSet {
+			inserted: vec![(H256::from_low_u64_be(key), b"value".to_vec())],
+			deleted: vec![],
+		};
+		db.commit(&overlay.insert::<io::Error>(&h1, 1, &H256::default(), changeset(1)).unwrap());
+		db.commit(&overlay.insert::<io::Error>(&h2, 1, &H256::default(), changeset(2)).unwrap());
+		assert_eq!(overlay.values.values.len(), 2);
+		assert_eq!(overlay.values.interned.len(), 1);
+		assert_eq!(overlay.get(&H256::from_low_u64_be(2)), Some(b"value".to_vec()));
+
+		let mut commit = CommitSet::default();
+		overlay.canonicalize::<io::Error>(&h1, &mut commit).unwrap();
+		overlay.apply_pending();
+		db.commit(&commit);
+		assert!(overlay.values.values.is_empty());
+		assert!(overlay.values.interned.is_empty());
+		assert_eq!(db.data.get(&H256::from_low_u64_be(1)), Some(&b"value".to_vec()));
+	}
+
+	#[test]
+	fn restore_wide_level_from_journal() {
+		let h1 = H256::random();
+		let mut db = make_db(&[1, 2]);
+		let mut overlay = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		db.commit(&overlay.insert::<io::Error>(&h1, 10, &H256::default(), make_changeset(&[3], &[])).unwrap());
+		for i in 0..20 {
+			let changeset = make_changeset(&[100 + i], &[]);
+			db.commit(&overlay.insert::<io::Error>(&H256::random(), 11, &h1, changeset).unwrap());
+		}
+		assert_eq!(db.meta.len(), 22);
+
+		let overlay2 = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		assert_eq!(overlay.levels, overlay2.levels);
+		assert_eq!(overlay.parents, overlay2.parents);
+		assert_eq!(overlay.values, overlay2.values);
+		assert_eq!(overlay.last_canonicalized, overlay2.last_canonicalized);
+	}
+
+	#[test]
+	fn restore_from_corrupted_journal() {
+		let h1 = H256::random();
+		let h2 = H256::random();
+		let h3 = H256::random();
+		let mut db = make_db(&[1, 2]);
+		let mut overlay = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		db.commit(&overlay.insert::<io::Error>(&h1, 10, &H256::default(), make_changeset(&[3, 4], &[2])).unwrap());
+		db.commit(&overlay.insert::<io::Error>(&h2, 11, &h1, make_changeset(&[5], &[3])).unwrap());
+		db.commit(&overlay.insert::<io::Error>(&h3, 12, &h2, make_changeset(&[6], &[])).unwrap());
+		// torn write of the second record
+		let record = db.meta.get_mut(&to_journal_key(11, 0)).unwrap();
+		record.pop();
+
+		let mut overlay2 = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		assert_eq!(overlay2.levels.len(), 1);
+		assert!(contains(&overlay2, 4));
+		assert!(!contains(&overlay2, 5));
+		assert!(!contains(&overlay2, 6));
+
+		// the rest of the journal is discarded with the next commit.
+		db.commit(&overlay2.insert::<io::Error>(&h2, 11, &h1, make_changeset(&[5], &[3])).unwrap());
+		assert!(db.meta.contains_key(&to_journal_key(11, 0)));
+		assert!(!db.meta.contains_key(&to_journal_key(12, 0)));
+		let overlay3 = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		assert_eq!(overlay2.levels, overlay3.levels);
+		assert_eq!(overlay2.parents, overlay3.parents);
+	}
+
+	#[test]
+	fn truncated_journals_are_kept_on_revert() {
+		let h1 = H256::random();
+		let h2 = H256::random();
+		let h3 = H256::random();
+		let mut db = make_db(&[1, 2]);
+		let mut overlay = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		db.commit(&overlay.insert::<io::Error>(&h1, 10, &H256::default(), make_changeset(&[3, 4], &[2])).unwrap());
+		db.commit(&overlay.insert::<io::Error>(&h2, 11, &h1, make_changeset(&[5], &[3])).unwrap());
+		db.commit(&overlay.insert::<io::Error>(&h3, 12, &h2, make_changeset(&[6], &[])).unwrap());
+		let record = db.meta.get_mut(&to_journal_key(11, 0)).unwrap();
+		record.pop();
+
+		// the commit deleting the truncated records is dropped.
+		let mut overlay2 = NonCanonicalOverlay::<H256, H256>::new(&db).unwrap();
+		let mut commit = CommitSet::default();
+		overlay2.canonicalize::<io::Error>(&h1, &mut commit).unwrap();
+		assert!(commit.meta.deleted.contains(&to_journal_key(12, 0)));
+		overlay2.revert_pending();
+
+		let mut commit = CommitSet::default();
+		overlay2.canonicalize::<io::Error>(&h1, &mut commit).unwrap();
+		assert!(commit.meta.deleted.contains(&to_journal_key(11, 0)));
+		assert!(commit.meta.deleted.contains(&to_journal_key(12, 0)));
+		db.commit(&commit);
+		overlay2.apply_pending();
+		assert!(!db.meta.contains_key(&to_journal_key(12, 0)));
+		assert!(overlay2.truncated_journals.is_empty());
+		assert!(overlay2.pending_truncated_journals.is_empty());
+	}
+
 	#[test]
 	fn restore_from_journal_after_canonicalize() {
 		let h1 = H256::random();
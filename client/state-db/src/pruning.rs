@@ -26,11 +26,14 @@
 
 use std::collections::{HashMap, HashSet, VecDeque};
 use codec::{Encode, Decode};
-use crate::{CommitSet, Error, MetaDb, to_meta_key, Hash};
+use crate::{
+	CommitSet, Error, MetaDb, to_meta_key, Hash, encode_checked, decode_checked, delete_truncated_journals,
+};
 use log::{trace, warn};
 
 const LAST_PRUNED: &[u8] = b"last_pruned";
 const PRUNING_JOURNAL: &[u8] = b"pruning_journal";
+const PRUNING_KEEP_PREFIXES: &[u8] = b"pruning_keep_prefixes";
 
 /// See module documentation.
 #[derive(parity_util_mem_derive::MallocSizeOf)]
@@ -51,12 +54,21 @@ pub struct RefWindow<BlockHash: Hash, Key: Hash> {
 	/// Setting this to false requires backend that supports reference
 	/// counting.
 	count_insertions: bool,
+	/// Key prefixes that are never deleted by pruning. Persisted in the DB.
+	keep_prefixes: Vec<Vec<u8>>,
+	/// Journal records found after a corrupted one on load. Deleted with the next commit.
+	truncated_journals: Vec<Vec<u8>>,
+	/// Truncated journal records handled by pending commits, restored on revert.
+	pending_truncated_journals: Vec<Vec<u8>>,
+	/// Encoded size of the journal records of `death_rows`.
+	journal_bytes: usize,
 }
 
 #[derive(Debug, PartialEq, Eq, parity_util_mem_derive::MallocSizeOf)]
 struct DeathRow<BlockHash: Hash, Key: Hash> {
 	hash: BlockHash,
 	journal_key: Vec<u8>,
+	journal_bytes: usize,
 	deleted: HashSet<Key>,
 }
 
@@ -71,7 +83,36 @@ fn to_journal_key(block: u64) -> Vec<u8> {
 	to_meta_key(PRUNING_JOURNAL, &block)
 }
 
-impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
+impl<BlockHash: Hash, Key: Hash + AsRef<[u8]>> RefWindow<BlockHash, Key> {
+	/// Keys of the pruning records present in `db`, corrupted ones included.
+	///
+	/// Journal records can only be located from a valid last pruned record, or when no block
+	/// was ever pruned.
+	pub fn meta_keys<D: MetaDb>(db: &D) -> Result<Vec<Vec<u8>>, Error<D::Error>> {
+		let mut keys = Vec::new();
+		let last_pruned_key = to_meta_key(LAST_PRUNED, &());
+		let mut block = match db.get_meta(&last_pruned_key).map_err(|e| Error::Db(e))? {
+			Some(buffer) => {
+				keys.push(last_pruned_key);
+				u64::decode(&mut buffer.as_slice()).ok().map(|last_pruned| last_pruned + 1)
+			},
+			None => Some(0),
+		};
+		let keep_prefixes_key = to_meta_key(PRUNING_KEEP_PREFIXES, &());
+		if db.get_meta(&keep_prefixes_key).map_err(|e| Error::Db(e))?.is_some() {
+			keys.push(keep_prefixes_key);
+		}
+		while let Some(number) = block {
+			let journal_key = to_journal_key(number);
+			if db.get_meta(&journal_key).map_err(|e| Error::Db(e))?.is_none() {
+				break;
+			}
+			keys.push(journal_key);
+			block = Some(number + 1);
+		}
+		Ok(keys)
+	}
+
 	pub fn new<D: MetaDb>(db: &D, count_insertions: bool) -> Result<RefWindow<BlockHash, Key>, Error<D::Error>> {
 		let last_pruned = db.get_meta(&to_meta_key(LAST_PRUNED, &()))
 			.map_err(|e| Error::Db(e))?;
@@ -79,6 +120,12 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 			Some(buffer) => u64::decode(&mut buffer.as_slice())? + 1,
 			None => 0,
 		};
+		let keep_prefixes = db.get_meta(&to_meta_key(PRUNING_KEEP_PREFIXES, &()))
+			.map_err(|e| Error::Db(e))?;
+		let keep_prefixes: Vec<Vec<u8>> = match keep_prefixes {
+			Some(buffer) => Decode::decode(&mut buffer.as_slice())?,
+			None => Vec::new(),
+		};
 		let mut block = pending_number;
 		let mut pruning = RefWindow {
 			death_rows: Default::default(),
@@ -87,6 +134,10 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 			pending_canonicalizations: 0,
 			pending_prunings: 0,
 			count_insertions,
+			keep_prefixes,
+			truncated_journals: Vec::new(),
+			pending_truncated_journals: Vec::new(),
+			journal_bytes: 0,
 		};
 		// read the journal
 		trace!(target: "state-db", "Reading pruning journal. Pending #{}", pending_number);
@@ -94,18 +145,53 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 			let journal_key = to_journal_key(block);
 			match db.get_meta(&journal_key).map_err(|e| Error::Db(e))? {
 				Some(record) => {
-					let record: JournalRecord<BlockHash, Key> = Decode::decode(&mut record.as_slice())?;
+					let record_bytes = record.len();
+					let record: JournalRecord<BlockHash, Key> = match decode_checked(&record) {
+						Some(record) => record,
+						None => {
+							warn!(target: "state-db", "Corrupted pruning journal entry {}, truncating journal", block);
+							break;
+						},
+					};
 					trace!(target: "state-db", "Pruning journal entry {} ({} inserted, {} deleted)", block, record.inserted.len(), record.deleted.len());
-					pruning.import(&record.hash, journal_key, record.inserted.into_iter(), record.deleted);
+					pruning.import(&record.hash, journal_key, record_bytes, record.inserted.into_iter(), record.deleted);
 				},
 				None => break,
 			}
 			block += 1;
 		}
+		// Everything journaled from a corrupted record on is discarded. The nodes these blocks
+		// would have pruned stay in the database for good. The records of the next canonicalized
+		// blocks are journaled from the truncation point on, right after the imported ones, so the
+		// window stays contiguous and `pending_number` keeps counting the pruned records.
+		let truncated_from = block;
+		loop {
+			let journal_key = to_journal_key(block);
+			if db.get_meta(&journal_key).map_err(|e| Error::Db(e))?.is_none() {
+				break;
+			}
+			pruning.truncated_journals.push(journal_key);
+			block += 1;
+		}
+		if !pruning.truncated_journals.is_empty() {
+			warn!(
+				target: "state-db",
+				"Dropped the pruning journal of {} blocks from #{}, the nodes they would prune are leaked",
+				pruning.truncated_journals.len(),
+				truncated_from,
+			);
+		}
 		Ok(pruning)
 	}
 
-	fn import<I: IntoIterator<Item=Key>>(&mut self, hash: &BlockHash, journal_key: Vec<u8>, inserted: I, deleted: Vec<Key>) {
+	fn import<I: IntoIterator<Item=Key>>(
+		&mut self,
+		hash: &BlockHash,
+		journal_key: Vec<u8>,
+		journal_bytes: usize,
+		inserted: I,
+		deleted: Vec<Key>,
+	) {
 		if self.count_insertions {
 			// remove all re-inserted keys from death rows
 			for k in inserted {
@@ -125,8 +211,10 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 				hash: hash.clone(),
 				deleted: deleted.into_iter().collect(),
 				journal_key: journal_key,
+				journal_bytes,
 			}
 		);
+		self.journal_bytes += journal_bytes;
 	}
 
 	pub fn window_size(&self) -> u64 {
@@ -141,6 +229,11 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		0
 	}
 
+	/// Encoded size in bytes of the journal records of the pruning window.
+	pub fn journal_bytes(&self) -> usize {
+		self.journal_bytes
+	}
+
 	pub fn pending(&self) -> u64 {
 		self.pending_number + self.pending_prunings as u64
 	}
@@ -149,12 +242,33 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		self.death_rows.iter().skip(self.pending_prunings).any(|r| r.hash == *hash)
 	}
 
+	/// Keys starting with one of the registered prefixes are never deleted by pruning.
+	pub fn keep_prefixes(&self) -> &[Vec<u8>] {
+		&self.keep_prefixes
+	}
+
+	/// Register a key prefix that must never be pruned. Adds the updated keep-set to `commit`.
+	/// Returns `false` if the prefix was already registered.
+	pub fn keep_prefix(&mut self, prefix: &[u8], commit: &mut CommitSet<Key>) -> bool {
+		if self.keep_prefixes.iter().any(|p| p.as_slice() == prefix) {
+			return false;
+		}
+		trace!(target: "state-db", "Keeping prefix {:?} from pruning", prefix);
+		self.keep_prefixes.push(prefix.to_vec());
+		commit.meta.inserted.push((to_meta_key(PRUNING_KEEP_PREFIXES, &()), self.keep_prefixes.encode()));
+		true
+	}
+
+	fn is_kept(&self, key: &Key) -> bool {
+		self.keep_prefixes.iter().any(|p| key.as_ref().starts_with(p))
+	}
+
 	/// Prune next block. Expects at least one block in the window. Adds changes to `commit`.
 	pub fn prune_one(&mut self, commit: &mut CommitSet<Key>) {
 		if let Some(pruned) = self.death_rows.get(self.pending_prunings) {
 			trace!(target: "state-db", "Pruning {:?} ({} deleted)", pruned.hash, pruned.deleted.len());
 			let index = self.pending_number + self.pending_prunings as u64;
-			commit.data.deleted.extend(pruned.deleted.iter().cloned());
+			commit.data.deleted.extend(pruned.deleted.iter().filter(|k| !self.is_kept(k)).cloned());
 			commit.meta.inserted.push((to_meta_key(LAST_PRUNED, &()), index.encode()));
 			commit.meta.deleted.push(pruned.journal_key.clone());
 			self.pending_prunings += 1;
@@ -179,17 +293,27 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		};
 		let block = self.pending_number + self.death_rows.len() as u64;
 		let journal_key = to_journal_key(block);
-		commit.meta.inserted.push((journal_key.clone(), journal_record.encode()));
-		self.import(&journal_record.hash, journal_key, journal_record.inserted.into_iter(), journal_record.deleted);
+		delete_truncated_journals(
+			&mut self.truncated_journals,
+			&mut self.pending_truncated_journals,
+			Some(&journal_key),
+			commit,
+		);
+		let encoded_record = encode_checked(&journal_record);
+		let journal_bytes = encoded_record.len();
+		commit.meta.inserted.push((journal_key.clone(), encoded_record));
+		self.import(&journal_record.hash, journal_key, journal_bytes, journal_record.inserted.into_iter(), journal_record.deleted);
 		self.pending_canonicalizations += 1;
 	}
 
 	/// Apply all pending changes
 	pub fn apply_pending(&mut self) {
 		self.pending_canonicalizations = 0;
+		self.pending_truncated_journals.clear();
 		for _ in 0 .. self.pending_prunings {
 			let pruned = self.death_rows.pop_front().expect("pending_prunings is always < death_rows.len()");
 			trace!(target: "state-db", "Applying pruning {:?} ({} deleted)", pruned.hash, pruned.deleted.len());
+			self.journal_bytes -= pruned.journal_bytes;
 			if self.count_insertions {
 				for k in pruned.deleted.iter() {
 					self.death_index.remove(&k);
@@ -206,13 +330,16 @@ impl<BlockHash: Hash, Key: Hash> RefWindow<BlockHash, Key> {
 		// Note that pending insertions might cause some existing deletions to be removed from `death_index`
 		// We don't bother to track and revert that for now. This means that a few nodes might end up no being
 		// deleted in case transaction fails and `revert_pending` is called.
-		self.death_rows.truncate(self.death_rows.len() - self.pending_canonicalizations);
+		let reverted = self.death_rows.len() - self.pending_canonicalizations;
+		self.journal_bytes -= self.death_rows.iter().skip(reverted).map(|r| r.journal_bytes).sum::<usize>();
+		self.death_rows.truncate(reverted);
 		if self.count_insertions {
 			let new_max_block = self.death_rows.len() as u64 + self.pending_number;
 			self.death_index.retain(|_, block| *block < new_max_block);
 		}
 		self.pending_canonicalizations = 0;
 		self.pending_prunings = 0;
+		self.truncated_journals.append(&mut self.pending_truncated_journals);
 	}
 }
 
@@ -228,6 +355,7 @@ mod tests {
 		assert_eq!(pruning.pending_number, restored.pending_number);
 		assert_eq!(pruning.death_rows, restored.death_rows);
 		assert_eq!(pruning.death_index, restored.death_index);
+		assert_eq!(pruning.keep_prefixes, restored.keep_prefixes);
 	}
 
 	#[test]
@@ -281,6 +409,35 @@ mod tests {
 		assert_eq!(pruning.pending_number, 1);
 	}
 
+	#[test]
+	fn truncated_journals_are_kept_on_revert() {
+		let mut db = make_db(&[1, 2, 3]);
+		let mut pruning: RefWindow<H256, H256> = RefWindow::new(&db, true).unwrap();
+		for i in 4..7 {
+			let mut commit = make_commit(&[i], &[]);
+			pruning.note_canonical(&H256::random(), &mut commit);
+			db.commit(&commit);
+		}
+		pruning.apply_pending();
+		// torn write of the second record
+		db.meta.get_mut(&super::to_journal_key(1)).unwrap().pop();
+
+		let mut pruning: RefWindow<H256, H256> = RefWindow::new(&db, true).unwrap();
+		assert_eq!(pruning.death_rows.len(), 1);
+		let mut commit = make_commit(&[7], &[]);
+		pruning.note_canonical(&H256::random(), &mut commit);
+		assert_eq!(commit.meta.deleted, vec![super::to_journal_key(2)]);
+		pruning.revert_pending();
+
+		let mut commit = make_commit(&[7], &[]);
+		pruning.note_canonical(&H256::random(), &mut commit);
+		assert_eq!(commit.meta.deleted, vec![super::to_journal_key(2)]);
+		db.commit(&commit);
+		pruning.apply_pending();
+		assert!(!db.meta.contains_key(&super::to_journal_key(2)));
+		check_journal(&pruning, &db);
+	}
+
 	#[test]
 	fn prune_two() {
 		let mut db = make_db(&[1, 2, 3]);
@@ -420,4 +577,26 @@ mod tests {
 		assert!(pruning.death_index.is_empty());
 	}
 
+	#[test]
+	fn keep_prefix_survives_pruning() {
+		let mut db = make_db(&[1, 2, 3]);
+		let mut pruning: RefWindow<H256, H256> = RefWindow::new(&db, true).unwrap();
+		let kept = H256::from_low_u64_be(2);
+		let mut commit = CommitSet::default();
+		assert!(pruning.keep_prefix(kept.as_bytes(), &mut commit));
+		assert!(!pruning.keep_prefix(kept.as_bytes(), &mut commit));
+		db.commit(&commit);
+		let mut commit = make_commit(&[4], &[2, 3]);
+		pruning.note_canonical(&H256::random(), &mut commit);
+		db.commit(&commit);
+		pruning.apply_pending();
+
+		check_journal(&pruning, &db);
+
+		let mut commit = CommitSet::default();
+		pruning.prune_one(&mut commit);
+		db.commit(&commit);
+		pruning.apply_pending();
+		assert!(db.data_eq(&make_db(&[1, 2, 4])));
+	}
 }
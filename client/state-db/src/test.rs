@@ -20,12 +20,13 @@
 
 use std::collections::HashMap;
 use sp_core::H256;
-use crate::{DBValue, ChangeSet, CommitSet, MetaDb, NodeDb};
+use crate::{DBValue, ChangeSet, CommitSet, MetaDb, NodeDb, ColumnNodeDb, ColumnId};
 
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct TestDb {
 	pub data: HashMap<H256, DBValue>,
 	pub meta: HashMap<Vec<u8>, DBValue>,
+	pub columns: HashMap<(ColumnId, H256), DBValue>,
 }
 
 impl MetaDb for TestDb {
@@ -45,6 +46,12 @@ impl NodeDb for TestDb {
 	}
 }
 
+impl ColumnNodeDb for TestDb {
+	fn get_in_column(&self, column: ColumnId, key: &H256) -> Result<Option<DBValue>, ()> {
+		Ok(self.columns.get(&(column, *key)).cloned())
+	}
+}
+
 impl TestDb {
 	pub fn commit(&mut self, commit: &CommitSet<H256>) {
 		self.data.extend(commit.data.inserted.iter().cloned());
@@ -56,6 +63,14 @@ impl TestDb {
 		for k in commit.meta.deleted.iter() {
 			self.meta.remove(k);
 		}
+		for (column, changes) in commit.columns.iter() {
+			for (k, v) in changes.inserted.iter() {
+				self.columns.insert((*column, *k), v.clone());
+			}
+			for k in changes.deleted.iter() {
+				self.columns.remove(&(*column, *k));
+			}
+		}
 	}
 
 	pub fn data_eq(&self, other: &TestDb) -> bool {
@@ -79,6 +94,7 @@ pub fn make_commit(inserted: &[u64], deleted: &[u64]) -> CommitSet<H256> {
 	CommitSet {
 		data: make_changeset(inserted, deleted),
 		meta: ChangeSet::default(),
+		columns: Vec::new(),
 	}
 }
 
@@ -91,6 +107,7 @@ pub fn make_db(inserted: &[u64]) -> TestDb {
 			})
 			.collect(),
 		meta: Default::default(),
+		columns: Default::default(),
 	}
 }
 
@@ -364,6 +364,24 @@ impl TaskExecutorExt {
 	}
 }
 
+/// Something giving the size of the storage proof recorded so far by an execution.
+pub trait ProofSizeProvider: Send + Sync {
+	/// Size in bytes of the proof recorded so far.
+	fn estimated_proof_size(&self) -> u64;
+}
+
+sp_externalities::decl_extension! {
+	/// Proof size extension, registered when the execution records a storage proof.
+	pub struct ProofSizeExt(Box<dyn ProofSizeProvider>);
+}
+
+impl ProofSizeExt {
+	/// New instance of proof size extension.
+	pub fn new(provider: impl ProofSizeProvider + 'static) -> Self {
+		Self(Box::new(provider))
+	}
+}
+
 /// Something that can spawn futures (blocking and non-blocking) with an assigned name.
 #[dyn_clonable::clonable]
 pub trait SpawnNamed: Clone + Send + Sync {
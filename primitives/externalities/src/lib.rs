@@ -137,9 +137,33 @@ pub trait Externalities: ExtensionStore {
 	/// Clear an entire child storage.
 	fn kill_child_storage(&mut self, child_info: &ChildInfo);
 
+	/// Clear at most `limit` entries of a child storage, in key order, starting at `cursor`
+	/// when given.
+	///
+	/// Returns the number of cleared entries, and the key to resume from with the next call
+	/// when entries remain.
+	fn kill_child_storage_limited(
+		&mut self,
+		child_info: &ChildInfo,
+		limit: u32,
+		cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>);
+
 	/// Clear storage entries which keys are start with the given prefix.
 	fn clear_prefix(&mut self, prefix: &[u8]);
 
+	/// Clear at most `limit` storage entries which keys start with the given prefix, in key
+	/// order, starting at `cursor` when given.
+	///
+	/// Returns the number of cleared entries, and the key to resume from with the next call
+	/// when entries with the prefix remain.
+	fn clear_prefix_limited(
+		&mut self,
+		prefix: &[u8],
+		limit: u32,
+		cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>);
+
 	/// Clear child storage entries which keys are start with the given prefix.
 	fn clear_child_prefix(
 		&mut self,
@@ -35,7 +35,7 @@ use sp_std::ops::Deref;
 #[cfg(feature = "std")]
 use sp_core::{
 	crypto::Pair,
-	traits::{KeystoreExt, CallInWasmExt, TaskExecutorExt},
+	traits::{KeystoreExt, CallInWasmExt, TaskExecutorExt, ProofSizeExt},
 	offchain::{OffchainExt, TransactionPoolExt},
 	hexdisplay::HexDisplay,
 	storage::ChildInfo,
@@ -762,6 +762,18 @@ sp_externalities::decl_extension! {
 	pub struct VerificationExt(BatchVerifier);
 }
 
+/// Interface that provides functions to query the storage proof recorded by the execution.
+#[runtime_interface]
+pub trait ProofSize {
+	/// Size in bytes of the storage proof recorded so far.
+	///
+	/// Returns `None` when the execution does not record a proof. Meant for block builders to
+	/// stop including extrinsics once the proof reaches a size budget.
+	fn storage_proof_size(&mut self) -> Option<u64> {
+		self.extension::<ProofSizeExt>().map(|ext| ext.estimated_proof_size())
+	}
+}
+
 /// Interface that provides functions to access the offchain functionality.
 ///
 /// These functions are being made available to the runtime and are called by the runtime.
@@ -1201,6 +1213,7 @@ pub type SubstrateHostFunctions = (
 	sandbox::HostFunctions,
 	crate::trie::HostFunctions,
 	offchain_index::HostFunctions,
+	proof_size::HostFunctions,
 );
 
 #[cfg(test)]
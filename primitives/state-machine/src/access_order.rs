@@ -0,0 +1,275 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend recording the order of the first accesses to each key, to build cache-warming
+//! profiles.
+
+use std::collections::HashSet;
+use codec::{Encode, Decode};
+use hash_db::Hasher;
+use parking_lot::Mutex;
+use sp_core::storage::ChildInfo;
+use crate::{backend::Backend, StorageKey, StorageValue, UsageInfo};
+
+/// Keys of a state, in the order they are expected to be accessed.
+///
+/// Built by `AccessOrderRecorder` from an execution, and replayed with `prefetch` before
+/// executing similar blocks so that the backend caches are warm.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode)]
+pub struct CacheWarmingProfile {
+	/// Storage key of the child trie, `None` for the top trie, and key.
+	pub keys: Vec<(Option<StorageKey>, StorageKey)>,
+}
+
+impl CacheWarmingProfile {
+	/// Read all keys of the profile from `backend`, in order.
+	///
+	/// Values are discarded, reading fails on the first backend error.
+	pub fn prefetch<H: Hasher, B: Backend<H>>(&self, backend: &B) -> Result<(), B::Error> {
+		for (child, key) in self.keys.iter() {
+			match child {
+				Some(storage_key) => {
+					backend.child_storage(&ChildInfo::new_default(storage_key), key)?;
+				},
+				None => {
+					backend.storage(key)?;
+				},
+			}
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Default)]
+struct AccessOrder {
+	seen: HashSet<(Option<StorageKey>, StorageKey)>,
+	keys: Vec<(Option<StorageKey>, StorageKey)>,
+}
+
+/// Backend recording the keys read through it, in the order of their first access.
+///
+/// Value, hash and next key reads are recorded; iterations over keys, root computations and
+/// writes are not.
+#[derive(Debug)]
+pub struct AccessOrderRecorder<B> {
+	backend: B,
+	order: Mutex<AccessOrder>,
+}
+
+impl<B> AccessOrderRecorder<B> {
+	/// Record the accesses made to `backend`.
+	pub fn new(backend: B) -> Self {
+		AccessOrderRecorder {
+			backend,
+			order: Default::default(),
+		}
+	}
+
+	/// The wrapped backend.
+	pub fn backend(&self) -> &B {
+		&self.backend
+	}
+
+	/// Return the wrapped backend, dropping the recorded accesses.
+	pub fn into_inner(self) -> B {
+		self.backend
+	}
+
+	/// Profile of the keys accessed so far, in the order of their first access.
+	pub fn profile(&self) -> CacheWarmingProfile {
+		CacheWarmingProfile { keys: self.order.lock().keys.clone() }
+	}
+
+	/// Take the profile of the keys accessed so far, the next access to any key is recorded
+	/// again.
+	pub fn take_profile(&self) -> CacheWarmingProfile {
+		let order = std::mem::take(&mut *self.order.lock());
+		CacheWarmingProfile { keys: order.keys }
+	}
+
+	fn record(&self, child_info: Option<&ChildInfo>, key: &[u8]) {
+		let access = (child_info.map(|child| child.storage_key().to_vec()), key.to_vec());
+		let mut order = self.order.lock();
+		if order.seen.insert(access.clone()) {
+			order.keys.push(access);
+		}
+	}
+}
+
+impl<B, H> Backend<H> for AccessOrderRecorder<B>
+	where
+		B: Backend<H>,
+		H: Hasher,
+{
+	type Error = B::Error;
+	type Transaction = B::Transaction;
+	type TrieBackendStorage = B::TrieBackendStorage;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<StorageValue>, Self::Error> {
+		self.record(None, key);
+		self.backend.storage(key)
+	}
+
+	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
+		self.record(None, key);
+		self.backend.storage_hash(key)
+	}
+
+	fn storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<StorageValue>>, Self::Error> {
+		for key in keys {
+			self.record(None, key);
+		}
+		self.backend.storage_batch(keys)
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageValue>, Self::Error> {
+		self.record(Some(child_info), key);
+		self.backend.child_storage(child_info, key)
+	}
+
+	fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, Self::Error> {
+		self.record(Some(child_info), key);
+		self.backend.child_storage_hash(child_info, key)
+	}
+
+	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		f: F,
+	) {
+		self.backend.for_keys_in_child_storage(child_info, f)
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
+		self.record(None, key);
+		self.backend.next_storage_key(key)
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<StorageKey>, Self::Error> {
+		self.record(Some(child_info), key);
+		self.backend.next_child_storage_key(child_info, key)
+	}
+
+	fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], f: F) {
+		self.backend.for_keys_with_prefix(prefix, f)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+		self.backend.for_key_values_with_prefix(prefix, f)
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		f: F,
+	) {
+		self.backend.for_child_keys_with_prefix(child_info, prefix, f)
+	}
+
+	fn storage_root<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, Self::Transaction) where H::Out: Ord {
+		self.backend.storage_root(delta)
+	}
+
+	fn child_storage_root<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord {
+		self.backend.child_storage_root(child_info, delta)
+	}
+
+	fn child_storage_roots<'a>(
+		&self,
+		child_deltas: Vec<(&'a ChildInfo, Vec<(&'a [u8], Option<&'a [u8]>)>)>,
+	) -> Vec<(H::Out, bool, Self::Transaction)> where H::Out: Ord {
+		self.backend.child_storage_roots(child_deltas)
+	}
+
+	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
+		self.backend.pairs()
+	}
+
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		self.backend.estimate_pairs_count()
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		self.backend.estimate_storage_size()
+	}
+
+	fn register_overlay_stats(&mut self, stats: &crate::stats::StateMachineStats) {
+		self.backend.register_overlay_stats(stats)
+	}
+
+	fn usage_info(&self) -> UsageInfo {
+		self.backend.usage_info()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::trie_backend::tests::test_trie;
+
+	#[test]
+	fn first_accesses_are_recorded_in_order() {
+		let recorder = AccessOrderRecorder::new(test_trie());
+		let child_info = ChildInfo::new_default(b"sub1");
+		assert_eq!(
+			Backend::<BlakeTwo256>::storage(&recorder, b"value2").unwrap(),
+			Some(vec![24]),
+		);
+		Backend::<BlakeTwo256>::child_storage(&recorder, &child_info, b"value3").unwrap();
+		Backend::<BlakeTwo256>::storage_hash(&recorder, b"key").unwrap();
+		Backend::<BlakeTwo256>::storage(&recorder, b"value2").unwrap();
+		Backend::<BlakeTwo256>::exists_storage(&recorder, b"missing").unwrap();
+
+		let profile = recorder.profile();
+		assert_eq!(profile.keys, vec![
+			(None, b"value2".to_vec()),
+			(Some(b"sub1".to_vec()), b"value3".to_vec()),
+			(None, b"key".to_vec()),
+			(None, b"missing".to_vec()),
+		]);
+		assert_eq!(CacheWarmingProfile::decode(&mut &profile.encode()[..]).unwrap(), profile);
+
+		let replayed = AccessOrderRecorder::new(test_trie());
+		profile.prefetch::<BlakeTwo256, _>(&replayed).unwrap();
+		assert_eq!(replayed.profile(), profile);
+
+		assert_eq!(recorder.take_profile(), profile);
+		Backend::<BlakeTwo256>::storage(&recorder, b"key").unwrap();
+		assert_eq!(recorder.profile().keys, vec![(None, b"key".to_vec())]);
+	}
+}
@@ -0,0 +1,126 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Journal of the storage accesses made through `Ext`.
+
+use std::{collections::BTreeSet, sync::Arc};
+use parking_lot::Mutex;
+use sp_core::storage::ChildInfo;
+use crate::StorageKey;
+
+/// Kind of a storage access.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum StorageAccessKind {
+	/// Value, hash or existence of the key is read.
+	Read,
+	/// The key following this one is read.
+	NextKey,
+	/// Value of the key is set, removed or appended to.
+	Write,
+	/// Keys starting with this one are removed.
+	ClearPrefix,
+	/// The whole child trie is removed, the key is empty.
+	KillChild,
+}
+
+/// A storage access.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct StorageAccess {
+	/// Storage key of the accessed child trie, `None` for the top trie.
+	pub child: Option<StorageKey>,
+	/// Accessed key.
+	pub key: StorageKey,
+	/// Kind of access.
+	pub kind: StorageAccessKind,
+}
+
+impl StorageAccess {
+	/// Whether the access only reads storage.
+	pub fn is_read(&self) -> bool {
+		match self.kind {
+			StorageAccessKind::Read | StorageAccessKind::NextKey => true,
+			_ => false,
+		}
+	}
+}
+
+/// Records the storage accesses of executions, in order.
+///
+/// Accesses rejected by the externalities, such as writes to protected keys, are still
+/// recorded. Clones share the journal.
+#[derive(Debug, Clone, Default)]
+pub struct StorageAccessTracker {
+	accesses: Arc<Mutex<Vec<StorageAccess>>>,
+}
+
+impl StorageAccessTracker {
+	/// Tracker with an empty journal.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Record an access to `key` of the child trie `child`, of the top trie if `None`.
+	pub fn record(&self, child: Option<&ChildInfo>, key: &[u8], kind: StorageAccessKind) {
+		self.accesses.lock().push(StorageAccess {
+			child: child.map(|child| child.storage_key().to_vec()),
+			key: key.to_vec(),
+			kind,
+		});
+	}
+
+	/// Accesses recorded so far, oldest first.
+	pub fn accesses(&self) -> Vec<StorageAccess> {
+		self.accesses.lock().clone()
+	}
+
+	/// Take the accesses recorded so far, leaving the journal empty.
+	pub fn take(&self) -> Vec<StorageAccess> {
+		std::mem::take(&mut *self.accesses.lock())
+	}
+
+	/// Distinct keys read so far, by child trie storage key.
+	///
+	/// Reading these keys from a backend gives the proof of the reads of the execution, the
+	/// keys following the `NextKey` ones must also be read.
+	pub fn read_keys(&self) -> BTreeSet<(Option<StorageKey>, StorageKey)> {
+		self.accesses.lock().iter()
+			.filter(|access| access.is_read())
+			.map(|access| (access.child.clone(), access.key.clone()))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn read_keys_are_deduplicated() {
+		let tracker = StorageAccessTracker::new();
+		let child = ChildInfo::new_default(b"sub");
+		tracker.record(None, b"a", StorageAccessKind::Read);
+		tracker.record(Some(&child), b"a", StorageAccessKind::NextKey);
+		tracker.clone().record(None, b"a", StorageAccessKind::Read);
+		tracker.record(None, b"b", StorageAccessKind::Write);
+
+		assert_eq!(tracker.accesses().len(), 4);
+		let read_keys: Vec<_> = tracker.read_keys().into_iter().collect();
+		assert_eq!(read_keys, vec![(None, b"a".to_vec()), (Some(b"sub".to_vec()), b"a".to_vec())]);
+		assert_eq!(tracker.take()[3].kind, StorageAccessKind::Write);
+		assert!(tracker.accesses().is_empty());
+	}
+}
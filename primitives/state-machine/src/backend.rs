@@ -18,10 +18,11 @@
 //! State machine backends. These manage the code and storage of contracts.
 
 use hash_db::Hasher;
-use codec::{Decode, Encode};
+use codec::{Decode, Encode, Codec};
+use sp_trie::{empty_child_trie_root, trie_types::Layout};
 use sp_core::{
 	traits::RuntimeCode,
-	storage::{ChildInfo, well_known_keys, TrackedStorageKey}
+	storage::{ChildInfo, ChildType, well_known_keys, TrackedStorageKey}
 };
 use crate::{
 	trie_backend::TrieBackend,
@@ -51,6 +52,11 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 		self.storage(key).map(|v| v.map(|v| H::hash(&v)))
 	}
 
+	/// Get the keyed storage of several keys, in the order of `keys`.
+	fn storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<StorageValue>>, Self::Error> {
+		keys.iter().map(|key| self.storage(key)).collect()
+	}
+
 	/// Get keyed child storage or None if there is nothing associated.
 	fn child_storage(
 		&self,
@@ -135,9 +141,37 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 	) -> (H::Out, bool, Self::Transaction) where H::Out: Ord;
 
+	/// Same as `child_storage_root` for several child tries, results are in the order of
+	/// `child_deltas`.
+	///
+	/// Backends may compute the roots concurrently.
+	fn child_storage_roots<'a>(
+		&self,
+		child_deltas: Vec<(&'a ChildInfo, Vec<(&'a [u8], Option<&'a [u8]>)>)>,
+	) -> Vec<(H::Out, bool, Self::Transaction)> where H::Out: Ord {
+		child_deltas.into_iter()
+			.map(|(child_info, delta)| self.child_storage_root(child_info, delta.into_iter()))
+			.collect()
+	}
+
 	/// Get all key/value pairs into a Vec.
 	fn pairs(&self) -> Vec<(StorageKey, StorageValue)>;
 
+	/// Estimate the number of key/value pairs of the top trie.
+	///
+	/// Meant for heuristics budgeting work, implementations may trade accuracy for speed. The
+	/// default implementation counts `pairs`.
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		Ok(self.pairs().len() as u64)
+	}
+
+	/// Estimate the total size in bytes of the keys and values of the top trie.
+	///
+	/// Same as `estimate_pairs_count`, the default implementation sums the sizes of `pairs`.
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		Ok(self.pairs().iter().map(|(key, value)| (key.len() + value.len()) as u64).sum())
+	}
+
 	/// Get all keys with given prefix
 	fn keys(&self, prefix: &[u8]) -> Vec<StorageKey> {
 		let mut all = Vec::new();
@@ -156,6 +190,36 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 		all
 	}
 
+	/// Get the default child tries of this state, found from their roots stored in the top
+	/// trie.
+	fn child_infos(&self) -> Vec<ChildInfo> {
+		let prefix = ChildType::ParentKeyId.parent_prefix();
+		let mut child_infos = Vec::new();
+		self.for_keys_with_prefix(prefix, |key| {
+			child_infos.push(ChildInfo::new_default(&key[prefix.len()..]));
+		});
+		child_infos
+	}
+
+	/// Iterate over the keys with given prefix, in order.
+	///
+	/// Unlike `keys`, keys are read one at a time as the iterator is advanced, so memory use
+	/// does not grow with the number of keys.
+	fn keys_iter<'a>(&'a self, prefix: &'a [u8]) -> KeysIter<'a, Self, H> where Self: Sized {
+		KeysIter::new(self, None, prefix)
+	}
+
+	/// Iterate over the keys of child storage with given prefix, in order.
+	///
+	/// See `keys_iter`.
+	fn child_keys_iter<'a>(
+		&'a self,
+		child_info: &'a ChildInfo,
+		prefix: &'a [u8],
+	) -> KeysIter<'a, Self, H> where Self: Sized {
+		KeysIter::new(self, Some(child_info), prefix)
+	}
+
 	/// Try convert into trie backend.
 	fn as_trie_backend(&mut self) -> Option<&TrieBackend<Self::TrieBackendStorage, H>> {
 		None
@@ -172,32 +236,49 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 			impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
 		)>,
 	) -> (H::Out, Self::Transaction) where H::Out: Ord + Encode {
-		let mut txs: Self::Transaction = Default::default();
-		let mut child_roots: Vec<_> = Default::default();
-		// child first
-		for (child_info, child_delta) in child_deltas {
-			let (child_root, empty, child_txs) =
-				self.child_storage_root(&child_info, child_delta);
-			let prefixed_storage_key = child_info.prefixed_storage_key();
-			txs.consolidate(child_txs);
-			if empty {
-				child_roots.push((prefixed_storage_key.into_inner(), None));
-			} else {
-				child_roots.push((prefixed_storage_key.into_inner(), Some(child_root.encode())));
-			}
-		}
-		let (root, parent_txs) = self.storage_root(delta
-			.map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..])))
-			.chain(
-				child_roots
-					.iter()
-					.map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..])))
-			)
-		);
-		txs.consolidate(parent_txs);
+		let child_deltas: Vec<_> = child_deltas
+			.map(|(child_info, child_delta)| (child_info, child_delta.collect::<Vec<_>>()))
+			.collect();
+		let (root, _, txs) = storage_root_with_child_roots::<_, H>(self, delta, child_deltas);
 		(root, txs)
 	}
 
+	/// Same as `full_storage_root`, but also returns a `(child_info, old_root, new_root)` entry
+	/// for every child trie whose root is changed by `child_deltas`.
+	///
+	/// A child trie whose old root cannot be read or decoded from the backend has no entry.
+	fn full_storage_root_with_child_deltas<'a>(
+		&self,
+		delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		child_deltas: impl Iterator<Item = (
+			&'a ChildInfo,
+			impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+		)>,
+	) -> (H::Out, Vec<(ChildInfo, H::Out, H::Out)>, Self::Transaction) where H::Out: Ord + Codec {
+		let child_deltas: Vec<_> = child_deltas
+			.map(|(child_info, child_delta)| (child_info, child_delta.collect::<Vec<_>>()))
+			.collect();
+		let old_roots: Vec<_> = child_deltas.iter()
+			.map(|(child_info, _)| {
+				let old_root = match self.storage(child_info.prefixed_storage_key().as_slice()) {
+					Ok(Some(encoded)) => Decode::decode(&mut &encoded[..]).ok(),
+					Ok(None) => Some(empty_child_trie_root::<Layout<H>>()),
+					Err(_) => None,
+				};
+				(*child_info, old_root)
+			})
+			.collect();
+		let (root, child_roots, txs) = storage_root_with_child_roots::<_, H>(self, delta, child_deltas);
+		let child_root_deltas = old_roots.into_iter()
+			.zip(child_roots)
+			.filter_map(|((child_info, old_root), child_root)| match old_root {
+				Some(old_root) if old_root != child_root => Some((child_info.clone(), old_root, child_root)),
+				_ => None,
+			})
+			.collect();
+		(root, child_root_deltas, txs)
+	}
+
 	/// Register stats from overlay of state machine.
 	///
 	/// By default nothing is registered.
@@ -238,6 +319,47 @@ pub trait Backend<H: Hasher>: std::fmt::Debug {
 	fn set_whitelist(&self, _: Vec<TrackedStorageKey>) {}
 }
 
+/// Storage root of `backend` with the changes of `delta` and `child_deltas`, along with the new
+/// root of each child trie of `child_deltas`, in the same order.
+fn storage_root_with_child_roots<'a, B, H>(
+	backend: &B,
+	delta: impl Iterator<Item=(&'a [u8], Option<&'a [u8]>)>,
+	child_deltas: Vec<(&'a ChildInfo, Vec<(&'a [u8], Option<&'a [u8]>)>)>,
+) -> (H::Out, Vec<H::Out>, B::Transaction)
+where
+	B: Backend<H> + ?Sized,
+	H: Hasher,
+	H::Out: Ord + Encode,
+{
+	let mut txs: B::Transaction = Default::default();
+	let mut child_roots: Vec<_> = Default::default();
+	let mut new_child_roots = Vec::with_capacity(child_deltas.len());
+	let child_infos: Vec<&ChildInfo> = child_deltas.iter().map(|(child_info, _)| *child_info).collect();
+	// child first
+	for (child_info, (child_root, empty, child_txs)) in child_infos.into_iter()
+		.zip(backend.child_storage_roots(child_deltas))
+	{
+		let prefixed_storage_key = child_info.prefixed_storage_key();
+		txs.consolidate(child_txs);
+		if empty {
+			child_roots.push((prefixed_storage_key.into_inner(), None));
+		} else {
+			child_roots.push((prefixed_storage_key.into_inner(), Some(child_root.encode())));
+		}
+		new_child_roots.push(child_root);
+	}
+	let (root, parent_txs) = backend.storage_root(delta
+		.map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..])))
+		.chain(
+			child_roots
+				.iter()
+				.map(|(k, v)| (&k[..], v.as_ref().map(|v| &v[..])))
+		)
+	);
+	txs.consolidate(parent_txs);
+	(root, new_child_roots, txs)
+}
+
 impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 	type Error = T::Error;
 	type Transaction = T::Transaction;
@@ -247,6 +369,14 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 		(*self).storage(key)
 	}
 
+	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
+		(*self).storage_hash(key)
+	}
+
+	fn storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<StorageValue>>, Self::Error> {
+		(*self).storage_batch(keys)
+	}
+
 	fn child_storage(
 		&self,
 		child_info: &ChildInfo,
@@ -255,6 +385,14 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 		(*self).child_storage(child_info, key)
 	}
 
+	fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, Self::Error> {
+		(*self).child_storage_hash(child_info, key)
+	}
+
 	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
 		&self,
 		child_info: &ChildInfo,
@@ -303,10 +441,25 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 		(*self).child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_roots<'b>(
+		&self,
+		child_deltas: Vec<(&'b ChildInfo, Vec<(&'b [u8], Option<&'b [u8]>)>)>,
+	) -> Vec<(H::Out, bool, Self::Transaction)> where H::Out: Ord {
+		(*self).child_storage_roots(child_deltas)
+	}
+
 	fn pairs(&self) -> Vec<(StorageKey, StorageValue)> {
 		(*self).pairs()
 	}
 
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		(*self).estimate_pairs_count()
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		(*self).estimate_storage_size()
+	}
+
 	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
 		(*self).for_key_values_with_prefix(prefix, f);
 	}
@@ -318,6 +471,80 @@ impl<'a, T: Backend<H>, H: Hasher> Backend<H> for &'a T {
 	}
 }
 
+/// Iterator over the keys of a backend, created by `Backend::keys_iter` and
+/// `Backend::child_keys_iter`.
+///
+/// Each key is found with `next_storage_key` from the previous one. Iteration stops after the
+/// first error.
+pub struct KeysIter<'a, B, H> {
+	backend: &'a B,
+	child_info: Option<&'a ChildInfo>,
+	prefix: &'a [u8],
+	last: Option<StorageKey>,
+	done: bool,
+	_marker: std::marker::PhantomData<H>,
+}
+
+impl<'a, B: Backend<H>, H: Hasher> KeysIter<'a, B, H> {
+	fn new(backend: &'a B, child_info: Option<&'a ChildInfo>, prefix: &'a [u8]) -> Self {
+		KeysIter {
+			backend,
+			child_info,
+			prefix,
+			last: None,
+			done: false,
+			_marker: std::marker::PhantomData,
+		}
+	}
+
+	fn first_key(&self) -> Result<Option<StorageKey>, B::Error> {
+		let exists = match self.child_info {
+			Some(child_info) => self.backend.exists_child_storage(child_info, self.prefix)?,
+			None => self.backend.exists_storage(self.prefix)?,
+		};
+		if exists {
+			Ok(Some(self.prefix.to_vec()))
+		} else {
+			self.next_key(self.prefix)
+		}
+	}
+
+	fn next_key(&self, key: &[u8]) -> Result<Option<StorageKey>, B::Error> {
+		match self.child_info {
+			Some(child_info) => self.backend.next_child_storage_key(child_info, key),
+			None => self.backend.next_storage_key(key),
+		}
+	}
+}
+
+impl<'a, B: Backend<H>, H: Hasher> Iterator for KeysIter<'a, B, H> {
+	type Item = Result<StorageKey, B::Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+		let next = match self.last.take() {
+			Some(last) => self.next_key(&last),
+			None => self.first_key(),
+		};
+		match next {
+			Ok(Some(key)) if key.starts_with(self.prefix) => {
+				self.last = Some(key.clone());
+				Some(Ok(key))
+			},
+			Ok(_) => {
+				self.done = true;
+				None
+			},
+			Err(e) => {
+				self.done = true;
+				Some(Err(e))
+			},
+		}
+	}
+}
+
 /// Trait that allows consolidate two transactions together.
 pub trait Consolidate {
 	/// Consolidate two transactions into one.
@@ -195,15 +195,18 @@ impl Externalities for BasicExternalities {
 		key: StorageKey,
 		value: Option<StorageValue>,
 	) {
-		let child_map = self.inner.children_default.entry(child_info.storage_key().to_vec())
-			.or_insert_with(|| StorageChild {
-				data: Default::default(),
-				child_info: child_info.to_owned(),
-			});
 		if let Some(value) = value {
-			child_map.data.insert(key, value);
-		} else {
-			child_map.data.remove(&key);
+			self.inner.children_default.entry(child_info.storage_key().to_vec())
+				.or_insert_with(|| StorageChild {
+					data: Default::default(),
+					child_info: child_info.to_owned(),
+				})
+				.data.insert(key, value);
+		} else if let Some(child) = self.inner.children_default.get_mut(child_info.storage_key()) {
+			child.data.remove(&key);
+			if child.data.is_empty() {
+				self.inner.children_default.remove(child_info.storage_key());
+			}
 		}
 	}
 
@@ -214,6 +217,37 @@ impl Externalities for BasicExternalities {
 		self.inner.children_default.remove(child_info.storage_key());
 	}
 
+	fn kill_child_storage_limited(
+		&mut self,
+		child_info: &ChildInfo,
+		limit: u32,
+		cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>) {
+		let child = match self.inner.children_default.get_mut(child_info.storage_key()) {
+			Some(child) => child,
+			None => return (0, None),
+		};
+		let start = cursor.unwrap_or(&[]);
+		let mut to_remove = child.data.range::<[u8], _>((Bound::Included(start), Bound::Unbounded))
+			.map(|(k, _)| k)
+			.take(limit as usize + 1)
+			.cloned()
+			.collect::<Vec<_>>();
+		let next = if to_remove.len() > limit as usize {
+			to_remove.pop()
+		} else {
+			None
+		};
+
+		for key in to_remove.iter() {
+			child.data.remove(key);
+		}
+		if child.data.is_empty() {
+			self.inner.children_default.remove(child_info.storage_key());
+		}
+		(to_remove.len() as u32, next)
+	}
+
 	fn clear_prefix(&mut self, prefix: &[u8]) {
 		if is_child_storage_key(prefix) {
 			warn!(
@@ -234,6 +268,42 @@ impl Externalities for BasicExternalities {
 		}
 	}
 
+	fn clear_prefix_limited(
+		&mut self,
+		prefix: &[u8],
+		limit: u32,
+		cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>) {
+		if is_child_storage_key(prefix) {
+			warn!(
+				target: "trie",
+				"Refuse to clear prefix that is part of child storage key via main storage"
+			);
+			return (0, None);
+		}
+
+		let start = match cursor {
+			Some(cursor) if cursor > prefix => cursor,
+			_ => prefix,
+		};
+		let mut to_remove = self.inner.top.range::<[u8], _>((Bound::Included(start), Bound::Unbounded))
+			.map(|(k, _)| k)
+			.take_while(|k| k.starts_with(prefix))
+			.take(limit as usize + 1)
+			.cloned()
+			.collect::<Vec<_>>();
+		let next = if to_remove.len() > limit as usize {
+			to_remove.pop()
+		} else {
+			None
+		};
+
+		for key in to_remove.iter() {
+			self.inner.top.remove(key);
+		}
+		(to_remove.len() as u32, next)
+	}
+
 	fn clear_child_prefix(
 		&mut self,
 		child_info: &ChildInfo,
@@ -249,6 +319,9 @@ impl Externalities for BasicExternalities {
 			for key in to_remove {
 				child.data.remove(&key);
 			}
+			if child.data.is_empty() {
+				self.inner.children_default.remove(child_info.storage_key());
+			}
 		}
 	}
 
@@ -257,6 +330,11 @@ impl Externalities for BasicExternalities {
 		key: Vec<u8>,
 		value: Vec<u8>,
 	) {
+		if is_child_storage_key(&key) {
+			warn!(target: "trie", "Refuse to append to child storage key via main storage");
+			return;
+		}
+
 		let current = self.inner.top.entry(key).or_default();
 		crate::ext::StorageAppend::new(current).append(value);
 	}
@@ -281,7 +359,7 @@ impl Externalities for BasicExternalities {
 			}
 		}
 
-		Layout::<Blake2Hasher>::trie_root(self.inner.top.clone()).as_ref().into()
+		Layout::<Blake2Hasher>::trie_root(top).as_ref().into()
 	}
 
 	fn child_storage_root(
@@ -374,6 +452,36 @@ mod tests {
 		assert_eq!(&ext.storage_root()[..], &ROOT);
 	}
 
+	#[test]
+	fn clear_prefix_limited_works() {
+		let mut ext = BasicExternalities::default();
+		ext.set_storage(b"aaa".to_vec(), b"0".to_vec());
+		ext.set_storage(b"abb".to_vec(), b"1".to_vec());
+		ext.set_storage(b"abc".to_vec(), b"2".to_vec());
+		ext.set_storage(b"abd".to_vec(), b"3".to_vec());
+
+		assert_eq!(ext.clear_prefix_limited(b"ab", 2, None), (2, Some(b"abd".to_vec())));
+		assert_eq!(ext.storage(b"abc"), None);
+		assert_eq!(ext.clear_prefix_limited(b"ab", 2, Some(b"abd")), (1, None));
+		assert_eq!(ext.storage(b"aaa"), Some(b"0".to_vec()));
+	}
+
+	#[test]
+	fn kill_child_storage_limited_works() {
+		let child_info = ChildInfo::new_default(b"storage_key");
+		let child_info = &child_info;
+		let mut ext = BasicExternalities::default();
+		ext.set_child_storage(child_info, b"a".to_vec(), b"0".to_vec());
+		ext.set_child_storage(child_info, b"b".to_vec(), b"1".to_vec());
+		ext.set_child_storage(child_info, b"c".to_vec(), b"2".to_vec());
+
+		assert_eq!(ext.kill_child_storage_limited(child_info, 2, None), (2, Some(b"c".to_vec())));
+		assert_eq!(ext.child_storage(child_info, b"b"), None);
+		assert_eq!(ext.child_storage(child_info, b"c"), Some(b"2".to_vec()));
+		assert_eq!(ext.kill_child_storage_limited(child_info, 2, Some(b"c")), (1, None));
+		assert_eq!(ext.kill_child_storage_limited(child_info, 2, None), (0, None));
+	}
+
 	#[test]
 	fn set_and_retrieve_code() {
 		let mut ext = BasicExternalities::default();
@@ -410,6 +518,29 @@ mod tests {
 		assert_eq!(ext.child_storage(child_info, b"doe"), None);
 	}
 
+	#[test]
+	fn storage_root_includes_child_roots() {
+		let child_info = ChildInfo::new_default(b"storage_key");
+		let empty_child_info = ChildInfo::new_default(b"empty_key");
+		let mut ext = BasicExternalities::default();
+		ext.set_storage(b"doe".to_vec(), b"reindeer".to_vec());
+		ext.set_child_storage(&child_info, b"dog".to_vec(), b"puppy".to_vec());
+		ext.set_child_storage(&child_info, b"cat".to_vec(), b"kitten".to_vec());
+		ext.set_child_storage(&empty_child_info, b"dog".to_vec(), b"puppy".to_vec());
+		ext.clear_child_storage(&empty_child_info, b"dog");
+
+		let backend = crate::in_memory_backend::new_in_mem::<Blake2Hasher>();
+		let (root, _) = backend.full_storage_root(
+			vec![(&b"doe"[..], Some(&b"reindeer"[..]))].into_iter(),
+			vec![
+				(&child_info, vec![(&b"cat"[..], Some(&b"kitten"[..])), (&b"dog"[..], Some(&b"puppy"[..]))].into_iter()),
+				(&empty_child_info, vec![].into_iter()),
+			].into_iter(),
+		);
+		assert_eq!(ext.storage_root(), root.as_ref().to_vec());
+		assert_eq!(ext.into_storages().children_default.len(), 1);
+	}
+
 	#[test]
 	fn basic_externalities_is_empty() {
 		// Make sure no values are set by default in `BasicExternalities`.
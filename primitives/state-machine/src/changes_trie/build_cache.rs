@@ -19,6 +19,7 @@
 
 use std::collections::{HashMap, HashSet};
 
+use codec::{Decode, Encode, Input, Output};
 use crate::StorageKey;
 use sp_core::storage::PrefixedStorageKey;
 
@@ -44,7 +45,7 @@ pub struct BuildCache<H, N> {
 }
 
 /// The action to perform when block-with-changes-trie is imported.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Encode, Decode)]
 pub enum CacheAction<H, N> {
 	/// Cache data that has been collected when CT has been built.
 	CacheBuildData(CachedBuildData<H, N>),
@@ -61,6 +62,45 @@ pub struct CachedBuildData<H, N> {
 	changed_keys: HashMap<Option<PrefixedStorageKey>, HashSet<StorageKey>>,
 }
 
+/// Changed keys are encoded ordered by child trie and key.
+impl<H: Encode, N: Encode> Encode for CachedBuildData<H, N> {
+	fn encode_to<T: Output>(&self, dest: &mut T) {
+		self.block.encode_to(dest);
+		self.trie_root.encode_to(dest);
+		self.digest_input_blocks.encode_to(dest);
+		let mut changed_keys: Vec<_> = self.changed_keys.iter()
+			.map(|(storage_key, keys)| {
+				let mut keys: Vec<_> = keys.iter().collect();
+				keys.sort();
+				(storage_key.as_ref().map(|storage_key| &storage_key[..]), keys)
+			})
+			.collect();
+		changed_keys.sort();
+		changed_keys.encode_to(dest);
+	}
+}
+
+impl<H: Decode, N: Decode> Decode for CachedBuildData<H, N> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+		let block = Decode::decode(input)?;
+		let trie_root = Decode::decode(input)?;
+		let digest_input_blocks = Decode::decode(input)?;
+		let changed_keys = <Vec<(Option<Vec<u8>>, Vec<StorageKey>)>>::decode(input)?
+			.into_iter()
+			.map(|(storage_key, keys)| (
+				storage_key.map(PrefixedStorageKey::new),
+				keys.into_iter().collect(),
+			))
+			.collect();
+		Ok(CachedBuildData {
+			block,
+			trie_root,
+			digest_input_blocks,
+			changed_keys,
+		})
+	}
+}
+
 /// The action to perform when block-with-changes-trie is imported.
 #[derive(Debug, PartialEq)]
 pub(crate) enum IncompleteCacheAction<N> {
@@ -258,7 +258,7 @@ pub fn build_changes_trie<'a, B: Backend<H>, H: Hasher, Number: BlockNumber>(
 
 	// prepare configuration range - we already know zero block. Current block may be the end block if configuration
 	// has been changed in this block
-	let is_config_changed = match changes.storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG) {
+	let is_config_changed = match changes.well_known_storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG) {
 		Some(Some(new_config)) => new_config != &state.config.encode()[..],
 		Some(None) => true,
 		None => false,
@@ -41,6 +41,32 @@ pub enum ExecutionError {
 	UnableToGenerateProof,
 	/// Invalid execution proof.
 	InvalidProof,
+	/// The called method is not in the method whitelist of the state machine.
+	MethodNotAllowed(String),
+	/// The call tried to write a protected well known key. Contains the key.
+	ProtectedKeyWrite(Vec<u8>),
+	/// The state root computed after executing a block differs from the one of its header.
+	/// Contains the encoded expected and computed roots.
+	StateRootMismatch(Vec<u8>, Vec<u8>),
+	/// The changes trie root computed after executing a block differs from the one of its
+	/// header. Contains the encoded expected and computed roots.
+	ChangesTrieRootMismatch(Option<Vec<u8>>, Option<Vec<u8>>),
+	/// The runtime nested `call_in_wasm` calls deeper than allowed. Contains the limit.
+	NestedCallLimitExceeded(usize),
+	/// The execution was cancelled through its `ExecutionInterrupt`.
+	Cancelled,
+	/// The execution was still running at the deadline of its `ExecutionInterrupt`.
+	DeadlineExceeded,
+	/// The execution policy gave no step to execute the method with. Contains the method.
+	NoExecutionStep(String),
+	/// The backend of a replayed execution is not the recorded one. Contains the encoded
+	/// recorded and found roots.
+	ReplayStateMismatch(Vec<u8>, Vec<u8>),
+	/// The runtime code of a replayed execution is not the recorded one. Contains the recorded
+	/// and found code hashes.
+	ReplayCodeMismatch(Vec<u8>, Vec<u8>),
+	/// The value of a well known key could not be decoded. Contains the key.
+	InvalidWellKnownValue(Vec<u8>),
 }
 
 impl fmt::Display for ExecutionError {
@@ -18,7 +18,8 @@
 //! Concrete externalities implementation.
 
 use crate::{
-	StorageKey, StorageValue, OverlayedChanges, StorageTransactionCache,
+	StorageKey, StorageValue, OverlayedChanges, StorageTransactionCache, ExecutionInterrupt,
+	StorageAccessKind, StorageAccessTracker, ExecutionError,
 	backend::Backend,
 	changes_trie::State as ChangesTrieState,
 };
@@ -26,7 +27,7 @@ use crate::{
 use hash_db::Hasher;
 use sp_core::{
 	offchain::storage::OffchainOverlayedChanges,
-	storage::{well_known_keys::is_child_storage_key, ChildInfo, TrackedStorageKey},
+	storage::{well_known_keys::{self, is_child_storage_key}, ChildInfo, TrackedStorageKey},
 	traits::Externalities, hexdisplay::HexDisplay,
 };
 use sp_trie::{trie_types::Layout, empty_child_trie_root};
@@ -42,6 +43,14 @@ const BENCHMARKING_FN: &str = "\
 	For that reason client started transactions before calling into runtime are not allowed.
 	Without client transactions the loop condition garantuees the success of the tx close.";
 
+/// Well known keys that cannot be written when protection is enabled, see
+/// `Ext::protect_well_known_keys`.
+const PROTECTED_WELL_KNOWN_KEYS: [&[u8]; 3] = [
+	well_known_keys::CODE,
+	well_known_keys::HEAP_PAGES,
+	well_known_keys::CHANGES_TRIE_CONFIG,
+];
+
 /// Errors that can occur when interacting with the externalities.
 #[derive(Debug, Copy, Clone)]
 pub enum Error<B, E> {
@@ -94,6 +103,12 @@ pub struct Ext<'a, H, N, B>
 	_phantom: std::marker::PhantomData<N>,
 	/// Extensions registered with this instance.
 	extensions: Option<&'a mut Extensions>,
+	/// Rejected writes to protected well known keys, `None` when protection is disabled.
+	rejected_writes: Option<Vec<StorageKey>>,
+	/// Interrupt aborting the execution at the next storage access once triggered.
+	interrupt: Option<ExecutionInterrupt>,
+	/// Journal of the storage accesses, `None` when tracking is disabled.
+	access_tracker: Option<StorageAccessTracker>,
 }
 
 impl<'a, H, N, B> Ext<'a, H, N, B>
@@ -122,20 +137,130 @@ where
 			id: rand::random(),
 			_phantom: Default::default(),
 			extensions,
+			rejected_writes: None,
+			interrupt: None,
+			access_tracker: None,
+		}
+	}
+
+	/// Reject writes to the `:code`, `:heappages` and `:changes_trie` well known keys.
+	///
+	/// Rejected writes are ignored and can be queried with `take_rejected_writes`.
+	pub fn protect_well_known_keys(&mut self) {
+		self.rejected_writes.get_or_insert_with(Vec::new);
+	}
+
+	/// Abort the execution at the next storage access once `interrupt` is triggered.
+	///
+	/// The execution is aborted by panicking, the caller is expected to catch it.
+	pub fn set_interrupt(&mut self, interrupt: ExecutionInterrupt) {
+		self.interrupt = Some(interrupt);
+	}
+
+	/// Panics if the interrupt is triggered. Must be called before any `AbortGuard::force_abort`.
+	fn check_interrupt(&self) {
+		if let Some(reason) = self.interrupt.as_ref().and_then(ExecutionInterrupt::triggered) {
+			panic!("Execution interrupted: {:?}", reason);
 		}
 	}
 
+	/// Record the storage accesses in `tracker`, in the order they are made.
+	pub fn set_access_tracker(&mut self, tracker: StorageAccessTracker) {
+		self.access_tracker = Some(tracker);
+	}
+
+	/// Record an access to `key` if tracking is enabled.
+	fn note_access(&self, child_info: Option<&ChildInfo>, key: &[u8], kind: StorageAccessKind) {
+		if let Some(tracker) = self.access_tracker.as_ref() {
+			tracker.record(child_info, key, kind);
+		}
+	}
+
+	/// Returns the keys of the writes rejected since protection was enabled.
+	pub fn take_rejected_writes(&mut self) -> Vec<StorageKey> {
+		self.rejected_writes.as_mut().map(std::mem::take).unwrap_or_default()
+	}
+
+	/// Check a write to `key`, or to all keys starting with `key` when `is_prefix` is set.
+	/// Returns `false` and records the rejection if it touches a protected key.
+	fn check_protected_write(&mut self, key: &[u8], is_prefix: bool) -> bool {
+		if let Some(rejected_writes) = self.rejected_writes.as_mut() {
+			let protected = PROTECTED_WELL_KNOWN_KEYS.iter().any(|protected|
+				if is_prefix { protected.starts_with(key) } else { *protected == key }
+			);
+			if protected {
+				warn!(target: "state", "Refuse to write protected well known key {}", HexDisplay::from(&key));
+				rejected_writes.push(key.to_vec());
+				return false;
+			}
+		}
+		true
+	}
+
 	/// Invalidates the currently cached storage root and the db transaction.
 	///
 	/// Called when there are changes that likely will invalidate the storage root.
+	/// Cached child trie roots are kept, they only depend on changes to their child trie.
 	fn mark_dirty(&mut self) {
+		let child_storage_roots = std::mem::take(&mut self.storage_transaction_cache.child_storage_roots);
 		self.storage_transaction_cache.reset();
+		self.storage_transaction_cache.child_storage_roots = child_storage_roots;
+	}
+
+	/// Same as `mark_dirty`, also invalidating the cached root of the child trie at `storage_key`.
+	fn mark_child_dirty(&mut self, storage_key: &[u8]) {
+		self.mark_dirty();
+		self.storage_transaction_cache.child_storage_roots.remove(storage_key);
 	}
 
 	/// Read only accessor for the scheduled overlay changes.
 	pub fn get_offchain_storage_changes(&self) -> &OffchainOverlayedChanges {
 		&*self.offchain_overlay
 	}
+
+	/// Runtime code stored at `:code`.
+	pub fn code(&self) -> Result<Option<StorageValue>, ExecutionError> {
+		self.well_known_storage(well_known_keys::CODE)
+	}
+
+	/// Heap pages stored at `:heappages`.
+	pub fn heap_pages(&self) -> Result<Option<u64>, ExecutionError> {
+		self.decode_well_known(well_known_keys::HEAP_PAGES)
+	}
+
+	/// Changes trie configuration stored at `:changes_trie`.
+	pub fn changes_trie_config(
+		&self,
+	) -> Result<Option<sp_core::ChangesTrieConfiguration>, ExecutionError> {
+		self.decode_well_known(well_known_keys::CHANGES_TRIE_CONFIG)
+	}
+
+	/// Read a well known key on behalf of the client.
+	///
+	/// Unlike runtime reads, backend errors are returned and the read is tallied apart in the
+	/// overlay statistics.
+	fn well_known_storage(&self, key: &[u8]) -> Result<Option<StorageValue>, ExecutionError> {
+		self.note_access(None, key, StorageAccessKind::Read);
+		let result = match self.overlay.well_known_storage(key) {
+			Some(value) => value.map(|value| value.to_vec()),
+			None => self.backend.storage(key).map_err(|e| ExecutionError::Backend(e.to_string()))?,
+		};
+		trace!(target: "state", "{:04x}: GetWellKnown {}={:?}",
+			self.id,
+			HexDisplay::from(&key),
+			result.as_ref().map(HexDisplay::from)
+		);
+		Ok(result)
+	}
+
+	fn decode_well_known<T: Decode>(&self, key: &[u8]) -> Result<Option<T>, ExecutionError> {
+		match self.well_known_storage(key)? {
+			Some(value) => T::decode(&mut &value[..])
+				.map(Some)
+				.map_err(|_| ExecutionError::InvalidWellKnownValue(key.to_vec())),
+			None => Ok(None),
+		}
+	}
 }
 
 #[cfg(test)]
@@ -176,6 +301,8 @@ where
 	}
 
 	fn storage(&self, key: &[u8]) -> Option<StorageValue> {
+		self.check_interrupt();
+		self.note_access(None, key, StorageAccessKind::Read);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let result = self.overlay.storage(key).map(|x| x.map(|x| x.to_vec())).unwrap_or_else(||
 			self.backend.storage(key).expect(EXT_NOT_ALLOWED_TO_FAIL));
@@ -188,6 +315,8 @@ where
 	}
 
 	fn storage_hash(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.check_interrupt();
+		self.note_access(None, key, StorageAccessKind::Read);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let result = self.overlay
 			.storage(key)
@@ -207,6 +336,8 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Option<StorageValue> {
+		self.check_interrupt();
+		self.note_access(Some(child_info), key, StorageAccessKind::Read);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let result = self.overlay
 			.child_storage(child_info, key)
@@ -231,6 +362,8 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Option<Vec<u8>> {
+		self.check_interrupt();
+		self.note_access(Some(child_info), key, StorageAccessKind::Read);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let result = self.overlay
 			.child_storage(child_info, key)
@@ -251,6 +384,8 @@ where
 	}
 
 	fn exists_storage(&self, key: &[u8]) -> bool {
+		self.check_interrupt();
+		self.note_access(None, key, StorageAccessKind::Read);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let result = match self.overlay.storage(key) {
 			Some(x) => x.is_some(),
@@ -271,6 +406,8 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> bool {
+		self.check_interrupt();
+		self.note_access(Some(child_info), key, StorageAccessKind::Read);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 
 		let result = match self.overlay.child_storage(child_info, key) {
@@ -290,6 +427,8 @@ where
 	}
 
 	fn next_storage_key(&self, key: &[u8]) -> Option<StorageKey> {
+		self.check_interrupt();
+		self.note_access(None, key, StorageAccessKind::NextKey);
 		let next_backend_key = self.backend.next_storage_key(key).expect(EXT_NOT_ALLOWED_TO_FAIL);
 		let next_overlay_key_change = self.overlay.next_storage_key_change(key);
 
@@ -309,6 +448,8 @@ where
 		child_info: &ChildInfo,
 		key: &[u8],
 	) -> Option<StorageKey> {
+		self.check_interrupt();
+		self.note_access(Some(child_info), key, StorageAccessKind::NextKey);
 		let next_backend_key = self.backend
 			.next_child_storage_key(child_info, key)
 			.expect(EXT_NOT_ALLOWED_TO_FAIL);
@@ -332,6 +473,8 @@ where
 	}
 
 	fn place_storage(&mut self, key: StorageKey, value: Option<StorageValue>) {
+		self.check_interrupt();
+		self.note_access(None, &key, StorageAccessKind::Write);
 		trace!(target: "state", "{:04x}: Put {}={:?}",
 			self.id,
 			HexDisplay::from(&key),
@@ -342,6 +485,9 @@ where
 			warn!(target: "trie", "Refuse to directly set child storage key");
 			return;
 		}
+		if !self.check_protected_write(&key, false) {
+			return;
+		}
 
 		self.mark_dirty();
 		self.overlay.set_storage(key, value);
@@ -353,6 +499,8 @@ where
 		key: StorageKey,
 		value: Option<StorageValue>,
 	) {
+		self.check_interrupt();
+		self.note_access(Some(child_info), &key, StorageAccessKind::Write);
 		trace!(target: "state", "{:04x}: PutChild({}) {}={:?}",
 			self.id,
 			HexDisplay::from(&child_info.storage_key()),
@@ -361,7 +509,7 @@ where
 		);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 
-		self.mark_dirty();
+		self.mark_child_dirty(child_info.storage_key());
 		self.overlay.set_child_storage(child_info, key, value);
 	}
 
@@ -369,20 +517,60 @@ where
 		&mut self,
 		child_info: &ChildInfo,
 	) {
+		self.check_interrupt();
+		self.note_access(Some(child_info), &[], StorageAccessKind::KillChild);
 		trace!(target: "state", "{:04x}: KillChild({})",
 			self.id,
 			HexDisplay::from(&child_info.storage_key()),
 		);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 
-		self.mark_dirty();
+		self.mark_child_dirty(child_info.storage_key());
 		self.overlay.clear_child_storage(child_info);
 		self.backend.for_keys_in_child_storage(child_info, |key| {
 			self.overlay.set_child_storage(child_info, key.to_vec(), None);
 		});
 	}
 
+	fn kill_child_storage_limited(
+		&mut self,
+		child_info: &ChildInfo,
+		limit: u32,
+		cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>) {
+		self.check_interrupt();
+		self.note_access(Some(child_info), &[], StorageAccessKind::KillChild);
+		trace!(target: "state", "{:04x}: KillChildLimited({}) limit={} cursor={:?}",
+			self.id,
+			HexDisplay::from(&child_info.storage_key()),
+			limit,
+			HexDisplay::from(&cursor.unwrap_or(&[])),
+		);
+		let _guard = sp_panic_handler::AbortGuard::force_abort();
+
+		self.mark_child_dirty(child_info.storage_key());
+		let start = cursor.unwrap_or(&[]);
+		// Keys are visited through the overlay and the backend merged, skipping cleared ones.
+		let mut next = if self.exists_child_storage(child_info, start) {
+			Some(start.to_vec())
+		} else {
+			self.next_child_storage_key(child_info, start)
+		};
+		let mut cleared = 0;
+		while let Some(key) = next {
+			if cleared == limit {
+				return (cleared, Some(key));
+			}
+			self.overlay.set_child_storage(child_info, key.clone(), None);
+			cleared += 1;
+			next = self.next_child_storage_key(child_info, &key);
+		}
+		(cleared, None)
+	}
+
 	fn clear_prefix(&mut self, prefix: &[u8]) {
+		self.check_interrupt();
+		self.note_access(None, prefix, StorageAccessKind::ClearPrefix);
 		trace!(target: "state", "{:04x}: ClearPrefix {}",
 			self.id,
 			HexDisplay::from(&prefix),
@@ -392,6 +580,9 @@ where
 			warn!(target: "trie", "Refuse to directly clear prefix that is part of child storage key");
 			return;
 		}
+		if !self.check_protected_write(prefix, true) {
+			return;
+		}
 
 		self.mark_dirty();
 		self.overlay.clear_prefix(prefix);
@@ -400,11 +591,62 @@ where
 		});
 	}
 
+	fn clear_prefix_limited(
+		&mut self,
+		prefix: &[u8],
+		limit: u32,
+		cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>) {
+		self.check_interrupt();
+		self.note_access(None, prefix, StorageAccessKind::ClearPrefix);
+		trace!(target: "state", "{:04x}: ClearPrefixLimited {} limit={} cursor={:?}",
+			self.id,
+			HexDisplay::from(&prefix),
+			limit,
+			HexDisplay::from(&cursor.unwrap_or(&[])),
+		);
+		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		if is_child_storage_key(prefix) {
+			warn!(target: "trie", "Refuse to directly clear prefix that is part of child storage key");
+			return (0, None);
+		}
+		if !self.check_protected_write(prefix, true) {
+			return (0, None);
+		}
+
+		self.mark_dirty();
+		let start = match cursor {
+			Some(cursor) if cursor > prefix => cursor,
+			_ => prefix,
+		};
+		// Keys are visited through the overlay and the backend merged, skipping cleared ones.
+		let mut next = if self.exists_storage(start) {
+			Some(start.to_vec())
+		} else {
+			self.next_storage_key(start)
+		};
+		let mut cleared = 0;
+		while let Some(key) = next {
+			if !key.starts_with(prefix) {
+				break;
+			}
+			if cleared == limit {
+				return (cleared, Some(key));
+			}
+			self.overlay.set_storage(key.clone(), None);
+			cleared += 1;
+			next = self.next_storage_key(&key);
+		}
+		(cleared, None)
+	}
+
 	fn clear_child_prefix(
 		&mut self,
 		child_info: &ChildInfo,
 		prefix: &[u8],
 	) {
+		self.check_interrupt();
+		self.note_access(Some(child_info), prefix, StorageAccessKind::ClearPrefix);
 		trace!(target: "state", "{:04x}: ClearChildPrefix({}) {}",
 			self.id,
 			HexDisplay::from(&child_info.storage_key()),
@@ -412,7 +654,7 @@ where
 		);
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 
-		self.mark_dirty();
+		self.mark_child_dirty(child_info.storage_key());
 		self.overlay.clear_child_prefix(child_info, prefix);
 		self.backend.for_child_keys_with_prefix(child_info, prefix, |key| {
 			self.overlay.set_child_storage(child_info, key.to_vec(), None);
@@ -424,6 +666,8 @@ where
 		key: Vec<u8>,
 		value: Vec<u8>,
 	) {
+		self.check_interrupt();
+		self.note_access(None, &key, StorageAccessKind::Write);
 		trace!(target: "state", "{:04x}: Append {}={}",
 			self.id,
 			HexDisplay::from(&key),
@@ -431,6 +675,9 @@ where
 		);
 
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
+		if !self.check_protected_write(&key, false) {
+			return;
+		}
 		self.mark_dirty();
 
 		let backend = &mut self.backend;
@@ -467,7 +714,14 @@ where
 		let _guard = sp_panic_handler::AbortGuard::force_abort();
 		let storage_key = child_info.storage_key();
 		let prefixed_storage_key = child_info.prefixed_storage_key();
-		if self.storage_transaction_cache.transaction_storage_root.is_some() {
+		if let Some(root) = self.storage_transaction_cache.child_storage_roots.get(storage_key) {
+			trace!(target: "state", "{:04x}: ChildRoot({})(cached) {}",
+				self.id,
+				HexDisplay::from(&storage_key),
+				HexDisplay::from(&root.as_slice()),
+			);
+			root.clone()
+		} else if self.storage_transaction_cache.transaction_storage_root.is_some() {
 			let root = self
 				.storage(prefixed_storage_key.as_slice())
 				.and_then(|k| Decode::decode(&mut &k[..]).ok())
@@ -506,6 +760,7 @@ where
 					HexDisplay::from(&storage_key.as_ref()),
 					HexDisplay::from(&root.as_ref()),
 				);
+				self.storage_transaction_cache.child_storage_roots.insert(storage_key.to_vec(), root.clone());
 				root
 			} else {
 				// empty overlay
@@ -555,7 +810,7 @@ where
 	}
 
 	fn storage_rollback_transaction(&mut self) -> Result<(), ()> {
-		self.mark_dirty();
+		self.storage_transaction_cache.reset();
 		self.overlay.rollback_transaction().map_err(|_| ())
 	}
 
@@ -924,6 +1179,34 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn child_storage_root_is_cached_per_child() {
+		let child1 = ChildInfo::new_default(b"Child1");
+		let child2 = ChildInfo::new_default(b"Child2");
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = TestBackend::default();
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		ext.place_child_storage(&child1, vec![10], Some(vec![10]));
+		ext.place_child_storage(&child2, vec![20], Some(vec![20]));
+		let root1 = ext.child_storage_root(&child1);
+		let root2 = ext.child_storage_root(&child2);
+		assert_eq!(ext.storage_transaction_cache.child_storage_roots.len(), 2);
+
+		// writing a child trie only invalidates its own root
+		ext.place_child_storage(&child2, vec![21], Some(vec![21]));
+		ext.place_storage(vec![1], Some(vec![1]));
+		assert_eq!(
+			ext.storage_transaction_cache.child_storage_roots.get(child1.storage_key()),
+			Some(&root1),
+		);
+		assert!(ext.storage_transaction_cache.child_storage_roots.get(child2.storage_key()).is_none());
+		assert_eq!(ext.child_storage_root(&child1), root1);
+		assert!(ext.child_storage_root(&child2) != root2);
+	}
+
 	#[test]
 	fn storage_append_works() {
 		let mut data = Vec::new();
@@ -943,4 +1226,81 @@ mod tests {
 
 		assert_eq!(Vec::<u32>::decode(&mut &data[..]).unwrap(), vec![1, 2]);
 	}
+
+	#[test]
+	fn access_tracker_records_accesses_in_order() {
+		let child_info = ChildInfo::new_default(b"Child1");
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage {
+			top: map![vec![10] => vec![10]],
+			children_default: map![],
+		}.into();
+		let tracker = StorageAccessTracker::new();
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		ext.set_access_tracker(tracker.clone());
+		assert_eq!(ext.storage(&[10]), Some(vec![10]));
+		ext.place_child_storage(&child_info, vec![20], Some(vec![20]));
+		assert_eq!(ext.child_storage(&child_info, &[20]), Some(vec![20]));
+		ext.clear_prefix(&[1]);
+
+		let access = |child: Option<&[u8]>, key: &[u8], kind| crate::StorageAccess {
+			child: child.map(|child| child.to_vec()),
+			key: key.to_vec(),
+			kind,
+		};
+		assert_eq!(tracker.accesses(), vec![
+			access(None, &[10], StorageAccessKind::Read),
+			access(Some(&b"Child1"[..]), &[20], StorageAccessKind::Write),
+			access(Some(&b"Child1"[..]), &[20], StorageAccessKind::Read),
+			access(None, &[1], StorageAccessKind::ClearPrefix),
+		]);
+	}
+
+	#[test]
+	fn well_known_keys_accessors_work() {
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(well_known_keys::HEAP_PAGES.to_vec(), Some(vec![1, 2]));
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage {
+			top: map![
+				well_known_keys::CODE.to_vec() => vec![42],
+				well_known_keys::CHANGES_TRIE_CONFIG.to_vec() => changes_trie_config().encode()
+			],
+			children_default: map![],
+		}.into();
+
+		let ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		assert_eq!(ext.code(), Ok(Some(vec![42])));
+		assert_eq!(ext.changes_trie_config(), Ok(Some(changes_trie_config())));
+		assert_eq!(
+			ext.heap_pages(),
+			Err(ExecutionError::InvalidWellKnownValue(well_known_keys::HEAP_PAGES.to_vec())),
+		);
+		drop(ext);
+
+		assert_eq!(*overlay.stats().reads_well_known.borrow(), 1);
+		assert_eq!(*overlay.stats().reads_modified.borrow(), 0);
+	}
+
+	#[test]
+	#[should_panic(expected = "Execution interrupted")]
+	fn exists_storage_checks_interrupt() {
+		let mut cache = StorageTransactionCache::default();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = prepare_offchain_overlay_with_changes();
+		let backend = Storage {
+			top: map![vec![10] => vec![10]],
+			children_default: map![],
+		}.into();
+
+		let mut ext = TestExt::new(&mut overlay, &mut offchain_overlay, &mut cache, &backend, None, None);
+		let interrupt = ExecutionInterrupt::new();
+		interrupt.cancel();
+		ext.set_interrupt(interrupt);
+		ext.exists_storage(&[10]);
+	}
 }
@@ -19,18 +19,19 @@
 use crate::{
 	StorageKey, StorageValue, StorageCollection,
 	trie_backend::TrieBackend,
+	trie_backend_essence::TrieBackendStorage,
 };
-use std::{collections::{BTreeMap, HashMap}};
-use hash_db::Hasher;
+use std::{collections::{BTreeMap, HashMap}, sync::Arc};
+use hash_db::{Hasher, HashDB, Prefix};
 use sp_trie::{
-	MemoryDB, TrieMut,
+	MemoryDB, TrieMut, DBValue,
 	trie_types::TrieDBMut,
 };
 use codec::Codec;
 use sp_core::storage::{ChildInfo, Storage};
 
 /// Insert input pairs into memory db.
-fn insert_into_memory_db<H, I>(mut root: H::Out, mdb: &mut MemoryDB<H>, input: I) -> H::Out
+fn insert_into_memory_db<H, I>(mut root: H::Out, mdb: &mut dyn HashDB<H, DBValue>, input: I) -> H::Out
 where
 	H: Hasher,
 	I: IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
@@ -92,32 +93,18 @@ where
 		&mut self,
 		changes: T,
 	) {
-		let mut new_child_roots = Vec::new();
-		let mut root_map = None;
-		let root = self.root().clone();
-		for (child_info, map) in changes {
-			if let Some(child_info) = child_info.as_ref() {
-				let prefix_storage_key = child_info.prefixed_storage_key();
-				let ch = insert_into_memory_db::<H, _>(root, self.backend_storage_mut(), map.clone().into_iter());
-				new_child_roots.push((prefix_storage_key.into_inner(), Some(ch.as_ref().into())));
-			} else {
-				root_map = Some(map);
-			}
-		}
+		insert_into_backend(self, changes)
+	}
 
-		let root = match root_map {
-			Some(map) => insert_into_memory_db::<H, _>(
-				root,
-				self.backend_storage_mut(),
-				map.into_iter().chain(new_child_roots.into_iter()),
-			),
-			None => insert_into_memory_db::<H, _>(
-				root,
-				self.backend_storage_mut(),
-				new_child_roots.into_iter(),
-			),
+	/// Fork the backend, see `ForkedMemoryDB`.
+	///
+	/// The nodes of `self` are copied once, forks of the returned backend share them.
+	pub fn fork(&self) -> TrieBackend<ForkedMemoryDB<H>, H> {
+		let storage = ForkedMemoryDB {
+			shared: vec![Arc::new(self.backend_storage().clone())],
+			local: Default::default(),
 		};
-		self.essence.set_root(root);
+		TrieBackend::new(storage, self.root().clone())
 	}
 
 	/// Merge trie nodes into this backend.
@@ -133,6 +120,120 @@ where
 	}
 }
 
+/// Insert values into the trie of `backend`, writing the new nodes to its storage.
+fn insert_into_backend<S, H, T>(backend: &mut TrieBackend<S, H>, changes: T)
+where
+	S: TrieBackendStorage<H> + HashDB<H, DBValue>,
+	H: Hasher,
+	H::Out: Codec + Ord,
+	T: IntoIterator<Item = (Option<ChildInfo>, StorageCollection)>,
+{
+	let mut new_child_roots = Vec::new();
+	let mut root_map = None;
+	let root = backend.root().clone();
+	for (child_info, map) in changes {
+		if let Some(child_info) = child_info.as_ref() {
+			let prefix_storage_key = child_info.prefixed_storage_key();
+			let ch = insert_into_memory_db::<H, _>(root, backend.backend_storage_mut(), map.clone().into_iter());
+			new_child_roots.push((prefix_storage_key.into_inner(), Some(ch.as_ref().into())));
+		} else {
+			root_map = Some(map);
+		}
+	}
+
+	let root = match root_map {
+		Some(map) => insert_into_memory_db::<H, _>(
+			root,
+			backend.backend_storage_mut(),
+			map.into_iter().chain(new_child_roots.into_iter()),
+		),
+		None => insert_into_memory_db::<H, _>(
+			root,
+			backend.backend_storage_mut(),
+			new_child_roots.into_iter(),
+		),
+	};
+	backend.essence.set_root(root);
+}
+
+/// Storage of a forked in-memory backend.
+///
+/// Nodes of the backends it was forked from are shared and never copied again, nodes written
+/// after the fork are kept in a local `MemoryDB`. Nodes removed from the trie are not removed
+/// from the shared ones, which only wastes memory as nodes are addressed by their hash.
+pub struct ForkedMemoryDB<H: Hasher> {
+	shared: Vec<Arc<MemoryDB<H>>>,
+	local: MemoryDB<H>,
+}
+
+impl<H: Hasher> TrieBackendStorage<H> for ForkedMemoryDB<H> {
+	type Overlay = MemoryDB<H>;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		Ok(HashDB::get(self, key, prefix))
+	}
+}
+
+impl<H: Hasher> hash_db::AsHashDB<H, DBValue> for ForkedMemoryDB<H> {
+	fn as_hash_db<'b>(&'b self) -> &'b (dyn HashDB<H, DBValue> + 'b) { self }
+	fn as_hash_db_mut<'b>(&'b mut self) -> &'b mut (dyn HashDB<H, DBValue> + 'b) { self }
+}
+
+impl<H: Hasher> HashDB<H, DBValue> for ForkedMemoryDB<H> {
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<DBValue> {
+		HashDB::get(&self.local, key, prefix).or_else(|| self.shared.iter()
+			.rev()
+			.find_map(|shared| HashDB::get(&**shared, key, prefix))
+		)
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		HashDB::get(self, key, prefix).is_some()
+	}
+
+	fn insert(&mut self, prefix: Prefix, value: &[u8]) -> H::Out {
+		HashDB::insert(&mut self.local, prefix, value)
+	}
+
+	fn emplace(&mut self, key: H::Out, prefix: Prefix, value: DBValue) {
+		HashDB::emplace(&mut self.local, key, prefix, value)
+	}
+
+	fn remove(&mut self, key: &H::Out, prefix: Prefix) {
+		HashDB::remove(&mut self.local, key, prefix)
+	}
+}
+
+impl<H: Hasher> TrieBackend<ForkedMemoryDB<H>, H>
+where
+	H::Out: Codec + Ord,
+{
+	/// Insert values into backend trie.
+	pub fn insert<
+		T: IntoIterator<Item = (Option<ChildInfo>, StorageCollection)>
+	>(
+		&mut self,
+		changes: T,
+	) {
+		insert_into_backend(self, changes)
+	}
+
+	/// Fork the backend, sharing its nodes with the returned one.
+	///
+	/// Only the nodes written since `self` was forked are copied.
+	pub fn fork(&self) -> Self {
+		let storage = self.backend_storage();
+		let mut shared = storage.shared.clone();
+		if !storage.local.keys().is_empty() {
+			shared.push(Arc::new(storage.local.clone()));
+		}
+		TrieBackend::new(
+			ForkedMemoryDB { shared, local: Default::default() },
+			self.root().clone(),
+		)
+	}
+}
+
 impl<H: Hasher> Clone for TrieBackend<MemoryDB<H>, H>
 where
 	H::Out: Codec + Ord,
@@ -232,4 +333,29 @@ mod tests {
 		let storage_key = child_info.prefixed_storage_key();
 		assert!(trie_backend.storage(storage_key.as_slice()).unwrap().is_some());
 	}
+
+	#[test]
+	fn forks_diverge_from_their_parent() {
+		let storage = new_in_mem::<BlakeTwo256>()
+			.update(vec![(None, vec![(b"a".to_vec(), Some(b"1".to_vec()))])]);
+		let mut fork = storage.fork();
+		fork.insert(vec![(None, vec![(b"b".to_vec(), Some(b"2".to_vec()))])]);
+		let mut second_fork = fork.fork();
+		second_fork.insert(vec![(None, vec![(b"a".to_vec(), None)])]);
+		fork.insert(vec![(None, vec![(b"c".to_vec(), Some(b"3".to_vec()))])]);
+
+		assert_eq!(storage.pairs(), vec![(b"a".to_vec(), b"1".to_vec())]);
+		assert_eq!(fork.pairs(), vec![
+			(b"a".to_vec(), b"1".to_vec()),
+			(b"b".to_vec(), b"2".to_vec()),
+			(b"c".to_vec(), b"3".to_vec()),
+		]);
+		assert_eq!(second_fork.pairs(), vec![(b"b".to_vec(), b"2".to_vec())]);
+		assert_eq!(
+			second_fork.storage_root(std::iter::empty()).0,
+			new_in_mem::<BlakeTwo256>()
+				.update(vec![(None, vec![(b"b".to_vec(), Some(b"2".to_vec()))])])
+				.storage_root(std::iter::empty()).0,
+		);
+	}
 }
@@ -19,14 +19,21 @@
 
 #![warn(missing_docs)]
 
-use std::{fmt, result, collections::HashMap, panic::UnwindSafe};
+use std::{
+	fmt, result, collections::HashMap, panic::{AssertUnwindSafe, UnwindSafe},
+	sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant},
+};
 use log::{warn, trace};
+use parking_lot::Mutex;
 use hash_db::Hasher;
 use codec::{Decode, Encode, Codec};
 use sp_core::{
 	offchain::storage::OffchainOverlayedChanges,
-	storage::ChildInfo, NativeOrEncoded, NeverNativeValue, hexdisplay::HexDisplay,
-	traits::{CodeExecutor, CallInWasmExt, RuntimeCode, SpawnNamed},
+	storage::{ChildInfo, well_known_keys}, NativeOrEncoded, NeverNativeValue,
+	hexdisplay::HexDisplay,
+	traits::{
+		CodeExecutor, CallInWasm, CallInWasmExt, RuntimeCode, SpawnNamed, MissingHostFunctions,
+	},
 };
 use sp_externalities::Extensions;
 
@@ -43,13 +50,22 @@ mod trie_backend;
 mod trie_backend_essence;
 mod stats;
 mod read_only;
+mod reconciliation;
+mod policy;
+mod replay;
+mod access_tracker;
+mod access_order;
+mod node_cache;
+mod runtime_code_cache;
+#[cfg(any(test, feature = "test-helpers"))]
+pub mod test_helpers;
 
 pub use sp_trie::{trie_types::{Layout, TrieDBMut}, StorageProof, TrieMut, DBValue, MemoryDB};
-pub use testing::TestExternalities;
+pub use testing::{TestExternalities, CheckpointHandle};
 pub use basic::BasicExternalities;
 pub use read_only::{ReadOnlyExternalities, InspectState};
 pub use ext::Ext;
-pub use backend::Backend;
+pub use backend::{Backend, KeysIter};
 pub use changes_trie::{
 	AnchorBlockId as ChangesTrieAnchorBlockId,
 	State as ChangesTrieState,
@@ -67,16 +83,25 @@ pub use changes_trie::{
 };
 pub use overlayed_changes::{
 	OverlayedChanges, StorageChanges, StorageTransactionCache, StorageKey, StorageValue,
-	StorageCollection, ChildStorageCollection,
+	StorageCollection, ChildStorageCollection, FrozenOverlay, ChangesSize, TransactionChanges,
+	TransactionEncoding, ChangeTag,
 };
 pub use proving_backend::{
-	create_proof_check_backend, ProofRecorder, ProvingBackend, ProvingBackendRecorder,
+	create_proof_check_backend, ProofRecorder, ProofSize, ProofSizeRecorder, ProvingBackend,
+	ProvingBackendRecorder,
 };
 pub use trie_backend_essence::{TrieBackendStorage, Storage};
 pub use trie_backend::TrieBackend;
 pub use error::{Error, ExecutionError};
-pub use in_memory_backend::new_in_mem;
-pub use stats::{UsageInfo, UsageUnit, StateMachineStats};
+pub use in_memory_backend::{new_in_mem, ForkedMemoryDB};
+pub use stats::{UsageInfo, UsageUnit, StateMachineStats, StatsReporter};
+pub use reconciliation::{StorageUsage, UsageReconciliation, ReconciliationReport};
+pub use policy::{ExecutionPolicy, ExecutionStep, MethodOverrides};
+pub use replay::{ExecutionReplay, replay_execution, REPLAY_MAGIC, REPLAY_VERSION};
+pub use access_tracker::{StorageAccess, StorageAccessKind, StorageAccessTracker};
+pub use access_order::{AccessOrderRecorder, CacheWarmingProfile};
+pub use node_cache::{TrieNodeCache, SharedTrieNodeCache, CachingTrieStorage, RemoteTrieStorage};
+pub use runtime_code_cache::{RuntimeCodeCache, DEFAULT_RUNTIME_CODE_CACHE_SIZE};
 
 const PROOF_CLOSE_TRANSACTION: &str = "\
 	Closing a transaction that was started in this function. Client initiated transactions
@@ -84,8 +109,60 @@ const PROOF_CLOSE_TRANSACTION: &str = "\
 
 type CallResult<R, E> = Result<NativeOrEncoded<R>, E>;
 
+/// Default maximum nesting depth of the `call_in_wasm` calls made by the runtime.
+pub const DEFAULT_MAX_NESTED_CALLS: usize = 8;
+
 /// Default handler of the execution manager.
-pub type DefaultHandler<R, E> = fn(CallResult<R, E>, CallResult<R, E>) -> CallResult<R, E>;
+pub type DefaultHandler<R, E> = fn(ConsensusMismatch<R, E>) -> CallResult<R, E>;
+
+/// Disagreement between the native and wasm executions of a call made with
+/// `ExecutionManager::Both`, handed to its handler.
+///
+/// Carries what is needed to investigate the disagreement offline. The result returned by the
+/// handler is the one of the call, the storage changes of the wasm execution are the ones kept
+/// in the overlay.
+pub struct ConsensusMismatch<R, E> {
+	/// Called runtime method.
+	pub method: String,
+	/// Encoded call data.
+	pub call_data: Vec<u8>,
+	/// Result of the wasm execution.
+	pub wasm_result: CallResult<R, E>,
+	/// Result of the native execution.
+	pub native_result: CallResult<R, E>,
+	/// Storage changes written by the wasm execution.
+	pub wasm_changes: TransactionChanges,
+	/// Storage changes written by the native execution, discarded.
+	pub native_changes: TransactionChanges,
+}
+
+/// Result of an execution, with how it was produced.
+pub struct ExecutionDetails<R> {
+	/// Result of the call, as returned by `execute_using_consensus_failure_handler`.
+	pub result: Result<NativeOrEncoded<R>, Box<dyn Error>>,
+	/// Whether the result was produced by the native runtime.
+	///
+	/// When the native and wasm results of `ExecutionManager::Both` differ, the result of the
+	/// handler is reported as a wasm one, its changes being the wasm ones.
+	pub was_native: bool,
+	/// Time spent in the execution, including the runs of both engines if any.
+	pub elapsed: Duration,
+	/// Overlay statistics of the execution, including the runs of both engines if any.
+	pub stats: StateMachineStats,
+}
+
+impl<R: fmt::Debug, E: fmt::Debug> fmt::Debug for ConsensusMismatch<R, E> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("ConsensusMismatch")
+			.field("method", &self.method)
+			.field("call_data", &HexDisplay::from(&self.call_data))
+			.field("wasm_result", &self.wasm_result)
+			.field("native_result", &self.native_result)
+			.field("wasm_changes", &self.wasm_changes)
+			.field("native_changes", &self.native_changes)
+			.finish()
+	}
+}
 
 /// Type of changes trie transaction.
 pub type ChangesTrieTransaction<H, N> = (
@@ -96,6 +173,9 @@ pub type ChangesTrieTransaction<H, N> = (
 /// Trie backend with in-memory storage.
 pub type InMemoryBackend<H> = TrieBackend<MemoryDB<H>, H>;
 
+/// Trie backend with in-memory storage, forked from an `InMemoryBackend`.
+pub type ForkedInMemoryBackend<H> = TrieBackend<ForkedMemoryDB<H>, H>;
+
 /// Strategy for executing a call into the runtime.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum ExecutionStrategy {
@@ -155,15 +235,18 @@ impl ExecutionStrategy {
 			ExecutionStrategy::AlwaysWasm => ExecutionManager::AlwaysWasm(BackendTrustLevel::Trusted),
 			ExecutionStrategy::NativeWhenPossible => ExecutionManager::NativeWhenPossible,
 			ExecutionStrategy::NativeElseWasm => ExecutionManager::NativeElseWasm,
-			ExecutionStrategy::Both => ExecutionManager::Both(|wasm_result, native_result| {
+			ExecutionStrategy::Both => ExecutionManager::Both(|mismatch| {
 				warn!(
-					"Consensus error between wasm {:?} and native {:?}. Using wasm.",
-					wasm_result,
-					native_result,
+					"Consensus error between wasm {:?} and native {:?} calling `{}`. Using wasm.",
+					mismatch.wasm_result,
+					mismatch.native_result,
+					mismatch.method,
 				);
-				warn!("   Native result {:?}", native_result);
-				warn!("   Wasm result {:?}", wasm_result);
-				wasm_result
+				warn!("   Native result {:?}", mismatch.native_result);
+				warn!("   Wasm result {:?}", mismatch.wasm_result);
+				warn!("   Native changes {:?}", mismatch.native_changes);
+				warn!("   Wasm changes {:?}", mismatch.wasm_changes);
+				mismatch.wasm_result
 			}),
 		}
 	}
@@ -184,6 +267,95 @@ fn always_untrusted_wasm<E, R: Decode>() -> ExecutionManager<DefaultHandler<R, E
 	ExecutionManager::AlwaysWasm(BackendTrustLevel::Untrusted)
 }
 
+/// Handle to abort an execution, on demand or once a deadline is passed.
+///
+/// The execution is aborted at its next storage access and fails with
+/// `ExecutionError::Cancelled` or `ExecutionError::DeadlineExceeded`. A runtime not accessing
+/// storage can not be interrupted. Clones share the cancellation.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionInterrupt {
+	cancelled: Arc<AtomicBool>,
+	deadline: Option<Instant>,
+}
+
+impl ExecutionInterrupt {
+	/// Interrupt only triggered by `cancel`.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Also trigger the interrupt at `deadline`.
+	pub fn with_deadline(mut self, deadline: Instant) -> Self {
+		self.deadline = Some(deadline);
+		self
+	}
+
+	/// Also trigger the interrupt once `timeout` has elapsed from now.
+	pub fn with_timeout(self, timeout: Duration) -> Self {
+		self.with_deadline(Instant::now() + timeout)
+	}
+
+	/// Trigger the interrupt.
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	/// Error reported by an execution aborted now, `None` if the interrupt is not triggered.
+	pub fn triggered(&self) -> Option<ExecutionError> {
+		if self.cancelled.load(Ordering::Relaxed) {
+			Some(ExecutionError::Cancelled)
+		} else if self.deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+			Some(ExecutionError::DeadlineExceeded)
+		} else {
+			None
+		}
+	}
+}
+
+/// Nesting depth of the `call_in_wasm` calls made during an execution.
+struct NestedCalls {
+	depth: usize,
+	limit: usize,
+	exceeded: bool,
+}
+
+/// `CallInWasm` registered for the runtime, refusing calls nested deeper than the limit.
+struct NestedCallLimiter<Exec> {
+	exec: Exec,
+	calls: Arc<Mutex<NestedCalls>>,
+}
+
+impl<Exec: CallInWasm> CallInWasm for NestedCallLimiter<Exec> {
+	fn call_in_wasm(
+		&self,
+		wasm_code: &[u8],
+		code_hash: Option<Vec<u8>>,
+		method: &str,
+		call_data: &[u8],
+		ext: &mut dyn sp_externalities::Externalities,
+		missing_host_functions: MissingHostFunctions,
+	) -> Result<Vec<u8>, String> {
+		{
+			let mut calls = self.calls.lock();
+			if calls.depth >= calls.limit {
+				calls.exceeded = true;
+				return Err(format!("Maximum depth of {} nested calls exceeded", calls.limit));
+			}
+			calls.depth += 1;
+		}
+		let result = self.exec.call_in_wasm(
+			wasm_code,
+			code_hash,
+			method,
+			call_data,
+			ext,
+			missing_host_functions,
+		);
+		self.calls.lock().depth -= 1;
+		result
+	}
+}
+
 /// The substrate state machine.
 pub struct StateMachine<'a, B, H, N, Exec>
 	where
@@ -202,6 +374,14 @@ pub struct StateMachine<'a, B, H, N, Exec>
 	storage_transaction_cache: Option<&'a mut StorageTransactionCache<B::Transaction, H, N>>,
 	runtime_code: &'a RuntimeCode<'a>,
 	stats: StateMachineStats,
+	allowed_methods: Option<&'a [&'a str]>,
+	protect_well_known_keys: bool,
+	rejected_writes: Vec<StorageKey>,
+	nested_calls: Arc<Mutex<NestedCalls>>,
+	interrupt: Option<ExecutionInterrupt>,
+	access_tracker: Option<StorageAccessTracker>,
+	stats_reporter: Option<Arc<dyn StatsReporter>>,
+	runtime_code_cache: RuntimeCodeCache,
 }
 
 impl<'a, B, H, N, Exec> Drop for StateMachine<'a, B, H, N, Exec> where
@@ -211,6 +391,9 @@ impl<'a, B, H, N, Exec> Drop for StateMachine<'a, B, H, N, Exec> where
 {
 	fn drop(&mut self) {
 		self.backend.register_overlay_stats(&self.stats);
+		if let Some(reporter) = self.stats_reporter.as_ref() {
+			reporter.report(self.method, &self.stats);
+		}
 	}
 }
 
@@ -234,7 +417,15 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 		runtime_code: &'a RuntimeCode,
 		spawn_handle: impl SpawnNamed + Send + 'static,
 	) -> Self {
-		extensions.register(CallInWasmExt::new(exec.clone()));
+		let nested_calls = Arc::new(Mutex::new(NestedCalls {
+			depth: 0,
+			limit: DEFAULT_MAX_NESTED_CALLS,
+			exceeded: false,
+		}));
+		extensions.register(CallInWasmExt::new(NestedCallLimiter {
+			exec: exec.clone(),
+			calls: nested_calls.clone(),
+		}));
 		extensions.register(sp_core::traits::TaskExecutorExt::new(spawn_handle));
 
 		Self {
@@ -249,6 +440,14 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			storage_transaction_cache: None,
 			runtime_code,
 			stats: StateMachineStats::default(),
+			allowed_methods: None,
+			protect_well_known_keys: false,
+			rejected_writes: Vec::new(),
+			nested_calls,
+			interrupt: None,
+			access_tracker: None,
+			stats_reporter: None,
+			runtime_code_cache: Default::default(),
 		}
 	}
 
@@ -265,6 +464,66 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 		self
 	}
 
+	/// Restrict the runtime entry points that can be executed.
+	///
+	/// Calling a method that is not part of `methods` makes execution fail with
+	/// `ExecutionError::MethodNotAllowed` without running the runtime. `None` allows any method.
+	pub fn with_method_whitelist(mut self, methods: Option<&'a [&'a str]>) -> Self {
+		self.allowed_methods = methods;
+		self
+	}
+
+	/// Protect the `:code`, `:heappages` and `:changes_trie` well known keys.
+	///
+	/// With protection enabled, a call writing one of these keys fails with
+	/// `ExecutionError::ProtectedKeyWrite` and none of its changes are kept in the overlay.
+	/// Meant for execution of untrusted calls, such as RPC dry-runs. Disabled by default.
+	pub fn with_well_known_keys_protection(mut self, protect: bool) -> Self {
+		self.protect_well_known_keys = protect;
+		self
+	}
+
+	/// Limit the nesting depth of the `call_in_wasm` calls made by the runtime.
+	///
+	/// Execution fails with `ExecutionError::NestedCallLimitExceeded` and none of its changes
+	/// are kept in the overlay if the runtime goes deeper. Defaults to `DEFAULT_MAX_NESTED_CALLS`.
+	pub fn with_max_nested_calls(self, limit: usize) -> Self {
+		self.nested_calls.lock().limit = limit;
+		self
+	}
+
+	/// Abort executions once `interrupt` is triggered.
+	///
+	/// Aborted executions leave the overlay untouched.
+	pub fn with_interrupt(mut self, interrupt: ExecutionInterrupt) -> Self {
+		self.interrupt = Some(interrupt);
+		self
+	}
+
+	/// Record the storage accesses of executions in `tracker`.
+	///
+	/// Accesses of failed executions are recorded too, the tracker keeps a handle to query
+	/// them after execution.
+	pub fn with_access_tracker(mut self, tracker: StorageAccessTracker) -> Self {
+		self.access_tracker = Some(tracker);
+		self
+	}
+
+	/// Report the statistics of this state machine to `reporter` when it is dropped.
+	pub fn with_stats_reporter(mut self, reporter: Arc<dyn StatsReporter>) -> Self {
+		self.stats_reporter = Some(reporter);
+		self
+	}
+
+	/// Fetch the runtime code through `cache`, shared with other state machines.
+	///
+	/// By default each state machine has its own cache, and only reuses the code between the
+	/// executions of a `Both` strategy.
+	pub fn with_runtime_code_cache(mut self, cache: RuntimeCodeCache) -> Self {
+		self.runtime_code_cache = cache;
+		self
+	}
+
 	/// Execute a call using the given state backend, overlayed changes, and call executor.
 	///
 	/// On an error, no prospective changes are written to the overlay.
@@ -282,6 +541,82 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 		).map(NativeOrEncoded::into_encoded)
 	}
 
+	/// Record the inputs of the next execution of this state machine, to replay it with
+	/// `replay_execution`. `extensions` describes the registered extensions.
+	pub fn replay_record(&self, extensions: Vec<String>) -> ExecutionReplay {
+		let (overlay_top, overlay_children) = ExecutionReplay::snapshot_overlay(&*self.overlay);
+		ExecutionReplay {
+			version: replay::REPLAY_VERSION,
+			method: self.method.into(),
+			call_data: self.call_data.to_vec(),
+			runtime_code_hash: self.runtime_code.hash.clone(),
+			heap_pages: self.runtime_code.heap_pages,
+			backend_root: self.backend.storage_root(std::iter::empty()).0.encode(),
+			overlay_top,
+			overlay_children,
+			extensions,
+		}
+	}
+
+	/// Execute a call following `policy`, trying its steps for the method in order until one
+	/// succeeds. Changes of a failed step are discarded.
+	///
+	/// Returns the error of the last step if none succeeds.
+	pub fn execute_with_policy(
+		&mut self,
+		policy: &dyn ExecutionPolicy,
+	) -> Result<Vec<u8>, Box<dyn Error>> {
+		let mut result = Err(Box::new(ExecutionError::NoExecutionStep(self.method.into())) as Box<dyn Error>);
+		for step in policy.steps(self.method) {
+			trace!(target: "state", "Executing {} with {:?}", self.method, step);
+			result = self.execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
+				step.get_manager(),
+				None,
+			).map(NativeOrEncoded::into_encoded);
+			if result.is_ok() {
+				break;
+			}
+		}
+		result
+	}
+
+	/// Execute `calls`, given as method and call data, one after the other.
+	///
+	/// Calls share the overlay, the storage transaction cache and the extensions of this state
+	/// machine, changes of a call are visible to the following ones. A failing call does not stop
+	/// the batch, its changes are discarded like with `execute`.
+	///
+	/// Returns the result of each call, in order.
+	pub fn execute_batch(
+		&mut self,
+		calls: &[(&'a str, &'a [u8])],
+		strategy: ExecutionStrategy,
+	) -> Vec<Result<Vec<u8>, Box<dyn Error>>> {
+		let (method, call_data) = (self.method, self.call_data);
+		let mut results = Vec::with_capacity(calls.len());
+		for &(method, call_data) in calls {
+			self.method = method;
+			self.call_data = call_data;
+			results.push(self.execute(strategy));
+		}
+		self.method = method;
+		self.call_data = call_data;
+		results
+	}
+
+	/// Whether the overlay holds a `:code` different from `runtime_code`, typically written by
+	/// a runtime upgrade during a previous execution.
+	///
+	/// Following calls would still run `runtime_code`, the code and the executor must be
+	/// refreshed before. The hash of `runtime_code` is expected to be the encoded `H` hash of the
+	/// code, as the one of `BackendRuntimeCode`.
+	pub fn runtime_code_changed(&self) -> bool {
+		match self.overlay.storage(well_known_keys::CODE) {
+			Some(code) => code.map(|code| H::hash(code).encode()).as_ref() != Some(&self.runtime_code.hash),
+			None => false,
+		}
+	}
+
 	fn execute_aux<R, NC>(
 		&mut self,
 		use_native: bool,
@@ -300,7 +635,9 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			None => &mut cache,
 		};
 
+		let depth = self.overlay.transaction_depth();
 		self.overlay.enter_runtime().expect("StateMachine is never called from the runtime; qed");
+		let overlay_stats = self.overlay.stats().clone();
 
 		let mut ext = Ext::new(
 			self.overlay,
@@ -310,6 +647,15 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			self.changes_trie_state.clone(),
 			Some(&mut self.extensions),
 		);
+		if self.protect_well_known_keys {
+			ext.protect_well_known_keys();
+		}
+		if let Some(interrupt) = self.interrupt.clone() {
+			ext.set_interrupt(interrupt);
+		}
+		if let Some(tracker) = self.access_tracker.clone() {
+			ext.set_access_tracker(tracker);
+		}
 
 		let id = ext.id;
 		trace!(
@@ -320,17 +666,26 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 			HexDisplay::from(&self.call_data),
 		);
 
+		let code_fetcher = self.runtime_code_cache.fetcher(self.runtime_code);
+		let runtime_code = code_fetcher.runtime_code();
 		let (result, was_native) = self.exec.call(
 			&mut ext,
-			self.runtime_code,
+			&runtime_code,
 			self.method,
 			self.call_data,
 			use_native,
 			native_call,
 		);
+		self.rejected_writes.extend(ext.take_rejected_writes());
 
 		self.overlay.exit_runtime()
 			.expect("Runtime is not able to call this function in the overlay; qed");
+		self.stats.add(&self.overlay.stats().since(&overlay_stats));
+		debug_assert_eq!(
+			self.overlay.transaction_depth(),
+			depth,
+			"Transactions opened by the runtime are closed when exiting it",
+		);
 
 		trace!(
 			target: "state", "{:04x}: Return. Native={:?}, Result={:?}",
@@ -346,43 +701,52 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 		&mut self,
 		mut native_call: Option<NC>,
 		on_consensus_failure: Handler,
-	) -> CallResult<R, Exec::Error>
+	) -> (CallResult<R, Exec::Error>, bool)
 		where
 			R: Decode + Encode + PartialEq,
 			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
-			Handler: FnOnce(
-				CallResult<R, Exec::Error>,
-				CallResult<R, Exec::Error>,
-			) -> CallResult<R, Exec::Error>
+			Handler: FnOnce(ConsensusMismatch<R, Exec::Error>) -> CallResult<R, Exec::Error>
 	{
 		self.overlay.start_transaction();
 		let (result, was_native) = self.execute_aux(true, native_call.take());
 
 		if was_native {
+			let native_changes = self.overlay.transaction_changes().expect(PROOF_CLOSE_TRANSACTION);
 			self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
+			self.reset_storage_transaction_cache();
+			self.overlay.start_transaction();
 			let (wasm_result, _) = self.execute_aux(
 				false,
 				native_call,
 			);
+			let wasm_changes = self.overlay.transaction_changes().expect(PROOF_CLOSE_TRANSACTION);
+			self.overlay.commit_transaction().expect(PROOF_CLOSE_TRANSACTION);
 
 			if (result.is_ok() && wasm_result.is_ok()
 				&& result.as_ref().ok() == wasm_result.as_ref().ok())
 				|| result.is_err() && wasm_result.is_err()
 			{
-				result
+				(result, true)
 			} else {
-				on_consensus_failure(wasm_result, result)
+				(on_consensus_failure(ConsensusMismatch {
+					method: self.method.into(),
+					call_data: self.call_data.to_vec(),
+					wasm_result,
+					native_result: result,
+					wasm_changes,
+					native_changes,
+				}), false)
 			}
 		} else {
 			self.overlay.commit_transaction().expect(PROOF_CLOSE_TRANSACTION);
-			result
+			(result, false)
 		}
 	}
 
 	fn execute_call_with_native_else_wasm_strategy<R, NC>(
 		&mut self,
 		mut native_call: Option<NC>,
-	) -> CallResult<R, Exec::Error>
+	) -> (CallResult<R, Exec::Error>, bool)
 		where
 			R: Decode + Encode + PartialEq,
 			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
@@ -395,20 +759,70 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 
 		if !was_native || result.is_ok() {
 			self.overlay.commit_transaction().expect(PROOF_CLOSE_TRANSACTION);
-			result
+			(result, was_native)
 		} else {
 			self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
-			let (wasm_result, _) = self.execute_aux(
+			self.reset_storage_transaction_cache();
+			self.execute_aux(
 				false,
 				native_call,
-			);
-			wasm_result
+			)
+		}
+	}
+
+	fn execute_with_manager<Handler, R, NC>(
+		&mut self,
+		manager: ExecutionManager<Handler>,
+		mut native_call: Option<NC>,
+	) -> (CallResult<R, Exec::Error>, bool)
+		where
+			R: Decode + Encode + PartialEq,
+			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
+			Handler: FnOnce(ConsensusMismatch<R, Exec::Error>) -> CallResult<R, Exec::Error>
+	{
+		match manager {
+			ExecutionManager::Both(on_consensus_failure) => {
+				self.execute_call_with_both_strategy(
+					native_call.take(),
+					on_consensus_failure,
+				)
+			},
+			ExecutionManager::NativeElseWasm => {
+				self.execute_call_with_native_else_wasm_strategy(
+					native_call.take(),
+				)
+			},
+			ExecutionManager::AlwaysWasm(_) => {
+				self.execute_aux(false, native_call)
+			},
+			ExecutionManager::NativeWhenPossible => {
+				self.execute_aux(true, native_call)
+			},
+		}
+	}
+
+	/// Restore the overlay after a panic interrupted an execution: leave the runtime and roll
+	/// back all the transactions opened above `client_depth`.
+	fn recover_overlay(&mut self, client_depth: usize) {
+		let _ = self.overlay.exit_runtime();
+		while self.overlay.transaction_depth() > client_depth {
+			self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
+		}
+		self.reset_storage_transaction_cache();
+	}
+
+	/// Drop the roots and transactions cached from changes that were rolled back.
+	fn reset_storage_transaction_cache(&mut self) {
+		if let Some(cache) = self.storage_transaction_cache.as_mut() {
+			cache.reset();
 		}
 	}
 
 	/// Execute a call using the given state backend, overlayed changes, and call executor.
 	///
-	/// On an error, no prospective changes are written to the overlay.
+	/// On an error, no prospective changes are written to the overlay. This includes a panic
+	/// of the executor on an untrusted backend or with an interrupt, which is reported as an
+	/// error.
 	///
 	/// Note: changes to code will be in place if this call is made again. For running partial
 	/// blocks (e.g. a transaction at a time), ensure a different method is used.
@@ -418,47 +832,289 @@ impl<'a, B, H, N, Exec> StateMachine<'a, B, H, N, Exec> where
 	pub fn execute_using_consensus_failure_handler<Handler, R, NC>(
 		&mut self,
 		manager: ExecutionManager<Handler>,
-		mut native_call: Option<NC>,
+		native_call: Option<NC>,
 	) -> Result<NativeOrEncoded<R>, Box<dyn Error>>
 		where
 			R: Decode + Encode + PartialEq,
 			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
-			Handler: FnOnce(
-				CallResult<R, Exec::Error>,
-				CallResult<R, Exec::Error>,
-			) -> CallResult<R, Exec::Error>
+			Handler: FnOnce(ConsensusMismatch<R, Exec::Error>) -> CallResult<R, Exec::Error>
+	{
+		self.execute_with_details(manager, native_call).result
+	}
+
+	/// Execute a call like `execute_using_consensus_failure_handler`, also returning which
+	/// engine produced the result, the time spent and the statistics of the state machine.
+	pub fn execute_with_details<Handler, R, NC>(
+		&mut self,
+		manager: ExecutionManager<Handler>,
+		native_call: Option<NC>,
+	) -> ExecutionDetails<R>
+		where
+			R: Decode + Encode + PartialEq,
+			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
+			Handler: FnOnce(ConsensusMismatch<R, Exec::Error>) -> CallResult<R, Exec::Error>
+	{
+		let started = Instant::now();
+		let stats = self.stats.clone();
+		let (result, was_native) = self.execute_checked(manager, native_call);
+		ExecutionDetails {
+			result,
+			was_native,
+			elapsed: started.elapsed(),
+			stats: self.stats.since(&stats),
+		}
+	}
+
+	fn execute_checked<Handler, R, NC>(
+		&mut self,
+		manager: ExecutionManager<Handler>,
+		native_call: Option<NC>,
+	) -> (Result<NativeOrEncoded<R>, Box<dyn Error>>, bool)
+		where
+			R: Decode + Encode + PartialEq,
+			NC: FnOnce() -> result::Result<R, String> + UnwindSafe,
+			Handler: FnOnce(ConsensusMismatch<R, Exec::Error>) -> CallResult<R, Exec::Error>
 	{
+		if let Some(allowed_methods) = self.allowed_methods {
+			if !allowed_methods.contains(&self.method) {
+				return (Err(Box::new(ExecutionError::MethodNotAllowed(self.method.into()))), false);
+			}
+		}
+
 		let changes_tries_enabled = self.changes_trie_state.is_some();
 		self.overlay.set_collect_extrinsics(changes_tries_enabled);
 
-		let result = {
-			match manager {
-				ExecutionManager::Both(on_consensus_failure) => {
-					self.execute_call_with_both_strategy(
-						native_call.take(),
-						on_consensus_failure,
-					)
-				},
-				ExecutionManager::NativeElseWasm => {
-					self.execute_call_with_native_else_wasm_strategy(
-						native_call.take(),
-					)
-				},
-				ExecutionManager::AlwaysWasm(trust_level) => {
-					let _abort_guard = match trust_level {
-						BackendTrustLevel::Trusted => None,
-						BackendTrustLevel::Untrusted => Some(sp_panic_handler::AbortGuard::never_abort()),
-					};
-					self.execute_aux(false, native_call).0
-				},
-				ExecutionManager::NativeWhenPossible => {
-					self.execute_aux(true, native_call).0
+		self.rejected_writes.clear();
+		self.nested_calls.lock().exceeded = false;
+		let client_depth = self.overlay.transaction_depth();
+		self.overlay.start_transaction();
+
+		// Interrupts abort the execution with a panic.
+		let catch_panics = self.interrupt.is_some() || match manager {
+			ExecutionManager::AlwaysWasm(BackendTrustLevel::Untrusted) => true,
+			_ => false,
+		};
+		let (result, was_native) = if catch_panics {
+			let _abort_guard = sp_panic_handler::AbortGuard::never_abort();
+			let result = std::panic::catch_unwind(AssertUnwindSafe(
+				|| self.execute_with_manager(manager, native_call)
+			));
+			match result {
+				Ok(result) => result,
+				Err(panic) => {
+					self.recover_overlay(client_depth);
+					if let Some(error) = self.interrupt.as_ref().and_then(ExecutionInterrupt::triggered) {
+						return (Err(Box::new(error)), false);
+					}
+					let message = panic.downcast_ref::<&str>().map(|m| m.to_string())
+						.or_else(|| panic.downcast_ref::<String>().cloned())
+						.unwrap_or_else(|| "Unknown panic".into());
+					return (Err(Box::new(ExecutionError::Backend(
+						format!("Execution panicked: {}", message),
+					))), false);
 				},
 			}
+		} else {
+			self.execute_with_manager(manager, native_call)
+		};
+
+		// An executor may report the interrupt as a regular failure.
+		let interrupted = match result {
+			Err(_) => self.interrupt.as_ref().and_then(ExecutionInterrupt::triggered),
+			Ok(_) => None,
+		};
+		let rejected = match self.rejected_writes.first() {
+			_ if interrupted.is_some() => interrupted,
+			Some(key) if self.protect_well_known_keys =>
+				Some(ExecutionError::ProtectedKeyWrite(key.clone())),
+			_ => {
+				let nested_calls = self.nested_calls.lock();
+				if nested_calls.exceeded {
+					Some(ExecutionError::NestedCallLimitExceeded(nested_calls.limit))
+				} else {
+					None
+				}
+			},
 		};
+		if let Some(error) = rejected {
+			self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
+			self.reset_storage_transaction_cache();
+			return (Err(Box::new(error)), false);
+		}
+		if result.is_err() {
+			self.overlay.rollback_transaction().expect(PROOF_CLOSE_TRANSACTION);
+			self.reset_storage_transaction_cache();
+		} else {
+			self.overlay.commit_transaction().expect(PROOF_CLOSE_TRANSACTION);
+		}
+		debug_assert_eq!(self.overlay.transaction_depth(), client_depth);
+
+		(result.map_err(|e| Box::new(e) as _), was_native)
+	}
+}
+
+/// A read-only view of a backend and of the changes overlayed on it.
+///
+/// It is cheap to clone and can be shared across threads to execute read-only runtime calls
+/// while the original `OverlayedChanges` keeps being modified. Creating it copies the changes
+/// once. Each call runs on top of the shared frozen changes and only copies the values it
+/// writes, whatever it writes is discarded.
+#[derive(Clone)]
+pub struct FrozenState<B> {
+	backend: B,
+	overlay: FrozenOverlay,
+}
+
+impl<B> FrozenState<B> {
+	/// Freeze the current content of `overlay` on top of `backend`.
+	pub fn new(backend: B, overlay: &OverlayedChanges) -> Self {
+		FrozenState {
+			backend,
+			overlay: overlay.freeze(),
+		}
+	}
+
+	/// The backend the changes are overlayed on.
+	pub fn backend(&self) -> &B {
+		&self.backend
+	}
+
+	/// Execute a read-only call against the frozen state.
+	///
+	/// Returns the SCALE encoded result of the executed function.
+	pub fn execute<H, Exec, Spawn>(
+		&self,
+		exec: &Exec,
+		spawn_handle: Spawn,
+		method: &str,
+		call_data: &[u8],
+		extensions: Extensions,
+		runtime_code: &RuntimeCode,
+		strategy: ExecutionStrategy,
+	) -> Result<Vec<u8>, Box<dyn Error>>
+	where
+		B: Backend<H>,
+		H: Hasher,
+		H::Out: Ord + 'static + codec::Codec,
+		Exec: CodeExecutor + Clone + 'static,
+		Spawn: SpawnNamed + Send + 'static,
+	{
+		let mut overlay = self.overlay.thaw();
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let mut sm = StateMachine::<_, H, u64, Exec>::new(
+			&self.backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlay,
+			&mut offchain_overlay,
+			exec,
+			method,
+			call_data,
+			extensions,
+			runtime_code,
+			spawn_handle,
+		);
+		sm.execute(strategy)
+	}
+}
+
+/// Outcome of a successful `execute_block`.
+pub struct BlockExecutionReport<Transaction, H: Hasher, N: ChangesTrieBlockNumber> {
+	/// Changes made by the block, ready to be committed to the backend.
+	pub storage_changes: StorageChanges<Transaction, H, N>,
+	/// Verified state root of the block.
+	pub state_root: H::Out,
+	/// Verified changes trie root of the block, `None` if changes tries are disabled.
+	pub changes_trie_root: Option<H::Out>,
+	/// Storage usage statistics of the execution.
+	pub stats: StateMachineStats,
+}
+
+/// Execute a block on top of `backend` and check the resulting roots against its header.
+///
+/// `block` is the SCALE encoded block passed to `Core_execute_block`. `parent_hash` is the hash
+/// of the block `backend` is the state of. `state_root` and `changes_trie_root` are the roots
+/// found in the header of the executed block.
+///
+/// Fails with `ExecutionError::StateRootMismatch` or `ExecutionError::ChangesTrieRootMismatch`
+/// if the roots computed after execution differ from the expected ones.
+pub fn execute_block<B, H, N, Exec, Spawn>(
+	backend: &B,
+	changes_trie_state: Option<ChangesTrieState<H, N>>,
+	parent_hash: H::Out,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	block: &[u8],
+	state_root: H::Out,
+	changes_trie_root: Option<H::Out>,
+	extensions: Extensions,
+	runtime_code: &RuntimeCode,
+	strategy: ExecutionStrategy,
+) -> Result<BlockExecutionReport<B::Transaction, H, N>, Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + 'static + codec::Codec,
+	N: ChangesTrieBlockNumber,
+	Exec: CodeExecutor + Clone + 'static,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let mut overlay = OverlayedChanges::default();
+	let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+	let mut cache = StorageTransactionCache::default();
+	{
+		let mut sm = StateMachine::<_, H, N, Exec>::new(
+			backend,
+			changes_trie_state.clone(),
+			&mut overlay,
+			&mut offchain_overlay,
+			exec,
+			"Core_execute_block",
+			block,
+			extensions,
+			runtime_code,
+			spawn_handle,
+		).with_storage_transaction_cache(Some(&mut cache));
+		sm.execute(strategy)?;
+	}
+	let stats = overlay.stats().clone();
+
+	let computed_state_root = overlay.storage_root(backend, &mut cache);
+	if computed_state_root != state_root {
+		return Err(Box::new(ExecutionError::StateRootMismatch(
+			state_root.encode(),
+			computed_state_root.encode(),
+		)));
+	}
 
-		result.map_err(|e| Box::new(e) as _)
+	let computed_changes_trie_root = overlay.changes_trie_root(
+		backend,
+		changes_trie_state.as_ref(),
+		parent_hash,
+		false,
+		&mut cache,
+	).map_err(|_| Box::new(ExecutionError::Backend(
+		"Failed to generate changes trie transaction".into(),
+	)) as Box<dyn Error>)?;
+	if computed_changes_trie_root != changes_trie_root {
+		return Err(Box::new(ExecutionError::ChangesTrieRootMismatch(
+			changes_trie_root.map(|r| r.encode()),
+			computed_changes_trie_root.map(|r| r.encode()),
+		)));
 	}
+
+	let storage_changes = overlay.into_storage_changes(
+		backend,
+		changes_trie_state.as_ref(),
+		parent_hash,
+		cache,
+	).map_err(|e| Box::new(ExecutionError::Backend(e)) as Box<dyn Error>)?;
+
+	Ok(BlockExecutionReport {
+		storage_changes,
+		state_root: computed_state_root,
+		changes_trie_root: computed_changes_trie_root,
+		stats,
+	})
 }
 
 /// Prove execution using the given state backend, overlayed changes, and call executor.
@@ -473,7 +1129,7 @@ pub fn prove_execution<B, H, N, Exec, Spawn>(
 ) -> Result<(Vec<u8>, StorageProof), Box<dyn Error>>
 where
 	B: Backend<H>,
-	H: Hasher,
+	H: Hasher + 'static,
 	H::Out: Ord + 'static + codec::Codec,
 	Exec: CodeExecutor + Clone + 'static,
 	N: crate::changes_trie::BlockNumber,
@@ -512,7 +1168,7 @@ pub fn prove_execution_on_trie_backend<S, H, N, Exec, Spawn>(
 ) -> Result<(Vec<u8>, StorageProof), Box<dyn Error>>
 where
 	S: trie_backend_essence::TrieBackendStorage<H>,
-	H: Hasher,
+	H: Hasher + 'static,
 	H::Out: Ord + 'static + codec::Codec,
 	Exec: CodeExecutor + 'static + Clone,
 	N: crate::changes_trie::BlockNumber,
@@ -520,6 +1176,8 @@ where
 {
 	let mut offchain_overlay = OffchainOverlayedChanges::default();
 	let proving_backend = proving_backend::ProvingBackend::new(trie_backend);
+	let mut extensions = Extensions::default();
+	extensions.register(proving_backend.proof_size_ext());
 	let mut sm = StateMachine::<_, H, N, Exec>::new(
 		&proving_backend,
 		None,
@@ -528,7 +1186,7 @@ where
 		exec,
 		method,
 		call_data,
-		Extensions::default(),
+		extensions,
 		runtime_code,
 		spawn_handle,
 	);
@@ -541,45 +1199,148 @@ where
 	Ok((result.into_encoded(), proof))
 }
 
-/// Check execution proof, generated by `prove_execution` call.
-pub fn execution_proof_check<H, N, Exec, Spawn>(
-	root: H::Out,
-	proof: StorageProof,
+/// Prove execution using the runtime code of the given state backend.
+///
+/// Same as `prove_execution`, the proof also holds the `:code` and `:heap_pages` entries of the
+/// state, so `execution_proof_check_with_code` can check it without a copy of the runtime.
+pub fn prove_execution_with_code<B, H, N, Exec, Spawn>(
+	mut backend: B,
 	overlay: &mut OverlayedChanges,
 	exec: &Exec,
 	spawn_handle: Spawn,
 	method: &str,
 	call_data: &[u8],
-	runtime_code: &RuntimeCode,
-) -> Result<Vec<u8>, Box<dyn Error>>
+) -> Result<(Vec<u8>, StorageProof), Box<dyn Error>>
 where
-	H: Hasher,
-	Exec: CodeExecutor + Clone + 'static,
+	B: Backend<H>,
+	H: Hasher + 'static,
 	H::Out: Ord + 'static + codec::Codec,
+	Exec: CodeExecutor + Clone + 'static,
 	N: crate::changes_trie::BlockNumber,
 	Spawn: SpawnNamed + Send + 'static,
 {
-	let trie_backend = create_proof_check_backend::<H>(root.into(), proof)?;
-	execution_proof_check_on_trie_backend::<_, N, _, _>(
-		&trie_backend,
+	let trie_backend = backend.as_trie_backend()
+		.ok_or_else(|| Box::new(ExecutionError::UnableToGenerateProof) as Box<dyn Error>)?;
+	prove_execution_with_code_on_trie_backend::<_, _, N, _, _>(
+		trie_backend,
 		overlay,
 		exec,
 		spawn_handle,
 		method,
 		call_data,
-		runtime_code,
 	)
 }
 
-/// Check execution proof on proving backend, generated by `prove_execution` call.
-pub fn execution_proof_check_on_trie_backend<H, N, Exec, Spawn>(
-	trie_backend: &TrieBackend<MemoryDB<H>, H>,
+/// Prove execution using the runtime code of the given trie backend.
+///
+/// See `prove_execution_with_code`.
+pub fn prove_execution_with_code_on_trie_backend<S, H, N, Exec, Spawn>(
+	trie_backend: &TrieBackend<S, H>,
 	overlay: &mut OverlayedChanges,
 	exec: &Exec,
 	spawn_handle: Spawn,
 	method: &str,
 	call_data: &[u8],
-	runtime_code: &RuntimeCode,
+) -> Result<(Vec<u8>, StorageProof), Box<dyn Error>>
+where
+	S: trie_backend_essence::TrieBackendStorage<H>,
+	H: Hasher + 'static,
+	H::Out: Ord + 'static + codec::Codec,
+	Exec: CodeExecutor + 'static + Clone,
+	N: crate::changes_trie::BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	// The code is recorded separately, executors may not fetch it when its hash is known.
+	let code_backend = proving_backend::ProvingBackend::new(trie_backend);
+	let backend_code = backend::BackendRuntimeCode::<_, H>::new(&code_backend);
+	let runtime_code = backend_code.runtime_code()
+		.map_err(|_| Box::new(ExecutionError::CodeEntryDoesNotExist) as Box<dyn Error>)?;
+	code_backend.storage(well_known_keys::CODE).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+	let (result, proof) = prove_execution_on_trie_backend::<_, _, N, _, _>(
+		trie_backend,
+		overlay,
+		exec,
+		spawn_handle,
+		method,
+		call_data,
+		&runtime_code,
+	)?;
+	Ok((result, StorageProof::merge(vec![proof, code_backend.extract_proof()])))
+}
+
+/// Check execution proof, generated by `prove_execution` call.
+pub fn execution_proof_check<H, N, Exec, Spawn>(
+	root: H::Out,
+	proof: StorageProof,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	method: &str,
+	call_data: &[u8],
+	runtime_code: &RuntimeCode,
+) -> Result<Vec<u8>, Box<dyn Error>>
+where
+	H: Hasher,
+	Exec: CodeExecutor + Clone + 'static,
+	H::Out: Ord + 'static + codec::Codec,
+	N: crate::changes_trie::BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let trie_backend = create_proof_check_backend::<H>(root.into(), proof)?;
+	execution_proof_check_on_trie_backend::<_, N, _, _>(
+		&trie_backend,
+		overlay,
+		exec,
+		spawn_handle,
+		method,
+		call_data,
+		runtime_code,
+	)
+}
+
+/// Check execution proof, generated by `prove_execution_with_code` call.
+///
+/// The runtime code is read from the proof, its hash is bound to `root` by the trie.
+pub fn execution_proof_check_with_code<H, N, Exec, Spawn>(
+	root: H::Out,
+	proof: StorageProof,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	method: &str,
+	call_data: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>>
+where
+	H: Hasher,
+	Exec: CodeExecutor + Clone + 'static,
+	H::Out: Ord + 'static + codec::Codec,
+	N: crate::changes_trie::BlockNumber,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let trie_backend = create_proof_check_backend::<H>(root, proof)?;
+	let backend_code = backend::BackendRuntimeCode::<_, H>::new(&trie_backend);
+	let runtime_code = backend_code.runtime_code()
+		.map_err(|_| Box::new(ExecutionError::CodeEntryDoesNotExist) as Box<dyn Error>)?;
+	execution_proof_check_on_trie_backend::<_, N, _, _>(
+		&trie_backend,
+		overlay,
+		exec,
+		spawn_handle,
+		method,
+		call_data,
+		&runtime_code,
+	)
+}
+
+/// Check execution proof on proving backend, generated by `prove_execution` call.
+pub fn execution_proof_check_on_trie_backend<H, N, Exec, Spawn>(
+	trie_backend: &TrieBackend<MemoryDB<H>, H>,
+	overlay: &mut OverlayedChanges,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	method: &str,
+	call_data: &[u8],
+	runtime_code: &RuntimeCode,
 ) -> Result<Vec<u8>, Box<dyn Error>>
 where
 	H: Hasher,
@@ -608,6 +1369,97 @@ where
 	).map(NativeOrEncoded::into_encoded)
 }
 
+/// Backend checking a proof, built once and reused for several checks against it.
+///
+/// Avoids decoding the proof again for each execution or read check.
+pub struct ProofCheckBackend<H: Hasher> {
+	backend: TrieBackend<MemoryDB<H>, H>,
+}
+
+impl<H> ProofCheckBackend<H>
+where
+	H: Hasher,
+	H::Out: Ord + 'static + Codec,
+{
+	/// Create the backend for `proof` of the state at `root`.
+	pub fn new(root: H::Out, proof: StorageProof) -> Result<Self, Box<dyn Error>> {
+		Ok(ProofCheckBackend {
+			backend: create_proof_check_backend::<H>(root, proof)?,
+		})
+	}
+
+	/// The underlying trie backend.
+	pub fn backend(&self) -> &TrieBackend<MemoryDB<H>, H> {
+		&self.backend
+	}
+
+	/// Check execution of `method` against the proof, see `execution_proof_check`.
+	pub fn check_execution<N, Exec, Spawn>(
+		&self,
+		overlay: &mut OverlayedChanges,
+		exec: &Exec,
+		spawn_handle: Spawn,
+		method: &str,
+		call_data: &[u8],
+		runtime_code: &RuntimeCode,
+	) -> Result<Vec<u8>, Box<dyn Error>>
+	where
+		Exec: CodeExecutor + Clone + 'static,
+		N: crate::changes_trie::BlockNumber,
+		Spawn: SpawnNamed + Send + 'static,
+	{
+		execution_proof_check_on_trie_backend::<_, N, _, _>(
+			&self.backend,
+			overlay,
+			exec,
+			spawn_handle,
+			method,
+			call_data,
+			runtime_code,
+		)
+	}
+
+	/// Check a storage read against the proof.
+	pub fn check_read(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+		read_proof_check_on_proving_backend(&self.backend, key)
+	}
+
+	/// Check a child storage read against the proof.
+	pub fn check_child_read(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+		read_child_proof_check_on_proving_backend(&self.backend, child_info, key)
+	}
+}
+
+/// Iterator checking storage reads against a proof, created by `read_proof_check_iter`.
+///
+/// Each key is checked when its item is pulled, yielding the key and its value or the error of
+/// the check.
+pub struct ReadProofCheckIter<H: Hasher, I> {
+	backend: ProofCheckBackend<H>,
+	keys: I,
+}
+
+impl<H, I> Iterator for ReadProofCheckIter<H, I>
+where
+	H: Hasher,
+	H::Out: Ord + 'static + Codec,
+	I: Iterator,
+	I::Item: AsRef<[u8]>,
+{
+	type Item = Result<(Vec<u8>, Option<Vec<u8>>), Box<dyn Error>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let backend = &self.backend;
+		self.keys.next().map(|key| backend.check_read(key.as_ref())
+			.map(|value| (key.as_ref().to_vec(), value))
+		)
+	}
+}
+
 /// Generate storage read proof.
 pub fn prove_read<B, H, I>(
 	mut backend: B,
@@ -627,6 +1479,121 @@ where
 	prove_read_on_trie_backend(trie_backend, keys)
 }
 
+/// Storage read proof generated by `prove_read_chunked`.
+#[derive(Debug, Clone)]
+pub struct PartialReadProof {
+	/// Proof of the values of `proven_keys`.
+	pub proof: StorageProof,
+	/// Keys included in the proof, in the order they were given.
+	pub proven_keys: Vec<Vec<u8>>,
+	/// `false` if generation was cancelled before all keys were proven.
+	pub complete: bool,
+}
+
+/// Generate storage read proof, `chunk_size` keys at a time.
+///
+/// `should_continue` is called between chunks with the number of keys proven so far, returning
+/// `false` cancels the generation. The returned proof then only covers the proven keys, the
+/// remaining keys can be proven by a later call and the proofs merged with
+/// `StorageProof::merge`.
+pub fn prove_read_chunked<B, H, I, F>(
+	mut backend: B,
+	keys: I,
+	chunk_size: usize,
+	should_continue: F,
+) -> Result<PartialReadProof, Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+	F: FnMut(usize) -> bool,
+{
+	let trie_backend = backend.as_trie_backend()
+		.ok_or_else(
+			|| Box::new(ExecutionError::UnableToGenerateProof) as Box<dyn Error>
+		)?;
+	prove_read_chunked_on_trie_backend(trie_backend, keys, chunk_size, should_continue)
+}
+
+/// Outcome of the read of a key by `prove_read_per_key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyReadResult {
+	/// The key has this value, included in the proof.
+	Proven(StorageValue),
+	/// The key is not in the storage, its absence is included in the proof.
+	Missing,
+	/// The backend failed to read the key, nothing about it is included in the proof.
+	Failed(String),
+}
+
+/// Storage read proof generated by `prove_read_per_key`.
+#[derive(Debug, Clone)]
+pub struct PerKeyReadProof {
+	/// Proof of the keys that were read successfully.
+	pub proof: StorageProof,
+	/// Outcome of the read of each key, in the order they were given.
+	pub results: Vec<(Vec<u8>, KeyReadResult)>,
+}
+
+/// Generate storage read proof, reporting the outcome of each key instead of failing on the
+/// first backend error.
+///
+/// The proof only covers the keys that were read successfully.
+pub fn prove_read_per_key<B, H, I>(
+	mut backend: B,
+	keys: I,
+) -> Result<PerKeyReadProof, Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+{
+	let trie_backend = backend.as_trie_backend()
+		.ok_or_else(
+			|| Box::new(ExecutionError::UnableToGenerateProof) as Box<dyn Error>
+		)?;
+	Ok(prove_read_per_key_on_trie_backend(trie_backend, keys))
+}
+
+/// Storage read proof generated by `prove_read_with_limit`.
+#[derive(Debug, Clone)]
+pub struct LimitedReadProof {
+	/// Proof of the values of `proven_keys`.
+	pub proof: StorageProof,
+	/// Keys included in the proof, in the order they were given.
+	pub proven_keys: Vec<Vec<u8>>,
+	/// First key left out of the proof, to resume from. `None` if all keys are proven.
+	pub next_key: Option<Vec<u8>>,
+}
+
+/// Generate storage read proof of at most `max_proof_bytes` of trie nodes.
+///
+/// Keys are proven in order until proving the next one would exceed the limit. A key whose
+/// proof alone exceeds the limit is never proven, callers resuming from `next_key` must raise
+/// the limit when no key was proven.
+pub fn prove_read_with_limit<B, H, I>(
+	mut backend: B,
+	keys: I,
+	max_proof_bytes: usize,
+) -> Result<LimitedReadProof, Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+{
+	let trie_backend = backend.as_trie_backend()
+		.ok_or_else(
+			|| Box::new(ExecutionError::UnableToGenerateProof) as Box<dyn Error>
+		)?;
+	prove_read_with_limit_on_trie_backend(trie_backend, keys, max_proof_bytes)
+}
+
 /// Generate child storage read proof.
 pub fn prove_child_read<B, H, I>(
 	mut backend: B,
@@ -666,6 +1633,130 @@ where
 	Ok(proving_backend.extract_proof())
 }
 
+/// Generate storage read proof on pre-created trie backend, `chunk_size` keys at a time.
+///
+/// See `prove_read_chunked`.
+pub fn prove_read_chunked_on_trie_backend<S, H, I, F>(
+	trie_backend: &TrieBackend<S, H>,
+	keys: I,
+	chunk_size: usize,
+	mut should_continue: F,
+) -> Result<PartialReadProof, Box<dyn Error>>
+where
+	S: trie_backend_essence::TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+	F: FnMut(usize) -> bool,
+{
+	let proving_backend = proving_backend::ProvingBackend::<_, H>::new(trie_backend);
+	let chunk_size = std::cmp::max(chunk_size, 1);
+	let mut keys = keys.into_iter().peekable();
+	let mut proven_keys = Vec::new();
+	let complete = loop {
+		for key in keys.by_ref().take(chunk_size) {
+			proving_backend
+				.storage(key.as_ref())
+				.map_err(|e| Box::new(e) as Box<dyn Error>)?;
+			proven_keys.push(key.as_ref().to_vec());
+		}
+		if keys.peek().is_none() {
+			break true;
+		}
+		if !should_continue(proven_keys.len()) {
+			break false;
+		}
+	};
+	Ok(PartialReadProof {
+		proof: proving_backend.extract_proof(),
+		proven_keys,
+		complete,
+	})
+}
+
+/// Generate storage read proof on pre-created trie backend, with the outcome of each key.
+///
+/// See `prove_read_per_key`.
+pub fn prove_read_per_key_on_trie_backend<S, H, I>(
+	trie_backend: &TrieBackend<S, H>,
+	keys: I,
+) -> PerKeyReadProof
+where
+	S: trie_backend_essence::TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+{
+	// Each key is recorded separately, so that the nodes read before a failure are left out.
+	let mut proofs = Vec::new();
+	let results: Vec<_> = keys.into_iter()
+		.map(|key| {
+			let key = key.as_ref().to_vec();
+			let proving_backend = proving_backend::ProvingBackend::<_, H>::new(trie_backend);
+			let result = match proving_backend.storage(&key) {
+				Ok(Some(value)) => KeyReadResult::Proven(value),
+				Ok(None) => KeyReadResult::Missing,
+				Err(e) => return (key, KeyReadResult::Failed(e)),
+			};
+			proofs.push(proving_backend.extract_proof());
+			(key, result)
+		})
+		.collect();
+	PerKeyReadProof {
+		proof: StorageProof::merge(proofs),
+		results,
+	}
+}
+
+/// Generate storage read proof on pre-created trie backend, of at most `max_proof_bytes` of
+/// trie nodes.
+///
+/// See `prove_read_with_limit`.
+pub fn prove_read_with_limit_on_trie_backend<S, H, I>(
+	trie_backend: &TrieBackend<S, H>,
+	keys: I,
+	max_proof_bytes: usize,
+) -> Result<LimitedReadProof, Box<dyn Error>>
+where
+	S: trie_backend_essence::TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+{
+	// Each key is recorded separately, so that only the nodes it adds to the proof are counted
+	// and the nodes of a key exceeding the limit are left out.
+	let mut nodes = HashMap::<H::Out, DBValue>::new();
+	let mut proof_bytes = 0;
+	let mut proven_keys = Vec::new();
+	let mut next_key = None;
+	for key in keys.into_iter() {
+		let recorder = ProofRecorder::<H>::default();
+		proving_backend::ProvingBackend::<_, H>::new_with_recorder(trie_backend, recorder.clone())
+			.storage(key.as_ref())
+			.map_err(|e| Box::new(e) as Box<dyn Error>)?;
+		let key_nodes: Vec<_> = recorder.read().iter()
+			.filter(|(hash, _)| !nodes.contains_key(*hash))
+			.filter_map(|(hash, node)| node.clone().map(|node| (hash.clone(), node)))
+			.collect();
+		let key_bytes: usize = key_nodes.iter().map(|(_, node)| node.len()).sum();
+		if proof_bytes + key_bytes > max_proof_bytes {
+			next_key = Some(key.as_ref().to_vec());
+			break;
+		}
+		proof_bytes += key_bytes;
+		nodes.extend(key_nodes);
+		proven_keys.push(key.as_ref().to_vec());
+	}
+	Ok(LimitedReadProof {
+		proof: StorageProof::new(nodes.into_iter().map(|(_, node)| node).collect()),
+		proven_keys,
+		next_key,
+	})
+}
+
 /// Generate storage read proof on pre-created trie backend.
 pub fn prove_child_read_on_trie_backend<S, H, I>(
 	trie_backend: &TrieBackend<S, H>,
@@ -709,6 +1800,27 @@ where
 	Ok(result)
 }
 
+/// Check storage read proof, generated by `prove_read` call, lazily.
+///
+/// Same as `read_proof_check`, but keys are checked one at a time as the returned iterator is
+/// advanced, so callers can stream the values and stop at the first failure.
+pub fn read_proof_check_iter<H, I>(
+	root: H::Out,
+	proof: StorageProof,
+	keys: I,
+) -> Result<ReadProofCheckIter<H, I::IntoIter>, Box<dyn Error>>
+where
+	H: Hasher,
+	H::Out: Ord + 'static + Codec,
+	I: IntoIterator,
+	I::Item: AsRef<[u8]>,
+{
+	Ok(ReadProofCheckIter {
+		backend: ProofCheckBackend::new(root, proof)?,
+		keys: keys.into_iter(),
+	})
+}
+
 /// Check child storage read proof, generated by `prove_child_read` call.
 pub fn read_child_proof_check<H, I>(
 	root: H::Out,
@@ -761,28 +1873,172 @@ where
 		.map_err(|e| Box::new(e) as Box<dyn Error>)
 }
 
-#[cfg(test)]
-mod tests {
-	use std::collections::BTreeMap;
-	use codec::Encode;
-	use super::*;
-	use super::ext::Ext;
-	use super::changes_trie::Configuration as ChangesTrieConfig;
-	use sp_core::{
-		map, traits::{Externalities, RuntimeCode}, testing::TaskExecutor,
-	};
-	use sp_runtime::traits::BlakeTwo256;
-
-	#[derive(Clone)]
-	struct DummyCodeExecutor {
-		change_changes_trie_config: bool,
-		native_available: bool,
-		native_succeeds: bool,
-		fallback_succeeds: bool,
-	}
-
-	impl CodeExecutor for DummyCodeExecutor {
-		type Error = u8;
+/// Generate proof of all the key/value pairs of the storage from `start` included to `end`
+/// excluded.
+///
+/// The proof includes the nodes locating the first key after the range, so that the checker
+/// knows no key of the range was left out. Child tries are not included.
+pub fn prove_range<B, H>(
+	mut backend: B,
+	start: &[u8],
+	end: &[u8],
+) -> Result<StorageProof, Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+{
+	let trie_backend = backend.as_trie_backend()
+		.ok_or_else(
+			|| Box::new(ExecutionError::UnableToGenerateProof) as Box<dyn Error>
+		)?;
+	prove_range_on_trie_backend(trie_backend, start, end)
+}
+
+/// Generate range proof on pre-created trie backend.
+///
+/// See `prove_range`.
+pub fn prove_range_on_trie_backend<S, H>(
+	trie_backend: &TrieBackend<S, H>,
+	start: &[u8],
+	end: &[u8],
+) -> Result<StorageProof, Box<dyn Error>>
+where
+	S: trie_backend_essence::TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+{
+	let proving_backend = proving_backend::ProvingBackend::<_, H>::new(trie_backend);
+	contiguous_pairs(&proving_backend, start, |key| key < end)?;
+	Ok(proving_backend.extract_proof())
+}
+
+/// Check range proof, generated by `prove_range` call.
+///
+/// Returns all the key/value pairs from `start` included to `end` excluded, in order. Fails if
+/// the proof does not cover the whole range.
+pub fn read_range_proof_check<H>(
+	root: H::Out,
+	proof: StorageProof,
+	start: &[u8],
+	end: &[u8],
+) -> Result<Vec<(StorageKey, StorageValue)>, Box<dyn Error>>
+where
+	H: Hasher,
+	H::Out: Ord + Codec,
+{
+	if start >= end {
+		return Ok(Vec::new());
+	}
+	let proving_backend = create_proof_check_backend::<H>(root, proof)?;
+	contiguous_pairs(&proving_backend, start, |key| key < end)
+}
+
+/// Generate proof of all the key/value pairs of the storage whose key starts with `prefix`.
+///
+/// Same as `prove_range`, the proof also shows that no other key has this prefix.
+pub fn prove_prefix<B, H>(
+	mut backend: B,
+	prefix: &[u8],
+) -> Result<StorageProof, Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+{
+	let trie_backend = backend.as_trie_backend()
+		.ok_or_else(
+			|| Box::new(ExecutionError::UnableToGenerateProof) as Box<dyn Error>
+		)?;
+	prove_prefix_on_trie_backend(trie_backend, prefix)
+}
+
+/// Generate prefix proof on pre-created trie backend.
+///
+/// See `prove_prefix`.
+pub fn prove_prefix_on_trie_backend<S, H>(
+	trie_backend: &TrieBackend<S, H>,
+	prefix: &[u8],
+) -> Result<StorageProof, Box<dyn Error>>
+where
+	S: trie_backend_essence::TrieBackendStorage<H>,
+	H: Hasher,
+	H::Out: Ord + Codec,
+{
+	let proving_backend = proving_backend::ProvingBackend::<_, H>::new(trie_backend);
+	contiguous_pairs(&proving_backend, prefix, |key| key.starts_with(prefix))?;
+	Ok(proving_backend.extract_proof())
+}
+
+/// Check prefix proof, generated by `prove_prefix` call.
+///
+/// Returns all the key/value pairs whose key starts with `prefix`, in order. Fails if the
+/// proof does not cover all of them.
+pub fn read_prefix_proof_check<H>(
+	root: H::Out,
+	proof: StorageProof,
+	prefix: &[u8],
+) -> Result<Vec<(StorageKey, StorageValue)>, Box<dyn Error>>
+where
+	H: Hasher,
+	H::Out: Ord + Codec,
+{
+	let proving_backend = create_proof_check_backend::<H>(root, proof)?;
+	contiguous_pairs(&proving_backend, prefix, |key| key.starts_with(prefix))
+}
+
+/// Read the key/value pairs of `backend` from `start` included, in order, until the first key
+/// out of `in_range`.
+fn contiguous_pairs<B: Backend<H>, H: Hasher>(
+	backend: &B,
+	start: &[u8],
+	in_range: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<(StorageKey, StorageValue)>, Box<dyn Error>> {
+	let mut pairs = Vec::new();
+	if !in_range(start) {
+		return Ok(pairs);
+	}
+	let mut key = start.to_vec();
+	let mut value = backend.storage(start).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+	loop {
+		if let Some(value) = value.take() {
+			pairs.push((key.clone(), value));
+		}
+		match backend.next_storage_key(&key).map_err(|e| Box::new(e) as Box<dyn Error>)? {
+			Some(next) if in_range(&next) => {
+				value = backend.storage(&next).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+				key = next;
+			},
+			_ => break,
+		}
+	}
+	Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use codec::Encode;
+	use super::*;
+	use super::ext::Ext;
+	use super::changes_trie::Configuration as ChangesTrieConfig;
+	use sp_core::{
+		map, traits::{Externalities, RuntimeCode, FetchRuntimeCode}, testing::TaskExecutor,
+	};
+	use sp_runtime::traits::BlakeTwo256;
+	use sp_externalities::ExternalitiesExt;
+	use crate::test_helpers::{MockExecutor, MockCall};
+
+	#[derive(Clone)]
+	struct DummyCodeExecutor {
+		change_changes_trie_config: bool,
+		native_available: bool,
+		native_succeeds: bool,
+		fallback_succeeds: bool,
+	}
+
+	impl CodeExecutor for DummyCodeExecutor {
+		type Error = u8;
 
 		fn call<
 			R: Encode + Decode + PartialEq,
@@ -828,22 +2084,635 @@ mod tests {
 		}
 	}
 
-	impl sp_core::traits::CallInWasm for DummyCodeExecutor {
-		fn call_in_wasm(
-			&self,
-			_: &[u8],
-			_: Option<Vec<u8>>,
-			_: &str,
-			_: &[u8],
-			_: &mut dyn Externalities,
-			_: sp_core::traits::MissingHostFunctions,
-		) -> std::result::Result<Vec<u8>, String> {
-			unimplemented!("Not required in tests.")
-		}
+	impl sp_core::traits::CallInWasm for DummyCodeExecutor {
+		fn call_in_wasm(
+			&self,
+			_: &[u8],
+			_: Option<Vec<u8>>,
+			_: &str,
+			_: &[u8],
+			_: &mut dyn Externalities,
+			_: sp_core::traits::MissingHostFunctions,
+		) -> std::result::Result<Vec<u8>, String> {
+			unimplemented!("Not required in tests.")
+		}
+	}
+
+	/// Executor whose runtime makes one nested `call_in_wasm` call and returns its result.
+	#[derive(Clone)]
+	struct NestedCallExecutor;
+
+	impl CodeExecutor for NestedCallExecutor {
+		type Error = u8;
+
+		fn call<
+			R: Encode + Decode + PartialEq,
+			NC: FnOnce() -> result::Result<R, String>,
+		>(
+			&self,
+			mut ext: &mut dyn Externalities,
+			_: &RuntimeCode,
+			_method: &str,
+			_data: &[u8],
+			use_native: bool,
+			_native_call: Option<NC>,
+		) -> (CallResult<R, Self::Error>, bool) {
+			ext.place_storage(b"nested".to_vec(), Some(vec![1]));
+			let mut nested_ext = BasicExternalities::default();
+			let result = ext.extension::<CallInWasmExt>()
+				.expect("Registered by the state machine")
+				.call_in_wasm(&[], None, "nested", &[], &mut nested_ext, MissingHostFunctions::Allow)
+				.unwrap_or_default();
+			(Ok(NativeOrEncoded::Encoded(result)), use_native)
+		}
+	}
+
+	/// Executor whose runtime panics after writing to the storage.
+	#[derive(Clone)]
+	struct PanickingExecutor;
+
+	impl CodeExecutor for PanickingExecutor {
+		type Error = u8;
+
+		fn call<
+			R: Encode + Decode + PartialEq,
+			NC: FnOnce() -> result::Result<R, String>,
+		>(
+			&self,
+			ext: &mut dyn Externalities,
+			_: &RuntimeCode,
+			_method: &str,
+			_data: &[u8],
+			_use_native: bool,
+			_native_call: Option<NC>,
+		) -> (CallResult<R, Self::Error>, bool) {
+			ext.place_storage(b"partial".to_vec(), Some(vec![1]));
+			ext.storage_start_transaction();
+			panic!("Missing trie node")
+		}
+	}
+
+	impl sp_core::traits::CallInWasm for PanickingExecutor {
+		fn call_in_wasm(
+			&self,
+			_: &[u8],
+			_: Option<Vec<u8>>,
+			_: &str,
+			_: &[u8],
+			_: &mut dyn Externalities,
+			_: sp_core::traits::MissingHostFunctions,
+		) -> std::result::Result<Vec<u8>, String> {
+			unimplemented!("Not required in tests.")
+		}
+	}
+
+	impl sp_core::traits::CallInWasm for NestedCallExecutor {
+		fn call_in_wasm(
+			&self,
+			_: &[u8],
+			_: Option<Vec<u8>>,
+			_: &str,
+			_: &[u8],
+			_: &mut dyn Externalities,
+			_: sp_core::traits::MissingHostFunctions,
+		) -> std::result::Result<Vec<u8>, String> {
+			Ok(vec![42])
+		}
+	}
+
+	#[test]
+	fn interrupted_execution_reports_reason() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = MockExecutor::new()
+			.with_method("test", MockCall::returning(vec![1]).with_write(b"key".to_vec(), Some(vec![1])));
+		let mut execute = |overlay: &mut OverlayedChanges, interrupt: ExecutionInterrupt| StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			overlay,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_interrupt(interrupt).execute(ExecutionStrategy::NativeWhenPossible);
+
+		let cancelled = ExecutionInterrupt::new();
+		cancelled.cancel();
+		assert_eq!(
+			format!("{:?}", execute(&mut overlayed_changes, cancelled).unwrap_err()),
+			format!("{:?}", ExecutionError::Cancelled),
+		);
+		let expired = ExecutionInterrupt::new().with_deadline(Instant::now());
+		assert_eq!(
+			format!("{:?}", execute(&mut overlayed_changes, expired).unwrap_err()),
+			format!("{:?}", ExecutionError::DeadlineExceeded),
+		);
+		assert!(overlayed_changes.is_empty());
+
+		let pending = ExecutionInterrupt::new().with_timeout(Duration::from_secs(3600));
+		assert_eq!(execute(&mut overlayed_changes, pending).unwrap(), vec![1]);
+		assert_eq!(overlayed_changes.storage(b"key"), Some(Some(&[1][..])));
+	}
+
+	#[derive(Default)]
+	struct MethodsReporter(Mutex<Vec<(String, u64, u64)>>);
+
+	impl StatsReporter for MethodsReporter {
+		fn report(&self, method: &str, stats: &StateMachineStats) {
+			self.0.lock().push((
+				method.to_string(),
+				*stats.reads_modified.borrow(),
+				*stats.writes_overlay.borrow(),
+			));
+		}
+	}
+
+	#[test]
+	fn stats_are_reported_on_drop() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = MockExecutor::new().with_method(
+			"test",
+			MockCall::returning(vec![1]).with_write(b"key".to_vec(), Some(vec![1])).with_read(b"key".to_vec()),
+		);
+		let reporter = Arc::new(MethodsReporter::default());
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_stats_reporter(reporter.clone());
+		assert_eq!(state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap(), vec![1]);
+		assert!(reporter.0.lock().is_empty());
+
+		drop(state_machine);
+		assert_eq!(*reporter.0.lock(), vec![("test".to_string(), 1, 1)]);
+	}
+
+	/// Executor whose runtime returns its own code.
+	#[derive(Clone)]
+	struct CodeReturningExecutor;
+
+	impl CodeExecutor for CodeReturningExecutor {
+		type Error = u8;
+
+		fn call<
+			R: Encode + Decode + PartialEq,
+			NC: FnOnce() -> result::Result<R, String>,
+		>(
+			&self,
+			_: &mut dyn Externalities,
+			runtime_code: &RuntimeCode,
+			_method: &str,
+			_data: &[u8],
+			use_native: bool,
+			_native_call: Option<NC>,
+		) -> (CallResult<R, Self::Error>, bool) {
+			match runtime_code.fetch_runtime_code() {
+				Some(code) => (Ok(NativeOrEncoded::Encoded(code.to_vec())), use_native),
+				None => (Err(0), use_native),
+			}
+		}
+	}
+
+	impl sp_core::traits::CallInWasm for CodeReturningExecutor {
+		fn call_in_wasm(
+			&self,
+			_: &[u8],
+			_: Option<Vec<u8>>,
+			_: &str,
+			_: &[u8],
+			_: &mut dyn Externalities,
+			_: sp_core::traits::MissingHostFunctions,
+		) -> std::result::Result<Vec<u8>, String> {
+			unimplemented!("Not required in tests.")
+		}
+	}
+
+	#[derive(Default)]
+	struct CountingCodeFetcher(Mutex<usize>);
+
+	impl FetchRuntimeCode for CountingCodeFetcher {
+		fn fetch_runtime_code<'a>(&'a self) -> Option<std::borrow::Cow<'a, [u8]>> {
+			*self.0.lock() += 1;
+			Some(b"return 42".to_vec().into())
+		}
+	}
+
+	#[test]
+	fn runtime_code_cache_is_shared_between_state_machines() {
+		let backend = trie_backend::tests::test_trie();
+		let fetcher = CountingCodeFetcher::default();
+		let runtime_code = RuntimeCode { code_fetcher: &fetcher, heap_pages: None, hash: vec![42] };
+		let cache = RuntimeCodeCache::default();
+
+		for strategy in &[ExecutionStrategy::Both, ExecutionStrategy::AlwaysWasm] {
+			let mut overlayed_changes = OverlayedChanges::default();
+			let mut offchain_overlayed_changes = Default::default();
+			let mut state_machine = StateMachine::new(
+				&backend,
+				changes_trie::disabled_state::<_, u64>(),
+				&mut overlayed_changes,
+				&mut offchain_overlayed_changes,
+				&CodeReturningExecutor,
+				"test",
+				&[],
+				Default::default(),
+				&runtime_code,
+				TaskExecutor::new(),
+			).with_runtime_code_cache(cache.clone());
+			assert_eq!(state_machine.execute(*strategy).unwrap(), b"return 42".to_vec());
+		}
+		assert_eq!(*fetcher.0.lock(), 1);
+		assert_eq!(cache.len(), 1);
+	}
+
+	#[test]
+	fn execute_batch_shares_overlay() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = MockExecutor::new()
+			.with_method("init", MockCall::returning(vec![1]).with_write(b"init".to_vec(), Some(vec![1])))
+			.with_method("fail", MockCall::failing().with_write(b"fail".to_vec(), Some(vec![2])))
+			.with_method("finalize", MockCall::returning(vec![3]).with_write(b"init".to_vec(), None));
+		let no_data: &[u8] = &[];
+		let calls = [("init", no_data), ("fail", no_data), ("finalize", no_data)];
+
+		let results = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).execute_batch(&calls, ExecutionStrategy::NativeWhenPossible);
+
+		assert_eq!(results.len(), 3);
+		assert_eq!(results[0].as_ref().unwrap(), &vec![1]);
+		assert!(results[1].is_err());
+		assert_eq!(results[2].as_ref().unwrap(), &vec![3]);
+		assert_eq!(
+			executor.calls().into_iter().map(|(method, _)| method).collect::<Vec<_>>(),
+			vec!["init", "fail", "finalize"],
+		);
+		assert_eq!(overlayed_changes.storage(b"init"), Some(None));
+		assert_eq!(overlayed_changes.storage(b"fail"), None);
+	}
+
+	#[test]
+	fn failed_call_does_not_leave_its_storage_root_cached() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let mut cache = StorageTransactionCache::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = MockExecutor::new()
+			.with_method(
+				"fail",
+				MockCall::failing().with_write(b"fail".to_vec(), Some(vec![2])).with_storage_root(),
+			)
+			.with_method("root", MockCall::returning(Vec::new()).with_storage_root());
+		let no_data: &[u8] = &[];
+		let calls = [("fail", no_data), ("root", no_data)];
+
+		let results = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		)
+			.with_storage_transaction_cache(Some(&mut cache))
+			.execute_batch(&calls, ExecutionStrategy::NativeWhenPossible);
+
+		assert!(results[0].is_err());
+		assert_eq!(
+			results[1].as_ref().unwrap(),
+			&backend.storage_root(std::iter::empty()).0.encode(),
+		);
+	}
+
+	#[test]
+	fn execute_works() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&DummyCodeExecutor {
+				change_changes_trie_config: false,
+				native_available: true,
+				native_succeeds: true,
+				fallback_succeeds: true,
+			},
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		);
+
+		assert_eq!(
+			state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap(),
+			vec![66],
+		);
+	}
+
+	#[test]
+	fn protected_well_known_keys_are_not_written() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = DummyCodeExecutor {
+			change_changes_trie_config: true,
+			native_available: true,
+			native_succeeds: true,
+			fallback_succeeds: true,
+		};
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_well_known_keys_protection(true);
+
+		let err = state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap_err();
+		assert_eq!(
+			format!("{:?}", err),
+			format!("{:?}", ExecutionError::ProtectedKeyWrite(
+				sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG.to_vec(),
+			)),
+		);
+		drop(state_machine);
+		assert!(overlayed_changes.storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG).is_none());
+	}
+
+	#[test]
+	fn failed_execution_leaves_overlay_untouched() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = DummyCodeExecutor {
+			change_changes_trie_config: true,
+			native_available: true,
+			native_succeeds: false,
+			fallback_succeeds: false,
+		};
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		);
+		assert!(state_machine.execute(ExecutionStrategy::NativeWhenPossible).is_err());
+		drop(state_machine);
+		assert!(overlayed_changes.storage(sp_core::storage::well_known_keys::CHANGES_TRIE_CONFIG).is_none());
+		assert_eq!(overlayed_changes.transaction_depth(), 0);
+	}
+
+	#[test]
+	fn untrusted_panic_leaves_overlay_untouched() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&PanickingExecutor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		);
+		let err = state_machine.execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
+			always_untrusted_wasm(),
+			None,
+		).err().unwrap();
+		assert!(format!("{:?}", err).contains("Missing trie node"));
+		drop(state_machine);
+		assert!(overlayed_changes.storage(b"partial").is_none());
+		assert_eq!(overlayed_changes.transaction_depth(), 0);
+		assert!(overlayed_changes.enter_runtime().is_ok());
+	}
+
+	#[test]
+	fn runtime_code_changes_are_detected() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let code = b"return 42".to_vec();
+		let runtime_code = RuntimeCode {
+			code_fetcher: &sp_core::traits::WrappedRuntimeCode(code.clone().into()),
+			heap_pages: None,
+			hash: BlakeTwo256::hash(&code).encode(),
+		};
+		let executor = MockExecutor::new()
+			.with_method("same", MockCall::returning(vec![]).with_write(well_known_keys::CODE.to_vec(), Some(code)))
+			.with_method("upgrade", MockCall::returning(vec![]).with_write(well_known_keys::CODE.to_vec(), Some(vec![1])));
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"same",
+			&[],
+			Default::default(),
+			&runtime_code,
+			TaskExecutor::new(),
+		);
+		state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap();
+		assert!(!state_machine.runtime_code_changed());
+
+		let no_data: &[u8] = &[];
+		let calls = [("upgrade", no_data), ("same", no_data)];
+		let results = state_machine.execute_batch(&calls, ExecutionStrategy::NativeWhenPossible);
+		assert!(results.iter().all(Result::is_ok));
+		assert!(!state_machine.runtime_code_changed());
+		state_machine.execute_batch(&calls[..1], ExecutionStrategy::NativeWhenPossible);
+		assert!(state_machine.runtime_code_changed());
+	}
+
+	#[test]
+	fn execute_limits_nested_calls() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&NestedCallExecutor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_max_nested_calls(0);
+		let err = state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap_err();
+		assert_eq!(format!("{:?}", err), format!("{:?}", ExecutionError::NestedCallLimitExceeded(0)));
+		drop(state_machine);
+		assert!(overlayed_changes.storage(b"nested").is_none());
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&NestedCallExecutor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		);
+		assert_eq!(state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap(), vec![42]);
+		drop(state_machine);
+		assert_eq!(overlayed_changes.storage(b"nested"), Some(Some(&[1][..])));
+	}
+
+	#[test]
+	fn execute_rejects_method_not_in_whitelist() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = Default::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = DummyCodeExecutor {
+			change_changes_trie_config: false,
+			native_available: true,
+			native_succeeds: true,
+			fallback_succeeds: true,
+		};
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		).with_method_whitelist(Some(&["other"]));
+
+		let err = state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap_err();
+		assert_eq!(format!("{:?}", err), format!("{:?}", ExecutionError::MethodNotAllowed("test".into())));
+	}
+
+
+	#[test]
+	fn execute_block_checks_roots() {
+		let backend = trie_backend::tests::test_trie();
+		let wasm_code = RuntimeCode::empty();
+		let root = backend.storage_root(std::iter::empty()).0;
+		let execute = |change_changes_trie_config| execute_block::<_, _, u64, _, _>(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			Default::default(),
+			&DummyCodeExecutor {
+				change_changes_trie_config,
+				native_available: true,
+				native_succeeds: true,
+				fallback_succeeds: true,
+			},
+			TaskExecutor::new(),
+			&[],
+			root,
+			None,
+			Default::default(),
+			&wasm_code,
+			ExecutionStrategy::NativeWhenPossible,
+		);
+
+		let report = execute(false).unwrap();
+		assert_eq!(report.state_root, root);
+		assert_eq!(report.changes_trie_root, None);
+		assert!(report.storage_changes.main_storage_changes.is_empty());
+
+		let err = execute(true).err().unwrap();
+		assert!(format!("{:?}", err).starts_with("StateRootMismatch"));
+
+		// Rewriting a value unchanged keeps the root and is counted in the stats.
+		let executor = MockExecutor::new().with_method(
+			"Core_execute_block",
+			MockCall::returning(vec![]).with_write(b"value1".to_vec(), Some(vec![42])).with_read(b"value1".to_vec()),
+		);
+		let report = execute_block::<_, _, u64, _, _>(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			Default::default(),
+			&executor,
+			TaskExecutor::new(),
+			&[],
+			root,
+			None,
+			Default::default(),
+			&wasm_code,
+			ExecutionStrategy::NativeWhenPossible,
+		).unwrap();
+		assert_eq!(*report.stats.reads_modified.borrow(), 1);
+		assert_eq!(*report.stats.writes_overlay.borrow(), 1);
 	}
 
 	#[test]
-	fn execute_works() {
+	fn execute_works_with_native_else_wasm() {
 		let backend = trie_backend::tests::test_trie();
 		let mut overlayed_changes = Default::default();
 		let mut offchain_overlayed_changes = Default::default();
@@ -867,15 +2736,11 @@ mod tests {
 			TaskExecutor::new(),
 		);
 
-		assert_eq!(
-			state_machine.execute(ExecutionStrategy::NativeWhenPossible).unwrap(),
-			vec![66],
-		);
+		assert_eq!(state_machine.execute(ExecutionStrategy::NativeElseWasm).unwrap(), vec![66]);
 	}
 
-
 	#[test]
-	fn execute_works_with_native_else_wasm() {
+	fn execution_details_report_the_engine_used() {
 		let backend = trie_backend::tests::test_trie();
 		let mut overlayed_changes = Default::default();
 		let mut offchain_overlayed_changes = Default::default();
@@ -889,7 +2754,7 @@ mod tests {
 			&DummyCodeExecutor {
 				change_changes_trie_config: false,
 				native_available: true,
-				native_succeeds: true,
+				native_succeeds: false,
 				fallback_succeeds: true,
 			},
 			"test",
@@ -899,7 +2764,53 @@ mod tests {
 			TaskExecutor::new(),
 		);
 
-		assert_eq!(state_machine.execute(ExecutionStrategy::NativeElseWasm).unwrap(), vec![66]);
+		let details = state_machine.execute_with_details::<_, NeverNativeValue, fn() -> _>(
+			ExecutionStrategy::NativeElseWasm.get_manager(),
+			None,
+		);
+		assert_eq!(details.result.unwrap().into_encoded(), vec![66]);
+		assert!(!details.was_native);
+
+		let details = state_machine.execute_with_details::<_, NeverNativeValue, fn() -> _>(
+			ExecutionStrategy::NativeWhenPossible.get_manager(),
+			None,
+		);
+		assert!(details.result.is_err());
+		assert!(details.was_native);
+	}
+
+	#[test]
+	fn execution_details_report_the_overlay_stats_of_the_call() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlayed_changes = OverlayedChanges::default();
+		let mut offchain_overlayed_changes = Default::default();
+		let wasm_code = RuntimeCode::empty();
+		let executor = MockExecutor::new().with_method(
+			"test",
+			MockCall::returning(vec![1]).with_write(b"key".to_vec(), Some(vec![1])).with_read(b"key".to_vec()),
+		);
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlayed_changes,
+			&mut offchain_overlayed_changes,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&wasm_code,
+			TaskExecutor::new(),
+		);
+		for _ in 0..2 {
+			let details = state_machine.execute_with_details::<_, NeverNativeValue, fn() -> _>(
+				ExecutionStrategy::NativeWhenPossible.get_manager(),
+				None,
+			);
+			assert_eq!(details.result.unwrap().into_encoded(), vec![1]);
+			assert_eq!(*details.stats.reads_modified.borrow(), 1);
+			assert_eq!(*details.stats.writes_overlay.borrow(), 1);
+		}
 	}
 
 	#[test]
@@ -930,9 +2841,9 @@ mod tests {
 
 		assert!(
 			state_machine.execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
-				ExecutionManager::Both(|we, _ne| {
+				ExecutionManager::Both(|mismatch: ConsensusMismatch<_, _>| {
 					consensus_failed = true;
-					we
+					mismatch.wasm_result
 				}),
 				None,
 			).is_err()
@@ -940,6 +2851,92 @@ mod tests {
 		assert!(consensus_failed);
 	}
 
+	#[test]
+	fn consensus_mismatch_reports_both_executions() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = OffchainOverlayedChanges::default();
+		let runtime_code = RuntimeCode::empty();
+		let executor = MockExecutor::new()
+			.with_wasm_succeeds(false)
+			.with_method("test", MockCall::returning(vec![7]).with_write(b"key".to_vec(), Some(vec![1])));
+
+		let mut mismatch = None;
+		let result = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlay,
+			&mut offchain_overlay,
+			&executor,
+			"test",
+			&[1, 2],
+			Default::default(),
+			&runtime_code,
+			TaskExecutor::new(),
+		).execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
+			ExecutionManager::Both(|m: ConsensusMismatch<NeverNativeValue, String>| {
+				let result = Ok(NativeOrEncoded::Encoded(vec![0]));
+				mismatch = Some(m);
+				result
+			}),
+			None,
+		);
+		assert!(result.is_ok());
+
+		let mismatch = mismatch.unwrap();
+		assert_eq!(mismatch.method, "test");
+		assert_eq!(mismatch.call_data, vec![1, 2]);
+		assert!(mismatch.native_result.is_ok());
+		assert!(mismatch.wasm_result.is_err());
+		let changes = TransactionChanges {
+			top: vec![(b"key".to_vec(), Some(vec![1]))],
+			children: Vec::new(),
+		};
+		assert_eq!(mismatch.native_changes, changes);
+		assert_eq!(mismatch.wasm_changes, changes);
+		assert_eq!(overlay.storage(b"key"), Some(Some(&[1][..])));
+	}
+
+	#[test]
+	fn execution_policy_falls_back_in_order() {
+		let backend = trie_backend::tests::test_trie();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = OffchainOverlayedChanges::default();
+		let runtime_code = RuntimeCode::empty();
+		let executor = MockExecutor::new()
+			.with_wasm_succeeds(false)
+			.with_method("test", MockCall::returning(vec![7]).with_write(b"key".to_vec(), Some(vec![1])));
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlay,
+			&mut offchain_overlay,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&runtime_code,
+			TaskExecutor::new(),
+		);
+
+		let policy = MethodOverrides::new(ExecutionStrategy::AlwaysWasm)
+			.with_method("test", vec![ExecutionStep::Wasm, ExecutionStep::Native, ExecutionStep::Wasm]);
+		assert_eq!(state_machine.execute_with_policy(&policy).unwrap(), vec![7]);
+		assert!(state_machine.execute_with_policy(&ExecutionStrategy::AlwaysWasm).is_err());
+		assert_eq!(
+			format!("{:?}", state_machine.execute_with_policy(&Vec::<ExecutionStep>::new()).unwrap_err()),
+			format!("{:?}", ExecutionError::NoExecutionStep("test".into())),
+		);
+		drop(state_machine);
+
+		assert_eq!(executor.calls(), vec![
+			("test".to_string(), false),
+			("test".to_string(), true),
+			("test".to_string(), false),
+		]);
+		assert_eq!(overlay.storage(b"key"), Some(Some(&[1][..])));
+	}
+
 	#[test]
 	fn prove_execution_and_proof_check_works() {
 		let executor = DummyCodeExecutor {
@@ -979,6 +2976,100 @@ mod tests {
 		assert_eq!(remote_result, local_result);
 	}
 
+	#[test]
+	fn prove_execution_with_code_and_proof_check_works() {
+		let executor = DummyCodeExecutor {
+			change_changes_trie_config: false,
+			native_available: true,
+			native_succeeds: true,
+			fallback_succeeds: true,
+		};
+
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(std::iter::empty()).0;
+		let (remote_result, remote_proof) = prove_execution_with_code_on_trie_backend::<_, _, u64, _, _>(
+			&remote_backend,
+			&mut Default::default(),
+			&executor,
+			TaskExecutor::new(),
+			"test",
+			&[],
+		).unwrap();
+		let code = read_proof_check::<BlakeTwo256, _>(
+			remote_root,
+			remote_proof.clone(),
+			&[well_known_keys::CODE],
+		).unwrap();
+		assert_eq!(code.get(well_known_keys::CODE), Some(&Some(b"return 42".to_vec())));
+
+		let local_result = execution_proof_check_with_code::<BlakeTwo256, u64, _, _>(
+			remote_root,
+			remote_proof,
+			&mut Default::default(),
+			&executor,
+			TaskExecutor::new(),
+			"test",
+			&[],
+		).unwrap();
+		assert_eq!(remote_result, vec![66]);
+		assert_eq!(remote_result, local_result);
+
+		// A proof without the code can not be checked without a copy of the runtime.
+		let (_, proof_without_code) = prove_execution::<_, _, u64, _, _>(
+			remote_backend,
+			&mut Default::default(),
+			&executor,
+			TaskExecutor::new(),
+			"test",
+			&[],
+			&RuntimeCode::empty(),
+		).unwrap();
+		assert!(execution_proof_check_with_code::<BlakeTwo256, u64, _, _>(
+			remote_root,
+			proof_without_code,
+			&mut Default::default(),
+			&executor,
+			TaskExecutor::new(),
+			"test",
+			&[],
+		).is_err());
+	}
+
+	#[test]
+	fn clear_prefix_limited_in_ext_resumes() {
+		let initial: BTreeMap<_, _> = map![
+			b"aaa".to_vec() => b"0".to_vec(),
+			b"abb".to_vec() => b"1".to_vec(),
+			b"abc".to_vec() => b"2".to_vec(),
+			b"bbb".to_vec() => b"3".to_vec()
+		];
+		let mut state = InMemoryBackend::<BlakeTwo256>::from(initial);
+		let backend = state.as_trie_backend().unwrap();
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"aba".to_vec(), Some(b"1312".to_vec()));
+		overlay.set_storage(b"abb".to_vec(), None);
+		overlay.set_storage(b"abd".to_vec(), Some(b"69".to_vec()));
+
+		let mut offchain_overlay = Default::default();
+		let mut cache = StorageTransactionCache::default();
+		let mut ext = Ext::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			backend,
+			changes_trie::disabled_state::<_, u64>(),
+			None,
+		);
+		assert_eq!(ext.clear_prefix_limited(b"ab", 1, None), (1, Some(b"abc".to_vec())));
+		assert_eq!(ext.storage(b"aba"), None);
+		assert_eq!(ext.storage(b"abc"), Some(b"2".to_vec()));
+		assert_eq!(ext.clear_prefix_limited(b"ab", 2, Some(b"abc")), (2, None));
+		assert_eq!(ext.clear_prefix_limited(b"ab", 2, None), (0, None));
+		assert_eq!(ext.storage(b"aaa"), Some(b"0".to_vec()));
+		assert_eq!(ext.storage(b"bbb"), Some(b"3".to_vec()));
+	}
+
 	#[test]
 	fn clear_prefix_in_ext_works() {
 		let initial: BTreeMap<_, _> = map![
@@ -1069,6 +3160,35 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn kill_child_storage_limited_in_ext_resumes() {
+		let child_info = ChildInfo::new_default(b"sub1");
+		let child_info = &child_info;
+		let mut state = new_in_mem::<BlakeTwo256>();
+		let backend = state.as_trie_backend().unwrap();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = OffchainOverlayedChanges::default();
+		let mut cache = StorageTransactionCache::default();
+		let mut ext = Ext::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			backend,
+			changes_trie::disabled_state::<_, u64>(),
+			None,
+		);
+
+		ext.set_child_storage(child_info, b"a".to_vec(), b"0".to_vec());
+		ext.set_child_storage(child_info, b"b".to_vec(), b"1".to_vec());
+		ext.set_child_storage(child_info, b"c".to_vec(), b"2".to_vec());
+		assert_eq!(ext.kill_child_storage_limited(child_info, 2, None), (2, Some(b"c".to_vec())));
+		assert_eq!(ext.child_storage(child_info, b"a"), None);
+		assert_eq!(ext.child_storage(child_info, b"c"), Some(b"2".to_vec()));
+		assert_eq!(ext.kill_child_storage_limited(child_info, 2, Some(b"c")), (1, None));
+		assert_eq!(ext.child_storage(child_info, b"c"), None);
+		assert_eq!(ext.kill_child_storage_limited(child_info, 2, None), (0, None));
+	}
+
 	#[test]
 	fn append_storage_works() {
 		let reference_data = vec![
@@ -1231,6 +3351,165 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn proof_check_backend_is_reused() {
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(::std::iter::empty()).0;
+		let child_info = ChildInfo::new_default(b"sub1");
+		let proof = StorageProof::merge(vec![
+			prove_read(trie_backend::tests::test_trie(), &[b"value1", b"value2"]).unwrap(),
+			prove_child_read(trie_backend::tests::test_trie(), &child_info, &[b"value3"]).unwrap(),
+		]);
+
+		let check = ProofCheckBackend::<BlakeTwo256>::new(remote_root, proof).unwrap();
+		assert_eq!(check.check_read(b"value2").unwrap(), Some(vec![24]));
+		assert_eq!(check.check_read(b"value1").unwrap(), Some(vec![42]));
+		assert_eq!(check.check_child_read(&child_info, b"value3").unwrap(), Some(vec![142]));
+		assert!(check.check_read(&[0xff]).is_err());
+		assert!(ProofCheckBackend::<BlakeTwo256>::new(Default::default(), StorageProof::empty()).is_err());
+	}
+
+	#[test]
+	fn prove_read_chunked_can_be_cancelled_and_resumed() {
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(::std::iter::empty()).0;
+		let keys: &[&[u8]] = &[b"key", b"value1", b"value2"];
+		let mut calls = Vec::new();
+		let partial = prove_read_chunked_on_trie_backend(&remote_backend, keys, 2, |proven| {
+			calls.push(proven);
+			false
+		}).unwrap();
+		assert_eq!(calls, vec![2]);
+		assert!(!partial.complete);
+		assert_eq!(partial.proven_keys, vec![b"key".to_vec(), b"value1".to_vec()]);
+		assert!(read_proof_check::<BlakeTwo256, _>(
+			remote_root,
+			partial.proof.clone(),
+			&partial.proven_keys,
+		).is_ok());
+
+		let rest = prove_read_chunked_on_trie_backend(&remote_backend, &keys[2..], 2, |_| true).unwrap();
+		assert!(rest.complete);
+		assert_eq!(rest.proven_keys, vec![b"value2".to_vec()]);
+		let proof = StorageProof::merge(vec![partial.proof, rest.proof]);
+		let local_result = read_proof_check::<BlakeTwo256, _>(remote_root, proof, keys).unwrap();
+		assert_eq!(local_result.get(&b"value2"[..]), Some(&Some(vec![24])));
+	}
+
+	#[test]
+	fn prove_read_with_limit_stops_before_exceeding_limit() {
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(::std::iter::empty()).0;
+		// Proving `[130]` needs a branch node that is not on the path of `key`.
+		let keys: &[&[u8]] = &[b"key", &[130], b"value2"];
+		let proof_size = |proof: StorageProof| proof.iter_nodes().map(|node| node.len()).sum::<usize>();
+		let key_proof_size = proof_size(prove_read_on_trie_backend(&remote_backend, &keys[..1]).unwrap());
+
+		let limited = prove_read_with_limit_on_trie_backend(&remote_backend, keys, key_proof_size)
+			.unwrap();
+		assert_eq!(limited.proven_keys, vec![b"key".to_vec()]);
+		assert_eq!(limited.next_key, Some(vec![130]));
+		assert_eq!(proof_size(limited.proof.clone()), key_proof_size);
+		let local_result = read_proof_check::<BlakeTwo256, _>(remote_root, limited.proof, &keys[..1])
+			.unwrap();
+		assert_eq!(local_result.get(&b"key"[..]), Some(&Some(b"value".to_vec())));
+
+		let nothing = prove_read_with_limit_on_trie_backend(&remote_backend, keys, 0).unwrap();
+		assert!(nothing.proven_keys.is_empty() && nothing.proof.is_empty());
+		assert_eq!(nothing.next_key, Some(b"key".to_vec()));
+
+		let all = prove_read_with_limit_on_trie_backend(&remote_backend, keys, usize::max_value())
+			.unwrap();
+		assert_eq!(all.proven_keys.len(), 3);
+		assert_eq!(all.next_key, None);
+		let local_result = read_proof_check::<BlakeTwo256, _>(remote_root, all.proof, keys).unwrap();
+		assert_eq!(local_result.get(&b"value2"[..]), Some(&Some(vec![24])));
+	}
+
+	#[test]
+	fn prove_range_and_proof_check_works() {
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(::std::iter::empty()).0;
+		let proof = prove_range_on_trie_backend(&remote_backend, b"value", b"value2").unwrap();
+		assert_eq!(
+			read_range_proof_check::<BlakeTwo256>(remote_root, proof, b"value", b"value2").unwrap(),
+			vec![(b"value1".to_vec(), vec![42])],
+		);
+
+		let proof = prove_range_on_trie_backend(&remote_backend, &[128], &[130]).unwrap();
+		assert_eq!(
+			read_range_proof_check::<BlakeTwo256>(remote_root, proof.clone(), &[128], &[130]).unwrap(),
+			vec![(vec![128], vec![128]), (vec![129], vec![129])],
+		);
+		// The proof does not hold the branches of the keys after `[143]`.
+		assert!(read_range_proof_check::<BlakeTwo256>(remote_root, proof, &[128], &[200]).is_err());
+
+		let empty = prove_range(remote_backend, b"value", b"key").unwrap();
+		assert!(empty.is_empty());
+		assert!(read_range_proof_check::<BlakeTwo256>(remote_root, empty, b"value", b"key")
+			.unwrap()
+			.is_empty());
+	}
+
+	#[test]
+	fn prove_prefix_and_proof_check_works() {
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(::std::iter::empty()).0;
+		let proof = prove_prefix_on_trie_backend(&remote_backend, b"value").unwrap();
+		assert_eq!(
+			read_prefix_proof_check::<BlakeTwo256>(remote_root, proof, b"value").unwrap(),
+			vec![(b"value1".to_vec(), vec![42]), (b"value2".to_vec(), vec![24])],
+		);
+
+		// Proving the values does not prove that no other key has the prefix.
+		let proof = prove_read_on_trie_backend(&remote_backend, &[b"value1", b"value2"]).unwrap();
+		assert!(read_prefix_proof_check::<BlakeTwo256>(remote_root, proof, b"value").is_err());
+
+		let proof = prove_prefix(remote_backend, b"none").unwrap();
+		assert!(read_prefix_proof_check::<BlakeTwo256>(remote_root, proof, b"none")
+			.unwrap()
+			.is_empty());
+	}
+
+	#[test]
+	fn read_proof_check_iter_checks_keys_lazily() {
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(::std::iter::empty()).0;
+		let proof = prove_read(remote_backend, &[b"value2"]).unwrap();
+		let keys: &[&[u8]] = &[b"value2", b"value3", &[130], b"key"];
+
+		let mut checks = read_proof_check_iter::<BlakeTwo256, _>(remote_root, proof, keys).unwrap();
+		assert_eq!(checks.next().unwrap().unwrap(), (b"value2".to_vec(), Some(vec![24])));
+		assert_eq!(checks.next().unwrap().unwrap(), (b"value3".to_vec(), None));
+		assert!(checks.next().unwrap().is_err());
+		assert!(checks.next().is_some());
+		assert!(checks.next().is_none());
+	}
+
+	#[test]
+	fn prove_read_per_key_reports_failed_keys() {
+		let remote_backend = trie_backend::tests::test_trie();
+		let remote_root = remote_backend.storage_root(::std::iter::empty()).0;
+		// A backend only holding the nodes needed to read `key` fails on other branches.
+		let partial_proof = prove_read(remote_backend, &[b"key"]).unwrap();
+		let partial_backend = create_proof_check_backend::<BlakeTwo256>(remote_root, partial_proof)
+			.unwrap();
+
+		let keys: &[&[u8]] = &[b"key", &[130], b"non-existing-key"];
+		let read = prove_read_per_key_on_trie_backend(&partial_backend, keys);
+		assert_eq!(read.results[0], (b"key".to_vec(), KeyReadResult::Proven(b"value".to_vec())));
+		assert!(matches!(read.results[1].1, KeyReadResult::Failed(_)));
+		assert_eq!(read.results[2], (b"non-existing-key".to_vec(), KeyReadResult::Missing));
+
+		let local_result = read_proof_check::<BlakeTwo256, _>(
+			remote_root,
+			read.proof,
+			&[&b"key"[..], &b"non-existing-key"[..]],
+		).unwrap();
+		assert_eq!(local_result.get(&b"key"[..]), Some(&Some(b"value".to_vec())));
+		assert_eq!(local_result.get(&b"non-existing-key"[..]), Some(&None));
+	}
+
 	#[test]
 	fn prove_read_and_proof_check_works() {
 		let child_info = ChildInfo::new_default(b"sub1");
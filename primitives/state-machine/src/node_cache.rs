@@ -0,0 +1,256 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of trie nodes, shared between the backends of successive executions, and storage
+//! fetching the missing nodes remotely.
+
+use std::sync::Arc;
+use hash_db::{Hasher, Prefix};
+use linked_hash_map::LinkedHashMap;
+use parking_lot::Mutex;
+use sp_trie::{DBValue, PrefixedMemoryDB};
+use crate::trie_backend_essence::TrieBackendStorage;
+
+/// Cache of encoded trie nodes by hash.
+///
+/// Nodes are addressed by the hash of their content, so a cached node stays valid for any
+/// state it is part of.
+pub trait TrieNodeCache<H: Hasher>: Send + Sync {
+	/// Get the cached node with this hash.
+	fn get(&self, hash: &H::Out) -> Option<DBValue>;
+
+	/// Cache a node read from the storage.
+	fn insert(&self, hash: H::Out, node: DBValue);
+}
+
+/// `TrieNodeCache` keeping the most recently used nodes, up to a total size.
+///
+/// Clones share the cache.
+pub struct SharedTrieNodeCache<H: Hasher> {
+	inner: Arc<Mutex<LruNodes<H>>>,
+}
+
+struct LruNodes<H: Hasher> {
+	nodes: LinkedHashMap<H::Out, DBValue>,
+	used_size: usize,
+	limit: usize,
+}
+
+impl<H: Hasher> Clone for SharedTrieNodeCache<H> {
+	fn clone(&self) -> Self {
+		SharedTrieNodeCache { inner: self.inner.clone() }
+	}
+}
+
+impl<H: Hasher> SharedTrieNodeCache<H> {
+	/// Cache of at most `limit` bytes of nodes and hashes.
+	pub fn new(limit: usize) -> Self {
+		SharedTrieNodeCache {
+			inner: Arc::new(Mutex::new(LruNodes {
+				nodes: LinkedHashMap::new(),
+				used_size: 0,
+				limit,
+			})),
+		}
+	}
+
+	/// Size in bytes of the cached nodes and hashes.
+	pub fn used_size(&self) -> usize {
+		self.inner.lock().used_size
+	}
+}
+
+impl<H: Hasher> TrieNodeCache<H> for SharedTrieNodeCache<H> {
+	fn get(&self, hash: &H::Out) -> Option<DBValue> {
+		self.inner.lock().nodes.get_refresh(hash).cloned()
+	}
+
+	fn insert(&self, hash: H::Out, node: DBValue) {
+		let mut lru = self.inner.lock();
+		let lru = &mut *lru;
+		let hash_size = hash.as_ref().len();
+		lru.used_size += hash_size + node.len();
+		if let Some(previous) = lru.nodes.insert(hash, node) {
+			lru.used_size -= hash_size + previous.len();
+		}
+		while lru.used_size > lru.limit {
+			match lru.nodes.pop_front() {
+				Some((hash, node)) => lru.used_size -= hash.as_ref().len() + node.len(),
+				None => break,
+			}
+		}
+	}
+}
+
+/// Trie backend storage reading nodes through a `TrieNodeCache`.
+///
+/// Successive backends built on the same cache, for instance one per imported block, share
+/// the nodes read by each of them.
+pub struct CachingTrieStorage<S, H: Hasher, C = SharedTrieNodeCache<H>> {
+	storage: S,
+	cache: C,
+	_marker: std::marker::PhantomData<H>,
+}
+
+impl<S, H: Hasher, C> CachingTrieStorage<S, H, C> {
+	/// Read the nodes of `storage` through `cache`.
+	pub fn new(storage: S, cache: C) -> Self {
+		CachingTrieStorage {
+			storage,
+			cache,
+			_marker: Default::default(),
+		}
+	}
+
+	/// The cached storage.
+	pub fn storage(&self) -> &S {
+		&self.storage
+	}
+}
+
+impl<S, H, C> TrieBackendStorage<H> for CachingTrieStorage<S, H, C>
+	where
+		S: TrieBackendStorage<H>,
+		H: Hasher,
+		C: TrieNodeCache<H>,
+{
+	type Overlay = S::Overlay;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		if let Some(node) = self.cache.get(key) {
+			return Ok(Some(node));
+		}
+		let node = self.storage.get(key, prefix)?;
+		if let Some(node) = node.as_ref() {
+			self.cache.insert(key.clone(), node.clone());
+		}
+		Ok(node)
+	}
+}
+
+/// Trie backend storage fetching nodes through a callback, for instance from a remote
+/// archive node.
+///
+/// Fetched nodes are checked against their hash and kept in a `TrieNodeCache`, so a
+/// `StateMachine` can run over a historical state without a local database.
+pub struct RemoteTrieStorage<H: Hasher, F, C = SharedTrieNodeCache<H>> {
+	fetch: F,
+	cache: C,
+	_marker: std::marker::PhantomData<H>,
+}
+
+impl<H: Hasher, F, C> RemoteTrieStorage<H, F, C> {
+	/// Fetch the missing nodes with `fetch`, keeping them in `cache`.
+	pub fn new(fetch: F, cache: C) -> Self {
+		RemoteTrieStorage {
+			fetch,
+			cache,
+			_marker: Default::default(),
+		}
+	}
+}
+
+impl<H, F, C> TrieBackendStorage<H> for RemoteTrieStorage<H, F, C>
+	where
+		H: Hasher,
+		F: Fn(&H::Out, Prefix) -> Result<Option<DBValue>, String> + Send + Sync,
+		C: TrieNodeCache<H>,
+{
+	type Overlay = PrefixedMemoryDB<H>;
+
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		if let Some(node) = self.cache.get(key) {
+			return Ok(Some(node));
+		}
+		let node = (self.fetch)(key, prefix)?;
+		if let Some(node) = node.as_ref() {
+			if H::hash(node) != *key {
+				return Err(format!("Fetched trie node does not match its hash {:?}", key));
+			}
+			self.cache.insert(key.clone(), node.clone());
+		}
+		Ok(node)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::{Backend, trie_backend::{TrieBackend, tests::test_trie}};
+
+	#[test]
+	fn nodes_are_shared_between_backends() {
+		let trie = test_trie();
+		let root = *trie.root();
+		let cache = SharedTrieNodeCache::<BlakeTwo256>::new(1024 * 1024);
+		let storage = CachingTrieStorage::<_, BlakeTwo256, _>::new(trie.into_storage(), cache.clone());
+		let backend = TrieBackend::new(storage, root);
+		assert_eq!(backend.storage(b"value1").unwrap(), Some(vec![42]));
+		assert!(cache.used_size() > 0);
+
+		// Nodes read by the first backend are served from the cache.
+		let empty = PrefixedMemoryDB::<BlakeTwo256>::default();
+		let backend = TrieBackend::new(CachingTrieStorage::<_, BlakeTwo256, _>::new(empty, cache), root);
+		assert_eq!(backend.storage(b"value1").unwrap(), Some(vec![42]));
+		assert!(backend.storage(&[130]).is_err());
+	}
+
+	#[test]
+	fn least_recently_used_nodes_are_evicted() {
+		let cache = SharedTrieNodeCache::<BlakeTwo256>::new(100);
+		let hashes: Vec<_> = (0u8..3).map(|i| BlakeTwo256::hash(&[i])).collect();
+		cache.insert(hashes[0], vec![0; 10]);
+		cache.insert(hashes[1], vec![1; 10]);
+		assert!(cache.get(&hashes[0]).is_some());
+		cache.insert(hashes[2], vec![2; 10]);
+		assert_eq!(cache.used_size(), 84);
+		assert_eq!(cache.get(&hashes[0]), Some(vec![0; 10]));
+		assert_eq!(cache.get(&hashes[1]), None);
+		assert_eq!(cache.get(&hashes[2]), Some(vec![2; 10]));
+	}
+
+	#[test]
+	fn remote_nodes_are_fetched_once() {
+		let trie = test_trie();
+		let root = *trie.root();
+		let remote = trie.into_storage();
+		let fetched = Arc::new(AtomicUsize::new(0));
+		let counter = fetched.clone();
+		let fetch = move |key: &<BlakeTwo256 as Hasher>::Out, prefix: Prefix| {
+			counter.fetch_add(1, Ordering::SeqCst);
+			TrieBackendStorage::<BlakeTwo256>::get(&remote, key, prefix)
+		};
+		let storage = RemoteTrieStorage::<BlakeTwo256, _>::new(fetch, SharedTrieNodeCache::new(1024 * 1024));
+		let backend = TrieBackend::new(storage, root);
+		assert_eq!(backend.storage(b"value1").unwrap(), Some(vec![42]));
+		let first_read = fetched.load(Ordering::SeqCst);
+		assert!(first_read > 0);
+		assert_eq!(backend.storage(b"value1").unwrap(), Some(vec![42]));
+		assert_eq!(fetched.load(Ordering::SeqCst), first_read);
+	}
+
+	#[test]
+	fn remote_nodes_not_matching_their_hash_are_rejected() {
+		let root = *test_trie().root();
+		let fetch = |_: &<BlakeTwo256 as Hasher>::Out, _: Prefix| Ok::<_, String>(Some(vec![0u8; 4]));
+		let storage = RemoteTrieStorage::<BlakeTwo256, _>::new(fetch, SharedTrieNodeCache::new(1024));
+		let backend = TrieBackend::new(storage, root);
+		assert!(backend.storage(b"value1").is_err());
+	}
+}
@@ -17,10 +17,11 @@
 
 //! Houses the code that implements the transactional overlay storage.
 
-use super::{StorageKey, StorageValue};
+use super::{StorageKey, StorageValue, ChangesSize};
 
-use itertools::Itertools;
-use std::collections::{HashSet, BTreeMap, BTreeSet};
+use itertools::{Itertools, EitherOrBoth};
+use std::collections::{HashSet, BTreeMap, BTreeSet, btree_map::Entry};
+use std::sync::Arc;
 use smallvec::SmallVec;
 use log::warn;
 
@@ -31,6 +32,10 @@ const PROOF_OVERLAY_NON_EMPTY: &str = "\
 type DirtyKeysSets = SmallVec<[HashSet<StorageKey>; 5]>;
 type Transactions = SmallVec<[InnerValue; 5]>;
 
+/// Read-only snapshot of the changes of an [`OverlayedChangeSet`], created by
+/// [`OverlayedChangeSet::freeze`]. Each value only holds a single, committed version.
+pub type FrozenChanges = Arc<BTreeMap<StorageKey, OverlayedValue>>;
+
 /// Error returned when trying to commit or rollback while no transaction is open or
 /// when the runtime is trying to close a transaction started by the client.
 #[derive(Debug)]
@@ -56,6 +61,15 @@ pub enum ExecutionMode {
 	Runtime,
 }
 
+/// Origin of a change, recorded to find out which part of a block last wrote a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeTag {
+	/// Change made by the extrinsic with this index.
+	Extrinsic(u32),
+	/// Change made by a named part of the block, such as `on_initialize` or the inherents.
+	Named(String),
+}
+
 #[derive(Debug, Default, Clone)]
 #[cfg_attr(test, derive(PartialEq))]
 struct InnerValue {
@@ -64,6 +78,8 @@ struct InnerValue {
 	/// The set of extrinsic indices where the values has been changed.
 	/// Is filled only if runtime has announced changes trie support.
 	extrinsics: BTreeSet<u32>,
+	/// Tag of the last change of the value, if it was tagged.
+	tag: Option<ChangeTag>,
 }
 
 /// An overlay that contains all versions of a value for a specific key.
@@ -80,6 +96,9 @@ pub struct OverlayedValue {
 pub struct OverlayedChangeSet {
 	/// Stores the changes that this overlay constitutes.
 	changes: BTreeMap<StorageKey, OverlayedValue>,
+	/// Changes this overlay was thawed from, shadowed by `changes`. A frozen value is copied
+	/// into `changes` when it is first written.
+	frozen: Option<FrozenChanges>,
 	/// Stores which keys are dirty per transaction. Needed in order to determine which
 	/// values to merge into the parent transaction on commit. The length of this vector
 	/// therefore determines how many nested transactions are currently open (depth).
@@ -90,6 +109,13 @@ pub struct OverlayedChangeSet {
 	num_client_transactions: usize,
 	/// Determines whether the node is using the overlay from the client or the runtime.
 	execution_mode: ExecutionMode,
+	/// Tag of the changes being made.
+	tag: Option<ChangeTag>,
+	/// Size of the changes when it was last settled, not counting `unsized_keys`. `None` until
+	/// the size is settled for the first time.
+	size: Option<ChangesSize>,
+	/// Keys written since the size was last settled.
+	unsized_keys: HashSet<StorageKey>,
 }
 
 impl Default for ExecutionMode {
@@ -109,6 +135,23 @@ impl OverlayedValue {
 		self.transactions.iter().flat_map(|t| t.extrinsics.iter()).unique()
 	}
 
+	/// Tag of the last change of the value as seen by the current transaction, `None` if it
+	/// was not tagged.
+	pub fn tag(&self) -> Option<&ChangeTag> {
+		self.transactions.last().expect(PROOF_OVERLAY_NON_EMPTY).tag.as_ref()
+	}
+
+	/// Copy of the value with all its versions merged into a single committed one.
+	fn collapsed(&self) -> Self {
+		let mut transactions = Transactions::new();
+		transactions.push(InnerValue {
+			value: self.value().cloned(),
+			extrinsics: self.extrinsics().cloned().collect(),
+			tag: self.tag().cloned(),
+		});
+		OverlayedValue { transactions }
+	}
+
 	/// Mutable reference to the most recent version.
 	fn value_mut(&mut self) -> &mut Option<StorageValue> {
 		&mut self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY).value
@@ -124,6 +167,11 @@ impl OverlayedValue {
 		&mut self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY).extrinsics
 	}
 
+	/// Mutable reference to the tag of the most recent version.
+	fn tag_mut(&mut self) -> &mut Option<ChangeTag> {
+		&mut self.transactions.last_mut().expect(PROOF_OVERLAY_NON_EMPTY).tag
+	}
+
 	/// Writes a new version of a value.
 	///
 	/// This makes sure that the old version is not overwritten and can be properly
@@ -133,6 +181,7 @@ impl OverlayedValue {
 		value: Option<StorageValue>,
 		first_write_in_tx: bool,
 		at_extrinsic: Option<u32>,
+		tag: Option<ChangeTag>,
 	) {
 		if first_write_in_tx || self.transactions.is_empty() {
 			self.transactions.push(InnerValue {
@@ -146,6 +195,7 @@ impl OverlayedValue {
 		if let Some(extrinsic) = at_extrinsic {
 			self.transaction_extrinsics_mut().insert(extrinsic);
 		}
+		*self.tag_mut() = tag;
 	}
 }
 
@@ -157,6 +207,25 @@ fn insert_dirty(set: &mut DirtyKeysSets, key: StorageKey) -> bool {
 	set.last_mut().map(|dk| dk.insert(key)).unwrap_or_default()
 }
 
+/// Mutable reference to the value of `key` in `changes`, copied from `frozen` if it was not
+/// written yet.
+fn entry<'a>(
+	changes: &'a mut BTreeMap<StorageKey, OverlayedValue>,
+	frozen: &Option<FrozenChanges>,
+	key: StorageKey,
+) -> &'a mut OverlayedValue {
+	match changes.entry(key) {
+		Entry::Occupied(entry) => entry.into_mut(),
+		Entry::Vacant(entry) => {
+			let value = frozen.as_ref()
+				.and_then(|frozen| frozen.get(entry.key()))
+				.cloned()
+				.unwrap_or_default();
+			entry.insert(value)
+		},
+	}
+}
+
 impl OverlayedChangeSet {
 	/// Create a new changeset at the same transaction state but without any contents.
 	///
@@ -168,18 +237,82 @@ impl OverlayedChangeSet {
 			dirty_keys: repeat(HashSet::new()).take(self.transaction_depth()).collect(),
 			num_client_transactions: self.num_client_transactions,
 			execution_mode: self.execution_mode,
+			tag: self.tag.clone(),
+			.. Default::default()
+		}
+	}
+
+	/// Take a read-only snapshot of the changes as seen by the current transaction.
+	///
+	/// Changes of open transactions are seen as committed by the snapshot. This copies every
+	/// change, unless the changeset was thawed and not modified since.
+	pub fn freeze(&self) -> FrozenChanges {
+		match &self.frozen {
+			Some(frozen) if self.changes.is_empty() => frozen.clone(),
+			_ => Arc::new(self.changes().map(|(key, value)| (key.clone(), value.collapsed())).collect()),
+		}
+	}
+
+	/// Create a changeset without open transaction on top of a snapshot.
+	///
+	/// The snapshot is shared, a value is only copied when the new changeset writes it.
+	pub fn thaw(frozen: &FrozenChanges) -> Self {
+		Self {
+			frozen: Some(frozen.clone()),
 			.. Default::default()
 		}
 	}
 
+	/// Size of the changes as seen by the current transaction.
+	///
+	/// Only the keys written since the last `settle_size` are measured, all changes are
+	/// measured if it was never called.
+	pub fn size(&self) -> ChangesSize {
+		match self.size {
+			Some(mut size) => {
+				for key in self.unsized_keys.iter() {
+					if let Some(value) = self.get(key) {
+						size.add(ChangesSize::of_change(key, value));
+					}
+				}
+				size
+			},
+			None => ChangesSize::of(self.changes()),
+		}
+	}
+
+	/// Record the current size, further calls to `size` only measure the keys written after.
+	pub fn settle_size(&mut self) {
+		self.size = Some(self.size());
+		self.unsized_keys.clear();
+	}
+
+	/// Stop counting `key` in the settled size before it is written.
+	fn unsize(&mut self, key: &[u8]) {
+		if self.size.is_none() || self.unsized_keys.contains(key) {
+			return;
+		}
+		let written = self.get(key).map(|value| ChangesSize::of_change(key, value));
+		if let (Some(size), Some(written)) = (self.size.as_mut(), written) {
+			size.sub(written);
+		}
+		self.unsized_keys.insert(key.to_vec());
+	}
+
 	/// True if no changes at all are contained in the change set.
 	pub fn is_empty(&self) -> bool {
-		self.changes.is_empty()
+		self.changes.is_empty() && self.frozen.as_ref().map_or(true, |frozen| frozen.is_empty())
 	}
 
 	/// Get an optional reference to the value stored for the specified key.
 	pub fn get(&self, key: &[u8]) -> Option<&OverlayedValue> {
 		self.changes.get(key)
+			.or_else(|| self.frozen.as_ref().and_then(|frozen| frozen.get(key)))
+	}
+
+	/// Tag the following changes with `tag`, or stop tagging them if `None`.
+	pub fn set_tag(&mut self, tag: Option<ChangeTag>) {
+		self.tag = tag;
 	}
 
 	/// Set a new value for the specified key.
@@ -191,8 +324,9 @@ impl OverlayedChangeSet {
 		value: Option<StorageValue>,
 		at_extrinsic: Option<u32>,
 	) {
-		let overlayed = self.changes.entry(key.clone()).or_default();
-		overlayed.set(value, insert_dirty(&mut self.dirty_keys, key), at_extrinsic);
+		self.unsize(&key);
+		let overlayed = entry(&mut self.changes, &self.frozen, key.clone());
+		overlayed.set(value, insert_dirty(&mut self.dirty_keys, key), at_extrinsic, self.tag.clone());
 	}
 
 	/// Get a mutable reference for a value.
@@ -205,7 +339,8 @@ impl OverlayedChangeSet {
 		init: impl Fn() -> StorageValue,
 		at_extrinsic: Option<u32>,
 	) -> &mut Option<StorageValue> {
-		let overlayed = self.changes.entry(key.clone()).or_default();
+		self.unsize(&key);
+		let overlayed = entry(&mut self.changes, &self.frozen, key.clone());
 		let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key);
 		let clone_into_new_tx = if let Some(tx) = overlayed.transactions.last() {
 			if first_write_in_tx {
@@ -218,7 +353,9 @@ impl OverlayedChangeSet {
 		};
 
 		if let Some(cloned) = clone_into_new_tx {
-			overlayed.set(cloned, first_write_in_tx, at_extrinsic);
+			overlayed.set(cloned, first_write_in_tx, at_extrinsic, self.tag.clone());
+		} else {
+			*overlayed.tag_mut() = self.tag.clone();
 		}
 		overlayed.value_mut()
 	}
@@ -231,21 +368,106 @@ impl OverlayedChangeSet {
 		predicate: impl Fn(&[u8], &OverlayedValue) -> bool,
 		at_extrinsic: Option<u32>,
 	) {
-		for (key, val) in self.changes.iter_mut().filter(|(k, v)| predicate(k, v)) {
-			val.set(None, insert_dirty(&mut self.dirty_keys, key.to_owned()), at_extrinsic);
+		let keys: Vec<StorageKey> = self.changes()
+			.filter(|(key, value)| predicate(key, value))
+			.map(|(key, _)| key.clone())
+			.collect();
+		for key in keys {
+			self.unsize(&key);
+			let first_write_in_tx = insert_dirty(&mut self.dirty_keys, key.clone());
+			entry(&mut self.changes, &self.frozen, key)
+				.set(None, first_write_in_tx, at_extrinsic, self.tag.clone());
 		}
 	}
 
+	/// Remove the changes matched by the predicate and return them.
+	///
+	/// Panics:
+	/// Panics if there are open transactions: `transaction_depth() > 0`
+	pub fn remove_where(
+		&mut self,
+		mut predicate: impl FnMut(&[u8], &OverlayedValue) -> bool,
+	) -> Vec<(StorageKey, OverlayedValue)> {
+		assert!(self.transaction_depth() == 0, "Remove is not allowed with open transactions.");
+		if let Some(frozen) = self.frozen.take() {
+			for (key, value) in frozen.iter() {
+				if !self.changes.contains_key(key) {
+					self.changes.insert(key.clone(), value.clone());
+				}
+			}
+		}
+		let keys: Vec<StorageKey> = self.changes.iter()
+			.filter(|(key, value)| predicate(key, value))
+			.map(|(key, _)| key.clone())
+			.collect();
+		let mut removed = Vec::with_capacity(keys.len());
+		for key in keys {
+			self.unsize(&key);
+			if let Some(value) = self.changes.remove(&key) {
+				removed.push((key, value));
+			}
+		}
+		removed
+	}
+
 	/// Get a list of all changes as seen by current transaction.
 	pub fn changes(&self) -> impl Iterator<Item=(&StorageKey, &OverlayedValue)> {
 		self.changes.iter()
+			.merge_join_by(
+				self.frozen.iter().flat_map(|frozen| frozen.iter()),
+				|(key, _), (frozen_key, _)| key.cmp(frozen_key),
+			)
+			.map(|change| match change {
+				EitherOrBoth::Left(change) | EitherOrBoth::Both(change, _) | EitherOrBoth::Right(change) =>
+					change,
+			})
+	}
+
+	/// Get a list of all changes as they would be seen if the `depth` innermost transactions
+	/// were rolled back. Keys only written by these transactions are omitted.
+	///
+	/// Returns an error if less than `depth` transactions are open.
+	pub fn changes_at_depth(&self, depth: usize)
+		-> Result<impl Iterator<Item=(&StorageKey, Option<&StorageValue>)>, NoOpenTransaction>
+	{
+		let kept = self.transaction_depth().checked_sub(depth).ok_or(NoOpenTransaction)?;
+		let dropped = &self.dirty_keys[kept..];
+		Ok(self.changes().filter_map(move |(key, value)| {
+			let dropped_layers = dropped.iter().filter(|keys| keys.contains(key)).count();
+			let layers = value.transactions.len();
+			if dropped_layers < layers {
+				Some((key, value.transactions[layers - dropped_layers - 1].value.as_ref()))
+			} else {
+				None
+			}
+		}))
+	}
+
+	/// Get the changes made by the innermost open transaction, with their current value.
+	///
+	/// Returns an error if no transaction is open.
+	pub fn transaction_changes(&self)
+		-> Result<impl Iterator<Item=(&StorageKey, Option<&StorageValue>)>, NoOpenTransaction>
+	{
+		let dirty = self.dirty_keys.last().ok_or(NoOpenTransaction)?;
+		Ok(self.changes.iter()
+			.filter(move |(key, _)| dirty.contains(*key))
+			.map(|(key, value)| (key, value.value())))
 	}
 
 	/// Get the change that is next to the supplied key.
 	pub fn next_change(&self, key: &[u8]) -> Option<(&[u8], &OverlayedValue)> {
 		use std::ops::Bound;
 		let range = (Bound::Excluded(key), Bound::Unbounded);
-		self.changes.range::<[u8], _>(range).next().map(|(k, v)| (&k[..], v))
+		let next = self.changes.range::<[u8], _>(range).next();
+		let next_frozen = self.frozen.as_ref()
+			.and_then(|frozen| frozen.range::<[u8], _>(range).next());
+		let next = match (next, next_frozen) {
+			(Some(next), Some(next_frozen)) if next_frozen.0 < next.0 => Some(next_frozen),
+			(Some(next), _) => Some(next),
+			(None, next_frozen) => next_frozen,
+		};
+		next.map(|(k, v)| (&k[..], v))
 	}
 
 	/// Consume this changeset and return all committed changes.
@@ -254,7 +476,15 @@ impl OverlayedChangeSet {
 	/// Panics if there are open transactions: `transaction_depth() > 0`
 	pub fn drain_commited(self) -> impl Iterator<Item=(StorageKey, Option<StorageValue>)> {
 		assert!(self.transaction_depth() == 0, "Drain is not allowed with open transactions.");
-		self.changes.into_iter().map(|(k, mut v)| (k, v.pop_transaction().value))
+		let changes = self.changes;
+		let frozen: Vec<_> = self.frozen.iter()
+			.flat_map(|frozen| frozen.iter())
+			.filter(|(k, _)| !changes.contains_key(*k))
+			.map(|(k, v)| (k.clone(), v.value().cloned()))
+			.collect();
+		changes.into_iter()
+			.map(|(k, mut v)| (k, v.pop_transaction().value))
+			.merge_by(frozen, |(k, _), (frozen_k, _)| k < frozen_k)
 	}
 
 	/// Returns the current nesting depth of the transaction stack.
@@ -335,6 +565,7 @@ impl OverlayedChangeSet {
 		}
 
 		for key in self.dirty_keys.pop().ok_or(NoOpenTransaction)? {
+			self.unsize(&key);
 			let overlayed = self.changes.get_mut(&key).expect("\
 				A write to an OverlayedValue is recorded in the dirty key set. Before an
 				OverlayedValue is removed, its containing dirty set is removed. This
@@ -366,6 +597,7 @@ impl OverlayedChangeSet {
 					let dropped_tx = overlayed.pop_transaction();
 					*overlayed.value_mut() = dropped_tx.value;
 					overlayed.transaction_extrinsics_mut().extend(dropped_tx.extrinsics);
+					*overlayed.tag_mut() = dropped_tx.tag;
 				}
 			}
 		}
@@ -409,6 +641,95 @@ mod test {
 		assert_eq!(is, expected);
 	}
 
+	#[test]
+	fn changes_at_depth_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		changeset.start_transaction();
+		changeset.set(b"key0".to_vec(), Some(b"val0-1".to_vec()), None);
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), None, None);
+		changeset.set(b"key2".to_vec(), Some(b"val2".to_vec()), None);
+
+		let at_depth = |depth| changeset.changes_at_depth(depth).unwrap()
+			.map(|(k, v)| (k.as_ref(), v.map(AsRef::as_ref)))
+			.collect::<Drained>();
+		assert_eq!(at_depth(0), vec![
+			(&b"key0"[..], Some(&b"val0-1"[..])),
+			(&b"key1"[..], None),
+			(&b"key2"[..], Some(&b"val2"[..])),
+		]);
+		assert_eq!(at_depth(1), vec![
+			(&b"key0"[..], Some(&b"val0-1"[..])),
+			(&b"key1"[..], Some(&b"val1"[..])),
+		]);
+		assert_eq!(at_depth(2), vec![
+			(&b"key0"[..], Some(&b"val0"[..])),
+		]);
+		assert!(changeset.changes_at_depth(3).is_err());
+	}
+
+	#[test]
+	fn thawed_changeset_copies_values_on_write() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), Some(1));
+		changeset.set(b"key2".to_vec(), Some(b"val2".to_vec()), Some(2));
+		changeset.start_transaction();
+		changeset.set(b"key2".to_vec(), Some(b"val2-1".to_vec()), Some(3));
+		let frozen = changeset.freeze();
+
+		let mut thawed = OverlayedChangeSet::thaw(&frozen);
+		assert_eq!(thawed.transaction_depth(), 0);
+		assert!(Arc::ptr_eq(&thawed.freeze(), &frozen));
+		thawed.start_transaction();
+		thawed.set(b"key1".to_vec(), Some(b"val1".to_vec()), Some(4));
+		thawed.set(b"key2".to_vec(), None, Some(5));
+		assert_eq!(thawed.next_change(b"key0").unwrap().0, b"key1");
+		assert_eq!(thawed.next_change(b"key1").unwrap().0, b"key2");
+		assert_changes(&thawed, &vec![
+			(b"key0", (Some(b"val0"), vec![1])),
+			(b"key1", (Some(b"val1"), vec![4])),
+			(b"key2", (None, vec![2, 3, 5])),
+		]);
+
+		thawed.rollback_transaction().unwrap();
+		assert_changes(&thawed, &vec![
+			(b"key0", (Some(b"val0"), vec![1])),
+			(b"key2", (Some(b"val2-1"), vec![2, 3])),
+		]);
+		thawed.set(b"key3".to_vec(), Some(b"val3".to_vec()), None);
+		assert_drained(thawed, vec![
+			(b"key0", Some(b"val0")),
+			(b"key2", Some(b"val2-1")),
+			(b"key3", Some(b"val3")),
+		]);
+		assert_eq!(frozen.len(), 2);
+		assert_eq!(frozen.get(&b"key2"[..]).unwrap().value(), Some(&b"val2-1".to_vec()));
+	}
+
+	#[test]
+	fn settled_size_follows_changes() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(b"key0".to_vec(), Some(b"val0".to_vec()), None);
+		changeset.settle_size();
+		changeset.start_transaction();
+		changeset.set(b"key1".to_vec(), Some(b"val1".to_vec()), None);
+		changeset.modify(b"key0".to_vec(), Default::default, None)
+			.as_mut()
+			.unwrap()
+			.extend_from_slice(b"-1");
+		assert_eq!(changeset.size(), ChangesSize { keys: 2, bytes: 18 });
+		changeset.settle_size();
+		assert_eq!(changeset.size(), ChangesSize::of(changeset.changes()));
+
+		changeset.rollback_transaction().unwrap();
+		assert_eq!(changeset.size(), ChangesSize { keys: 1, bytes: 8 });
+		changeset.settle_size();
+		changeset.clear_where(|_, _| true, None);
+		assert_eq!(changeset.size(), ChangesSize { keys: 1, bytes: 4 });
+	}
+
 	#[test]
 	fn no_transaction_works() {
 		let mut changeset = OverlayedChangeSet::default();
@@ -27,15 +27,18 @@ use crate::{
 	},
 	stats::StateMachineStats,
 };
-use self::changeset::OverlayedChangeSet;
+use self::changeset::{OverlayedChangeSet, FrozenChanges};
 
-use std::collections::HashMap;
-use codec::{Decode, Encode};
+use std::{collections::HashMap, fmt, sync::Arc};
+use codec::{Decode, Encode, Input, Output};
 use sp_core::storage::{well_known_keys::EXTRINSIC_INDEX, ChildInfo};
-use sp_core::offchain::storage::OffchainOverlayedChanges;
-use hash_db::Hasher;
+use sp_core::offchain::storage::{OffchainOverlayedChange, OffchainOverlayedChanges};
+use sp_trie::{DBValue, MemoryDB, PrefixedMemoryDB};
+use hash_db::{Hasher, HashDB};
 
-pub use self::changeset::{OverlayedValue, NoOpenTransaction, AlreadyInRuntime, NotInRuntime};
+pub use self::changeset::{
+	OverlayedValue, NoOpenTransaction, AlreadyInRuntime, NotInRuntime, ChangeTag,
+};
 
 /// Storage key.
 pub type StorageKey = Vec<u8>;
@@ -49,6 +52,17 @@ pub type StorageCollection = Vec<(StorageKey, Option<StorageValue>)>;
 /// In memory arrays of storage values for multiple child tries.
 pub type ChildStorageCollection = Vec<(StorageKey, StorageCollection)>;
 
+/// Storage changes made by a transaction of the overlay, see
+/// `OverlayedChanges::transaction_changes`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionChanges {
+	/// Changes of the top trie, ordered by key.
+	pub top: StorageCollection,
+	/// Changes of the child tries, ordered by storage key then key. Child tries left unchanged
+	/// are omitted.
+	pub children: ChildStorageCollection,
+}
+
 /// The set of changes that are overlaid onto the backend.
 ///
 /// It allows changes to be modified using nestable transactions.
@@ -62,6 +76,68 @@ pub struct OverlayedChanges {
 	collect_extrinsics: bool,
 	/// Collect statistic on this execution.
 	stats: StateMachineStats,
+	/// Callback to notify when the changes grow too big.
+	size_watcher: Option<SizeWatcher>,
+}
+
+/// Number of changed keys and their size in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChangesSize {
+	/// Number of changed keys, deleted keys included.
+	pub keys: usize,
+	/// Size of the changed keys and of their new values.
+	pub bytes: usize,
+}
+
+impl ChangesSize {
+	fn of<'a>(changes: impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)>) -> Self {
+		changes.fold(ChangesSize::default(), |mut size, (key, value)| {
+			size.add(ChangesSize::of_change(key, value));
+			size
+		})
+	}
+
+	fn of_change(key: &[u8], value: &OverlayedValue) -> Self {
+		ChangesSize {
+			keys: 1,
+			bytes: key.len() + value.value().map_or(0, |v| v.len()),
+		}
+	}
+
+	fn add(&mut self, other: ChangesSize) {
+		self.keys += other.keys;
+		self.bytes += other.bytes;
+	}
+
+	fn sub(&mut self, other: ChangesSize) {
+		self.keys -= other.keys;
+		self.bytes -= other.bytes;
+	}
+
+	fn exceeds(&self, threshold: &ChangesSize) -> bool {
+		self.keys > threshold.keys || self.bytes > threshold.bytes
+	}
+}
+
+#[derive(Clone)]
+struct SizeWatcher {
+	threshold: ChangesSize,
+	callback: Arc<dyn Fn(ChangesSize) + Send + Sync>,
+}
+
+impl fmt::Debug for SizeWatcher {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("SizeWatcher").field("threshold", &self.threshold).finish()
+	}
+}
+
+/// A read-only snapshot of [`OverlayedChanges`], created by [`OverlayedChanges::freeze`].
+///
+/// Cloning is cheap and the snapshot can be shared across threads.
+#[derive(Debug, Clone)]
+pub struct FrozenOverlay {
+	top: FrozenChanges,
+	children: Arc<HashMap<StorageKey, (FrozenChanges, ChildInfo)>>,
 }
 
 /// A storage changes structure that can be generated by the data collected in [`OverlayedChanges`].
@@ -111,6 +187,148 @@ impl<Transaction, H: Hasher, N: BlockNumber> StorageChanges<Transaction, H, N> {
 	}
 }
 
+/// SCALE encoding of the changes, to apply them in another process than the one that executed
+/// the block.
+impl<Transaction, H, N> Encode for StorageChanges<Transaction, H, N> where
+	Transaction: TransactionEncoding,
+	H: Hasher,
+	H::Out: Encode,
+	N: BlockNumber,
+{
+	fn encode_to<T: Output>(&self, dest: &mut T) {
+		self.main_storage_changes.encode_to(dest);
+		self.child_storage_changes.encode_to(dest);
+		let offchain_storage_changes = match &self.offchain_storage_changes {
+			OffchainOverlayedChanges::Disabled => None,
+			OffchainOverlayedChanges::Enabled(changes) => {
+				let mut changes: Vec<_> = changes.iter()
+					.map(|(key, change)| (key, match change {
+						OffchainOverlayedChange::SetValue(value) => Some(value),
+						OffchainOverlayedChange::Remove => None,
+					}))
+					.collect();
+				changes.sort();
+				Some(changes)
+			},
+		};
+		offchain_storage_changes.encode_to(dest);
+		self.transaction.encode_transaction_to(dest);
+		self.transaction_storage_root.encode_to(dest);
+		match &self.changes_trie_transaction {
+			Some((transaction, cache_action)) => {
+				1u8.encode_to(dest);
+				transaction.encode_transaction_to(dest);
+				cache_action.encode_to(dest);
+			},
+			None => 0u8.encode_to(dest),
+		}
+	}
+}
+
+impl<Transaction, H, N> Decode for StorageChanges<Transaction, H, N> where
+	Transaction: TransactionEncoding,
+	H: Hasher,
+	H::Out: Decode,
+	N: BlockNumber,
+{
+	fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+		let main_storage_changes = Decode::decode(input)?;
+		let child_storage_changes = Decode::decode(input)?;
+		let offchain_storage_changes = match <Option<Vec<((Vec<u8>, Vec<u8>), Option<Vec<u8>>)>>>::decode(input)? {
+			Some(changes) => OffchainOverlayedChanges::Enabled(changes.into_iter()
+				.map(|(key, value)| (key, match value {
+					Some(value) => OffchainOverlayedChange::SetValue(value),
+					None => OffchainOverlayedChange::Remove,
+				}))
+				.collect()
+			),
+			None => OffchainOverlayedChanges::Disabled,
+		};
+		let transaction = Transaction::decode_transaction(input)?;
+		let transaction_storage_root = Decode::decode(input)?;
+		let changes_trie_transaction = match u8::decode(input)? {
+			0 => None,
+			1 => Some((MemoryDB::<H>::decode_transaction(input)?, Decode::decode(input)?)),
+			_ => return Err("Invalid changes trie transaction".into()),
+		};
+		Ok(StorageChanges {
+			main_storage_changes,
+			child_storage_changes,
+			offchain_storage_changes,
+			transaction,
+			transaction_storage_root,
+			changes_trie_transaction,
+		})
+	}
+}
+
+/// Backend transaction that can be SCALE encoded as part of `StorageChanges`.
+pub trait TransactionEncoding: Sized {
+	/// Encode the transaction into `dest`.
+	fn encode_transaction_to<T: Output>(&self, dest: &mut T);
+
+	/// Decode a transaction encoded by `encode_transaction_to`.
+	fn decode_transaction<I: Input>(input: &mut I) -> Result<Self, codec::Error>;
+}
+
+impl<H: Hasher> TransactionEncoding for MemoryDB<H> {
+	fn encode_transaction_to<T: Output>(&self, dest: &mut T) {
+		encode_db_entries(self.clone().drain().into_iter()
+			.map(|(key, (value, rc))| (key.as_ref().to_vec(), value, rc)), dest)
+	}
+
+	fn decode_transaction<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+		decode_db_entries::<H, _, _>(input)
+	}
+}
+
+impl<H: Hasher> TransactionEncoding for PrefixedMemoryDB<H> {
+	fn encode_transaction_to<T: Output>(&self, dest: &mut T) {
+		encode_db_entries(self.clone().drain().into_iter()
+			.map(|(key, (value, rc))| (key, value, rc)), dest)
+	}
+
+	fn decode_transaction<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+		decode_db_entries::<H, _, _>(input)
+	}
+}
+
+/// Encode the entries of a drained memory db, with their reference count, ordered by key.
+fn encode_db_entries<T: Output>(
+	entries: impl Iterator<Item=(Vec<u8>, DBValue, i32)>,
+	dest: &mut T,
+) {
+	let mut entries: Vec<_> = entries.collect();
+	entries.sort_by(|a, b| a.0.cmp(&b.0));
+	entries.encode_to(dest);
+}
+
+/// Rebuild a memory db from entries encoded by `encode_db_entries`.
+///
+/// Keys end with the node hash, the bytes before it are the key prefix.
+fn decode_db_entries<H, DB, I>(input: &mut I) -> Result<DB, codec::Error> where
+	H: Hasher,
+	DB: HashDB<H, DBValue> + Default,
+	I: Input,
+{
+	let mut db = DB::default();
+	for (key, value, rc) in <Vec<(Vec<u8>, DBValue, i32)>>::decode(input)? {
+		if key.len() < H::LENGTH {
+			return Err("Invalid transaction key".into());
+		}
+		let (prefix, hash) = key.split_at(key.len() - H::LENGTH);
+		let mut node_hash = H::Out::default();
+		node_hash.as_mut().copy_from_slice(hash);
+		for _ in 0..rc {
+			db.emplace(node_hash, (prefix, None), value.clone());
+		}
+		for _ in rc..0 {
+			db.remove(&node_hash, (prefix, None));
+		}
+	}
+	Ok(db)
+}
+
 /// The storage transaction are calculated as part of the `storage_root` and
 /// `changes_trie_storage_root`. These transactions can be reused for importing the block into the
 /// storage. So, we cache them to not require a recomputation of those transactions.
@@ -123,6 +341,8 @@ pub struct StorageTransactionCache<Transaction, H: Hasher, N: BlockNumber> {
 	pub(crate) changes_trie_transaction: Option<Option<ChangesTrieTransaction<H, N>>>,
 	/// The storage root after applying the changes trie transaction.
 	pub(crate) changes_trie_transaction_storage_root: Option<Option<H::Out>>,
+	/// Encoded roots of the child tries computed on their own, by child storage key.
+	pub(crate) child_storage_roots: HashMap<StorageKey, Vec<u8>>,
 }
 
 impl<Transaction, H: Hasher, N: BlockNumber> StorageTransactionCache<Transaction, H, N> {
@@ -139,6 +359,7 @@ impl<Transaction, H: Hasher, N: BlockNumber> Default for StorageTransactionCache
 			transaction_storage_root: None,
 			changes_trie_transaction: None,
 			changes_trie_transaction_storage_root: None,
+			child_storage_roots: HashMap::new(),
 		}
 	}
 }
@@ -156,17 +377,82 @@ impl<Transaction: Default, H: Hasher, N: BlockNumber> Default for StorageChanges
 	}
 }
 
+impl FrozenOverlay {
+	/// Create new overlayed changes, without open transaction, starting from the content of the
+	/// snapshot.
+	///
+	/// The snapshot is shared and not copied: a value is only copied when the returned changes
+	/// write it, so modifications are not visible from the snapshot.
+	pub fn thaw(&self) -> OverlayedChanges {
+		OverlayedChanges {
+			top: OverlayedChangeSet::thaw(&self.top),
+			children: self.children.iter()
+				.map(|(key, (frozen, info))| (key.clone(), (OverlayedChangeSet::thaw(frozen), info.clone())))
+				.collect(),
+			collect_extrinsics: false,
+			stats: Default::default(),
+			size_watcher: None,
+		}
+	}
+}
+
 impl OverlayedChanges {
 	/// Whether no changes are contained in the top nor in any of the child changes.
 	pub fn is_empty(&self) -> bool {
 		self.top.is_empty() && self.children.is_empty()
 	}
 
+	/// Take a read-only snapshot of the changes as seen by the current transaction.
+	///
+	/// The snapshot is not affected by further modifications of `self`. Taking it copies the
+	/// changes once, thawing it afterwards does not.
+	pub fn freeze(&self) -> FrozenOverlay {
+		FrozenOverlay {
+			top: self.top.freeze(),
+			children: Arc::new(self.children.iter()
+				.map(|(key, (changeset, info))| (key.clone(), (changeset.freeze(), info.clone())))
+				.collect()),
+		}
+	}
+
 	/// Ask to collect/not to collect extrinsics indices where key(s) has been changed.
 	pub fn set_collect_extrinsics(&mut self, collect_extrinsics: bool) {
 		self.collect_extrinsics = collect_extrinsics;
 	}
 
+	/// Whether extrinsics indices where keys have been changed are collected.
+	pub fn collects_extrinsics(&self) -> bool {
+		self.collect_extrinsics
+	}
+
+	/// Tag the following changes with `tag`, or stop tagging them if `None`.
+	///
+	/// Unlike extrinsic indices, tags are recorded whether changes tries are enabled or not.
+	pub fn set_change_tag(&mut self, tag: Option<ChangeTag>) {
+		for (changeset, _) in self.children.values_mut() {
+			changeset.set_tag(tag.clone());
+		}
+		self.top.set_tag(tag);
+	}
+
+	/// Tag of the last change of `key`, `None` if the key is unchanged or if its last change
+	/// was not tagged.
+	pub fn change_tag(&self, key: &[u8]) -> Option<&ChangeTag> {
+		self.top.get(key).and_then(OverlayedValue::tag)
+	}
+
+	/// Same as `change_tag`, for a key of a child trie.
+	pub fn child_change_tag(&self, child_info: &ChildInfo, key: &[u8]) -> Option<&ChangeTag> {
+		self.children.get(child_info.storage_key())
+			.and_then(|(changeset, _)| changeset.get(key))
+			.and_then(OverlayedValue::tag)
+	}
+
+	/// Statistics of the reads and writes that hit this overlay.
+	pub fn stats(&self) -> &StateMachineStats {
+		&self.stats
+	}
+
 	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
 	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
 	/// value has been set.
@@ -179,6 +465,17 @@ impl OverlayedChanges {
 		})
 	}
 
+	/// Same as `storage`, for reads of well known keys made by the client rather than the
+	/// runtime. These are tallied apart in the statistics.
+	pub fn well_known_storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+		self.top.get(key).map(|x| {
+			let value = x.value();
+			let size_read = value.map(|x| x.len() as u64).unwrap_or(0);
+			self.stats.tally_read_well_known(size_read);
+			value.map(AsRef::as_ref)
+		})
+	}
+
 	/// Returns mutable reference to current value.
 	/// If there is no value in the overlay, the given callback is used to initiate the value.
 	/// Warning this function registers a change, so the mutable reference MUST be modified.
@@ -293,6 +590,44 @@ impl OverlayedChanges {
 		changeset.clear_where(|key, _| key.starts_with(prefix), extrinsic_index);
 	}
 
+	/// Number of keys changed in the top storage and in all the child storages, as seen by the
+	/// current transaction.
+	pub fn changed_keys_count(&self) -> usize {
+		self.changes_size().keys
+	}
+
+	/// Size in bytes of the changed keys and values in the top storage and in all the child
+	/// storages, as seen by the current transaction.
+	pub fn pending_bytes(&self) -> usize {
+		self.changes_size().bytes
+	}
+
+	/// Size of the changes of each child storage, as seen by the current transaction.
+	pub fn children_changes_size(&self) -> impl Iterator<Item=(&ChildInfo, ChangesSize)> {
+		self.children.values().map(|(changeset, info)| (info, changeset.size()))
+	}
+
+	/// Size of all the changes, as seen by the current transaction.
+	pub fn changes_size(&self) -> ChangesSize {
+		let mut size = self.top.size();
+		for (_, child_size) in self.children_changes_size() {
+			size.add(child_size);
+		}
+		size
+	}
+
+	/// Register a `callback` invoked each time control returns from the runtime with changes
+	/// exceeding `threshold` in number of keys or in bytes. `None` removes the callback.
+	///
+	/// This allows block authors to stop including extrinsics before the storage diff gets
+	/// too large.
+	pub fn set_size_watcher(
+		&mut self,
+		watcher: Option<(ChangesSize, Arc<dyn Fn(ChangesSize) + Send + Sync>)>,
+	) {
+		self.size_watcher = watcher.map(|(threshold, callback)| SizeWatcher { threshold, callback });
+	}
+
 	/// Returns the current nesting depth of the transaction stack.
 	///
 	/// A value of zero means that no transaction is open and changes are committed on write.
@@ -366,6 +701,19 @@ impl OverlayedChanges {
 			changeset.exit_runtime()
 				.expect("Top and children changesets are entering runtime in lockstep; qed");
 		}
+		if self.size_watcher.is_some() {
+			// Only the keys written since the previous call are measured.
+			self.top.settle_size();
+			for (changeset, _) in self.children.values_mut() {
+				changeset.settle_size();
+			}
+		}
+		if let Some(watcher) = self.size_watcher.as_ref() {
+			let size = self.changes_size();
+			if size.exceeds(&watcher.threshold) {
+				(watcher.callback)(size);
+			}
+		}
 		Ok(())
 	}
 
@@ -453,6 +801,8 @@ impl OverlayedChanges {
 			.take()
 			.expect("Changes trie transaction was generated by `changes_trie_root`; qed");
 
+		cache.child_storage_roots.clear();
+
 		let offchain_storage_changes = Default::default();
 		let (main_storage_changes, child_storage_changes) = self.drain_committed();
 
@@ -466,6 +816,54 @@ impl OverlayedChanges {
 		})
 	}
 
+	/// Drop the changes writing the value a key already has in `backend`, typically a value
+	/// modified then restored during the block. They are left out of the storage transaction
+	/// and of the changes trie.
+	///
+	/// Must be called before the storage root is computed with a cache meant for
+	/// `drain_storage_changes`. Fails if a transaction is open.
+	///
+	/// Returns the number and size of the dropped changes.
+	pub fn minimize_changes<H: Hasher, B: Backend<H>>(
+		&mut self,
+		backend: &B,
+	) -> Result<ChangesSize, String> {
+		if self.transaction_depth() > 0 {
+			return Err("Changes can not be minimized with open transactions".into());
+		}
+
+		let mut error = None;
+		let mut elided = ChangesSize::default();
+		let removed = self.top.remove_where(|key, value| match backend.storage(key) {
+			Ok(current) => current.as_ref() == value.value(),
+			Err(e) => {
+				error.get_or_insert(format!("{:?}", e));
+				false
+			},
+		});
+		elided.add(ChangesSize::of(removed.iter().map(|(key, value)| (key, value))));
+
+		for (changeset, child_info) in self.children.values_mut() {
+			let child_info = &*child_info;
+			let removed = changeset.remove_where(
+				|key, value| match backend.child_storage(child_info, key) {
+					Ok(current) => current.as_ref() == value.value(),
+					Err(e) => {
+						error.get_or_insert(format!("{:?}", e));
+						false
+					},
+				}
+			);
+			elided.add(ChangesSize::of(removed.iter().map(|(key, value)| (key, value))));
+		}
+		self.children.retain(|_, (changeset, _)| !changeset.is_empty());
+
+		match error {
+			Some(e) => Err(e),
+			None => Ok(elided),
+		}
+	}
+
 	/// Inserts storage entry responsible for current extrinsic index.
 	#[cfg(test)]
 	pub(crate) fn set_extrinsic_index(&mut self, extrinsic_index: u32) {
@@ -513,6 +911,86 @@ impl OverlayedChanges {
 		root
 	}
 
+	/// Storage changes made by the innermost open transaction, with their current value.
+	///
+	/// Returns an error if no transaction is open.
+	pub fn transaction_changes(&self) -> Result<TransactionChanges, NoOpenTransaction> {
+		let top = self.top.transaction_changes()?
+			.map(|(k, v)| (k.clone(), v.cloned()))
+			.collect();
+		let mut children: ChildStorageCollection = self.children.iter()
+			.map(|(storage_key, (changeset, _))| {
+				let changes = changeset.transaction_changes()
+					.expect("Top and children changesets are started in lockstep; qed")
+					.map(|(k, v)| (k.clone(), v.cloned()))
+					.collect::<StorageCollection>();
+				(storage_key.clone(), changes)
+			})
+			.filter(|(_, changes)| !changes.is_empty())
+			.collect();
+		children.sort_by(|a, b| a.0.cmp(&b.0));
+		Ok(TransactionChanges { top, children })
+	}
+
+	/// All changes as seen by the current transaction, with their current value. Nothing is
+	/// committed nor drained.
+	///
+	/// Child changes are ordered by storage key, child tries without changes are omitted.
+	pub fn export_changes(&self) -> (StorageCollection, ChildStorageCollection) {
+		let top = self.top.changes()
+			.map(|(k, v)| (k.clone(), v.value().cloned()))
+			.collect();
+		let mut children: ChildStorageCollection = self.children.iter()
+			.map(|(storage_key, (changeset, _))| (
+				storage_key.clone(),
+				changeset.changes().map(|(k, v)| (k.clone(), v.value().cloned())).collect::<StorageCollection>(),
+			))
+			.filter(|(_, changes)| !changes.is_empty())
+			.collect();
+		children.sort_by(|a, b| a.0.cmp(&b.0));
+		(top, children)
+	}
+
+	/// Set all changes of `top` and `children`, as returned by `export_changes`.
+	///
+	/// Child tries are default child tries identified by their storage key. Changes are made
+	/// in the current transaction and can be rolled back with it.
+	pub fn apply_collection(&mut self, top: StorageCollection, children: ChildStorageCollection) {
+		for (key, value) in top {
+			self.set_storage(key, value);
+		}
+		for (storage_key, changes) in children {
+			let child_info = ChildInfo::new_default(&storage_key);
+			for (key, value) in changes {
+				self.set_child_storage(&child_info, key, value);
+			}
+		}
+	}
+
+	/// Generate the storage root using `backend` and the changes as they would be seen if the
+	/// `depth` innermost open transactions were rolled back. The overlay is not modified.
+	///
+	/// Returns an error if less than `depth` transactions are open.
+	pub fn storage_root_at_depth<H: Hasher, B: Backend<H>>(
+		&self,
+		backend: &B,
+		depth: usize,
+	) -> Result<H::Out, NoOpenTransaction>
+		where H::Out: Ord + Encode,
+	{
+		let delta = self.top.changes_at_depth(depth)?
+			.map(|(k, v)| (&k[..], v.map(|v| &v[..])));
+		let mut child_delta = Vec::with_capacity(self.children.len());
+		for (changeset, info) in self.children.values() {
+			let changes = changeset.changes_at_depth(depth)
+				.expect("Top and children changesets are started in lockstep; qed")
+				.map(|(k, v)| (&k[..], v.map(|v| &v[..])));
+			child_delta.push((info, changes));
+		}
+
+		Ok(backend.full_storage_root(delta, child_delta.into_iter()).0)
+	}
+
 	/// Generate the changes trie root.
 	///
 	/// Returns the changes trie root and caches the storage transaction into the given `cache`.
@@ -583,6 +1061,182 @@ mod tests {
 		)
 	}
 
+	#[test]
+	fn storage_root_at_depth_ignores_rolled_back_layers() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut overlay = OverlayedChanges::default();
+		let child_info = ChildInfo::new_default(b"child");
+		overlay.set_storage(b"key".to_vec(), Some(b"value".to_vec()));
+		let reference = overlay.clone();
+		overlay.start_transaction();
+		overlay.set_storage(b"key".to_vec(), Some(b"other".to_vec()));
+		overlay.set_child_storage(&child_info, b"key".to_vec(), Some(b"value".to_vec()));
+
+		let mut cache = StorageTransactionCache::<_, _, u64>::default();
+		let root = overlay.storage_root(&backend, &mut cache);
+		assert_eq!(overlay.storage_root_at_depth(&backend, 0).unwrap(), root);
+		assert_eq!(
+			overlay.storage_root_at_depth(&backend, 1).unwrap(),
+			reference.storage_root(&backend, &mut cache),
+		);
+		assert!(overlay.storage_root_at_depth(&backend, 2).is_err());
+		assert_eq!(overlay.transaction_depth(), 1);
+	}
+
+	#[test]
+	fn minimize_changes_drops_restored_values() {
+		let initial: BTreeMap<_, _> = vec![
+			(b"doe".to_vec(), b"reindeer".to_vec()),
+			(b"dog".to_vec(), b"puppy".to_vec()),
+		].into_iter().collect();
+		let backend = InMemoryBackend::<Blake2Hasher>::from(initial);
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"doe".to_vec(), Some(b"deer".to_vec()));
+		overlay.set_storage(b"doe".to_vec(), Some(b"reindeer".to_vec()));
+		overlay.set_storage(b"dog".to_vec(), Some(b"hound".to_vec()));
+		overlay.set_storage(b"cat".to_vec(), None);
+		overlay.set_child_storage(&child_info, b"key".to_vec(), None);
+
+		overlay.start_transaction();
+		assert!(overlay.minimize_changes(&backend).is_err());
+		overlay.commit_transaction().unwrap();
+
+		let mut cache = StorageTransactionCache::<_, _, u64>::default();
+		let root = overlay.storage_root(&backend, &mut cache);
+		assert_eq!(
+			overlay.minimize_changes(&backend).unwrap(),
+			ChangesSize { keys: 3, bytes: 17 },
+		);
+		assert_eq!(overlay.changes().map(|(k, _)| k.clone()).collect::<Vec<_>>(), vec![b"dog".to_vec()]);
+		assert_eq!(overlay.children().count(), 0);
+		let mut cache = StorageTransactionCache::<_, _, u64>::default();
+		assert_eq!(overlay.storage_root(&backend, &mut cache), root);
+	}
+
+	#[test]
+	fn changes_size_and_watcher() {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+
+		let mut overlayed = OverlayedChanges::default();
+		let child_info = ChildInfo::new_default(b"child");
+		let notified = Arc::new(AtomicUsize::new(0));
+		let notified_by_watcher = notified.clone();
+		let callback: Arc<dyn Fn(ChangesSize) + Send + Sync> =
+			Arc::new(move |size: ChangesSize| notified_by_watcher.store(size.keys, Ordering::SeqCst));
+		overlayed.set_size_watcher(Some((ChangesSize { keys: 2, bytes: usize::max_value() }, callback)));
+
+		overlayed.enter_runtime().unwrap();
+		overlayed.set_storage(vec![1, 2], Some(vec![3, 4, 5]));
+		overlayed.set_storage(vec![2], None);
+		overlayed.exit_runtime().unwrap();
+		assert_eq!(overlayed.changed_keys_count(), 2);
+		assert_eq!(overlayed.pending_bytes(), 6);
+		assert_eq!(notified.load(Ordering::SeqCst), 0);
+
+		overlayed.enter_runtime().unwrap();
+		overlayed.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlayed.exit_runtime().unwrap();
+		assert_eq!(
+			overlayed.children_changes_size().collect::<Vec<_>>(),
+			vec![(&child_info, ChangesSize { keys: 1, bytes: 2 })],
+		);
+		assert_eq!(overlayed.changes_size(), ChangesSize { keys: 3, bytes: 8 });
+		assert_eq!(notified.load(Ordering::SeqCst), 3);
+	}
+
+	#[test]
+	fn export_changes_does_not_drain() {
+		let mut overlay = OverlayedChanges::default();
+		let child_info = ChildInfo::new_default(b"child");
+		overlay.set_storage(b"key".to_vec(), Some(b"value".to_vec()));
+		overlay.start_transaction();
+		overlay.set_storage(b"removed".to_vec(), None);
+		overlay.set_child_storage(&child_info, b"key".to_vec(), Some(b"value".to_vec()));
+
+		let (top, children) = overlay.export_changes();
+		assert_eq!(top, vec![
+			(b"key".to_vec(), Some(b"value".to_vec())),
+			(b"removed".to_vec(), None),
+		]);
+		assert_eq!(children, vec![(b"child".to_vec(), vec![(b"key".to_vec(), Some(b"value".to_vec()))])]);
+		assert_eq!(overlay.transaction_depth(), 1);
+		assert_eq!(overlay.export_changes(), (top, children));
+	}
+
+	#[test]
+	fn apply_collection_restores_exported_changes() {
+		let mut overlay = OverlayedChanges::default();
+		let child_info = ChildInfo::new_default(b"child");
+		overlay.set_storage(b"key".to_vec(), Some(b"value".to_vec()));
+		overlay.set_storage(b"removed".to_vec(), None);
+		overlay.set_child_storage(&child_info, b"key".to_vec(), Some(b"value".to_vec()));
+		let (top, children) = overlay.export_changes();
+
+		let mut imported = OverlayedChanges::default();
+		imported.set_storage(b"other".to_vec(), Some(b"other".to_vec()));
+		imported.start_transaction();
+		imported.apply_collection(top.clone(), children.clone());
+		assert_eq!(imported.storage(b"key"), Some(Some(&b"value"[..])));
+		assert_eq!(imported.storage(b"removed"), Some(None));
+		assert_eq!(imported.child_storage(&child_info, b"key"), Some(Some(&b"value"[..])));
+
+		imported.rollback_transaction().unwrap();
+		assert_eq!(imported.storage(b"key"), None);
+		imported.apply_collection(top, children);
+		overlay.set_storage(b"other".to_vec(), Some(b"other".to_vec()));
+		assert_eq!(imported.export_changes(), overlay.export_changes());
+	}
+
+	#[test]
+	fn storage_changes_encoding_roundtrips() {
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"key".to_vec(), Some(b"value".to_vec()));
+		overlay.set_child_storage(&ChildInfo::new_default(b"child"), b"key".to_vec(), Some(b"value".to_vec()));
+		let mut changes = overlay.drain_storage_changes::<_, _, u64>(
+			&backend,
+			None,
+			Default::default(),
+			&mut Default::default(),
+		).unwrap();
+		let mut changes_trie_db = MemoryDB::<Blake2Hasher>::default();
+		changes_trie_db.insert(hash_db::EMPTY_PREFIX, b"node");
+		changes.changes_trie_transaction = Some((changes_trie_db, crate::ChangesTrieCacheAction::Clear));
+
+		let encoded = changes.encode();
+		let mut decoded = StorageChanges::<MemoryDB<Blake2Hasher>, Blake2Hasher, u64>::decode(
+			&mut &encoded[..],
+		).unwrap();
+		assert_eq!(decoded.main_storage_changes, changes.main_storage_changes);
+		assert_eq!(decoded.child_storage_changes, changes.child_storage_changes);
+		assert_eq!(decoded.transaction_storage_root, changes.transaction_storage_root);
+		assert_eq!(decoded.encode(), encoded);
+		assert_eq!(decoded.transaction.drain(), changes.transaction.drain());
+		let (mut decoded_db, decoded_action) = decoded.changes_trie_transaction.unwrap();
+		let (mut changes_trie_db, action) = changes.changes_trie_transaction.unwrap();
+		assert_eq!(decoded_action, action);
+		assert_eq!(decoded_db.drain(), changes_trie_db.drain());
+	}
+
+	#[test]
+	fn frozen_overlay_is_not_affected_by_later_changes() {
+		fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+		let mut overlayed = OverlayedChanges::default();
+		overlayed.set_storage(vec![1], Some(vec![1]));
+		let frozen = overlayed.freeze();
+		assert_send_sync(&frozen);
+
+		overlayed.set_storage(vec![1], Some(vec![2]));
+		let mut thawed = frozen.thaw();
+		thawed.set_storage(vec![1], Some(vec![3]));
+
+		assert_eq!(overlayed.storage(&[1]).unwrap(), Some(&[2][..]));
+		assert_eq!(thawed.storage(&[1]).unwrap(), Some(&[3][..]));
+		assert_eq!(frozen.thaw().storage(&[1]).unwrap(), Some(&[1][..]));
+	}
+
 	#[test]
 	fn overlayed_storage_works() {
 		let mut overlayed = OverlayedChanges::default();
@@ -653,6 +1307,39 @@ mod tests {
 		assert_eq!(&ext.storage_root()[..], &ROOT);
 	}
 
+	#[test]
+	fn change_tags_follow_the_last_change() {
+		let mut overlay = OverlayedChanges::default();
+		let child_info = ChildInfo::new_default(b"sub1");
+		let initialize = ChangeTag::Named("on_initialize".into());
+
+		overlay.set_change_tag(Some(initialize.clone()));
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.set_change_tag(Some(ChangeTag::Extrinsic(0)));
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![3]));
+		overlay.set_child_storage(&child_info, vec![1], None);
+		assert_eq!(overlay.change_tag(&[1]), Some(&initialize));
+		assert_eq!(overlay.change_tag(&[2]), Some(&ChangeTag::Extrinsic(0)));
+		assert_eq!(overlay.child_change_tag(&child_info, &[1]), Some(&ChangeTag::Extrinsic(0)));
+
+		overlay.rollback_transaction().unwrap();
+		assert_eq!(overlay.change_tag(&[2]), Some(&initialize));
+		assert_eq!(overlay.child_change_tag(&child_info, &[1]), Some(&initialize));
+
+		overlay.start_transaction();
+		overlay.set_storage(vec![2], Some(vec![4]));
+		overlay.commit_transaction().unwrap();
+		assert_eq!(overlay.change_tag(&[2]), Some(&ChangeTag::Extrinsic(0)));
+
+		overlay.set_change_tag(None);
+		overlay.set_storage(vec![1], None);
+		assert_eq!(overlay.change_tag(&[1]), None);
+		assert_eq!(overlay.change_tag(&[3]), None);
+	}
+
 	#[test]
 	fn extrinsic_changes_are_collected() {
 		let mut overlay = OverlayedChanges::default();
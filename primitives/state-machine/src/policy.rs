@@ -0,0 +1,127 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Execution policies: the way a call is executed, as a chain of steps tried in order.
+
+use std::{collections::HashMap, fmt};
+use codec::{Decode, Encode};
+use crate::{BackendTrustLevel, DefaultHandler, ExecutionManager, ExecutionStrategy};
+
+/// A single way of executing a call.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ExecutionStep {
+	/// Execute with the native equivalent if it is compatible with the wasm module, with the
+	/// wasm module otherwise.
+	Native,
+	/// Execute with the wasm module, panics of the backend are not caught.
+	Wasm,
+	/// Execute with the wasm module, panics of the backend are caught and reported as a failure
+	/// of the step.
+	UntrustedWasm,
+	/// Execute with both the native equivalent and the wasm module, see
+	/// `ExecutionStrategy::Both`.
+	Both,
+}
+
+impl ExecutionStep {
+	/// Gets the corresponding manager for the execution step.
+	pub fn get_manager<E: fmt::Debug, R: Decode + Encode>(
+		self,
+	) -> ExecutionManager<DefaultHandler<R, E>> {
+		match self {
+			ExecutionStep::Native => ExecutionManager::NativeWhenPossible,
+			ExecutionStep::Wasm => ExecutionManager::AlwaysWasm(BackendTrustLevel::Trusted),
+			ExecutionStep::UntrustedWasm => ExecutionManager::AlwaysWasm(BackendTrustLevel::Untrusted),
+			ExecutionStep::Both => ExecutionStrategy::Both.get_manager(),
+		}
+	}
+}
+
+/// Chooses how calls are executed.
+///
+/// The steps returned for a call are tried in order until one succeeds. Changes of a failed
+/// step are discarded before the next one, the error of the last step is the one of the call.
+pub trait ExecutionPolicy {
+	/// Steps to execute a call to `method` with, at least one.
+	fn steps(&self, method: &str) -> Vec<ExecutionStep>;
+}
+
+impl ExecutionPolicy for ExecutionStrategy {
+	fn steps(&self, _method: &str) -> Vec<ExecutionStep> {
+		match self {
+			ExecutionStrategy::NativeWhenPossible => vec![ExecutionStep::Native],
+			ExecutionStrategy::AlwaysWasm => vec![ExecutionStep::Wasm],
+			ExecutionStrategy::Both => vec![ExecutionStep::Both],
+			ExecutionStrategy::NativeElseWasm => vec![ExecutionStep::Native, ExecutionStep::Wasm],
+		}
+	}
+}
+
+/// The same chain of steps for every method.
+impl ExecutionPolicy for Vec<ExecutionStep> {
+	fn steps(&self, _method: &str) -> Vec<ExecutionStep> {
+		self.clone()
+	}
+}
+
+/// Policy with per-method overrides of a default policy.
+pub struct MethodOverrides<P> {
+	default: P,
+	overrides: HashMap<String, Vec<ExecutionStep>>,
+}
+
+impl<P: ExecutionPolicy> MethodOverrides<P> {
+	/// Policy following `default` for every method.
+	pub fn new(default: P) -> Self {
+		MethodOverrides {
+			default,
+			overrides: HashMap::new(),
+		}
+	}
+
+	/// Execute calls to `method` with `steps` instead of the default policy.
+	pub fn with_method(mut self, method: &str, steps: Vec<ExecutionStep>) -> Self {
+		self.overrides.insert(method.into(), steps);
+		self
+	}
+}
+
+impl<P: ExecutionPolicy> ExecutionPolicy for MethodOverrides<P> {
+	fn steps(&self, method: &str) -> Vec<ExecutionStep> {
+		match self.overrides.get(method) {
+			Some(steps) => steps.clone(),
+			None => self.default.steps(method),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn overrides_apply_to_their_method() {
+		let policy = MethodOverrides::new(ExecutionStrategy::NativeElseWasm)
+			.with_method("Core_execute_block", vec![
+				ExecutionStep::Native,
+				ExecutionStep::Wasm,
+				ExecutionStep::UntrustedWasm,
+			]);
+		assert_eq!(policy.steps("Core_version"), vec![ExecutionStep::Native, ExecutionStep::Wasm]);
+		assert_eq!(policy.steps("Core_execute_block").len(), 3);
+	}
+}
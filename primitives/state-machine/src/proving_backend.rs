@@ -17,7 +17,7 @@
 
 //! Proving state machine backend.
 
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, collections::{HashMap, HashSet}};
 use parking_lot::RwLock;
 use codec::{Decode, Codec};
 use log::debug;
@@ -30,7 +30,7 @@ pub use sp_trie::{Recorder, trie_types::{Layout, TrieError}};
 use crate::trie_backend::TrieBackend;
 use crate::trie_backend_essence::{Ephemeral, TrieBackendEssence, TrieBackendStorage};
 use crate::{Error, ExecutionError, Backend, DBValue};
-use sp_core::storage::ChildInfo;
+use sp_core::{storage::ChildInfo, traits::{ProofSizeExt, ProofSizeProvider}};
 
 /// Patricia trie-based backend specialized in get value proofs.
 pub struct ProvingBackendRecorder<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> {
@@ -113,6 +113,46 @@ impl<'a, S, H> ProvingBackendRecorder<'a, S, H>
 /// data.
 pub type ProofRecorder<H> = Arc<RwLock<HashMap<<H as Hasher>::Out, Option<DBValue>>>>;
 
+/// Proof size recorder, only keeps the size of each queried trie node.
+///
+/// Allows estimating the size of a proof at a fraction of the memory cost of a `ProofRecorder`.
+pub type ProofSizeRecorder<H> = Arc<RwLock<HashMap<<H as Hasher>::Out, usize>>>;
+
+/// Shared handle on the recorder of a `ProvingBackend`, giving the size of its proof.
+pub struct ProofSize<H: Hasher> {
+	proof_recorder: ProofRecorder<H>,
+	size_recorder: Option<ProofSizeRecorder<H>>,
+}
+
+impl<H: Hasher> Clone for ProofSize<H> {
+	fn clone(&self) -> Self {
+		ProofSize {
+			proof_recorder: self.proof_recorder.clone(),
+			size_recorder: self.size_recorder.clone(),
+		}
+	}
+}
+
+impl<H: Hasher> ProofSize<H> {
+	/// Total size of the trie nodes recorded so far.
+	pub fn estimated_proof_size(&self) -> usize {
+		match self.size_recorder.as_ref() {
+			Some(size_recorder) => size_recorder.read().values().sum(),
+			None => self.proof_recorder
+				.read()
+				.values()
+				.map(|v| v.as_ref().map_or(0, |v| v.len()))
+				.sum(),
+		}
+	}
+}
+
+impl<H: Hasher> ProofSizeProvider for ProofSize<H> {
+	fn estimated_proof_size(&self) -> u64 {
+		ProofSize::estimated_proof_size(self) as u64
+	}
+}
+
 /// Patricia trie-based backend which also tracks all touched storage trie values.
 /// These can be sent to remote node and used as a proof of execution.
 pub struct ProvingBackend<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> (
@@ -123,6 +163,12 @@ pub struct ProvingBackend<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> (
 pub struct ProofRecorderBackend<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> {
 	backend: &'a S,
 	proof_recorder: ProofRecorder<H>,
+	/// When defined, only node sizes are recorded here and `proof_recorder` stays empty.
+	size_recorder: Option<ProofSizeRecorder<H>>,
+	/// Keys whose reads are not recorded.
+	whitelist: HashSet<Vec<u8>>,
+	/// Set while reading a whitelisted key.
+	unrecorded: AtomicBool,
 }
 
 impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> ProvingBackend<'a, S, H>
@@ -144,10 +190,75 @@ impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> ProvingBackend<'a, S, H>
 		let recorder = ProofRecorderBackend {
 			backend: essence.backend_storage(),
 			proof_recorder,
+			size_recorder: None,
+			whitelist: Default::default(),
+			unrecorded: AtomicBool::new(false),
+		};
+		ProvingBackend(TrieBackend::new(recorder, root))
+	}
+
+	/// Create new proving backend recording only the hash and size of the queried nodes.
+	///
+	/// No proof can be extracted from it, `extract_proof` returns an empty proof, but
+	/// `estimated_proof_size` can be used to evaluate the size of the proof a full
+	/// recording would produce.
+	pub fn new_hash_only(
+		backend: &'a TrieBackend<S, H>,
+		size_recorder: ProofSizeRecorder<H>,
+	) -> Self {
+		let essence = backend.essence();
+		let root = essence.root().clone();
+		let recorder = ProofRecorderBackend {
+			backend: essence.backend_storage(),
+			proof_recorder: Default::default(),
+			size_recorder: Some(size_recorder),
+			whitelist: Default::default(),
+			unrecorded: AtomicBool::new(false),
 		};
 		ProvingBackend(TrieBackend::new(recorder, root))
 	}
 
+	/// Do not record reads of the values at `keys`.
+	///
+	/// Meant for keys read by every execution, such as `:code`, that the checker of the proof
+	/// is expected to have cached. Trie nodes on the path of a whitelisted key are still
+	/// recorded when reading other keys.
+	pub fn with_whitelist(mut self, keys: impl IntoIterator<Item = Vec<u8>>) -> Self {
+		self.0.backend_storage_mut().whitelist.extend(keys);
+		self
+	}
+
+	/// Run `read` of `key`, without recording it if `key` is whitelisted.
+	fn read_whitelisted<R>(&self, key: &[u8], read: impl FnOnce() -> R) -> R {
+		let recorder = self.0.essence().backend_storage();
+		if !recorder.whitelist.contains(key) {
+			return read();
+		}
+		recorder.unrecorded.store(true, Ordering::Relaxed);
+		let result = read();
+		recorder.unrecorded.store(false, Ordering::Relaxed);
+		result
+	}
+
+	/// Total size of the trie nodes recorded so far.
+	pub fn estimated_proof_size(&self) -> usize {
+		self.proof_size().estimated_proof_size()
+	}
+
+	/// Handle on the recorder, giving the size of the proof as it is recorded.
+	pub fn proof_size(&self) -> ProofSize<H> {
+		let recorder = self.0.essence().backend_storage();
+		ProofSize {
+			proof_recorder: recorder.proof_recorder.clone(),
+			size_recorder: recorder.size_recorder.clone(),
+		}
+	}
+
+	/// Extension giving the runtime the size of the proof as it is recorded.
+	pub fn proof_size_ext(&self) -> ProofSizeExt where H: 'static {
+		ProofSizeExt::new(self.proof_size())
+	}
+
 	/// Extracting the gathered unordered proof.
 	pub fn extract_proof(&self) -> StorageProof {
 		let trie_nodes = self.0.essence().backend_storage().proof_recorder
@@ -165,6 +276,16 @@ impl<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> TrieBackendStorage<H>
 	type Overlay = S::Overlay;
 
 	fn get(&self, key: &H::Out, prefix: Prefix) -> Result<Option<DBValue>, String> {
+		if self.unrecorded.load(Ordering::Relaxed) {
+			return self.backend.get(key, prefix);
+		}
+		if let Some(size_recorder) = self.size_recorder.as_ref() {
+			let backend_value = self.backend.get(key, prefix)?;
+			if let Some(value) = backend_value.as_ref() {
+				size_recorder.write().insert(key.clone(), value.len());
+			}
+			return Ok(backend_value);
+		}
 		if let Some(v) = self.proof_recorder.read().get(key) {
 			return Ok(v.clone());
 		}
@@ -193,7 +314,20 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 	type TrieBackendStorage = S;
 
 	fn storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
-		self.0.storage(key)
+		self.read_whitelisted(key, || self.0.storage(key))
+	}
+
+	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
+		self.read_whitelisted(key, || self.0.storage_hash(key))
+	}
+
+	fn storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+		let whitelist = &self.0.essence().backend_storage().whitelist;
+		if keys.iter().any(|key| whitelist.contains(*key)) {
+			keys.iter().map(|key| self.storage(key)).collect()
+		} else {
+			self.0.storage_batch(keys)
+		}
 	}
 
 	fn child_storage(
@@ -204,6 +338,14 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.child_storage(child_info, key)
 	}
 
+	fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, Self::Error> {
+		self.0.child_storage_hash(child_info, key)
+	}
+
 	fn for_keys_in_child_storage<F: FnMut(&[u8])>(
 		&self,
 		child_info: &ChildInfo,
@@ -245,6 +387,14 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.pairs()
 	}
 
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		self.0.estimate_pairs_count()
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		self.0.estimate_storage_size()
+	}
+
 	fn keys(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
 		self.0.keys(prefix)
 	}
@@ -272,6 +422,13 @@ impl<'a, S, H> Backend<H> for ProvingBackend<'a, S, H>
 		self.0.child_storage_root(child_info, delta)
 	}
 
+	fn child_storage_roots<'b>(
+		&self,
+		child_deltas: Vec<(&'b ChildInfo, Vec<(&'b [u8], Option<&'b [u8]>)>)>,
+	) -> Vec<(H::Out, bool, Self::Transaction)> where H::Out: Ord {
+		self.0.child_storage_roots(child_deltas)
+	}
+
 	fn register_overlay_stats(&mut self, _stats: &crate::stats::StateMachineStats) { }
 
 	fn usage_info(&self) -> crate::stats::UsageInfo {
@@ -326,6 +483,59 @@ mod tests {
 		assert!(!backend.extract_proof().is_empty());
 	}
 
+	#[test]
+	fn hash_only_recording_estimates_proof_size() {
+		let trie_backend = test_trie();
+		let full = test_proving(&trie_backend);
+		let hash_only = ProvingBackend::new_hash_only(&trie_backend, Default::default());
+		for key in &[&b"key"[..], &b"value1"[..], &[200u8][..]] {
+			assert_eq!(full.storage(key).unwrap(), hash_only.storage(key).unwrap());
+		}
+
+		let proof_size: usize = full.extract_proof().iter_nodes().map(|n| n.len()).sum();
+		assert!(proof_size > 0);
+		assert_eq!(full.estimated_proof_size(), proof_size);
+		assert_eq!(hash_only.estimated_proof_size(), proof_size);
+		assert!(hash_only.extract_proof().is_empty());
+	}
+
+	#[test]
+	fn proof_size_ext_follows_recording() {
+		let trie_backend = test_trie();
+		let backend = test_proving(&trie_backend);
+		let ext = backend.proof_size_ext();
+		assert_eq!(ext.estimated_proof_size(), 0);
+		backend.storage(b"key").unwrap();
+		assert!(ext.estimated_proof_size() > 0);
+		assert_eq!(ext.estimated_proof_size(), backend.estimated_proof_size() as u64);
+	}
+
+	#[test]
+	fn whitelisted_keys_are_not_recorded() {
+		let trie_backend = test_trie();
+		let backend = test_proving(&trie_backend).with_whitelist(vec![b"key".to_vec()]);
+		assert_eq!(backend.storage(b"key").unwrap(), Some(b"value".to_vec()));
+		assert!(backend.extract_proof().is_empty());
+
+		assert_eq!(backend.storage_batch(&[&b"key"[..], &b"value1"[..]]).unwrap()[1], Some(vec![42]));
+		let proof = backend.extract_proof();
+		assert!(!proof.is_empty());
+		let checker = create_proof_check_backend::<BlakeTwo256>(*trie_backend.root(), proof).unwrap();
+		assert_eq!(checker.storage(b"value1").unwrap(), Some(vec![42]));
+	}
+
+	#[test]
+	fn storage_hash_is_provable() {
+		let trie_backend = test_trie();
+		let backend = test_proving(&trie_backend);
+		let hash = backend.storage_hash(b"value1").unwrap();
+		assert_eq!(hash, Some(BlakeTwo256::hash(&[42])));
+
+		let proof = backend.extract_proof();
+		let checker = create_proof_check_backend::<BlakeTwo256>(*trie_backend.root(), proof).unwrap();
+		assert_eq!(checker.storage_hash(b"value1").unwrap(), hash);
+	}
+
 	#[test]
 	fn proof_is_invalid_when_does_not_contains_root() {
 		use sp_core::H256;
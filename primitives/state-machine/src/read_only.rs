@@ -133,10 +133,28 @@ impl<'a, H: Hasher, B: 'a + Backend<H>> Externalities for ReadOnlyExternalities<
 		unimplemented!("kill_child_storage is not supported in ReadOnlyExternalities")
 	}
 
+	fn kill_child_storage_limited(
+		&mut self,
+		_child_info: &ChildInfo,
+		_limit: u32,
+		_cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>) {
+		unimplemented!("kill_child_storage_limited is not supported in ReadOnlyExternalities")
+	}
+
 	fn clear_prefix(&mut self, _prefix: &[u8]) {
 		unimplemented!("clear_prefix is not supported in ReadOnlyExternalities")
 	}
 
+	fn clear_prefix_limited(
+		&mut self,
+		_prefix: &[u8],
+		_limit: u32,
+		_cursor: Option<&[u8]>,
+	) -> (u32, Option<Vec<u8>>) {
+		unimplemented!("clear_prefix_limited is not supported in ReadOnlyExternalities")
+	}
+
 	fn clear_child_prefix(
 		&mut self,
 		_child_info: &ChildInfo,
@@ -0,0 +1,222 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reconciliation of the storage usage declared before an execution with the one measured
+//! during it.
+
+use std::collections::BTreeMap;
+use crate::{
+	changes_trie::NO_EXTRINSIC_INDEX,
+	overlayed_changes::OverlayedChanges,
+	stats::UsageInfo,
+};
+
+/// Storage usage of an execution or of a single extrinsic.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageUsage {
+	/// Number of storage reads.
+	pub reads: u64,
+	/// Number of storage writes.
+	pub writes: u64,
+	/// Size in bytes of the storage proof.
+	pub proof_bytes: u64,
+}
+
+impl StorageUsage {
+	/// Component-wise difference, saturating at zero.
+	pub fn saturating_sub(&self, other: &StorageUsage) -> StorageUsage {
+		StorageUsage {
+			reads: self.reads.saturating_sub(other.reads),
+			writes: self.writes.saturating_sub(other.writes),
+			proof_bytes: self.proof_bytes.saturating_sub(other.proof_bytes),
+		}
+	}
+
+	/// Whether no component exceeds the one of `other`.
+	pub fn fits_in(&self, other: &StorageUsage) -> bool {
+		self.reads <= other.reads
+			&& self.writes <= other.writes
+			&& self.proof_bytes <= other.proof_bytes
+	}
+}
+
+/// Declared storage usage against the measured one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsageReconciliation {
+	/// Usage declared by the caller before execution.
+	pub declared: StorageUsage,
+	/// Usage measured during execution.
+	pub measured: StorageUsage,
+}
+
+impl UsageReconciliation {
+	/// Whether the measured usage stays within the declared one.
+	pub fn within_declared(&self) -> bool {
+		self.measured.fits_in(&self.declared)
+	}
+
+	/// Declared usage that was not consumed, that is what can be refunded.
+	pub fn unused(&self) -> StorageUsage {
+		self.declared.saturating_sub(&self.measured)
+	}
+
+	/// Measured usage that was not declared.
+	pub fn excess(&self) -> StorageUsage {
+		self.measured.saturating_sub(&self.declared)
+	}
+}
+
+/// Report of the storage usage of an execution, produced after it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReconciliationReport {
+	/// Usage of the whole execution.
+	///
+	/// Reads are the ones that hit the backend, as counted by its `UsageInfo`. Writes are the
+	/// ones that hit the overlay, as counted by its `StateMachineStats`.
+	pub total: UsageReconciliation,
+	/// Usage per extrinsic index, only filled when the overlay collects extrinsics indices.
+	///
+	/// Only writes can be attributed to an extrinsic: the measured reads and proof bytes of an
+	/// extrinsic are left to zero. A write is a key changed by the extrinsic.
+	pub extrinsics: BTreeMap<u32, UsageReconciliation>,
+}
+
+impl ReconciliationReport {
+	/// Build the report of an execution that produced `overlay`.
+	///
+	/// `backend_usage` is the usage of the backend during the execution, as returned by
+	/// `Backend::usage_info`. `proof_bytes` is the size of the proof recorded during the
+	/// execution, for instance `ProvingBackend::estimated_proof_size`, and zero when no proof
+	/// was recorded.
+	pub fn new(
+		declared: StorageUsage,
+		declared_extrinsics: BTreeMap<u32, StorageUsage>,
+		overlay: &OverlayedChanges,
+		backend_usage: &UsageInfo,
+		proof_bytes: u64,
+	) -> Self {
+		let stats = overlay.stats();
+		let total = UsageReconciliation {
+			declared,
+			measured: StorageUsage {
+				reads: backend_usage.reads.ops,
+				writes: *stats.writes_overlay.borrow(),
+				proof_bytes,
+			},
+		};
+
+		let mut extrinsics = BTreeMap::new();
+		if overlay.collects_extrinsics() {
+			let mut measured = BTreeMap::<u32, u64>::new();
+			let children = overlay.children()
+				.flat_map(|(changes, _)| changes.map(|(_, value)| value));
+			for value in overlay.changes().map(|(_, value)| value).chain(children) {
+				for extrinsic in value.extrinsics() {
+					if *extrinsic != NO_EXTRINSIC_INDEX {
+						*measured.entry(*extrinsic).or_default() += 1;
+					}
+				}
+			}
+
+			for (index, declared) in declared_extrinsics {
+				extrinsics.insert(index, UsageReconciliation { declared, ..Default::default() });
+			}
+			for (index, writes) in measured {
+				extrinsics.entry(index).or_insert_with(UsageReconciliation::default)
+					.measured.writes = writes;
+			}
+		}
+
+		ReconciliationReport {
+			total,
+			extrinsics,
+		}
+	}
+
+	/// Whether every measured usage stays within the declared one.
+	pub fn within_declared(&self) -> bool {
+		self.total.within_declared() && self.extrinsics.values().all(|e| e.within_declared())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn usage(reads: u64, writes: u64, proof_bytes: u64) -> StorageUsage {
+		StorageUsage { reads, writes, proof_bytes }
+	}
+
+	fn backend_usage(reads: u64) -> UsageInfo {
+		let mut usage = UsageInfo::empty();
+		usage.reads.ops = reads;
+		usage
+	}
+
+	#[test]
+	fn reconciles_per_extrinsic() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(vec![100], Some(vec![101]));
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![2], Some(vec![3]));
+		overlay.set_storage(vec![3], None);
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![1], Some(vec![4]));
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[4][..])));
+
+		let declared_extrinsics = vec![
+			(0, usage(1, 1, 0)),
+			(1, usage(0, 1, 0)),
+		].into_iter().collect();
+		let report = ReconciliationReport::new(
+			usage(10, 10, 100),
+			declared_extrinsics,
+			&overlay,
+			&backend_usage(7),
+			60,
+		);
+
+		assert!(report.total.within_declared());
+		assert_eq!(report.total.measured.reads, 7);
+		assert_eq!(report.total.unused().proof_bytes, 40);
+		assert_eq!(report.extrinsics.len(), 3);
+		assert!(report.extrinsics[&0].within_declared());
+		assert_eq!(report.extrinsics[&0].unused(), usage(1, 0, 0));
+		assert_eq!(report.extrinsics[&1].excess(), usage(0, 1, 0));
+		assert_eq!(report.extrinsics[&2].measured.writes, 1);
+		assert!(!report.within_declared());
+	}
+
+	#[test]
+	fn no_extrinsics_without_collection() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![2]));
+		let declared_extrinsics = vec![(0, usage(1, 1, 0))].into_iter().collect();
+		let report = ReconciliationReport::new(
+			usage(0, 0, 0),
+			declared_extrinsics,
+			&overlay,
+			&UsageInfo::empty(),
+			0,
+		);
+		assert!(report.extrinsics.is_empty());
+		assert_eq!(report.total.excess(), usage(0, 1, 0));
+	}
+}
@@ -0,0 +1,231 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Replay files: the complete inputs of an execution, to execute it again elsewhere.
+//!
+//! A replay file starts with `REPLAY_MAGIC` followed by the SCALE encoded `ExecutionReplay`.
+//! It is meant to be attached to reports of diverging executions, the state itself is not
+//! included: the execution is replayed against a backend provided by the reader, which must
+//! have the recorded root.
+
+use codec::{Decode, Encode};
+use hash_db::Hasher;
+use sp_core::{
+	NativeOrEncoded, NeverNativeValue, storage::ChildInfo,
+	offchain::storage::OffchainOverlayedChanges,
+	traits::{CodeExecutor, RuntimeCode, SpawnNamed},
+};
+use sp_externalities::Extensions;
+use crate::{
+	Backend, Error, ExecutionError, ExecutionStrategy, OverlayedChanges, StateMachine,
+	overlayed_changes::{StorageCollection, ChildStorageCollection},
+};
+
+/// First bytes of a replay file.
+pub const REPLAY_MAGIC: &[u8; 8] = b"smreplay";
+
+/// Version of the replay format written by this crate.
+pub const REPLAY_VERSION: u32 = 1;
+
+/// Inputs of an execution.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct ExecutionReplay {
+	/// Version of the format, `REPLAY_VERSION` when written by this crate.
+	pub version: u32,
+	/// Called runtime method.
+	pub method: String,
+	/// Encoded call data.
+	pub call_data: Vec<u8>,
+	/// Hash of the runtime code, as in `RuntimeCode::hash`.
+	pub runtime_code_hash: Vec<u8>,
+	/// Heap pages of the runtime code.
+	pub heap_pages: Option<u64>,
+	/// Encoded storage root of the backend.
+	pub backend_root: Vec<u8>,
+	/// Top changes of the overlay before execution.
+	pub overlay_top: StorageCollection,
+	/// Child changes of the overlay before execution, by storage key of default child tries.
+	pub overlay_children: ChildStorageCollection,
+	/// Description of the extensions registered for the execution. Extensions cannot be
+	/// serialized, those of the replay are provided by the reader.
+	pub extensions: Vec<String>,
+}
+
+impl ExecutionReplay {
+	/// Serialize into the content of a replay file.
+	pub fn to_file(&self) -> Vec<u8> {
+		let mut file = REPLAY_MAGIC.to_vec();
+		self.encode_to(&mut file);
+		file
+	}
+
+	/// Deserialize the content of a replay file.
+	pub fn from_file(mut file: &[u8]) -> Result<Self, codec::Error> {
+		if !file.starts_with(REPLAY_MAGIC) {
+			return Err("Not a replay file".into());
+		}
+		file = &file[REPLAY_MAGIC.len()..];
+		let replay = Self::decode(&mut file)?;
+		if replay.version != REPLAY_VERSION {
+			return Err("Unsupported replay file version".into());
+		}
+		if !file.is_empty() {
+			return Err("Trailing bytes after replay".into());
+		}
+		Ok(replay)
+	}
+
+	/// Snapshot the values of the changes of `overlay`, as seen by its current transaction.
+	pub(crate) fn snapshot_overlay(overlay: &OverlayedChanges) -> (StorageCollection, ChildStorageCollection) {
+		overlay.export_changes()
+	}
+
+	/// Overlay with the recorded changes.
+	pub fn overlay(&self) -> OverlayedChanges {
+		let mut overlay = OverlayedChanges::default();
+		for (key, value) in self.overlay_top.iter() {
+			overlay.set_storage(key.clone(), value.clone());
+		}
+		for (storage_key, changes) in self.overlay_children.iter() {
+			let child_info = ChildInfo::new_default(storage_key);
+			for (key, value) in changes.iter() {
+				overlay.set_child_storage(&child_info, key.clone(), value.clone());
+			}
+		}
+		overlay
+	}
+}
+
+/// Execute `replay` again against `backend`, with `runtime_code`.
+///
+/// `backend` and `runtime_code` must be the recorded ones, which is checked with the recorded
+/// root and code hash. Returns the result of the execution and the overlay it produced.
+pub fn replay_execution<B, H, Exec, Spawn>(
+	replay: &ExecutionReplay,
+	backend: &B,
+	exec: &Exec,
+	spawn_handle: Spawn,
+	extensions: Extensions,
+	runtime_code: &RuntimeCode,
+	strategy: ExecutionStrategy,
+) -> Result<(Vec<u8>, OverlayedChanges), Box<dyn Error>>
+where
+	B: Backend<H>,
+	H: Hasher,
+	H::Out: Ord + 'static + codec::Codec,
+	Exec: CodeExecutor + Clone + 'static,
+	Spawn: SpawnNamed + Send + 'static,
+{
+	let root = backend.storage_root(std::iter::empty()).0.encode();
+	if root != replay.backend_root {
+		return Err(Box::new(ExecutionError::ReplayStateMismatch(
+			replay.backend_root.clone(),
+			root,
+		)));
+	}
+	if runtime_code.hash != replay.runtime_code_hash {
+		return Err(Box::new(ExecutionError::ReplayCodeMismatch(
+			replay.runtime_code_hash.clone(),
+			runtime_code.hash.clone(),
+		)));
+	}
+
+	let mut overlay = replay.overlay();
+	let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+	let result = StateMachine::<_, H, u64, Exec>::new(
+		backend,
+		None,
+		&mut overlay,
+		&mut offchain_overlay,
+		exec,
+		&replay.method,
+		&replay.call_data,
+		extensions,
+		runtime_code,
+		spawn_handle,
+	).execute_using_consensus_failure_handler::<_, NeverNativeValue, fn() -> _>(
+		strategy.get_manager(),
+		None,
+	).map(NativeOrEncoded::into_encoded)?;
+	Ok((result, overlay))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::testing::TaskExecutor;
+	use sp_runtime::traits::BlakeTwo256;
+	use crate::{
+		changes_trie, trie_backend::tests::test_trie,
+		test_helpers::{MockCall, MockExecutor},
+	};
+
+	#[test]
+	fn replays_recorded_execution() {
+		let backend = test_trie();
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(b"before".to_vec(), Some(vec![3]));
+		overlay.set_child_storage(&ChildInfo::new_default(b"sub1"), b"value3".to_vec(), None);
+		let mut offchain_overlay = OffchainOverlayedChanges::disabled();
+		let runtime_code = RuntimeCode::empty();
+		let executor = MockExecutor::new()
+			.with_method("test", MockCall::returning(vec![7]).with_write(b"key".to_vec(), Some(vec![1])));
+
+		let mut state_machine = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlay,
+			&mut offchain_overlay,
+			&executor,
+			"test",
+			&[1, 2],
+			Default::default(),
+			&runtime_code,
+			TaskExecutor::new(),
+		);
+		let replay = state_machine.replay_record(vec!["none".into()]);
+		assert_eq!(state_machine.execute(ExecutionStrategy::NativeElseWasm).unwrap(), vec![7]);
+		drop(state_machine);
+
+		let replay = ExecutionReplay::from_file(&replay.to_file()).unwrap();
+		assert_eq!(replay.method, "test");
+		assert_eq!(replay.overlay_children[0].1, vec![(b"value3".to_vec(), None)]);
+		let (result, replayed) = replay_execution::<_, BlakeTwo256, _, _>(
+			&replay,
+			&backend,
+			&executor,
+			TaskExecutor::new(),
+			Default::default(),
+			&runtime_code,
+			ExecutionStrategy::NativeElseWasm,
+		).unwrap();
+		assert_eq!(result, vec![7]);
+		assert_eq!(ExecutionReplay::snapshot_overlay(&replayed), ExecutionReplay::snapshot_overlay(&overlay));
+
+		let other_backend = crate::new_in_mem::<BlakeTwo256>();
+		assert!(replay_execution::<_, BlakeTwo256, _, _>(
+			&replay,
+			&other_backend,
+			&executor,
+			TaskExecutor::new(),
+			Default::default(),
+			&runtime_code,
+			ExecutionStrategy::NativeElseWasm,
+		).is_err());
+		assert!(ExecutionReplay::from_file(b"not a replay").is_err());
+	}
+}
@@ -0,0 +1,170 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of runtime codes by hash, shared between executions.
+
+use std::{borrow::Cow, sync::Arc};
+use linked_hash_map::LinkedHashMap;
+use parking_lot::Mutex;
+use sp_core::traits::{FetchRuntimeCode, RuntimeCode};
+
+/// Default number of runtime codes kept by a `RuntimeCodeCache`.
+pub const DEFAULT_RUNTIME_CODE_CACHE_SIZE: usize = 4;
+
+/// Cache of the runtime codes fetched by executions, keyed by code hash.
+///
+/// Clones share the cache, so a `StateMachine` can reuse the code fetched by a previous one
+/// instead of reading `:code` from its backend again.
+#[derive(Clone)]
+pub struct RuntimeCodeCache {
+	inner: Arc<Mutex<CachedCodes>>,
+}
+
+struct CachedCodes {
+	codes: LinkedHashMap<Vec<u8>, Arc<Vec<u8>>>,
+	limit: usize,
+}
+
+impl Default for RuntimeCodeCache {
+	fn default() -> Self {
+		Self::new(DEFAULT_RUNTIME_CODE_CACHE_SIZE)
+	}
+}
+
+impl RuntimeCodeCache {
+	/// Cache keeping at most `limit` codes, the least recently used are dropped first.
+	pub fn new(limit: usize) -> Self {
+		RuntimeCodeCache {
+			inner: Arc::new(Mutex::new(CachedCodes {
+				codes: LinkedHashMap::new(),
+				limit,
+			})),
+		}
+	}
+
+	/// Number of cached codes.
+	pub fn len(&self) -> usize {
+		self.inner.lock().codes.len()
+	}
+
+	/// Whether no code is cached.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Get the cached code with the given hash.
+	pub fn get(&self, hash: &[u8]) -> Option<Arc<Vec<u8>>> {
+		self.inner.lock().codes.get_refresh(hash).cloned()
+	}
+
+	/// Cache `code` under `hash`.
+	pub fn insert(&self, hash: Vec<u8>, code: Vec<u8>) {
+		let mut cached = self.inner.lock();
+		cached.codes.insert(hash, Arc::new(code));
+		while cached.codes.len() > cached.limit {
+			cached.codes.pop_front();
+		}
+	}
+
+	/// Fetcher of `runtime_code` going through this cache.
+	///
+	/// Codes with an empty hash, as `RuntimeCode::empty`, are never cached.
+	pub(crate) fn fetcher<'a>(&'a self, runtime_code: &'a RuntimeCode<'a>) -> CachedCodeFetcher<'a> {
+		let cached = if runtime_code.hash.is_empty() {
+			None
+		} else {
+			self.get(&runtime_code.hash)
+		};
+		CachedCodeFetcher {
+			cache: self,
+			runtime_code,
+			cached,
+		}
+	}
+}
+
+/// `FetchRuntimeCode` serving the code from a `RuntimeCodeCache`, and caching the code
+/// fetched on a miss.
+pub(crate) struct CachedCodeFetcher<'a> {
+	cache: &'a RuntimeCodeCache,
+	runtime_code: &'a RuntimeCode<'a>,
+	cached: Option<Arc<Vec<u8>>>,
+}
+
+impl<'a> CachedCodeFetcher<'a> {
+	/// `RuntimeCode` fetching its code through this fetcher.
+	pub(crate) fn runtime_code(&self) -> RuntimeCode {
+		RuntimeCode {
+			code_fetcher: self,
+			heap_pages: self.runtime_code.heap_pages,
+			hash: self.runtime_code.hash.clone(),
+		}
+	}
+}
+
+impl<'a> FetchRuntimeCode for CachedCodeFetcher<'a> {
+	fn fetch_runtime_code<'b>(&'b self) -> Option<Cow<'b, [u8]>> {
+		if let Some(code) = self.cached.as_ref() {
+			return Some(Cow::Borrowed(&code[..]));
+		}
+		let code = self.runtime_code.fetch_runtime_code()?;
+		if !self.runtime_code.hash.is_empty() {
+			self.cache.insert(self.runtime_code.hash.clone(), code.to_vec());
+		}
+		Some(code)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	struct CountingFetcher(AtomicUsize);
+
+	impl FetchRuntimeCode for CountingFetcher {
+		fn fetch_runtime_code<'a>(&'a self) -> Option<Cow<'a, [u8]>> {
+			self.0.fetch_add(1, Ordering::SeqCst);
+			Some(Cow::Owned(vec![1, 2, 3]))
+		}
+	}
+
+	#[test]
+	fn code_is_fetched_once_per_hash() {
+		let fetcher = CountingFetcher(AtomicUsize::new(0));
+		let runtime_code = RuntimeCode { code_fetcher: &fetcher, heap_pages: Some(8), hash: vec![1] };
+		let cache = RuntimeCodeCache::new(1);
+
+		for _ in 0..2 {
+			let cached = cache.fetcher(&runtime_code);
+			let code = cached.runtime_code();
+			assert_eq!(code.heap_pages, Some(8));
+			assert_eq!(code.fetch_runtime_code().unwrap(), &[1, 2, 3][..]);
+		}
+		assert_eq!(fetcher.0.load(Ordering::SeqCst), 1);
+
+		let other = RuntimeCode { code_fetcher: &fetcher, heap_pages: None, hash: vec![2] };
+		cache.fetcher(&other).runtime_code().fetch_runtime_code().unwrap();
+		assert_eq!(cache.len(), 1);
+		assert!(cache.get(&[1]).is_none());
+
+		let empty = RuntimeCode { code_fetcher: &fetcher, heap_pages: None, hash: Vec::new() };
+		cache.fetcher(&empty).runtime_code().fetch_runtime_code().unwrap();
+		cache.fetcher(&empty).runtime_code().fetch_runtime_code().unwrap();
+		assert_eq!(fetcher.0.load(Ordering::SeqCst), 4);
+	}
+}
@@ -47,6 +47,9 @@ pub struct UsageInfo {
 	pub cache_reads: UsageUnit,
 	/// Modified value read statistics.
 	pub modified_reads: UsageUnit,
+	/// Reads of well known keys made by the client through the state machine overlay, not
+	/// included in the other statistics.
+	pub well_known_reads: UsageUnit,
 	/// Memory used.
 	pub memory: usize,
 
@@ -73,6 +76,13 @@ pub struct StateMachineStats {
 	/// Size in bytes of the writes overlay
 	/// operation.
 	pub bytes_writes_overlay: RefCell<u64>,
+	/// Number of reads of well known keys
+	/// made by the client that hit the
+	/// state machine overlay.
+	pub reads_well_known: RefCell<u64>,
+	/// Size in bytes of the well known
+	/// keys reads.
+	pub bytes_read_well_known: RefCell<u64>,
 }
 
 impl StateMachineStats {
@@ -82,6 +92,22 @@ impl StateMachineStats {
 		*self.bytes_read_modified.borrow_mut() += *other.bytes_read_modified.borrow();
 		*self.writes_overlay.borrow_mut() += *other.writes_overlay.borrow();
 		*self.bytes_writes_overlay.borrow_mut() += *other.bytes_writes_overlay.borrow();
+		*self.reads_well_known.borrow_mut() += *other.reads_well_known.borrow();
+		*self.bytes_read_well_known.borrow_mut() += *other.bytes_read_well_known.borrow();
+	}
+
+	/// Stats accumulated since `earlier`, a previous copy of these stats.
+	pub fn since(&self, earlier: &StateMachineStats) -> StateMachineStats {
+		let since = |now: &RefCell<u64>, earlier: &RefCell<u64>|
+			RefCell::new(now.borrow().saturating_sub(*earlier.borrow()));
+		StateMachineStats {
+			reads_modified: since(&self.reads_modified, &earlier.reads_modified),
+			bytes_read_modified: since(&self.bytes_read_modified, &earlier.bytes_read_modified),
+			writes_overlay: since(&self.writes_overlay, &earlier.writes_overlay),
+			bytes_writes_overlay: since(&self.bytes_writes_overlay, &earlier.bytes_writes_overlay),
+			reads_well_known: since(&self.reads_well_known, &earlier.reads_well_known),
+			bytes_read_well_known: since(&self.bytes_read_well_known, &earlier.bytes_read_well_known),
+		}
 	}
 }
 
@@ -98,6 +124,7 @@ impl UsageInfo {
 			removed_nodes: UsageUnit::default(),
 			cache_reads: UsageUnit::default(),
 			modified_reads: UsageUnit::default(),
+			well_known_reads: UsageUnit::default(),
 			memory: 0,
 			started: Instant::now(),
 			span: Default::default(),
@@ -109,9 +136,18 @@ impl UsageInfo {
 		self.modified_reads.bytes += *count.bytes_read_modified.borrow();
 		self.overlay_writes.ops += *count.writes_overlay.borrow();
 		self.overlay_writes.bytes += *count.bytes_writes_overlay.borrow();
+		self.well_known_reads.ops += *count.reads_well_known.borrow();
+		self.well_known_reads.bytes += *count.bytes_read_well_known.borrow();
 	}
 }
 
+/// Receiver of the statistics of state machines, such as a metrics exporter.
+pub trait StatsReporter: Send + Sync {
+	/// Report the statistics of a state machine executing `method`, called when the state
+	/// machine is dropped.
+	fn report(&self, method: &str, stats: &StateMachineStats);
+}
+
 impl StateMachineStats {
 	/// Tally one read modified operation, of some length.
 	pub fn tally_read_modified(&self, data_bytes: u64) {
@@ -123,4 +159,9 @@ impl StateMachineStats {
 		*self.writes_overlay.borrow_mut() += 1;
 		*self.bytes_writes_overlay.borrow_mut() += data_bytes;
 	}
+	/// Tally one read of a well known key, of some length.
+	pub fn tally_read_well_known(&self, data_bytes: u64) {
+		*self.reads_well_known.borrow_mut() += 1;
+		*self.bytes_read_well_known.borrow_mut() += data_bytes;
+	}
 }
@@ -0,0 +1,236 @@
+// This file is part of Substrate.
+
+// Copyright (C) 2017-2020 Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: Apache-2.0
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// 	http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities to test code driving runtime executions, enabled by the `test-helpers` feature.
+
+use std::{collections::HashMap, panic::UnwindSafe, sync::Arc};
+use codec::Codec;
+use parking_lot::Mutex;
+use sp_core::{
+	NativeOrEncoded,
+	traits::{CallInWasm, CodeExecutor, Externalities, MissingHostFunctions, RuntimeCode},
+};
+use crate::{StorageKey, StorageValue};
+
+/// Scripted outcome of a runtime method called on a `MockExecutor`.
+#[derive(Clone, Debug, Default)]
+pub struct MockCall {
+	result: Option<Vec<u8>>,
+	writes: Vec<(StorageKey, Option<StorageValue>)>,
+	reads: Vec<StorageKey>,
+	storage_root: bool,
+}
+
+impl MockCall {
+	/// Call returning the encoded `result`.
+	pub fn returning(result: Vec<u8>) -> Self {
+		MockCall {
+			result: Some(result),
+			writes: Vec::new(),
+			reads: Vec::new(),
+			storage_root: false,
+		}
+	}
+
+	/// Call failing.
+	pub fn failing() -> Self {
+		MockCall {
+			result: None,
+			writes: Vec::new(),
+			reads: Vec::new(),
+			storage_root: false,
+		}
+	}
+
+	/// Write `value` at `key` when called, `None` deletes the key.
+	///
+	/// Writes are applied before the call returns, whether it fails or not.
+	pub fn with_write(mut self, key: StorageKey, value: Option<StorageValue>) -> Self {
+		self.writes.push((key, value));
+		self
+	}
+
+	/// Read `key` when called, after the writes.
+	pub fn with_read(mut self, key: StorageKey) -> Self {
+		self.reads.push(key);
+		self
+	}
+
+	/// Compute the storage root after the writes, whether the call fails or not. A succeeding
+	/// call returns it instead of its scripted result.
+	pub fn with_storage_root(mut self) -> Self {
+		self.storage_root = true;
+		self
+	}
+}
+
+/// Configurable `CodeExecutor` for tests.
+///
+/// Calls follow the `MockCall` registered for their method, `MockCall::returning(vec![])` for
+/// unregistered ones. Native and wasm execution can independently be made to fail, native
+/// execution can also be made unavailable. Clones share the log of the calls made.
+#[derive(Clone)]
+pub struct MockExecutor {
+	native_available: bool,
+	native_succeeds: bool,
+	wasm_succeeds: bool,
+	methods: HashMap<String, MockCall>,
+	calls: Arc<Mutex<Vec<(String, bool)>>>,
+}
+
+impl Default for MockExecutor {
+	fn default() -> Self {
+		MockExecutor {
+			native_available: true,
+			native_succeeds: true,
+			wasm_succeeds: true,
+			methods: HashMap::new(),
+			calls: Default::default(),
+		}
+	}
+}
+
+impl MockExecutor {
+	/// Executor with native execution available, where every call succeeds.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Script the outcome of `method`.
+	pub fn with_method(mut self, method: &str, call: MockCall) -> Self {
+		self.methods.insert(method.into(), call);
+		self
+	}
+
+	/// Whether native execution is available.
+	pub fn with_native_available(mut self, available: bool) -> Self {
+		self.native_available = available;
+		self
+	}
+
+	/// Whether native calls succeed, a failing native call fails whatever its `MockCall`.
+	pub fn with_native_succeeds(mut self, succeeds: bool) -> Self {
+		self.native_succeeds = succeeds;
+		self
+	}
+
+	/// Whether wasm calls succeed, a failing wasm call fails whatever its `MockCall`.
+	pub fn with_wasm_succeeds(mut self, succeeds: bool) -> Self {
+		self.wasm_succeeds = succeeds;
+		self
+	}
+
+	/// Calls made so far, as the method name and whether native execution was used.
+	pub fn calls(&self) -> Vec<(String, bool)> {
+		self.calls.lock().clone()
+	}
+
+	fn run(&self, ext: &mut dyn Externalities, method: &str, native: bool) -> Result<Vec<u8>, String> {
+		self.calls.lock().push((method.into(), native));
+		let call = self.methods.get(method).cloned()
+			.unwrap_or_else(|| MockCall::returning(Vec::new()));
+		for (key, value) in call.writes {
+			ext.place_storage(key, value);
+		}
+		for key in call.reads {
+			ext.storage(&key);
+		}
+		let storage_root = if call.storage_root { Some(ext.storage_root()) } else { None };
+		let result = call.result.map(|result| storage_root.unwrap_or(result));
+		let succeeds = if native { self.native_succeeds } else { self.wasm_succeeds };
+		match result {
+			Some(result) if succeeds => Ok(result),
+			_ => Err(format!(
+				"Mock {} call to `{}` failed",
+				if native { "native" } else { "wasm" },
+				method,
+			)),
+		}
+	}
+}
+
+impl CodeExecutor for MockExecutor {
+	type Error = String;
+
+	fn call<
+		R: Codec + PartialEq,
+		NC: FnOnce() -> Result<R, String> + UnwindSafe,
+	>(
+		&self,
+		ext: &mut dyn Externalities,
+		_runtime_code: &RuntimeCode,
+		method: &str,
+		_data: &[u8],
+		use_native: bool,
+		_native_call: Option<NC>,
+	) -> (Result<NativeOrEncoded<R>, Self::Error>, bool) {
+		let using_native = use_native && self.native_available;
+		(self.run(ext, method, using_native).map(NativeOrEncoded::Encoded), using_native)
+	}
+}
+
+impl CallInWasm for MockExecutor {
+	fn call_in_wasm(
+		&self,
+		_wasm_code: &[u8],
+		_code_hash: Option<Vec<u8>>,
+		method: &str,
+		_call_data: &[u8],
+		ext: &mut dyn Externalities,
+		_missing_host_functions: MissingHostFunctions,
+	) -> Result<Vec<u8>, String> {
+		self.run(ext, method, false)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use sp_core::testing::TaskExecutor;
+	use crate::{
+		changes_trie, trie_backend::tests::test_trie, ExecutionStrategy, OverlayedChanges,
+		StateMachine,
+	};
+
+	#[test]
+	fn native_else_wasm_falls_back_to_wasm() {
+		let backend = test_trie();
+		let mut overlay = OverlayedChanges::default();
+		let mut offchain_overlay = Default::default();
+		let runtime_code = RuntimeCode::empty();
+		let executor = MockExecutor::new()
+			.with_native_succeeds(false)
+			.with_method("test", MockCall::returning(vec![7]).with_write(b"key".to_vec(), Some(vec![1])));
+
+		let result = StateMachine::new(
+			&backend,
+			changes_trie::disabled_state::<_, u64>(),
+			&mut overlay,
+			&mut offchain_overlay,
+			&executor,
+			"test",
+			&[],
+			Default::default(),
+			&runtime_code,
+			TaskExecutor::new(),
+		).execute(ExecutionStrategy::NativeElseWasm).unwrap();
+
+		assert_eq!(result, vec![7]);
+		assert_eq!(executor.calls(), vec![("test".to_string(), true), ("test".to_string(), false)]);
+		assert_eq!(overlay.storage(b"key"), Some(Some(&[1][..])));
+	}
+}
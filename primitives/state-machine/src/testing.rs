@@ -60,6 +60,18 @@ where
 	changes_trie_config: Option<ChangesTrieConfiguration>,
 	changes_trie_storage: ChangesTrieInMemoryStorage<H, N>,
 	extensions: Extensions,
+	checkpoints: Vec<Checkpoint<H>>,
+}
+
+/// Handle to a state of `TestExternalities`, created by `TestExternalities::checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointHandle(usize);
+
+/// State saved by `TestExternalities::checkpoint`.
+struct Checkpoint<H: Hasher> where H::Out: codec::Codec + Ord {
+	overlay: OverlayedChanges,
+	offchain_overlay: OffchainOverlayedChanges,
+	backend: InMemoryBackend<H>,
 }
 
 impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
@@ -124,6 +136,7 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 			changes_trie_storage: ChangesTrieInMemoryStorage::new(),
 			backend: storage.into(),
 			storage_transaction_cache: Default::default(),
+			checkpoints: Vec::new(),
 		}
 	}
 
@@ -153,7 +166,7 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 	}
 
 	/// Return a new backend with all pending value.
-	pub fn commit_all(&self) -> InMemoryBackend<H> {
+	pub fn as_backend(&self) -> InMemoryBackend<H> {
 		let top: Vec<_> = self.overlay.changes()
 			.map(|(k, v)| (k.clone(), v.value().cloned()))
 			.collect();
@@ -171,6 +184,57 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		self.backend.update(transaction)
 	}
 
+	/// Commit all pending changes to the backend, leaving the overlay empty.
+	///
+	/// Changes tries are not built. Fails if a storage transaction is still open.
+	pub fn commit_all(&mut self) -> Result<(), String> {
+		if self.overlay.transaction_depth() > 0 {
+			return Err("Changes can not be committed with open transactions".into());
+		}
+		let changes = self.overlay.drain_storage_changes::<_, _, N>(
+			&self.backend,
+			None,
+			Default::default(),
+			&mut Default::default(),
+		)?;
+		self.backend = self.backend.update_backend(
+			changes.transaction_storage_root,
+			changes.transaction,
+		);
+		self.storage_transaction_cache = Default::default();
+		Ok(())
+	}
+
+	/// Save the current storage, to come back to it with `rollback_to`.
+	///
+	/// The backend and the pending changes are copied, extensions and the changes trie storage
+	/// are not saved.
+	pub fn checkpoint(&mut self) -> CheckpointHandle {
+		self.checkpoints.push(Checkpoint {
+			overlay: self.overlay.clone(),
+			offchain_overlay: self.offchain_overlay.clone(),
+			backend: self.backend.clone(),
+		});
+		CheckpointHandle(self.checkpoints.len() - 1)
+	}
+
+	/// Restore the storage saved by `checkpoint`.
+	///
+	/// The checkpoint stays valid and can be rolled back to again, checkpoints created after it
+	/// are dropped. Panics if `checkpoint` was dropped by a previous rollback.
+	pub fn rollback_to(&mut self, checkpoint: CheckpointHandle) {
+		assert!(
+			checkpoint.0 < self.checkpoints.len(),
+			"Rolling back to a checkpoint dropped by a previous rollback",
+		);
+		self.checkpoints.truncate(checkpoint.0 + 1);
+		let saved = &self.checkpoints[checkpoint.0];
+		self.overlay = saved.overlay.clone();
+		self.offchain_overlay = saved.offchain_overlay.clone();
+		self.backend = saved.backend.clone();
+		self.storage_transaction_cache = Default::default();
+	}
+
 	/// Execute the given closure while `self` is set as externalities.
 	///
 	/// Returns the result of the given closure.
@@ -195,7 +259,7 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> PartialEq for TestExternalities<H, N>
 	/// This doesn't test if they are in the same state, only if they contains the
 	/// same data at this state
 	fn eq(&self, other: &TestExternalities<H, N>) -> bool {
-		self.commit_all().eq(&other.commit_all())
+		self.as_backend().eq(&other.as_backend())
 	}
 }
 
@@ -269,6 +333,50 @@ mod tests {
 		assert_eq!(&ext.storage(CODE).unwrap(), &code);
 	}
 
+	#[test]
+	fn rollback_to_checkpoint_restores_storage() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert(b"doe".to_vec(), b"reindeer".to_vec());
+		ext.ext().set_storage(b"dog".to_vec(), b"puppy".to_vec());
+		let root = ext.ext().storage_root();
+		let checkpoint = ext.checkpoint();
+
+		ext.insert(b"doe".to_vec(), b"deer".to_vec());
+		ext.ext().set_storage(b"dog".to_vec(), b"wolf".to_vec());
+		let inner = ext.checkpoint();
+		ext.ext().clear_storage(b"doe");
+		assert!(ext.ext().storage_root() != root);
+
+		ext.rollback_to(checkpoint);
+		assert_eq!(ext.ext().storage(b"doe"), Some(b"reindeer".to_vec()));
+		assert_eq!(ext.ext().storage(b"dog"), Some(b"puppy".to_vec()));
+		assert_eq!(ext.ext().storage_root(), root);
+
+		ext.ext().set_storage(b"cat".to_vec(), b"kitten".to_vec());
+		ext.rollback_to(checkpoint);
+		assert_eq!(ext.ext().storage(b"cat"), None);
+		assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ext.rollback_to(inner))).is_err());
+	}
+
+	#[test]
+	fn commit_all_moves_changes_to_backend() {
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::default();
+		ext.insert(b"doe".to_vec(), b"reindeer".to_vec());
+		ext.ext().set_storage(b"dog".to_vec(), b"puppy".to_vec());
+		ext.ext().clear_storage(b"doe");
+		let root = ext.ext().storage_root();
+
+		ext.commit_all().unwrap();
+		assert!(ext.overlay.changes().next().is_none());
+		assert_eq!(ext.backend.storage(b"dog").unwrap(), Some(b"puppy".to_vec()));
+		assert_eq!(ext.backend.storage(b"doe").unwrap(), None);
+		assert_eq!(ext.backend.root().as_ref(), &root[..]);
+		assert_eq!(ext.ext().storage_root(), root);
+
+		ext.overlay.start_transaction();
+		assert!(ext.commit_all().is_err());
+	}
+
 	#[test]
 	fn check_send() {
 		fn assert_send<T: Send>() {}
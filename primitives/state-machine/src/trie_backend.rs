@@ -28,6 +28,13 @@ use crate::{
 	trie_backend_essence::{TrieBackendEssence, TrieBackendStorage, Ephemeral},
 };
 
+/// Number of changed child tries from which their roots are computed on several threads.
+const PARALLEL_CHILD_ROOTS_THRESHOLD: usize = 4;
+/// Maximum number of threads computing child trie roots.
+const MAX_CHILD_ROOTS_THREADS: usize = 8;
+/// Number of trie descents used to estimate the number and size of the pairs.
+const ESTIMATE_WALKS: u32 = 64;
+
 /// Patricia trie-based backend. Transaction type is an overlay of changes to commit.
 pub struct TrieBackend<S: TrieBackendStorage<H>, H: Hasher> {
 	pub (crate) essence: TrieBackendEssence<S, H>,
@@ -84,6 +91,14 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		self.essence.storage(key)
 	}
 
+	fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, Self::Error> {
+		self.essence.storage_hash(key)
+	}
+
+	fn storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<StorageValue>>, Self::Error> {
+		self.essence.storage_batch(keys)
+	}
+
 	fn child_storage(
 		&self,
 		child_info: &ChildInfo,
@@ -92,6 +107,14 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		self.essence.child_storage(child_info, key)
 	}
 
+	fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, Self::Error> {
+		self.essence.child_storage_hash(child_info, key)
+	}
+
 	fn next_storage_key(&self, key: &[u8]) -> Result<Option<StorageKey>, Self::Error> {
 		self.essence.next_storage_key(key)
 	}
@@ -231,6 +254,42 @@ impl<S: TrieBackendStorage<H>, H: Hasher> Backend<H> for TrieBackend<S, H> where
 		(root, is_default, write_overlay)
 	}
 
+	fn child_storage_roots<'a>(
+		&self,
+		child_deltas: Vec<(&'a ChildInfo, Vec<(&'a [u8], Option<&'a [u8]>)>)>,
+	) -> Vec<(H::Out, bool, Self::Transaction)> where H::Out: Ord {
+		if child_deltas.len() < PARALLEL_CHILD_ROOTS_THRESHOLD {
+			return child_deltas.into_iter()
+				.map(|(child_info, delta)| self.child_storage_root(child_info, delta.into_iter()))
+				.collect();
+		}
+		// The `SpawnNamed` handle given to `StateMachine` only runs `'static` tasks, and is not
+		// reachable from a backend: the tasks borrow `self` and the deltas, so scoped threads are
+		// used instead. Each thread computes the roots of a contiguous chunk, joining the threads
+		// in order keeps the results in the order of `child_deltas`.
+		let threads = std::cmp::min(MAX_CHILD_ROOTS_THREADS, child_deltas.len());
+		let chunk_size = (child_deltas.len() + threads - 1) / threads;
+		crossbeam_utils::thread::scope(|scope| {
+			let handles: Vec<_> = child_deltas.chunks(chunk_size)
+				.map(|chunk| scope.spawn(move |_| chunk.iter()
+					.map(|(child_info, delta)| self.child_storage_root(child_info, delta.iter().cloned()))
+					.collect::<Vec<_>>()
+				))
+				.collect();
+			handles.into_iter()
+				.flat_map(|handle| handle.join().expect("Child trie root computation panicked"))
+				.collect()
+		}).expect("Child trie root computation panicked")
+	}
+
+	fn estimate_pairs_count(&self) -> Result<u64, Self::Error> {
+		self.essence.estimate_pairs(ESTIMATE_WALKS).map(|(count, _)| count)
+	}
+
+	fn estimate_storage_size(&self) -> Result<u64, Self::Error> {
+		self.essence.estimate_pairs(ESTIMATE_WALKS).map(|(_, size)| size)
+	}
+
 	fn as_trie_backend(&mut self) -> Option<&TrieBackend<Self::TrieBackendStorage, H>> {
 		Some(self)
 	}
@@ -309,6 +368,37 @@ pub mod tests {
 		assert_eq!(test_trie().storage(b"non-existing-key").unwrap(), None);
 	}
 
+	#[test]
+	fn storage_hash_matches_value_hash() {
+		let test_trie = test_trie();
+		let child_info = ChildInfo::new_default(CHILD_KEY_1);
+		assert_eq!(test_trie.storage_hash(b"key").unwrap(), Some(BlakeTwo256::hash(b"value")));
+		assert_eq!(test_trie.storage_hash(b"non-existing-key").unwrap(), None);
+		assert_eq!(
+			test_trie.child_storage_hash(&child_info, b"value3").unwrap(),
+			Some(BlakeTwo256::hash(&[142])),
+		);
+		assert_eq!(test_trie.child_storage_hash(&child_info, b"value1").unwrap(), None);
+	}
+
+	#[test]
+	fn storage_batch_matches_single_reads() {
+		let test_trie = test_trie();
+		let keys: Vec<&[u8]> = vec![
+			&b"value2"[..],
+			&b"non-existing-key"[..],
+			&b"key"[..],
+			&[130][..],
+			&b"value1"[..],
+		];
+		let expected = keys.iter()
+			.map(|key| test_trie.storage(key).unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(test_trie.storage_batch(&keys).unwrap(), expected);
+		assert_eq!(expected[1], None);
+		assert_eq!(expected[3], Some(vec![130]));
+	}
+
 	#[test]
 	fn pairs_are_not_empty_on_non_empty_storage() {
 		assert!(!test_trie().pairs().is_empty());
@@ -322,6 +412,50 @@ pub mod tests {
 		).pairs().is_empty());
 	}
 
+	#[test]
+	fn child_infos_lists_child_tries() {
+		assert_eq!(test_trie().child_infos(), vec![ChildInfo::new_default(CHILD_KEY_1)]);
+		let empty = TrieBackend::<PrefixedMemoryDB<BlakeTwo256>, BlakeTwo256>::new(
+			PrefixedMemoryDB::default(),
+			Default::default(),
+		);
+		assert!(empty.child_infos().is_empty());
+	}
+
+	#[test]
+	fn keys_iter_streams_keys_with_prefix() {
+		let trie = test_trie();
+		let keys: Vec<_> = trie.keys_iter(b"value").collect::<Result<_, _>>().unwrap();
+		assert_eq!(keys, vec![b"value1".to_vec(), b"value2".to_vec()]);
+		let keys: Vec<_> = trie.keys_iter(b"value1").collect::<Result<_, _>>().unwrap();
+		assert_eq!(keys, vec![b"value1".to_vec()]);
+		assert_eq!(trie.keys_iter(&[]).count(), trie.keys(&[]).len());
+
+		let child_info = ChildInfo::new_default(CHILD_KEY_1);
+		let keys: Vec<_> = trie.child_keys_iter(&child_info, &[]).collect::<Result<_, _>>().unwrap();
+		assert_eq!(keys, vec![b"value3".to_vec(), b"value4".to_vec()]);
+	}
+
+	#[test]
+	fn pairs_estimates_are_close_to_exact_values() {
+		let empty = TrieBackend::<PrefixedMemoryDB<BlakeTwo256>, BlakeTwo256>::new(
+			PrefixedMemoryDB::default(),
+			BlakeTwo256::hash(&[0u8]),
+		);
+		assert_eq!(empty.estimate_pairs_count().unwrap(), 0);
+		assert_eq!(empty.estimate_storage_size().unwrap(), 0);
+		assert!(test_trie().estimate_pairs_count().unwrap() > 0);
+
+		let storage: std::collections::BTreeMap<_, _> = (0u32..1000)
+			.map(|i| (BlakeTwo256::hash(&i.encode()).as_ref().to_vec(), vec![0u8; 32]))
+			.collect();
+		let backend = TrieBackend::<sp_trie::MemoryDB<BlakeTwo256>, BlakeTwo256>::from(storage);
+		let count = backend.estimate_pairs_count().unwrap();
+		assert!(count > 500 && count < 2000, "Estimated {} pairs", count);
+		let size = backend.estimate_storage_size().unwrap();
+		assert!(size > 32_000 && size < 128_000, "Estimated {} bytes", size);
+	}
+
 	#[test]
 	fn storage_root_is_non_default() {
 		assert!(test_trie().storage_root(iter::empty()).0 != H256::repeat_byte(0));
@@ -341,6 +475,84 @@ pub mod tests {
 		assert!(new_root != test_trie().storage_root(iter::empty()).0);
 	}
 
+	#[test]
+	fn full_storage_root_reports_child_root_deltas() {
+		let trie = test_trie();
+		let child_info = ChildInfo::new_default(CHILD_KEY_1);
+		let old_root = trie.storage(child_info.prefixed_storage_key().as_slice()).unwrap()
+			.map(|r| H256::decode(&mut &r[..]).unwrap())
+			.unwrap();
+		let child_delta = || iter::once((&b"new-key"[..], Some(&b"new-value"[..])));
+
+		let (root, deltas, _) = trie.full_storage_root_with_child_deltas(
+			iter::empty(),
+			iter::once((&child_info, child_delta())),
+		);
+		assert_eq!(root, trie.full_storage_root(iter::empty(), iter::once((&child_info, child_delta()))).0);
+		assert_eq!(deltas.len(), 1);
+		assert_eq!(deltas[0].0, child_info);
+		assert_eq!(deltas[0].1, old_root);
+		assert!(deltas[0].2 != old_root);
+
+		let (_, deltas, _) = trie.full_storage_root_with_child_deltas(
+			iter::empty(),
+			iter::once((&child_info, iter::empty())),
+		);
+		assert!(deltas.is_empty());
+	}
+
+	#[test]
+	fn full_storage_root_skips_undecodable_old_child_root() {
+		let child_info = ChildInfo::new_default(CHILD_KEY_1);
+		let storage: std::collections::BTreeMap<_, _> = iter::once(
+			(child_info.prefixed_storage_key().into_inner(), b"not a root".to_vec()),
+		).collect();
+		let trie: TrieBackend<sp_trie::MemoryDB<BlakeTwo256>, BlakeTwo256> = storage.into();
+
+		let (root, deltas, _) = trie.full_storage_root_with_child_deltas(
+			iter::empty(),
+			iter::once((&child_info, iter::once((&b"new-key"[..], Some(&b"new-value"[..]))))),
+		);
+		assert!(deltas.is_empty());
+		assert!(root != trie.storage_root(iter::empty()).0);
+	}
+
+	#[test]
+	fn child_storage_roots_match_single_computations() {
+		let trie = test_trie();
+		let child_infos: Vec<_> = (0..6u8)
+			.map(|i| {
+				let storage_key = if i == 0 { CHILD_KEY_1.to_vec() } else { vec![i] };
+				ChildInfo::new_default(&storage_key)
+			})
+			.collect();
+		let values: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i]).collect();
+		let child_deltas: Vec<_> = child_infos.iter()
+			.zip(values.iter())
+			.map(|(child_info, value)| {
+				let delta: Vec<(&[u8], Option<&[u8]>)> = match value[0] {
+					3 => vec![],
+					_ => vec![(&b"new-key"[..], Some(&value[..]))],
+				};
+				(child_info, delta)
+			})
+			.collect();
+		assert!(child_deltas.len() >= PARALLEL_CHILD_ROOTS_THRESHOLD);
+
+		let expected: Vec<_> = child_deltas.iter()
+			.map(|(child_info, delta)| trie.child_storage_root(child_info, delta.iter().cloned()))
+			.collect();
+		let roots = trie.child_storage_roots(child_deltas);
+		assert_eq!(roots.len(), expected.len());
+		for ((root, empty, mut tx), (expected_root, expected_empty, mut expected_tx)) in roots.into_iter()
+			.zip(expected.into_iter())
+		{
+			assert_eq!(root, expected_root);
+			assert_eq!(empty, expected_empty);
+			assert_eq!(tx.drain(), expected_tx.drain());
+		}
+	}
+
 	#[test]
 	fn prefix_walking_works() {
 		let trie = test_trie();
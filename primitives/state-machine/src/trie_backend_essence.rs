@@ -18,14 +18,17 @@
 //! Trie-based state machine backend essence used to read values
 //! from storage.
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ops::Deref;
 use std::sync::Arc;
 use log::{debug, warn};
-use hash_db::{self, Hasher, Prefix};
+use hash_db::{self, Hasher, Prefix, EMPTY_PREFIX};
 use sp_trie::{Trie, MemoryDB, PrefixedMemoryDB, DBValue,
 	empty_child_trie_root, read_trie_value, read_child_trie_value,
 	for_keys_in_child_trie, KeySpacedDB, TrieDBIterator};
 use sp_trie::trie_types::{TrieDB, TrieError, Layout};
+use trie_db::{TrieLayout, NodeCodec, NibbleVec, NibbleSlice, node::{Node, NodeHandle}};
 use crate::{backend::Consolidate, StorageKey, StorageValue};
 use sp_core::storage::ChildInfo;
 use codec::Encode;
@@ -164,6 +167,29 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 		read_trie_value::<Layout<H>, _>(self, &self.root, key).map_err(map_e)
 	}
 
+	/// Get the hash of the value of storage at given key.
+	///
+	/// The value is hashed in place in the trie node holding it, it is not copied.
+	pub fn storage_hash(&self, key: &[u8]) -> Result<Option<H::Out>, String> {
+		let map_e = |e| format!("Trie lookup error: {}", e);
+
+		TrieDB::<H>::new(self, &self.root).map_err(map_e)?
+			.get_with(key, |value: &[u8]| H::hash(value))
+			.map_err(map_e)
+	}
+
+	/// Get the values at the given keys, in the order of `keys`.
+	///
+	/// Lookups share the trie nodes they fetch: nodes on the path of several keys are read once
+	/// from the storage.
+	pub fn storage_batch(&self, keys: &[&[u8]]) -> Result<Vec<Option<StorageValue>>, String> {
+		let map_e = |e| format!("Trie lookup error: {}", e);
+
+		let nodes = BatchNodes { essence: self, fetched: Default::default() };
+		let trie = TrieDB::<H>::new(&nodes, &self.root).map_err(map_e)?;
+		keys.iter().map(|key| trie.get(key).map_err(map_e)).collect()
+	}
+
 	/// Get the value of child storage at given key.
 	pub fn child_storage(
 		&self,
@@ -179,6 +205,28 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 			.map_err(map_e)
 	}
 
+	/// Get the hash of the value of child storage at given key.
+	///
+	/// The value is hashed in place in the trie node holding it, it is not copied.
+	pub fn child_storage_hash(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<H::Out>, String> {
+		let encoded_root = self.child_root(child_info)?
+			.unwrap_or_else(|| empty_child_trie_root::<Layout<H>>().encode());
+		// root is fetched from DB, not writable by runtime, so it's always valid.
+		let mut root = H::Out::default();
+		root.as_mut().copy_from_slice(&encoded_root[..]);
+
+		let map_e = |e| format!("Trie lookup error: {}", e);
+
+		let db = KeySpacedDB::new(self, child_info.keyspace());
+		TrieDB::<H>::new(&db, &root).map_err(map_e)?
+			.get_with(key, |value: &[u8]| H::hash(value))
+			.map_err(map_e)
+	}
+
 	/// Retrieve all entries keys of child storage and call `f` for each of those keys.
 	pub fn for_keys_in_child_storage<F: FnMut(&[u8])>(
 		&self,
@@ -263,6 +311,71 @@ impl<S: TrieBackendStorage<H>, H: Hasher> TrieBackendEssence<S, H> where H::Out:
 	pub fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
 		self.keys_values_with_prefix_inner(&self.root, prefix, f, None)
 	}
+
+	/// Estimate the number of key/value pairs of the trie and their total size in bytes, keys
+	/// included, from `walks` descents from the root.
+	///
+	/// Each descent follows one pseudo-randomly chosen child of every branch and weights the
+	/// values met by the product of the numbers of children of the branches above them, which
+	/// gives unbiased estimates. Descents only depend on the trie, so do the estimates.
+	pub fn estimate_pairs(&self, walks: u32) -> Result<(u64, u64), String> {
+		if self.root == self.empty {
+			return Ok((0, 0));
+		}
+		let walks = std::cmp::max(walks, 1);
+		let (mut count, mut size) = (0f64, 0f64);
+		for walk in 0..walks {
+			let mut weight = 1f64;
+			let mut key = NibbleVec::new();
+			let mut data = self.node_data(&self.root, EMPTY_PREFIX)?;
+			for depth in 0u32.. {
+				let node = <Layout<H> as TrieLayout>::Codec::decode(&data)
+					.map_err(|e| format!("Trie node decoding error: {:?}", e))?;
+				let (partial, children, value) = match node {
+					Node::Empty => break,
+					Node::Leaf(partial, value) => (partial, None, Some(value)),
+					Node::Extension(..) => return Err("Unexpected trie extension node".into()),
+					Node::Branch(children, value) => (NibbleSlice::new(&[]), Some(children), value),
+					Node::NibbledBranch(partial, children, value) => (partial, Some(children), value),
+				};
+				for i in 0..partial.len() {
+					key.push(partial.at(i));
+				}
+				if let Some(value) = value {
+					count += weight;
+					size += weight * (key.len() / 2 + value.len()) as f64;
+				}
+				let children: Vec<_> = match children {
+					Some(children) => children.iter()
+						.enumerate()
+						.filter_map(|(index, child)| child.clone().map(|child| (index as u8, child)))
+						.collect(),
+					None => break,
+				};
+				if children.is_empty() {
+					break;
+				}
+				let choice = H::hash(&(walk, depth).encode()).as_ref()[0] as usize % children.len();
+				weight *= children.len() as f64;
+				let (index, child) = children[choice].clone();
+				key.push(index);
+				data = match child {
+					NodeHandle::Hash(hash) => {
+						let mut child_hash = H::Out::default();
+						child_hash.as_mut().copy_from_slice(hash);
+						self.node_data(&child_hash, key.as_prefix())?
+					},
+					NodeHandle::Inline(inline) => inline.to_vec(),
+				};
+			}
+		}
+		Ok(((count / walks as f64) as u64, (size / walks as f64) as u64))
+	}
+
+	fn node_data(&self, hash: &H::Out, prefix: Prefix) -> Result<DBValue, String> {
+		self.storage.get(hash, prefix)?
+			.ok_or_else(|| format!("Trie node {:?} is missing", hash))
+	}
 }
 
 pub(crate) struct Ephemeral<'a, S: 'a + TrieBackendStorage<H>, H: 'a + Hasher> {
@@ -418,6 +531,31 @@ impl<S: TrieBackendStorage<H>, H: Hasher> hash_db::HashDBRef<H, DBValue>
 	}
 }
 
+/// Nodes fetched by the lookups of a `storage_batch` call.
+///
+/// Nodes are content addressed within a trie, so they are cached by hash only.
+struct BatchNodes<'a, S: TrieBackendStorage<H>, H: Hasher> {
+	essence: &'a TrieBackendEssence<S, H>,
+	fetched: RefCell<HashMap<H::Out, Option<DBValue>>>,
+}
+
+impl<'a, S: TrieBackendStorage<H>, H: Hasher> hash_db::HashDBRef<H, DBValue>
+	for BatchNodes<'a, S, H>
+{
+	fn get(&self, key: &H::Out, prefix: Prefix) -> Option<DBValue> {
+		if let Some(node) = self.fetched.borrow().get(key) {
+			return node.clone();
+		}
+		let node = hash_db::HashDB::get(self.essence, key, prefix);
+		self.fetched.borrow_mut().insert(key.clone(), node.clone());
+		node
+	}
+
+	fn contains(&self, key: &H::Out, prefix: Prefix) -> bool {
+		hash_db::HashDBRef::get(self, key, prefix).is_some()
+	}
+}
+
 
 #[cfg(test)]
 mod test {